@@ -0,0 +1,116 @@
+//! Throughput of the sled enrich stage, applying the changes of each block on
+//! its own against coalescing them with `batch_blocks`
+//!
+//! Every run enriches the same block over and over into a fresh db: its
+//! outputs are inserted again and its inputs looked up and removed, so each
+//! block writes about as much as it would during a sync.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+use gasket::runtime::Worker as _;
+use scrolls::enrich::sled::{Config, Worker};
+use scrolls::model;
+
+/// A multiple of the batch sizes, so that the last batch is full and
+/// applied without waiting for the input to go idle
+const BLOCKS: usize = 500;
+
+static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+struct Stage {
+    worker: Worker,
+    upstream: OutputPort<model::RawBlockPayload>,
+    downstream: TwoPhaseInputPort<model::EnrichedBlockPayload>,
+    dir: std::path::PathBuf,
+}
+
+impl Stage {
+    fn new(batch_blocks: Option<usize>) -> Self {
+        let run = RUNS.fetch_add(1, Ordering::Relaxed);
+        let dir = std::env::temp_dir().join(format!(
+            "scrolls-bench-enrich-{}-{}",
+            std::process::id(),
+            run
+        ));
+
+        let config = Config {
+            db_path: Some(dir.to_string_lossy().to_string()),
+            batch_blocks,
+            ..Default::default()
+        };
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, BLOCKS);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, BLOCKS);
+
+        let mut worker = Worker::new(config, Default::default(), input, output);
+        worker.bootstrap().unwrap();
+
+        Self {
+            worker,
+            upstream,
+            downstream,
+            dir,
+        }
+    }
+
+    fn run(&mut self, cbor: &[u8]) {
+        for _ in 0..BLOCKS {
+            self.upstream
+                .send(model::RawBlockPayload::roll_forward(cbor.to_vec()))
+                .unwrap();
+        }
+
+        for _ in 0..BLOCKS {
+            self.worker.work().unwrap();
+        }
+
+        for _ in 0..BLOCKS {
+            self.downstream.recv_or_idle().unwrap();
+            self.downstream.commit();
+        }
+    }
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn enrich_batch(c: &mut Criterion) {
+    let cbor = hex::decode(include_str!("../assets/test.block")).unwrap();
+
+    let mut group = c.benchmark_group("enrich_sled");
+    group.throughput(Throughput::Elements(BLOCKS as u64));
+    group.sample_size(10);
+
+    for batch_blocks in [None, Some(10), Some(100)] {
+        let name = match batch_blocks {
+            Some(x) => format!("batch_blocks_{}", x),
+            None => "per_block".to_string(),
+        };
+
+        group.bench_function(name, |b| {
+            b.iter_batched(
+                || Stage::new(batch_blocks),
+                |mut stage| {
+                    stage.run(&cbor);
+                    stage
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, enrich_batch);
+criterion_main!(benches);
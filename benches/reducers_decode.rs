@@ -0,0 +1,108 @@
+//! Throughput of the reducers stage, decoding each block on the stage thread
+//! against decoding the buffered ones ahead on a pool with `decode_ahead`
+//!
+//! The stage is spawned as it is in the daemon and fed the same block over
+//! and over, a run is over once every block got its `BlockFinished`.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+use scrolls::{bootstrap, crosscut, model, reducers};
+
+const BLOCKS: usize = 500;
+
+struct Stage {
+    pipeline: bootstrap::Pipeline,
+    upstream: OutputPort<model::EnrichedBlockPayload>,
+    downstream: TwoPhaseInputPort<model::CRDTCommand>,
+}
+
+impl Stage {
+    fn spawn(decode_ahead: Option<usize>) -> Self {
+        let configs: Vec<reducers::Config> = serde_json::from_value(serde_json::json!([
+            { "type": "PointByTx" },
+            { "type": "PoolByStake" },
+        ]))
+        .unwrap();
+
+        let mut reducer = reducers::Bootstrapper::new(
+            configs,
+            &crosscut::ChainWellKnownInfo::mainnet(),
+            &Default::default(),
+            decode_ahead,
+            None,
+        );
+
+        let mut upstream = OutputPort::default();
+        connect_ports(&mut upstream, reducer.borrow_input_port(), BLOCKS);
+
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(reducer.borrow_output_port(), &mut downstream, BLOCKS * 10);
+
+        let mut pipeline = bootstrap::Pipeline::new();
+        reducer.spawn_stages(&mut pipeline);
+
+        Self {
+            pipeline,
+            upstream,
+            downstream,
+        }
+    }
+
+    fn run(&mut self, cbor: &[u8]) {
+        for _ in 0..BLOCKS {
+            self.upstream
+                .send(model::EnrichedBlockPayload::roll_forward(
+                    cbor.to_vec(),
+                    Default::default(),
+                ))
+                .unwrap();
+        }
+
+        let mut finished = 0;
+
+        while finished < BLOCKS {
+            match self.downstream.recv_or_idle() {
+                Ok(msg) => {
+                    if let model::CRDTCommand::BlockFinished(_) = msg.payload {
+                        finished += 1;
+                    }
+
+                    self.downstream.commit();
+                }
+                Err(gasket::error::Error::RecvIdle) => continue,
+                Err(err) => panic!("reducers stage failed: {:?}", err),
+            }
+        }
+    }
+}
+
+impl Drop for Stage {
+    fn drop(&mut self) {
+        for tether in self.pipeline.tethers.drain(..) {
+            let _ = tether.dismiss_stage();
+        }
+    }
+}
+
+fn reducers_decode(c: &mut Criterion) {
+    let cbor = hex::decode(include_str!("../assets/test.block")).unwrap();
+
+    let mut group = c.benchmark_group("reducers_decode");
+    group.throughput(Throughput::Elements(BLOCKS as u64));
+    group.sample_size(10);
+
+    for decode_ahead in [None, Some(4), Some(16)] {
+        let name = match decode_ahead {
+            Some(x) => format!("decode_ahead_{}", x),
+            None => "on_stage_thread".to_string(),
+        };
+
+        let mut stage = Stage::spawn(decode_ahead);
+        group.bench_function(name, |b| b.iter(|| stage.run(&cbor)));
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, reducers_decode);
+criterion_main!(benches);
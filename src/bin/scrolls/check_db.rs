@@ -0,0 +1,56 @@
+use clap;
+use scrolls::enrich;
+
+use crate::daemon::ConfigRoot;
+
+/// Reports the health of the enrich db without running the pipeline
+///
+/// Nothing is written to the db, but sled still takes its exclusive lock so
+/// this can't run next to a daemon using the same db.
+pub fn run(args: &Args) -> Result<(), scrolls::Error> {
+    env_logger::init();
+
+    let config = ConfigRoot::new(&args.config)
+        .map_err(|err| scrolls::Error::ConfigError(format!("{:?}", err)))?;
+
+    let store = match config.enrich {
+        Some(enrich::Config::Sled(x)) => x.open_store()?,
+        _ => return Err(scrolls::Error::config("no sled enrich configured")),
+    };
+
+    let report = store.check()?;
+
+    println!("utxos: {}", report.utxo_count);
+
+    match report.address_index_count {
+        Some(x) => println!("address index entries: {}", x),
+        None => println!("address index: disabled"),
+    }
+
+    match report.ring_size {
+        Some(x) => println!("consumed ring blocks: {}", x),
+        None => println!("consumed ring: disabled"),
+    }
+
+    if report.issues.is_empty() {
+        println!("no issues found");
+        return Ok(());
+    }
+
+    for issue in report.issues.iter() {
+        println!("  - {}", issue);
+    }
+
+    Err(scrolls::Error::message(format!(
+        "found {} issues in the enrich db",
+        report.issues.len()
+    )))
+}
+
+#[derive(clap::Args)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    #[clap(long, value_parser)]
+    //#[clap(description = "config file to load")]
+    config: Option<std::path::PathBuf>,
+}
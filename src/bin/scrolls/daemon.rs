@@ -34,15 +34,21 @@ impl From<ChainConfig> for crosscut::ChainWellKnownInfo {
 }
 
 #[derive(Deserialize)]
-struct ConfigRoot {
-    source: sources::Config,
-    enrich: Option<enrich::Config>,
-    reducers: Vec<reducers::Config>,
-    storage: storage::Config,
-    intersect: crosscut::IntersectConfig,
+pub(crate) struct ConfigRoot {
+    pub(crate) source: sources::Config,
+    pub(crate) enrich: Option<enrich::Config>,
+    pub(crate) reducers: Vec<reducers::Config>,
+    pub(crate) storage: storage::Config,
+    pub(crate) intersect: crosscut::IntersectConfig,
     finalize: Option<crosscut::FinalizeConfig>,
-    chain: Option<ChainConfig>,
-    policy: Option<crosscut::policies::RuntimePolicy>,
+    pub(crate) chain: Option<ChainConfig>,
+    pub(crate) policy: Option<crosscut::policies::RuntimePolicy>,
+    pub(crate) threads: Option<crosscut::threads::Config>,
+    pub(crate) sample_every: Option<u64>,
+    pub(crate) dedup_block_commands: Option<bool>,
+    pub(crate) duplicate_block_window: Option<usize>,
+    pub(crate) volatile: Option<reducers::confirmation::Config>,
+    pub(crate) epoch_commit: Option<reducers::epoch_commit::Config>,
 }
 
 impl ConfigRoot {
@@ -67,7 +73,7 @@ impl ConfigRoot {
     }
 }
 
-fn should_stop(pipeline: &bootstrap::Pipeline) -> bool {
+pub(crate) fn should_stop(pipeline: &bootstrap::Pipeline) -> bool {
     pipeline
         .tethers
         .iter()
@@ -80,7 +86,39 @@ fn should_stop(pipeline: &bootstrap::Pipeline) -> bool {
         })
 }
 
-fn shutdown(pipeline: bootstrap::Pipeline) {
+fn metrics_snapshot(pipeline: &bootstrap::Pipeline) -> Vec<String> {
+    pipeline
+        .tethers
+        .iter()
+        .map(|tether| match tether.read_metrics() {
+            Ok(readings) => format!("{:?}", readings),
+            Err(_) => String::new(),
+        })
+        .collect()
+}
+
+/// Waits until the metrics of every stage stop changing
+///
+/// When the source finishes on its own (finalize config), the blocks it
+/// already sent might still be in-flight in later stages. Since ports can't be
+/// inspected, we consider the pipeline drained once no stage reports progress.
+pub(crate) fn wait_for_drain(pipeline: &bootstrap::Pipeline) {
+    let mut last = metrics_snapshot(pipeline);
+
+    loop {
+        std::thread::sleep(Duration::from_millis(1500));
+
+        let current = metrics_snapshot(pipeline);
+
+        if current == last {
+            break;
+        }
+
+        last = current;
+    }
+}
+
+pub(crate) fn shutdown(pipeline: bootstrap::Pipeline) {
     for tether in pipeline.tethers {
         let state = tether.check_state();
         log::warn!("dismissing stage: {} with state {:?}", tether.name(), state);
@@ -97,6 +135,27 @@ fn shutdown(pipeline: bootstrap::Pipeline) {
     }
 }
 
+#[cfg(target_family = "unix")]
+fn serve_control(args: &Args, resume: &crosscut::ready::ReadyGate) -> Result<(), scrolls::Error> {
+    match &args.control_socket {
+        Some(path) => crosscut::control::serve(path, resume.clone()),
+        None if args.paused => Err(scrolls::Error::ConfigError(
+            "--paused requires a --control-socket to resume".into(),
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(not(target_family = "unix"))]
+fn serve_control(args: &Args, _resume: &crosscut::ready::ReadyGate) -> Result<(), scrolls::Error> {
+    match (&args.control_socket, args.paused) {
+        (None, false) => Ok(()),
+        _ => Err(scrolls::Error::ConfigError(
+            "the control socket is only available on unix".into(),
+        )),
+    }
+}
+
 pub fn run(args: &Args) -> Result<(), scrolls::Error> {
     console::initialize(&args.console);
 
@@ -106,17 +165,56 @@ pub fn run(args: &Args) -> Result<(), scrolls::Error> {
     let chain = config.chain.unwrap_or_default().into();
     let policy = config.policy.unwrap_or_default().into();
 
+    let finalize = match args.once {
+        true => Some(config.finalize.unwrap_or_default().stop_at_tip()),
+        false => config.finalize,
+    };
+
     let source = config
         .source
-        .bootstrapper(&chain, &config.intersect, &config.finalize, &policy);
+        .bootstrapper(&chain, &config.intersect, &finalize, &policy)?;
+
+    let threads = config.threads.unwrap_or_default();
+
+    let mut enrich = config
+        .enrich
+        .unwrap_or_default()
+        .bootstrapper(&policy, threads.enrich_pool_size());
+
+    let mut reducer = reducers::Bootstrapper::new(
+        config.reducers,
+        &chain,
+        &policy,
+        threads.reducers_decode_ahead(),
+        threads.reducers_pool_size(),
+    );
+
+    reducer.sample_every(config.sample_every);
+    reducer.dedup_block_commands(config.dedup_block_commands.unwrap_or(false));
+    reducer.idle_sleep(threads.reducers_idle_sleep());
+    reducer.skip_duplicate_blocks(config.duplicate_block_window);
+    reducer.confirm_blocks(config.volatile);
+    reducer.commit_by_epoch(config.epoch_commit)?;
 
-    let enrich = config.enrich.unwrap_or_default().bootstrapper(&policy);
+    let mut storage = config.storage.plugin(&chain, &config.intersect, &policy)?;
+    storage.record_recent_points(config.duplicate_block_window);
 
-    let reducer = reducers::Bootstrapper::new(config.reducers, &chain, &policy);
+    // only the cursor of the daemon tells where the enrich db should be, the
+    // rebuild runs keep cursors of their own
+    enrich.reconcile_with(storage.build_cursor());
 
-    let storage = config.storage.plugin(&chain, &config.intersect, &policy);
+    let resume = match args.paused {
+        true => crosscut::ready::ReadyGate::default(),
+        false => crosscut::ready::ReadyGate::opened(),
+    };
 
-    let pipeline = bootstrap::build(source, enrich, reducer, storage)?;
+    serve_control(args, &resume)?;
+
+    let pipeline = bootstrap::build(source, enrich, reducer, storage, &threads, resume)?;
+
+    if args.paused {
+        log::warn!("scrolls started paused, send `resume` to the control socket to start");
+    }
 
     log::info!("scrolls is running...");
 
@@ -127,6 +225,8 @@ pub fn run(args: &Args) -> Result<(), scrolls::Error> {
 
     log::info!("Scrolls is stopping...");
 
+    wait_for_drain(&pipeline);
+
     shutdown(pipeline);
 
     Ok(())
@@ -142,4 +242,16 @@ pub struct Args {
     #[clap(long, value_parser)]
     //#[clap(description = "type of progress to display")],
     console: Option<console::Mode>,
+
+    #[clap(long, action)]
+    //#[clap(description = "process up to the current tip and exit")]
+    once: bool,
+
+    #[clap(long, action)]
+    //#[clap(description = "start without consuming the chain until resumed")]
+    paused: bool,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "unix socket accepting control commands")]
+    control_socket: Option<std::path::PathBuf>,
 }
@@ -0,0 +1,80 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+use clap;
+use scrolls::{crosscut, enrich};
+
+use crate::daemon::ConfigRoot;
+
+/// Seeds an empty enrich db with a utxo snapshot instead of replaying the
+/// chain from the origin
+///
+/// The pipeline has to resume right after the snapshot point, otherwise the
+/// utxo set would miss (or double apply) the blocks in between. The storage
+/// cursor has to be at that point or, for a fresh storage, the `[intersect]`
+/// config has to point at it.
+pub fn run(args: &Args) -> Result<(), scrolls::Error> {
+    env_logger::init();
+
+    let config = ConfigRoot::new(&args.config)
+        .map_err(|err| scrolls::Error::ConfigError(format!("{:?}", err)))?;
+
+    let enrich = match config.enrich {
+        Some(enrich::Config::Sled(x)) => x,
+        _ => return Err(scrolls::Error::config("no sled enrich configured")),
+    };
+
+    let file = File::open(&args.snapshot).map_err(scrolls::Error::storage)?;
+    let mut reader = BufReader::new(file);
+
+    let mut header = String::new();
+    reader
+        .read_line(&mut header)
+        .map_err(scrolls::Error::storage)?;
+
+    let (slot, hash) = enrich::sled::parse_snapshot_point(&header)?;
+
+    let chain = config.chain.unwrap_or_default().into();
+    let policy = config.policy.unwrap_or_default().into();
+
+    let cursor = config
+        .storage
+        .plugin(&chain, &config.intersect, &policy)?
+        .build_cursor()
+        .last_point()?;
+
+    match (cursor, &config.intersect) {
+        (Some(crosscut::PointArg::Specific(s, h)), _) if s == slot && h == hash => (),
+        (Some(point), _) => {
+            return Err(scrolls::Error::config(format!(
+                "the storage cursor is at {:?}, not at the snapshot point {},{}",
+                point, slot, hash
+            )))
+        }
+        (None, crosscut::IntersectConfig::Point(s, h)) if *s == slot && *h == hash => (),
+        (None, _) => {
+            return Err(scrolls::Error::config(format!(
+                "the storage has no cursor, set the intersect config to the snapshot point {},{}",
+                slot, hash
+            )))
+        }
+    }
+
+    let imported = enrich.import_snapshot(reader)?;
+
+    println!("imported {} utxos as of slot {}", imported, slot);
+
+    Ok(())
+}
+
+#[derive(clap::Args)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    #[clap(long, value_parser)]
+    //#[clap(description = "config file to load")]
+    config: Option<std::path::PathBuf>,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "utxo snapshot file to import")]
+    snapshot: std::path::PathBuf,
+}
@@ -1,8 +1,12 @@
 use clap::Parser;
 use std::process;
 
+mod check_db;
 mod console;
 mod daemon;
+mod import_snapshot;
+mod rebuild;
+mod replay;
 
 #[derive(Parser)]
 #[clap(name = "Scrolls")]
@@ -10,6 +14,10 @@ mod daemon;
 #[clap(author, version, about, long_about = None)]
 enum Scrolls {
     Daemon(daemon::Args),
+    CheckDb(check_db::Args),
+    Rebuild(rebuild::Args),
+    ImportSnapshot(import_snapshot::Args),
+    Replay(replay::Args),
 }
 
 fn main() {
@@ -17,6 +25,10 @@ fn main() {
 
     let result = match args {
         Scrolls::Daemon(x) => daemon::run(&x),
+        Scrolls::CheckDb(x) => check_db::run(&x),
+        Scrolls::Rebuild(x) => rebuild::run(&x),
+        Scrolls::ImportSnapshot(x) => import_snapshot::run(&x),
+        Scrolls::Replay(x) => replay::run(&x),
     };
 
     if let Err(err) = &result {
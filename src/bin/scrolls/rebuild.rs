@@ -0,0 +1,183 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use clap;
+use scrolls::{bootstrap, crosscut, reducers, storage};
+
+use crate::console;
+use crate::daemon::{self, ConfigRoot};
+
+/// Rebuilds the collection of a single reducer, leaving the others untouched
+///
+/// The keys under the prefix are deleted and the chain is replayed from the
+/// start point up to the block the store is currently at, through that
+/// reducer only. The replay keeps its own cursor, so an interrupted rebuild
+/// resumes where it stopped instead of clearing the prefix again, and the
+/// cursor of the daemon is never moved.
+///
+/// The daemon has to be stopped while this runs: its writes to the prefix
+/// would be lost or doubled. The sled enrich db of the daemon is left alone,
+/// the replay builds its own next to it (suffixed with `rebuild.{reducer}`)
+/// and deletes it once done. It only holds the outputs produced since the
+/// start point, so reducers that look up consumed utxos need a replay from
+/// the origin.
+pub fn run(args: &Args) -> Result<(), scrolls::Error> {
+    console::initialize(&args.console);
+
+    let config = ConfigRoot::new(&args.config)
+        .map_err(|err| scrolls::Error::ConfigError(format!("{:?}", err)))?;
+
+    let chain = config.chain.unwrap_or_default().into();
+    let policy = config.policy.unwrap_or_default().into();
+
+    let redis = match config.storage {
+        storage::Config::Redis(x) => x,
+        _ => {
+            return Err(scrolls::Error::config(
+                "rebuild only supports the redis storage",
+            ))
+        }
+    };
+
+    let target = match redis
+        .clone()
+        .bootstrapper(&chain, &config.intersect)
+        .build_cursor()
+        .last_point()?
+    {
+        Some(crosscut::PointArg::Specific(slot, hash)) => (slot, hash),
+        _ => {
+            return Err(scrolls::Error::config(
+                "the store has no cursor, nothing to rebuild",
+            ))
+        }
+    };
+
+    let mut replay = redis.clone();
+    replay.cursor_key = Some(format!("{}.rebuild.{}", redis.cursor_key(), args.reducer));
+
+    let resumed = replay
+        .clone()
+        .bootstrapper(&chain, &config.intersect)
+        .build_cursor()
+        .last_point()?;
+
+    let threads = config.threads.unwrap_or_default();
+
+    let scratch = config
+        .enrich
+        .unwrap_or_default()
+        .scratch(&format!("rebuild.{}", args.reducer));
+
+    match resumed {
+        Some(point) => log::warn!("resuming the rebuild from {:?}", point),
+        None => {
+            let prefix = crosscut::keys::Prefix::new(
+                Some(&args.key_prefix),
+                args.key_separator.as_deref(),
+            );
+
+            let removed = redis.clear_prefix(prefix)?;
+            log::warn!("cleared {} keys under {}", removed, args.key_prefix);
+
+            // left over by a rebuild that stopped before its first block
+            scratch.remove_db()?;
+        }
+    }
+
+    let intersect = match &args.from {
+        Some(raw) => match crosscut::PointArg::from_str(raw)? {
+            crosscut::PointArg::Origin => crosscut::IntersectConfig::Origin,
+            crosscut::PointArg::Specific(slot, hash) => {
+                crosscut::IntersectConfig::Point(slot, hash)
+            }
+        },
+        None => config.intersect.clone(),
+    };
+
+    let finalize = Some(crosscut::FinalizeConfig::default().stop_at_hash(target.1.clone()));
+
+    let source = config
+        .source
+        .bootstrapper(&chain, &intersect, &finalize, &policy)?;
+
+    let enrich = scratch
+        .clone()
+        .bootstrapper(&policy, threads.enrich_pool_size());
+
+    let mut reducer = reducers::Bootstrapper::new(
+        config.reducers,
+        &chain,
+        &policy,
+        threads.reducers_decode_ahead(),
+        threads.reducers_pool_size(),
+    );
+
+    reducer.retain_only(&args.reducer)?;
+    reducer.sample_every(config.sample_every);
+    reducer.dedup_block_commands(config.dedup_block_commands.unwrap_or(false));
+    reducer.idle_sleep(threads.reducers_idle_sleep());
+    reducer.skip_duplicate_blocks(config.duplicate_block_window);
+
+    let mut storage = storage::Config::Redis(replay.clone()).plugin(&chain, &intersect, &policy)?;
+    storage.record_recent_points(config.duplicate_block_window);
+
+    let resume = crosscut::ready::ReadyGate::opened();
+    let pipeline = bootstrap::build(source, enrich, reducer, storage, &threads, resume)?;
+
+    log::info!("rebuilding {} up to slot {}", args.reducer, target.0);
+
+    while !daemon::should_stop(&pipeline) {
+        console::refresh(&args.console, &pipeline);
+        std::thread::sleep(Duration::from_millis(1500));
+    }
+
+    daemon::wait_for_drain(&pipeline);
+    daemon::shutdown(pipeline);
+
+    let reached = replay
+        .clone()
+        .bootstrapper(&chain, &intersect)
+        .build_cursor()
+        .last_point()?;
+
+    match reached {
+        Some(crosscut::PointArg::Specific(_, hash)) if hash == target.1 => {
+            replay.clear_cursor()?;
+            scratch.remove_db()?;
+            log::info!("{} rebuilt", args.reducer);
+            Ok(())
+        }
+        _ => Err(scrolls::Error::message(
+            "the replay stopped before reaching the cursor of the store, run the same command again to resume",
+        )),
+    }
+}
+
+#[derive(clap::Args)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    #[clap(long, value_parser)]
+    //#[clap(description = "config file to load")]
+    config: Option<std::path::PathBuf>,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "type of progress to display")],
+    console: Option<console::Mode>,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "name of the reducer to rebuild, e.g. balance_by_address")]
+    reducer: String,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "key prefix of the collection to clear")]
+    key_prefix: String,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "separator of the key parts, as set in the reducer config, defaults to `.`")]
+    key_separator: Option<String>,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "point to replay from (`slot,hash` or `origin`), defaults to the intersect config")]
+    from: Option<String>,
+}
@@ -0,0 +1,76 @@
+use std::time::Duration;
+
+use clap;
+use scrolls::{bootstrap, enrich, reducers};
+
+use crate::console;
+use crate::daemon::{self, ConfigRoot};
+
+/// Runs the reducers over a capture of enriched blocks, without a node or an
+/// enrich db
+///
+/// The capture is written by the sled enrich stage when `capture_path` is
+/// set. Blocks are replayed from the start of the file into the storage of
+/// the config, the source and enrich sections are ignored. The replay stops
+/// once the whole file was reduced.
+pub fn run(args: &Args) -> Result<(), scrolls::Error> {
+    console::initialize(&args.console);
+
+    let config = ConfigRoot::new(&args.config)
+        .map_err(|err| scrolls::Error::ConfigError(format!("{:?}", err)))?;
+
+    let chain = config.chain.unwrap_or_default().into();
+    let policy = config.policy.unwrap_or_default().into();
+
+    let threads = config.threads.unwrap_or_default();
+
+    let replay = enrich::capture::Bootstrapper::new(&args.capture.to_string_lossy());
+
+    let mut reducer = reducers::Bootstrapper::new(
+        config.reducers,
+        &chain,
+        &policy,
+        threads.reducers_decode_ahead(),
+        threads.reducers_pool_size(),
+    );
+
+    reducer.sample_every(config.sample_every);
+    reducer.dedup_block_commands(config.dedup_block_commands.unwrap_or(false));
+    reducer.idle_sleep(threads.reducers_idle_sleep());
+    reducer.skip_duplicate_blocks(config.duplicate_block_window);
+    reducer.confirm_blocks(config.volatile);
+    reducer.commit_by_epoch(config.epoch_commit)?;
+
+    let mut storage = config.storage.plugin(&chain, &config.intersect, &policy)?;
+    storage.record_recent_points(config.duplicate_block_window);
+
+    let pipeline = bootstrap::build_replay(replay, reducer, storage, &threads)?;
+
+    log::info!("replaying {}", args.capture.display());
+
+    while !daemon::should_stop(&pipeline) {
+        console::refresh(&args.console, &pipeline);
+        std::thread::sleep(Duration::from_millis(1500));
+    }
+
+    daemon::wait_for_drain(&pipeline);
+    daemon::shutdown(pipeline);
+
+    Ok(())
+}
+
+#[derive(clap::Args)]
+#[clap(author, version, about, long_about = None)]
+pub struct Args {
+    #[clap(long, value_parser)]
+    //#[clap(description = "config file to load")]
+    config: Option<std::path::PathBuf>,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "type of progress to display")],
+    console: Option<console::Mode>,
+
+    #[clap(long, value_parser)]
+    //#[clap(description = "capture file written by the enrich stage")]
+    capture: std::path::PathBuf,
+}
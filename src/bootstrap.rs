@@ -1,4 +1,5 @@
-use crate::{enrich, reducers, sources, storage};
+use crate::crosscut::threads::with_affinity;
+use crate::{crosscut, enrich, reducers, sources, storage};
 
 use gasket::{messaging::connect_ports, runtime::Tether};
 
@@ -23,15 +24,71 @@ pub fn build(
     mut enrich: enrich::Bootstrapper,
     mut reducer: reducers::Bootstrapper,
     mut storage: storage::Bootstrapper,
+    threads: &crosscut::threads::Config,
+    resume: crosscut::ready::ReadyGate,
 ) -> Result<Pipeline, crate::Error> {
     let cursor = storage.build_cursor();
+    reducer.seed_recent_blocks(storage.build_cursor());
+
+    let enrich_ready = enrich.ready_gate();
+    let enrich_inflight = enrich.inflight();
 
     let mut pipeline = Pipeline::new();
 
     connect_ports(source.borrow_output_port(), enrich.borrow_input_port(), 100);
 
+    // in fused mode the reducers stage reads the source output directly
+    if !threads.fused() {
+        connect_ports(
+            enrich.borrow_output_port(),
+            reducer.borrow_input_port(),
+            100,
+        );
+    }
+
     connect_ports(
-        enrich.borrow_output_port(),
+        reducer.borrow_output_port(),
+        storage.borrow_input_port(),
+        100,
+    );
+
+    // stage threads inherit the affinity of this thread at spawn time
+    with_affinity(threads.source.as_ref(), || {
+        source.spawn_stages(&mut pipeline, cursor, enrich_ready, enrich_inflight, resume)
+    });
+
+    if threads.fused() {
+        with_affinity(threads.reducers.as_ref(), || {
+            reducer.spawn_fused(enrich, &mut pipeline)
+        });
+    } else {
+        with_affinity(threads.enrich.as_ref(), || {
+            enrich.spawn_stages(&mut pipeline)
+        });
+        with_affinity(threads.reducers.as_ref(), || {
+            reducer.spawn_stages(&mut pipeline)
+        });
+    }
+
+    with_affinity(threads.storage.as_ref(), || {
+        storage.spawn_stages(&mut pipeline)
+    });
+
+    Ok(pipeline)
+}
+
+/// Builds a pipeline feeding the reducers from a capture of enriched blocks
+/// instead of a source and an enrich stage, see `enrich::capture`
+pub fn build_replay(
+    mut replay: enrich::capture::Bootstrapper,
+    mut reducer: reducers::Bootstrapper,
+    mut storage: storage::Bootstrapper,
+    threads: &crosscut::threads::Config,
+) -> Result<Pipeline, crate::Error> {
+    let mut pipeline = Pipeline::new();
+
+    connect_ports(
+        replay.borrow_output_port(),
         reducer.borrow_input_port(),
         100,
     );
@@ -42,10 +99,17 @@ pub fn build(
         100,
     );
 
-    source.spawn_stages(&mut pipeline, cursor);
-    enrich.spawn_stages(&mut pipeline);
-    reducer.spawn_stages(&mut pipeline);
-    storage.spawn_stages(&mut pipeline);
+    with_affinity(threads.source.as_ref(), || {
+        replay.spawn_stages(&mut pipeline)
+    });
+
+    with_affinity(threads.reducers.as_ref(), || {
+        reducer.spawn_stages(&mut pipeline)
+    });
+
+    with_affinity(threads.storage.as_ref(), || {
+        storage.spawn_stages(&mut pipeline)
+    });
 
     Ok(pipeline)
 }
@@ -0,0 +1,222 @@
+//! Address resolution shared by reducers keyed by address
+
+use pallas::ledger::addresses::{Address, ShelleyDelegationPart, ShelleyPaymentPart, StakeAddress};
+use pallas::ledger::traverse::MultiEraOutput;
+use serde::Deserialize;
+
+use super::policies::UnknownAddressAction;
+
+/// Key under which outputs with an undecodable address are bucketed
+pub const UNKNOWN_ADDRESS: &str = "unknown-address";
+
+/// Key under which enterprise addresses are grouped when bucketed
+pub const NO_STAKE: &str = "no-stake";
+
+/// What reducers grouping by stake do with enterprise addresses, the shelley
+/// addresses without a stake part
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+pub enum EnterpriseHandling {
+    /// Key each one by its own address
+    Separate,
+    /// Group all of them under the `no-stake` key
+    Bucketed,
+    /// Leave their outputs out
+    Skip,
+}
+
+impl Default for EnterpriseHandling {
+    fn default() -> Self {
+        EnterpriseHandling::Separate
+    }
+}
+
+/// Decodes the address of outputs, applying the `unknown_addresses` policy
+/// to the ones that can't be decoded instead of failing the stage
+pub struct AddressResolver {
+    action: UnknownAddressAction,
+    unknown: gasket::metrics::Counter,
+}
+
+impl AddressResolver {
+    pub fn new(action: Option<UnknownAddressAction>) -> Self {
+        AddressResolver {
+            action: action.unwrap_or_default(),
+            unknown: Default::default(),
+        }
+    }
+
+    /// Address of the output, none if it can't be decoded. Those are counted
+    /// as unknown whatever the policy.
+    pub fn address(&self, output: &MultiEraOutput) -> Option<Address> {
+        match output.address() {
+            Ok(x) => Some(x),
+            Err(err) => {
+                log::debug!("output with unknown address: {}", err);
+                self.unknown.inc(1);
+                None
+            }
+        }
+    }
+
+    /// Address of the output as rendered by `render`, `unknown-address` if it
+    /// can't be decoded and the policy buckets them, none if it skips them
+    pub fn key(
+        &self,
+        output: &MultiEraOutput,
+        render: impl FnOnce(Address) -> String,
+    ) -> Option<String> {
+        match (self.address(output), self.action) {
+            (Some(x), _) => Some(render(x)),
+            (None, UnknownAddressAction::Bucket) => Some(UNKNOWN_ADDRESS.to_string()),
+            (None, UnknownAddressAction::Skip) => None,
+        }
+    }
+
+    /// Key of the output for the reducers grouping by stake, see
+    /// `stake_or_enterprise`. Undecodable addresses are handled as in `key`.
+    pub fn stake_key(
+        &self,
+        output: &MultiEraOutput,
+        enterprise: EnterpriseHandling,
+    ) -> Option<String> {
+        match (self.address(output), self.action) {
+            (Some(x), _) => stake_or_enterprise(x, enterprise),
+            (None, UnknownAddressAction::Bucket) => Some(UNKNOWN_ADDRESS.to_string()),
+            (None, UnknownAddressAction::Skip) => None,
+        }
+    }
+
+    pub fn unknown(&self) -> &gasket::metrics::Counter {
+        &self.unknown
+    }
+}
+
+/// Groups shelley addresses by their stake address when they have one, any
+/// other address stands on its own
+pub fn stake_or_address(address: Address) -> String {
+    match address {
+        Address::Shelley(s) => match StakeAddress::try_from(s.clone()) {
+            Ok(x) => x.to_bech32().unwrap_or_else(|_| s.to_string()),
+            Err(_) => s.to_string(),
+        },
+        x => x.to_string(),
+    }
+}
+
+/// Like `stake_or_address`, with the enterprise addresses handled as
+/// configured: none if they're skipped
+pub fn stake_or_enterprise(address: Address, enterprise: EnterpriseHandling) -> Option<String> {
+    let is_enterprise = match &address {
+        Address::Shelley(s) => matches!(s.delegation(), ShelleyDelegationPart::Null),
+        _ => false,
+    };
+
+    match (is_enterprise, enterprise) {
+        (true, EnterpriseHandling::Bucketed) => Some(NO_STAKE.to_string()),
+        (true, EnterpriseHandling::Skip) => None,
+        _ => Some(stake_or_address(address)),
+    }
+}
+
+/// Hash of the script locking the address, none for key addresses
+pub fn script_hash(address: &Address) -> Option<String> {
+    match address {
+        Address::Shelley(x) => match x.payment() {
+            ShelleyPaymentPart::Script(hash) => Some(hash.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        script_hash, stake_or_address, stake_or_enterprise, AddressResolver, EnterpriseHandling,
+        NO_STAKE, UNKNOWN_ADDRESS,
+    };
+    use crate::crosscut::policies::UnknownAddressAction;
+    use pallas::codec::minicbor;
+    use pallas::codec::utils::Bytes;
+    use pallas::ledger::addresses::Address;
+    use pallas::ledger::primitives::alonzo;
+    use pallas::ledger::traverse::{Era, MultiEraOutput};
+
+    #[test]
+    fn groups_by_stake_when_available() {
+        let addr = Address::from_bech32("addr1q86gknmykuldcngv0atyy56ex598p6m8f24nf9nmehmgpgfcmswqs6wnpls37lh7s3du977cxw67a9dpndnmafjs08asyqxe39").unwrap();
+
+        assert_eq!(
+            stake_or_address(addr),
+            "stake1uyudc8qgd8fslcgl0mlggk7zl0vr8d0wjksekea75eg8n7cw33m0s"
+        );
+    }
+
+    #[test]
+    fn enterprise_addresses_follow_the_handling() {
+        // header type 6, a payment key hash and no stake part
+        let mut bytes = vec![0x61];
+        bytes.extend([0x11; 28]);
+        let enterprise = Address::from_bytes(&bytes).unwrap();
+
+        assert_eq!(
+            stake_or_enterprise(enterprise.clone(), EnterpriseHandling::Separate),
+            Some(enterprise.to_string())
+        );
+
+        assert_eq!(
+            stake_or_enterprise(enterprise.clone(), EnterpriseHandling::Bucketed),
+            Some(NO_STAKE.to_string())
+        );
+
+        assert_eq!(
+            stake_or_enterprise(enterprise, EnterpriseHandling::Skip),
+            None
+        );
+
+        // addresses with a stake part are grouped by it whatever the handling
+        let staked = Address::from_bech32("addr1q86gknmykuldcngv0atyy56ex598p6m8f24nf9nmehmgpgfcmswqs6wnpls37lh7s3du977cxw67a9dpndnmafjs08asyqxe39").unwrap();
+
+        assert_eq!(
+            stake_or_enterprise(staked, EnterpriseHandling::Skip),
+            Some("stake1uyudc8qgd8fslcgl0mlggk7zl0vr8d0wjksekea75eg8n7cw33m0s".to_string())
+        );
+    }
+
+    #[test]
+    fn only_script_addresses_have_a_hash() {
+        let key = Address::from_bech32("addr1q86gknmykuldcngv0atyy56ex598p6m8f24nf9nmehmgpgfcmswqs6wnpls37lh7s3du977cxw67a9dpndnmafjs08asyqxe39").unwrap();
+        assert_eq!(script_hash(&key), None);
+
+        let script =
+            Address::from_bech32("addr1w8phkx6acpnf78fuvxn0mkew3l0fd058hzquvz7w36x4gtcyjy7wx")
+                .unwrap();
+
+        assert_eq!(
+            script_hash(&script).as_deref(),
+            Some("c37b1b5dc0669f1d3c61a6fddb2e8fde96be87b881c60bce8e8d542f")
+        );
+    }
+
+    #[test]
+    fn unknown_addresses_follow_the_policy() {
+        // header type 9 isn't assigned to any kind of address
+        let output = alonzo::TransactionOutput {
+            address: Bytes::from(vec![0x90; 29]),
+            amount: alonzo::Value::Coin(2_000_000),
+            datum_hash: None,
+        };
+
+        let cbor = minicbor::to_vec(&output).unwrap();
+        let output = MultiEraOutput::decode(Era::Alonzo, &cbor).unwrap();
+
+        let skip = AddressResolver::new(None);
+        assert_eq!(skip.key(&output, |x| x.to_string()), None);
+
+        let bucket = AddressResolver::new(Some(UnknownAddressAction::Bucket));
+        assert_eq!(
+            bucket.key(&output, |x| x.to_string()),
+            Some(UNKNOWN_ADDRESS.to_string())
+        );
+    }
+}
@@ -117,6 +117,14 @@ pub enum IntersectConfig {
     Origin,
     Point(u64, String),
     Fallbacks(Vec<(u64, String)>),
+    /// Start N slots behind the tip of the node at the time of connecting
+    ///
+    /// The chain is walked from the closest well-known point of the chain
+    /// info before the computed slot (the first Shelley block on mainnet) and
+    /// blocks before the slot are skipped. Reducers that accumulate values
+    /// over the whole history (balances, counters, supplies) produce invalid
+    /// results under this mode since they never see the earlier blocks.
+    TipMinus(u64),
 }
 
 impl IntersectConfig {
@@ -130,6 +138,16 @@ impl IntersectConfig {
         }
     }
 
+    /// Slot of the first block to process, relative to the given tip
+    pub fn get_start_slot(&self, tip: &Point) -> Option<u64> {
+        match self {
+            IntersectConfig::TipMinus(slots) => {
+                Some(tip.slot_or_default().saturating_sub(*slots))
+            }
+            _ => None,
+        }
+    }
+
     pub fn get_fallbacks(&self) -> Option<Vec<Point>> {
         match self {
             IntersectConfig::Fallbacks(all) => {
@@ -152,13 +170,36 @@ impl IntersectConfig {
 ///   1. a block with the given hash
 ///   2. the first block on or after a given absolute slot
 ///   3. TODO: a total of X blocks 
-#[derive(Deserialize, Debug, Clone)]
+///
+/// Independently, `at_tip` stops the source once it reaches the tip of the
+/// chain, which is useful for batch-style runs.
+#[derive(Deserialize, Debug, Clone, Default)]
 pub struct FinalizeConfig {
     until_hash: Option<String>,
     max_block_slot: Option<u64>,
+    at_tip: Option<bool>,
     // max_block_quantity: Option<u64>,
 }
 
+impl FinalizeConfig {
+    pub fn stop_at_tip(mut self) -> Self {
+        self.at_tip = Some(true);
+        self
+    }
+
+    pub fn stop_at_hash(mut self, hash: String) -> Self {
+        self.until_hash = Some(hash);
+        self
+    }
+}
+
+pub fn should_finalize_at_tip(config: &Option<FinalizeConfig>) -> bool {
+    match config {
+        Some(x) => x.at_tip.unwrap_or(false),
+        None => false,
+    }
+}
+
 pub fn should_finalize(
     config: &Option<FinalizeConfig>,
     last_point: &Point,
@@ -318,3 +359,24 @@ impl Default for ChainWellKnownInfo {
         Self::mainnet()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::IntersectConfig;
+
+    #[test]
+    fn tip_minus_start_slot() {
+        let tip = Point::Specific(90_000, vec![1u8; 32]);
+
+        let intersect = IntersectConfig::TipMinus(3_600);
+        assert_eq!(intersect.get_start_slot(&tip), Some(86_400));
+
+        // can't go further back than the origin
+        let intersect = IntersectConfig::TipMinus(100_000);
+        assert_eq!(intersect.get_start_slot(&tip), Some(0));
+
+        assert_eq!(IntersectConfig::Tip.get_start_slot(&tip), None);
+    }
+}
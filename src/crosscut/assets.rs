@@ -0,0 +1,231 @@
+use bech32::{ToBase32, Variant};
+use blake2::digest::{Update, VariableOutput};
+use blake2::Blake2bVar;
+use pallas::crypto::hash::Hash;
+use pallas::ledger::traverse::MultiEraOutput;
+use serde::Deserialize;
+
+/// Length in bytes of the CIP-14 asset fingerprint hash
+pub const FINGERPRINT_HASH_LEN: usize = 20;
+
+const FINGERPRINT_HRP: &str = "asset";
+
+/// Computes the raw Blake2b-160 hash of `policy_id ++ asset_name` (CIP-14)
+pub fn fingerprint_hash(policy_id: &[u8], asset_name: &[u8]) -> [u8; FINGERPRINT_HASH_LEN] {
+    let mut hasher = Blake2bVar::new(FINGERPRINT_HASH_LEN).unwrap();
+    hasher.update(policy_id);
+    hasher.update(asset_name);
+
+    let mut buf = [0u8; FINGERPRINT_HASH_LEN];
+    hasher.finalize_variable(&mut buf).unwrap();
+
+    buf
+}
+
+/// Computes the bech32 asset fingerprint as defined by CIP-14
+pub fn asset_fingerprint(policy_id: &[u8], asset_name: &[u8]) -> Result<String, bech32::Error> {
+    let hash = fingerprint_hash(policy_id, asset_name);
+    bech32::encode(FINGERPRINT_HRP, hash.to_base32(), Variant::Bech32)
+}
+
+/// Checks that `expected` is the CIP-14 fingerprint of the given asset
+pub fn verify_fingerprint(policy_id: &[u8], asset_name: &[u8], expected: &str) -> bool {
+    match asset_fingerprint(policy_id, asset_name) {
+        Ok(x) => x == expected,
+        Err(_) => false,
+    }
+}
+
+/// How asset names are rendered in keys and values
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum NameEncoding {
+    Hex,
+    /// Names as text, assets whose name isn't valid UTF-8 are skipped
+    Ascii,
+    /// Names as text, invalid UTF-8 sequences are replaced
+    Utf8Lossy,
+    Base64,
+}
+
+impl Default for NameEncoding {
+    fn default() -> Self {
+        NameEncoding::Hex
+    }
+}
+
+/// Renders an asset name, `None` if the encoding can't represent it
+pub fn decode_name(name: &[u8], encoding: NameEncoding) -> Option<String> {
+    match encoding {
+        NameEncoding::Hex => Some(hex::encode(name)),
+        NameEncoding::Ascii => String::from_utf8(name.to_vec()).ok(),
+        NameEncoding::Utf8Lossy => Some(String::from_utf8_lossy(name).to_string()),
+        NameEncoding::Base64 => Some(base64::encode(name)),
+    }
+}
+
+/// Asset id as used in keys: hex policy id followed by the rendered name
+pub fn asset_id(policy_id: &Hash<28>, name: &[u8], encoding: NameEncoding) -> Option<String> {
+    decode_name(name, encoding).map(|name| format!("{}{}", policy_id, name))
+}
+
+/// Cap on the number of native assets an output can carry to be indexed
+///
+/// Outputs packed with thousands of dust assets make per-asset reducers emit
+/// one command per asset, which is enough for a single tx to stall the
+/// pipeline and flood the store. Outputs above the cap are skipped as a
+/// whole, which means the collections miss every asset they hold, not only
+/// the ones above the cap. The cap is checked on both the produced and the
+/// consumed side, so a skipped output never leaves dangling entries as long
+/// as the value isn't changed mid-sync. Only the produced side counts them,
+/// each skipped output is counted once.
+#[derive(Default)]
+pub struct AssetLimit {
+    max: Option<usize>,
+    skipped: gasket::metrics::Counter,
+}
+
+impl AssetLimit {
+    pub fn new(max: Option<usize>) -> Self {
+        AssetLimit {
+            max,
+            skipped: Default::default(),
+        }
+    }
+
+    /// Whether the produced output is within the cap, outputs that aren't
+    /// are counted as skipped
+    pub fn admits(&self, output: &MultiEraOutput) -> bool {
+        let admitted = self.admits_spent(output);
+
+        if !admitted {
+            self.skipped.inc(1);
+        }
+
+        admitted
+    }
+
+    /// Whether the consumed output is within the cap, it was already counted
+    /// when it was produced
+    pub fn admits_spent(&self, output: &MultiEraOutput) -> bool {
+        match self.max {
+            Some(max) => output.non_ada_assets().len() <= max,
+            None => true,
+        }
+    }
+
+    pub fn skipped(&self) -> &gasket::metrics::Counter {
+        &self.skipped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pallas::codec::minicbor;
+    use pallas::codec::utils::{Bytes, KeyValuePairs};
+    use pallas::ledger::primitives::alonzo;
+    use pallas::ledger::traverse::Era;
+
+    // test vectors taken from the CIP-14 specification
+    const VECTORS: [(&str, &str, &str, &str); 3] = [
+        (
+            "7eae28af2208be856f7a119668ae52a49b73725e326dc16579dcc373",
+            "",
+            "1cadfc0e7068801d51d240d14a4085f2a3673cbb",
+            "asset1rjklcrnsdzqp65wjgrg55sy9723kw09mlgvlc3",
+        ),
+        (
+            "7eae28af2208be856f7a119668ae52a49b73725e326dc16579dcc373",
+            "504154415445",
+            "8cd54e31e4ea696e42344ed563eb00269e2a1da5",
+            "asset13n25uv0yaf5kus35fm2k86cqy60z58d9xmde92",
+        ),
+        (
+            "1e349c9bdea19fd6c147626a5260bc44b71635f398b67c59881df209",
+            "504154415445",
+            "bb2a1a2d8ae9e3ed880382df56bdb80adb2dff80",
+            "asset1hv4p5tv2a837mzqrst04d0dcptdjmluqvdx9k3",
+        ),
+    ];
+
+    #[test]
+    fn matches_cip14_vectors() {
+        for (policy, name, hash, fingerprint) in VECTORS {
+            let policy = hex::decode(policy).unwrap();
+            let name = hex::decode(name).unwrap();
+
+            assert_eq!(hex::encode(fingerprint_hash(&policy, &name)), hash);
+            assert_eq!(asset_fingerprint(&policy, &name).unwrap(), fingerprint);
+            assert!(verify_fingerprint(&policy, &name, fingerprint));
+        }
+    }
+
+    #[test]
+    fn rejects_wrong_fingerprint() {
+        let policy = hex::decode(VECTORS[0].0).unwrap();
+
+        assert!(!verify_fingerprint(&policy, b"PATATE", VECTORS[0].3));
+    }
+
+    #[test]
+    fn name_encodings() {
+        let name = b"PATATE";
+
+        assert_eq!(
+            decode_name(name, NameEncoding::Hex).unwrap(),
+            "504154415445"
+        );
+        assert_eq!(decode_name(name, NameEncoding::Ascii).unwrap(), "PATATE");
+        assert_eq!(
+            decode_name(name, NameEncoding::Utf8Lossy).unwrap(),
+            "PATATE"
+        );
+        assert_eq!(decode_name(name, NameEncoding::Base64).unwrap(), "UEFUQVRF");
+
+        let invalid = [0xffu8, 0x41];
+        assert_eq!(decode_name(&invalid, NameEncoding::Ascii), None);
+        assert_eq!(
+            decode_name(&invalid, NameEncoding::Utf8Lossy).unwrap(),
+            "\u{fffd}A"
+        );
+    }
+
+    fn output_with_assets(count: usize) -> Vec<u8> {
+        let names: Vec<_> = (0..count)
+            .map(|i| (Bytes::from(format!("dust{}", i).into_bytes()), 1u64))
+            .collect();
+
+        let output = alonzo::TransactionOutput {
+            address: Bytes::from(vec![0x61; 29]),
+            amount: alonzo::Value::Multiasset(
+                2_000_000,
+                KeyValuePairs::from(vec![(Hash::new([7u8; 28]), KeyValuePairs::from(names))]),
+            ),
+            datum_hash: None,
+        };
+
+        minicbor::to_vec(&output).unwrap()
+    }
+
+    #[test]
+    fn outputs_above_the_cap_are_skipped() {
+        let regular = output_with_assets(3);
+        let regular = MultiEraOutput::decode(Era::Alonzo, &regular).unwrap();
+
+        let bomb = output_with_assets(5_000);
+        let bomb = MultiEraOutput::decode(Era::Alonzo, &bomb).unwrap();
+        assert_eq!(bomb.non_ada_assets().len(), 5_000);
+
+        let limit = AssetLimit::new(Some(100));
+        assert!(limit.admits(&regular));
+        assert!(!limit.admits(&bomb));
+        assert_eq!(limit.skipped().get(), 1);
+
+        // spending it later doesn't count it again
+        assert!(!limit.admits_spent(&bomb));
+        assert!(limit.admits_spent(&regular));
+        assert_eq!(limit.skipped().get(), 1);
+
+        assert!(AssetLimit::new(None).admits(&bomb));
+    }
+}
@@ -0,0 +1,205 @@
+//! Balances kept by reducers in a local sled db
+//!
+//! The store can't be read back by reducers, those that need the balance of a
+//! holder before a block keep their own copy along with a ring of the prior
+//! balances of the recent blocks, so that a rollback can restore them. The db
+//! must grow along with the store: wipe both when re-syncing from scratch.
+
+use pallas::codec::minicbor;
+use pallas::network::miniprotocols::Point;
+
+/// Balances of a block before it was applied, by balance key
+type UndoEntry = Vec<(String, Vec<u8>)>;
+
+fn ring_key(slot: u64, hash: &[u8]) -> Vec<u8> {
+    // big-endian slot first so that the tree is sorted by chain order
+    let mut key = slot.to_be_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+fn ring_key_slot(key: &[u8]) -> u64 {
+    let mut slot = [0u8; 8];
+    slot.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(slot)
+}
+
+fn decode_balance(raw: &[u8]) -> Result<i128, crate::Error> {
+    let raw: [u8; 16] = raw
+        .try_into()
+        .map_err(|_| crate::Error::storage("invalid holder balance"))?;
+
+    Ok(i128::from_be_bytes(raw))
+}
+
+/// Holder balances along with the changes of the recent blocks
+pub struct Balances {
+    balances: sled::Tree,
+    ring: sled::Tree,
+    ring_len: usize,
+    window: usize,
+}
+
+impl Balances {
+    /// Opens the trees holding the balances and their undo ring, keeping the
+    /// prior balances of the last `window` blocks
+    pub fn open(
+        db: &sled::Db,
+        balances_tree: &str,
+        ring_tree: &str,
+        window: usize,
+    ) -> Result<Self, crate::Error> {
+        let balances = db.open_tree(balances_tree).map_err(crate::Error::storage)?;
+        let ring = db.open_tree(ring_tree).map_err(crate::Error::storage)?;
+
+        Ok(Self {
+            ring_len: ring.len(),
+            balances,
+            ring,
+            window,
+        })
+    }
+
+    pub fn get(&self, key: &str) -> Result<i128, crate::Error> {
+        match self.balances.get(key).map_err(crate::Error::storage)? {
+            Some(raw) => decode_balance(&raw),
+            None => Ok(0),
+        }
+    }
+
+    /// Non-empty balances whose key starts with `prefix`
+    pub fn scan(&self, prefix: &str) -> Result<Vec<(String, i128)>, crate::Error> {
+        self.balances
+            .scan_prefix(prefix)
+            .map(|entry| {
+                let (key, raw) = entry.map_err(crate::Error::storage)?;
+                let key = String::from_utf8_lossy(&key).to_string();
+
+                Ok((key, decode_balance(&raw)?))
+            })
+            .collect()
+    }
+
+    fn set(&self, key: &str, balance: i128) -> Result<(), crate::Error> {
+        match balance {
+            0 => self.balances.remove(key).map(|_| ()),
+            x => self
+                .balances
+                .insert(key, x.to_be_bytes().to_vec())
+                .map(|_| ()),
+        }
+        .map_err(crate::Error::storage)
+    }
+
+    /// Writes the new balances of a block, as (key, prior, new), keeping the
+    /// prior ones in the ring so that the block can be undone
+    ///
+    /// The ring entry goes first: restoring prior balances is idempotent,
+    /// an entry without its balances written is harmless.
+    pub fn apply(
+        &mut self,
+        slot: u64,
+        hash: &[u8],
+        changes: &[(String, i128, i128)],
+    ) -> Result<(), crate::Error> {
+        let entry: UndoEntry = changes
+            .iter()
+            .map(|(key, prior, _)| (key.clone(), prior.to_be_bytes().to_vec()))
+            .collect();
+
+        let value = minicbor::to_vec(entry).map_err(crate::Error::cbor)?;
+
+        self.ring
+            .insert(ring_key(slot, hash), value)
+            .map_err(crate::Error::storage)?;
+
+        self.ring_len += 1;
+
+        for (key, _, new) in changes {
+            self.set(key, *new)?;
+        }
+
+        while self.ring_len > self.window {
+            self.ring.pop_min().map_err(crate::Error::storage)?;
+            self.ring_len -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the balances as of the point, newest block first. Returns
+    /// the number of reverted blocks.
+    ///
+    /// Fails without touching anything if the point is older than the blocks
+    /// kept in the ring, see `enrich::sled` for the same rule on utxos.
+    pub fn undo(&mut self, point: &Point) -> Result<usize, crate::Error> {
+        if self.ring_len >= self.window {
+            let oldest = self.ring.first().map_err(crate::Error::storage)?;
+
+            let past = match (oldest, point) {
+                (None, _) => false,
+                (Some(_), Point::Origin) => true,
+                (Some((key, _)), Point::Specific(slot, _)) => ring_key_slot(&key) > *slot,
+            };
+
+            if past {
+                return Err(crate::Error::message(format!(
+                    "can't roll back holder balances to {:?}, it's older than the rollback window",
+                    point
+                )));
+            }
+        }
+
+        let mut undone = 0;
+
+        while let Some((key, value)) = self.ring.last().map_err(crate::Error::storage)? {
+            let keep = match point {
+                Point::Origin => false,
+                Point::Specific(slot, _) => ring_key_slot(&key) <= *slot,
+            };
+
+            if keep {
+                break;
+            }
+
+            let entry: UndoEntry = minicbor::decode(&value).map_err(crate::Error::cbor)?;
+
+            for (key, prior) in entry {
+                self.set(&key, decode_balance(&prior)?)?;
+            }
+
+            // only forget the entry once it was applied
+            self.ring.remove(key).map_err(crate::Error::storage)?;
+            undone += 1;
+        }
+
+        self.ring_len = self.ring_len.saturating_sub(undone);
+
+        Ok(undone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::Balances;
+
+    #[test]
+    fn rollback_restores_balances() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut balances = Balances::open(&db, "balances", "ring", 10).unwrap();
+
+        balances.apply(10, &[1], &[("a x".into(), 0, 5)]).unwrap();
+        balances
+            .apply(20, &[2], &[("a x".into(), 5, 0), ("a y".into(), 0, 5)])
+            .unwrap();
+
+        assert_eq!(balances.get("a x").unwrap(), 0);
+        assert_eq!(balances.scan("a ").unwrap(), vec![("a y".to_string(), 5)]);
+
+        assert_eq!(balances.undo(&Point::Specific(10, vec![1])).unwrap(), 1);
+        assert_eq!(balances.get("a x").unwrap(), 5);
+        assert_eq!(balances.get("a y").unwrap(), 0);
+    }
+}
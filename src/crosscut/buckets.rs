@@ -0,0 +1,57 @@
+//! Bucketing of addresses to bound the cardinality of address-keyed collections
+//!
+//! Instead of one key per address, reducers can key by the first bits of a
+//! hash of the address, aggregating every address that falls into the same
+//! bucket. This is lossy: a bucket can't be traced back to its addresses and
+//! values of unrelated addresses are mixed together. It's only meant for
+//! sampling and analytics where the distribution matters more than any single
+//! address.
+
+use pallas::crypto::hash::Hasher;
+
+/// Upper bound of the bucket bits, larger values are clamped
+pub const MAX_BITS: u8 = 32;
+
+/// Bucket holding the address, out of `2^bits`
+pub fn address_bucket(address: &str, bits: u8) -> u32 {
+    let bits = bits.min(MAX_BITS);
+
+    if bits == 0 {
+        return 0;
+    }
+
+    let hash = Hasher::<256>::hash(address.as_bytes());
+
+    let mut head = [0u8; 4];
+    head.copy_from_slice(&hash[..4]);
+
+    u32::from_be_bytes(head) >> (32 - bits as u32)
+}
+
+/// Key member for the address: the address itself, or its bucket when
+/// bucketing is enabled
+pub fn address_key(address: &str, bits: Option<u8>) -> String {
+    match bits {
+        Some(bits) => format!("bucket.{}", address_bucket(address, bits)),
+        None => address.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{address_bucket, address_key};
+
+    #[test]
+    fn buckets_are_bounded_by_bits() {
+        let addresses: Vec<_> = (0..200).map(|i| format!("addr_test{}", i)).collect();
+
+        for bits in [0u8, 1, 4, 12] {
+            for address in addresses.iter() {
+                assert!(address_bucket(address, bits) < (1u32 << bits));
+            }
+        }
+
+        assert_eq!(address_key("addr_test1", None), "addr_test1");
+        assert!(address_key("addr_test1", Some(4)).starts_with("bucket."));
+    }
+}
@@ -0,0 +1,113 @@
+//! Control socket to operate a running daemon
+//!
+//! A unix socket accepting one command per line, each answered with a single
+//! line. Supported commands:
+//!
+//! - `resume`: starts consuming the chain if the daemon was started paused
+//! - `status`: answers `paused` or `running`
+
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+
+use super::ready::ReadyGate;
+
+fn answer(command: &str, resume: &ReadyGate) -> &'static str {
+    match command {
+        "resume" => {
+            if !resume.is_open() {
+                log::warn!("resuming chain consumption");
+            }
+
+            resume.open();
+            "ok"
+        }
+        "status" => match resume.is_open() {
+            true => "running",
+            false => "paused",
+        },
+        _ => "unknown command",
+    }
+}
+
+fn handle(stream: UnixStream, resume: &ReadyGate) -> std::io::Result<()> {
+    let mut writer = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        writeln!(writer, "{}", answer(line.trim(), resume))?;
+    }
+
+    Ok(())
+}
+
+/// Binds the socket and serves it from a background thread
+///
+/// A file left behind at the path by a previous run is replaced.
+pub fn serve(path: &Path, resume: ReadyGate) -> Result<(), crate::Error> {
+    let invalid = |err: std::io::Error| {
+        crate::Error::config(format!("can't bind control socket {:?}: {}", path, err))
+    };
+
+    if path.exists() {
+        std::fs::remove_file(path).map_err(invalid)?;
+    }
+
+    let listener = UnixListener::bind(path).map_err(invalid)?;
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let result = stream.and_then(|x| handle(x, &resume));
+
+            if let Err(err) = result {
+                log::warn!("control socket error: {}", err);
+            }
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+
+    use super::serve;
+    use crate::crosscut::ready::ReadyGate;
+
+    fn send(stream: &mut UnixStream, command: &str) -> String {
+        writeln!(stream, "{}", command).unwrap();
+
+        let mut line = String::new();
+        BufReader::new(stream.try_clone().unwrap())
+            .read_line(&mut line)
+            .unwrap();
+
+        line.trim().to_string()
+    }
+
+    // the source stage itself is driven through the socket in
+    // `sources::n2n::chainsync`
+    #[test]
+    fn resume_opens_the_gate() {
+        let path =
+            std::env::temp_dir().join(format!("scrolls-control-{}.sock", std::process::id()));
+
+        let resume = ReadyGate::default();
+        serve(&path, resume.clone()).unwrap();
+
+        let mut stream = UnixStream::connect(&path).unwrap();
+
+        assert_eq!(send(&mut stream, "status"), "paused");
+        assert!(!resume.is_open());
+
+        assert_eq!(send(&mut stream, "resume"), "ok");
+        assert!(resume.is_open());
+
+        assert_eq!(send(&mut stream, "status"), "running");
+        assert_eq!(send(&mut stream, "pause"), "unknown command");
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
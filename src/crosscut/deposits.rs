@@ -0,0 +1,81 @@
+//! Deposits and refunds triggered by certificates
+//!
+//! Certificates don't carry the amounts, they are protocol parameters which
+//! aren't tracked by scrolls, so they come from the config. Only what's
+//! visible in a block is accounted for: pool deposits are refunded at the
+//! epoch boundary following a retirement, by the ledger, and never show up
+//! as a refund here.
+//!
+//! The reducers adding them up (`epoch_stats`, `fee_stats`) subtract the
+//! amounts of rolled back blocks like any other of their totals.
+
+use pallas::ledger::primitives::alonzo::Certificate;
+use pallas::ledger::traverse::MultiEraTx;
+use serde::Deserialize;
+
+/// Key deposit on mainnet since Shelley, in lovelace
+pub const DEFAULT_KEY_DEPOSIT: u64 = 2_000_000;
+
+#[derive(Deserialize, Clone, Default)]
+pub struct Config {
+    /// Deposit of a stake key registration, refunded when the key is
+    /// deregistered. Defaults to 2 ADA.
+    pub key_deposit: Option<u64>,
+
+    /// Deposit of a pool registration. Not counted unless set: a certificate
+    /// updating the parameters of a registered pool looks the same as a
+    /// first registration but takes no deposit, and telling them apart
+    /// requires the ledger state. When set, updates are counted as well.
+    pub pool_deposit: Option<u64>,
+}
+
+impl Config {
+    /// Deposit taken and refund given by a single certificate
+    pub fn cert_amounts(&self, cert: &Certificate) -> (u64, u64) {
+        let key_deposit = self.key_deposit.unwrap_or(DEFAULT_KEY_DEPOSIT);
+
+        match cert {
+            Certificate::StakeRegistration(_) => (key_deposit, 0),
+            Certificate::StakeDeregistration(_) => (0, key_deposit),
+            Certificate::PoolRegistration { .. } => (self.pool_deposit.unwrap_or_default(), 0),
+            _ => (0, 0),
+        }
+    }
+
+    /// Total deposits and refunds of the certificates of the tx
+    pub fn tx_amounts(&self, tx: &MultiEraTx) -> (u64, u64) {
+        tx.certs()
+            .iter()
+            .filter_map(|cert| cert.as_alonzo())
+            .map(|cert| self.cert_amounts(cert))
+            .fold((0, 0), |(d, r), (deposit, refund)| {
+                (d + deposit, r + refund)
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, DEFAULT_KEY_DEPOSIT};
+    use pallas::crypto::hash::Hash;
+    use pallas::ledger::primitives::alonzo::{Certificate, StakeCredential};
+
+    #[test]
+    fn key_registrations_are_deposits() {
+        let cred = StakeCredential::AddrKeyhash(Hash::new([1u8; 28]));
+        let config = Config::default();
+
+        assert_eq!(
+            config.cert_amounts(&Certificate::StakeRegistration(cred.clone())),
+            (DEFAULT_KEY_DEPOSIT, 0)
+        );
+
+        assert_eq!(
+            config.cert_amounts(&Certificate::StakeDeregistration(cred.clone())),
+            (0, DEFAULT_KEY_DEPOSIT)
+        );
+
+        let delegation = Certificate::StakeDelegation(cred, Hash::new([2u8; 28]));
+        assert_eq!(config.cert_amounts(&delegation), (0, 0));
+    }
+}
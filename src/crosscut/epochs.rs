@@ -31,3 +31,49 @@ pub fn block_epoch(chain: &super::ChainWellKnownInfo, block: &MultiEraBlock) ->
         _ => post_byron_epoch_for_slot(chain.shelley_known_slot, chain.shelley_epoch_length, slot),
     }
 }
+
+/// Epoch of a slot when the block isn't at hand, slots before the known
+/// shelley one are taken as byron
+pub fn slot_epoch(chain: &super::ChainWellKnownInfo, slot: u64) -> u64 {
+    if slot < chain.shelley_known_slot {
+        return byron_epoch_for_slot(chain.byron_epoch_length, chain.byron_slot_length, slot);
+    }
+
+    post_byron_epoch_for_slot(chain.shelley_known_slot, chain.shelley_epoch_length, slot)
+}
+
+/// Follows the epoch of the blocks as they come, telling when one rolls over
+pub struct EpochTracker {
+    chain: super::ChainWellKnownInfo,
+    current: Option<u64>,
+}
+
+impl EpochTracker {
+    pub fn new(chain: &super::ChainWellKnownInfo) -> Self {
+        Self {
+            chain: chain.clone(),
+            current: None,
+        }
+    }
+
+    pub fn current(&self) -> Option<u64> {
+        self.current
+    }
+
+    /// Moves to the epoch of the slot, returning the epoch it leaves if the
+    /// slot starts a new one
+    pub fn advance(&mut self, slot: u64) -> Option<u64> {
+        let epoch = slot_epoch(&self.chain, slot);
+
+        match self.current.replace(epoch) {
+            Some(previous) if previous != epoch => Some(previous),
+            _ => None,
+        }
+    }
+
+    /// Goes back to the epoch of the slot after a rollback, none when there's
+    /// no block to go back to
+    pub fn reset(&mut self, slot: Option<u64>) {
+        self.current = slot.map(|x| slot_epoch(&self.chain, x));
+    }
+}
@@ -0,0 +1,93 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// Count of the messages a stage sent that the next one hasn't committed yet
+///
+/// The sender counts every message it emits, the receiver acknowledges each
+/// one once it commits its input port. Unlike the capacity of the channel in
+/// between, this also covers the message being worked on downstream.
+#[derive(Clone, Default)]
+pub struct Inflight {
+    inner: Arc<(Mutex<usize>, Condvar)>,
+}
+
+impl Inflight {
+    pub fn emitted(&self) {
+        *self.inner.0.lock().unwrap() += 1;
+    }
+
+    pub fn acknowledged(&self) {
+        let (lock, cvar) = &*self.inner;
+
+        let mut count = lock.lock().unwrap();
+        *count = count.saturating_sub(1);
+
+        cvar.notify_all();
+    }
+
+    pub fn count(&self) -> usize {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Blocks until fewer than `max` messages are in flight, returns false if
+    /// the timeout elapsed
+    pub fn wait_below(&self, max: usize, timeout: Duration) -> bool {
+        let (lock, cvar) = &*self.inner;
+        let max = max.max(1);
+
+        let (count, _) = cvar
+            .wait_timeout_while(lock.lock().unwrap(), timeout, |count| *count >= max)
+            .unwrap();
+
+        *count < max
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    use super::Inflight;
+
+    #[test]
+    fn cap_limits_messages_in_flight() {
+        let inflight = Inflight::default();
+        let downstream = inflight.clone();
+
+        // an unbounded channel, only the cap holds the sender back
+        let (tx, rx) = mpsc::channel::<usize>();
+
+        let handle = std::thread::spawn(move || {
+            let mut peak = 0;
+
+            for _ in 0..50 {
+                rx.recv().unwrap();
+                peak = peak.max(downstream.count());
+                std::thread::sleep(Duration::from_millis(1));
+                downstream.acknowledged();
+            }
+
+            peak
+        });
+
+        for i in 0..50 {
+            assert!(inflight.wait_below(5, Duration::from_secs(5)));
+            inflight.emitted();
+            tx.send(i).unwrap();
+        }
+
+        assert!(handle.join().unwrap() <= 5);
+        assert_eq!(inflight.count(), 0);
+
+        // nothing acknowledges, the sender gives up waiting
+        for _ in 0..5 {
+            inflight.emitted();
+        }
+
+        assert!(!inflight.wait_below(5, Duration::from_millis(20)));
+        assert!(inflight.wait_below(6, Duration::from_millis(20)));
+    }
+}
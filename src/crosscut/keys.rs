@@ -0,0 +1,77 @@
+//! Construction of the keys written by reducers
+//!
+//! A key is the prefix of the reducer followed by a few parts (address,
+//! asset, epoch...), joined by a separator. The separator is `.` unless the
+//! reducer config sets `key_separator`. Parts aren't escaped: asset names and
+//! metadata can contain the separator themselves, so consumers splitting keys
+//! should pick a separator that can't show up in them.
+
+/// Separator used when the reducer config doesn't set one
+pub const DEFAULT_SEPARATOR: &str = ".";
+
+/// Prefix of the keys of a reducer, along with the separator of their parts
+#[derive(Debug, Clone, Copy)]
+pub struct Prefix<'a> {
+    name: Option<&'a str>,
+    separator: &'a str,
+}
+
+impl<'a> Prefix<'a> {
+    pub fn new(name: Option<&'a str>, separator: Option<&'a str>) -> Self {
+        Self {
+            name,
+            separator: separator.unwrap_or(DEFAULT_SEPARATOR),
+        }
+    }
+
+    /// Uses `name` as the prefix if none was set
+    pub fn or(self, name: &'a str) -> Self {
+        Self {
+            name: self.name.or(Some(name)),
+            ..self
+        }
+    }
+
+    pub fn separator(&self) -> &'a str {
+        self.separator
+    }
+
+    /// Joins the prefix, if any, and the parts
+    pub fn key(&self, parts: &[&str]) -> String {
+        let mut key = self.name.map(String::from).unwrap_or_default();
+
+        for part in parts {
+            if !key.is_empty() {
+                key.push_str(self.separator);
+            }
+
+            key.push_str(part);
+        }
+
+        key
+    }
+}
+
+impl<'a> From<Option<&'a str>> for Prefix<'a> {
+    fn from(name: Option<&'a str>) -> Self {
+        Self::new(name, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Prefix;
+
+    #[test]
+    fn parts_are_joined_by_separator() {
+        let prefix = Prefix::new(Some("c1"), None);
+        assert_eq!(prefix.key(&["addr1", "42"]), "c1.addr1.42");
+
+        let prefix = Prefix::new(Some("c1"), Some(":"));
+        assert_eq!(prefix.key(&["asset.name", "42"]), "c1:asset.name:42");
+
+        let prefix = Prefix::new(None, Some(":"));
+        assert_eq!(prefix.key(&["addr1"]), "addr1");
+        assert_eq!(prefix.or("default").key(&["addr1"]), "default:addr1");
+    }
+}
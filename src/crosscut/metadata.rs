@@ -0,0 +1,76 @@
+use std::ops::Deref;
+
+use pallas::codec::utils::KeyValuePairs;
+use pallas::ledger::primitives::alonzo::Metadatum;
+use serde_json::Value;
+
+/// Converts a metadatum into its JSON representation
+///
+/// Integers are always rendered as decimal strings, whatever their size:
+/// metadata integers range from -2^64 to 2^64 - 1, which overflows an i64 and
+/// loses precision as a JSON number (an f64 to most parsers) past 2^53.
+/// Numeric consumers have to parse them. Bytes are hex-encoded and maps are
+/// converted using `kv_pairs_to_hashmap`.
+pub fn metadatum_to_value(m: &Metadatum) -> Value {
+    match m {
+        Metadatum::Int(int_value) => Value::String(int_value.to_string()),
+        Metadatum::Bytes(bytes) => Value::String(hex::encode(bytes.as_slice())),
+        Metadatum::Text(text) => Value::String(text.clone()),
+        Metadatum::Array(array) => {
+            let json_array: Vec<Value> = array.iter().map(metadatum_to_value).collect();
+            Value::Array(json_array)
+        }
+        Metadatum::Map(kv_pairs) => {
+            let json_object = kv_pairs_to_hashmap(kv_pairs);
+            Value::Object(json_object)
+        }
+    }
+}
+
+/// Converts a metadatum map into a JSON object
+///
+/// Entries keyed by text or by an integer are kept, integer keys written as
+/// decimal strings like integer values. Any other type of key is ignored.
+pub fn kv_pairs_to_hashmap(
+    kv_pairs: &KeyValuePairs<Metadatum, Metadatum>,
+) -> serde_json::Map<String, Value> {
+    let mut hashmap = serde_json::Map::new();
+
+    for (key, value) in kv_pairs.deref() {
+        let key = match key {
+            Metadatum::Text(x) => x.clone(),
+            Metadatum::Int(x) => x.to_string(),
+            _ => continue,
+        };
+
+        hashmap.insert(key, metadatum_to_value(value));
+    }
+
+    hashmap
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::codec::minicbor;
+    use pallas::ledger::primitives::alonzo::Metadatum;
+    use serde_json::json;
+
+    use super::metadatum_to_value;
+
+    // { "big": 2^64 - 1, "neg": -2^64, 1: 1, h'00': "x" }
+    const BIG_INTS: &str = "a4636269671bffffffffffffffff636e65673bffffffffffffffff010141006178";
+
+    #[test]
+    fn big_ints_are_kept_as_strings() {
+        let metadata: Metadatum = minicbor::decode(&hex::decode(BIG_INTS).unwrap()).unwrap();
+
+        assert_eq!(
+            metadatum_to_value(&metadata),
+            json!({
+                "big": "18446744073709551615",
+                "neg": "-18446744073709551616",
+                "1": "1",
+            })
+        );
+    }
+}
@@ -1,7 +1,24 @@
 mod args;
+pub mod addresses;
+pub mod assets;
+pub mod balances;
+pub mod buckets;
+#[cfg(target_family = "unix")]
+pub mod control;
+pub mod deposits;
 pub mod epochs;
 pub mod filters;
+pub mod inflight;
+pub mod keys;
+pub mod metadata;
+pub mod pinning;
+pub mod plutus;
 pub mod policies;
+pub mod ready;
+pub mod refs;
+pub mod threads;
+pub mod throttle;
 pub mod time;
+pub mod timing;
 
 pub use args::*;
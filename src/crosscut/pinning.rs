@@ -0,0 +1,123 @@
+//! Media URIs of asset metadata, handed to a pinning service
+//!
+//! NFT metadata points at its media through the CIP-25 `image` field and the
+//! `src` of each entry of `files`. Indexers often want those pinned on a
+//! content-addressed store before the original host drops them. The asset
+//! metadata reducer extracts the URIs and hands each one to a `Pinner`,
+//! recording what it answered. Only the no-op pinner ships for now, it's the
+//! integration point for a service client.
+
+use pallas::codec::utils::KeyValuePairs;
+use pallas::ledger::primitives::alonzo::Metadatum;
+use serde::Deserialize;
+
+/// Schemes of the URIs worth pinning, inline `data:` URIs and relative
+/// paths are left out
+const PINNABLE_SCHEMES: [&str; 3] = ["ipfs://", "ar://", "https://"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PinStatus {
+    /// The pinner doesn't pin anything
+    Skipped,
+    /// The request was accepted, the outcome isn't known yet
+    Queued,
+    Pinned,
+    Failed,
+}
+
+impl PinStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PinStatus::Skipped => "skipped",
+            PinStatus::Queued => "queued",
+            PinStatus::Pinned => "pinned",
+            PinStatus::Failed => "failed",
+        }
+    }
+}
+
+/// Receives the media URIs of minted assets
+///
+/// Called from the reducers stage for each URI of each mint, blocks wait on
+/// it: implementations talking to a remote service should queue the request
+/// and answer `Queued`. Blocks reduced again after a restart hand their URIs
+/// over again.
+pub trait Pinner: Send + Sync {
+    fn pin(&self, uri: &str) -> PinStatus;
+}
+
+/// Accepts every URI without pinning it
+pub struct NoopPinner;
+
+impl Pinner for NoopPinner {
+    fn pin(&self, _uri: &str) -> PinStatus {
+        PinStatus::Skipped
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type")]
+pub enum Config {
+    Noop,
+}
+
+impl Config {
+    pub fn pinner(self) -> Box<dyn Pinner> {
+        match self {
+            Config::Noop => Box::new(NoopPinner),
+        }
+    }
+}
+
+/// Text of a metadatum, joining the chunks of strings that CIP-25 splits in
+/// arrays to fit the 64 bytes limit
+fn chunked_text(value: &Metadatum) -> Option<String> {
+    match value {
+        Metadatum::Text(x) => Some(x.clone()),
+        Metadatum::Array(chunks) => chunks
+            .iter()
+            .map(|x| match x {
+                Metadatum::Text(x) => Some(x.as_str()),
+                _ => None,
+            })
+            .collect::<Option<Vec<_>>>()
+            .map(|x| x.concat()),
+        _ => None,
+    }
+}
+
+fn text_entry(map: &KeyValuePairs<Metadatum, Metadatum>, name: &str) -> Option<String> {
+    map.iter().find_map(|(key, value)| match key {
+        Metadatum::Text(key) if key == name => chunked_text(value),
+        _ => None,
+    })
+}
+
+/// URIs of the `image` and `files` of an asset entry, in that order and
+/// without duplicates, limited to the schemes worth pinning
+pub fn media_uris(asset: &KeyValuePairs<Metadatum, Metadatum>) -> Vec<String> {
+    let mut found: Vec<String> = text_entry(asset, "image").into_iter().collect();
+
+    let files = asset.iter().find_map(|(key, value)| match (key, value) {
+        (Metadatum::Text(key), Metadatum::Array(files)) if key == "files" => Some(files),
+        _ => None,
+    });
+
+    for file in files.into_iter().flat_map(|x| x.iter()) {
+        if let Metadatum::Map(file) = file {
+            found.extend(text_entry(file, "src"));
+        }
+    }
+
+    let mut uris = vec![];
+
+    for uri in found {
+        let pinnable = PINNABLE_SCHEMES.iter().any(|x| uri.starts_with(x));
+
+        if pinnable && !uris.contains(&uri) {
+            uris.push(uri);
+        }
+    }
+
+    uris
+}
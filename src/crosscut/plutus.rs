@@ -0,0 +1,179 @@
+//! Json rendering of plutus data
+//!
+//! Follows the detailed schema of cardano-cli, which Ogmios and Blockfrost
+//! also use: `{"constructor": n, "fields": [..]}`, `{"list": [..]}`,
+//! `{"map": [{"k": .., "v": ..}]}`, `{"int": n}` and `{"bytes": "hex"}`.
+//! Integers that don't fit in 64 bits are rendered as a decimal string, json
+//! numbers that large don't survive most parsers anyway.
+
+use pallas::ledger::primitives::alonzo::{BigInt, Constr, PlutusData};
+use serde_json::{json, Value};
+
+/// Index of the constructor, from its tag as defined by CIP-5
+fn constructor_index(constr: &Constr<PlutusData>) -> Option<u64> {
+    match constr.tag {
+        121..=127 => Some(constr.tag - 121),
+        1280..=1400 => Some(constr.tag - 1280 + 7),
+        102 => constr.any_constructor,
+        _ => None,
+    }
+}
+
+/// Decimal digits of a big-endian magnitude
+fn decimal(bytes: &[u8]) -> String {
+    // little-endian base 10 digits
+    let mut digits: Vec<u8> = vec![];
+
+    for byte in bytes {
+        let mut carry = *byte as u32;
+
+        for digit in digits.iter_mut() {
+            let x = *digit as u32 * 256 + carry;
+            *digit = (x % 10) as u8;
+            carry = x / 10;
+        }
+
+        while carry > 0 {
+            digits.push((carry % 10) as u8);
+            carry /= 10;
+        }
+    }
+
+    if digits.is_empty() {
+        return "0".into();
+    }
+
+    digits.iter().rev().map(|x| char::from(b'0' + x)).collect()
+}
+
+/// Adds one to a big-endian magnitude
+fn increment(bytes: &[u8]) -> Vec<u8> {
+    let mut bytes = bytes.to_vec();
+
+    for byte in bytes.iter_mut().rev() {
+        let (x, overflow) = byte.overflowing_add(1);
+        *byte = x;
+
+        if !overflow {
+            return bytes;
+        }
+    }
+
+    bytes.insert(0, 1);
+    bytes
+}
+
+/// A json number if the integer fits in 64 bits, its decimal string if not
+fn int_json(decimal: String) -> Value {
+    if let Ok(x) = decimal.parse::<i64>() {
+        return json!(x);
+    }
+
+    if let Ok(x) = decimal.parse::<u64>() {
+        return json!(x);
+    }
+
+    Value::String(decimal)
+}
+
+fn big_int_json(value: &BigInt) -> Value {
+    match value {
+        BigInt::Int(x) => int_json(i128::from(x.0).to_string()),
+        BigInt::BigUInt(x) => int_json(decimal(x)),
+        // bignums encode the negative -1 - n
+        BigInt::BigNInt(x) => int_json(format!("-{}", decimal(&increment(x)))),
+    }
+}
+
+/// Renders the datum in the detailed schema, see the module docs
+pub fn datum_to_json(data: &PlutusData) -> Value {
+    match data {
+        PlutusData::Constr(x) => json!({
+            "constructor": constructor_index(x),
+            "fields": x.fields.iter().map(datum_to_json).collect::<Vec<_>>(),
+        }),
+        PlutusData::Map(x) => json!({
+            "map": x
+                .iter()
+                .map(|(k, v)| json!({ "k": datum_to_json(k), "v": datum_to_json(v) }))
+                .collect::<Vec<_>>(),
+        }),
+        PlutusData::BigInt(x) => json!({ "int": big_int_json(x) }),
+        PlutusData::BoundedBytes(x) => json!({ "bytes": hex::encode(x.as_slice()) }),
+        PlutusData::Array(x) => json!({
+            "list": x.iter().map(datum_to_json).collect::<Vec<_>>(),
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::codec::minicbor;
+    use pallas::ledger::primitives::alonzo::PlutusData;
+    use serde_json::json;
+
+    use super::datum_to_json;
+
+    fn decode(hex_cbor: &str) -> PlutusData {
+        minicbor::decode(&hex::decode(hex_cbor).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn constructors_use_their_index() {
+        // Constr 0 [1, h'ab']
+        assert_eq!(
+            datum_to_json(&decode("d879820141ab")),
+            json!({ "constructor": 0, "fields": [{ "int": 1 }, { "bytes": "ab" }] })
+        );
+
+        // Constr 7 []
+        assert_eq!(
+            datum_to_json(&decode("d9050080")),
+            json!({ "constructor": 7, "fields": [] })
+        );
+
+        // Constr 200 [] through the general tag
+        assert_eq!(
+            datum_to_json(&decode("d8668218c880")),
+            json!({ "constructor": 200, "fields": [] })
+        );
+    }
+
+    #[test]
+    fn lists_and_maps_are_nested() {
+        // { 1: [2, -5] }
+        assert_eq!(
+            datum_to_json(&decode("a101820224")),
+            json!({ "map": [{ "k": { "int": 1 }, "v": { "list": [{ "int": 2 }, { "int": -5 }] } }] })
+        );
+    }
+
+    #[test]
+    fn large_ints_are_kept_exact() {
+        assert_eq!(
+            datum_to_json(&decode("1bffffffffffffffff")),
+            json!({ "int": u64::MAX })
+        );
+
+        // -2^64, the smallest plain cbor int
+        assert_eq!(
+            datum_to_json(&decode("3bffffffffffffffff")),
+            json!({ "int": "-18446744073709551616" })
+        );
+
+        // bignum 2^64
+        assert_eq!(
+            datum_to_json(&decode("c249010000000000000000")),
+            json!({ "int": "18446744073709551616" })
+        );
+
+        // negative bignum -1 - 2^64
+        assert_eq!(
+            datum_to_json(&decode("c349010000000000000000")),
+            json!({ "int": "-18446744073709551617" })
+        );
+
+        // bignums that fit are plain numbers
+        assert_eq!(datum_to_json(&decode("c2420100")), json!({ "int": 256 }));
+    }
+}
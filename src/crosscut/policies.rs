@@ -15,12 +15,30 @@ impl Default for ErrorAction {
     }
 }
 
+/// What reducers do with outputs whose address can't be decoded
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
+pub enum UnknownAddressAction {
+    /// Leave the output out, counted by the `unknown_addresses` metric
+    Skip,
+    /// Index the output under the `unknown-address` key, for the reducers
+    /// keyed by address
+    Bucket,
+}
+
+impl Default for UnknownAddressAction {
+    fn default() -> Self {
+        UnknownAddressAction::Skip
+    }
+}
+
 #[derive(Clone, Serialize, Deserialize, Default)]
 pub struct RuntimePolicy {
     pub missing_data: Option<ErrorAction>,
     pub cbor_errors: Option<ErrorAction>,
     pub ledger_errors: Option<ErrorAction>,
+    pub reducer_panics: Option<ErrorAction>,
     pub any_error: Option<ErrorAction>,
+    pub unknown_addresses: Option<UnknownAddressAction>,
 }
 
 #[inline]
@@ -56,8 +74,10 @@ impl<T> AppliesPolicy for Result<T, crate::Error> {
                 // apply specific actions for each type of error
                 match &err {
                     crate::Error::MissingUtxo(_) => handle_error(err, &policy.missing_data),
+                    crate::Error::MissingDatum(_) => handle_error(err, &policy.missing_data),
                     crate::Error::CborError(_) => handle_error(err, &policy.cbor_errors),
                     crate::Error::LedgerError(_) => handle_error(err, &policy.ledger_errors),
+                    crate::Error::ReducerPanic(_) => handle_error(err, &policy.reducer_panics),
                     _ => Err(err),
                 }
             }
@@ -0,0 +1,73 @@
+use std::{
+    sync::{Arc, Condvar, Mutex},
+    time::Duration,
+};
+
+/// A one-way flag used to order the startup of stages
+///
+/// Stages bootstrap concurrently. A downstream stage opens the gate once it's
+/// able to process data, upstream stages wait on it before they start
+/// producing.
+#[derive(Clone, Default)]
+pub struct ReadyGate {
+    inner: Arc<(Mutex<bool>, Condvar)>,
+}
+
+impl ReadyGate {
+    /// Creates a gate that is already open, for stages without warm-up
+    pub fn opened() -> Self {
+        let gate = Self::default();
+        gate.open();
+        gate
+    }
+
+    pub fn open(&self) {
+        let (lock, cvar) = &*self.inner;
+        *lock.lock().unwrap() = true;
+        cvar.notify_all();
+    }
+
+    pub fn is_open(&self) -> bool {
+        *self.inner.0.lock().unwrap()
+    }
+
+    /// Blocks until the gate is open, returns false if the timeout elapsed
+    pub fn wait_timeout(&self, timeout: Duration) -> bool {
+        let (lock, cvar) = &*self.inner;
+
+        let (open, _) = cvar
+            .wait_timeout_while(lock.lock().unwrap(), timeout, |open| !*open)
+            .unwrap();
+
+        *open
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ReadyGate;
+
+    #[test]
+    fn waiter_is_released_once_opened() {
+        let gate = ReadyGate::default();
+        let downstream = gate.clone();
+
+        let handle = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            downstream.open();
+        });
+
+        assert!(!gate.is_open());
+        assert!(gate.wait_timeout(Duration::from_secs(5)));
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn waiter_times_out_if_never_opened() {
+        let gate = ReadyGate::default();
+        assert!(!gate.wait_timeout(Duration::from_millis(20)));
+    }
+}
@@ -0,0 +1,44 @@
+//! Keys of the outputs of txs
+//!
+//! The enrich db stores each utxo under the same `{tx hash}#{index}` key that
+//! reducers use to refer to it once it's spent. Both sides build it through
+//! these functions, a lookup would silently miss if the two drifted apart.
+
+use pallas::crypto::hash::Hash;
+use pallas::ledger::traverse::OutputRef;
+
+/// Key of the output at `index` of the tx
+pub fn output_key(tx_hash: &Hash<32>, index: u64) -> String {
+    ref_key(&OutputRef::new(*tx_hash, index))
+}
+
+/// Key of the output pointed at by an input
+pub fn ref_key(output_ref: &OutputRef) -> String {
+    output_ref.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::crypto::hash::Hash;
+    use pallas::ledger::traverse::{Era, MultiEraTx};
+
+    use super::{output_key, ref_key};
+
+    // babbage tx spending 1111..11#0 and using 2222..22#0 as reference input
+    const TX: &str = "84a40081825820111111111111111111111111111111111111111111111111111111111111111100018182581d61000000000000000000000000000000000000000000000000000000001a001e8480021a000298101281825820222222222222222222222222222222222222222222222222222222222222222200a0f5f6";
+
+    #[test]
+    fn produced_and_spent_keys_are_identical() {
+        let cbor = hex::decode(TX).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+
+        // the key enrich stores the output under when it's produced...
+        let produced = output_key(&Hash::new([0x11; 32]), 0);
+
+        // ...and the one reducers look it up with when it's spent
+        let spent = ref_key(&tx.consumes()[0].output_ref());
+
+        assert_eq!(produced.as_bytes(), spent.as_bytes());
+        assert_eq!(produced, format!("{}#0", "11".repeat(32)));
+    }
+}
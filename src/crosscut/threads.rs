@@ -0,0 +1,199 @@
+//! Thread placement hints for the stages of the pipeline
+//!
+//! Each gasket stage runs on its own thread, the enrich stage additionally
+//! fans out utxo lookups to a rayon pool. On a machine shared with a node,
+//! that pool can take every core and starve the source and reducer threads.
+//! Hints allow pinning each stage to a set of cores and sizing the pool.
+//!
+//! By default no hint is applied: every thread may run on any core and the
+//! enrich pool has one thread per available core.
+
+use std::time::Duration;
+
+use serde::Deserialize;
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct StageThreads {
+    /// Ids of the cores the threads of the stage are allowed to run on
+    pub cores: Option<Vec<usize>>,
+
+    /// Number of threads of the stage worker pool. Only used by stages that
+    /// have one (enrich, reducers), defaults to one per core available to the
+    /// stage.
+    pub threads: Option<usize>,
+
+    /// Number of buffered blocks the reducers stage decodes ahead, on its
+    /// pool, while reducing the current one. Disabled by default.
+    pub decode_ahead: Option<usize>,
+
+    /// Milliseconds the reducers stage sleeps each time its input is idle,
+    /// so that it doesn't keep polling while upstream is slow. Disabled by
+    /// default.
+    pub idle_sleep_ms: Option<u64>,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Config {
+    pub source: Option<StageThreads>,
+    pub enrich: Option<StageThreads>,
+    pub reducers: Option<StageThreads>,
+    pub storage: Option<StageThreads>,
+
+    /// Run enrich inline in the reducers stage instead of in its own stage.
+    /// The merged stage, lookup pool included, runs on the `reducers` cores
+    /// while the size of the pool still comes from `enrich`. Disabled by
+    /// default.
+    pub fused: Option<bool>,
+}
+
+impl Config {
+    pub fn fused(&self) -> bool {
+        self.fused.unwrap_or_default()
+    }
+
+    pub fn enrich_pool_size(&self) -> Option<usize> {
+        self.enrich.as_ref().and_then(|x| x.threads)
+    }
+
+    pub fn reducers_decode_ahead(&self) -> Option<usize> {
+        self.reducers.as_ref().and_then(|x| x.decode_ahead)
+    }
+
+    pub fn reducers_pool_size(&self) -> Option<usize> {
+        self.reducers.as_ref().and_then(|x| x.threads)
+    }
+
+    pub fn reducers_idle_sleep(&self) -> Option<Duration> {
+        self.reducers
+            .as_ref()
+            .and_then(|x| x.idle_sleep_ms)
+            .map(Duration::from_millis)
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod affinity {
+    use std::mem::{size_of, zeroed};
+
+    pub type CpuSet = libc::cpu_set_t;
+
+    pub fn current() -> std::io::Result<CpuSet> {
+        unsafe {
+            let mut set: CpuSet = zeroed();
+
+            if libc::sched_getaffinity(0, size_of::<CpuSet>(), &mut set) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+
+            Ok(set)
+        }
+    }
+
+    pub fn set(set: &CpuSet) -> std::io::Result<()> {
+        unsafe {
+            if libc::sched_setaffinity(0, size_of::<CpuSet>(), set) != 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn from_cores(cores: &[usize]) -> Result<CpuSet, crate::Error> {
+        let max = 8 * size_of::<CpuSet>();
+
+        unsafe {
+            let mut set: CpuSet = zeroed();
+
+            for core in cores {
+                if *core >= max {
+                    return Err(crate::Error::config(format!("invalid core id {}", core)));
+                }
+
+                libc::CPU_SET(*core, &mut set);
+            }
+
+            Ok(set)
+        }
+    }
+}
+
+/// Runs `f` with the calling thread restricted to the cores of the hints
+///
+/// Threads inherit the affinity of the thread that spawns them, so stages
+/// spawned inside `f` keep running on those cores once the affinity of the
+/// calling thread is restored. Hints that can't be applied are logged and
+/// ignored.
+#[cfg(target_os = "linux")]
+pub fn with_affinity<T>(hints: Option<&StageThreads>, f: impl FnOnce() -> T) -> T {
+    let cores = match hints.and_then(|x| x.cores.as_ref()) {
+        Some(x) if !x.is_empty() => x,
+        _ => return f(),
+    };
+
+    let previous = match affinity::current() {
+        Ok(x) => x,
+        Err(err) => {
+            log::warn!("can't read thread affinity, ignoring hints: {}", err);
+            return f();
+        }
+    };
+
+    let applied = affinity::from_cores(cores)
+        .and_then(|set| affinity::set(&set).map_err(|err| crate::Error::config(err.to_string())));
+
+    if let Err(err) = applied {
+        log::warn!("can't set thread affinity, ignoring hints: {}", err);
+        return f();
+    }
+
+    let out = f();
+
+    if let Err(err) = affinity::set(&previous) {
+        log::warn!("can't restore thread affinity: {}", err);
+    }
+
+    out
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn with_affinity<T>(hints: Option<&StageThreads>, f: impl FnOnce() -> T) -> T {
+    if hints.and_then(|x| x.cores.as_ref()).is_some() {
+        log::warn!("thread affinity is only supported on linux, ignoring hints");
+    }
+
+    f()
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::{affinity, with_affinity, StageThreads};
+
+    fn allowed_cores() -> Vec<usize> {
+        let set = affinity::current().unwrap();
+
+        (0..libc::CPU_SETSIZE as usize)
+            .filter(|x| unsafe { libc::CPU_ISSET(*x, &set) })
+            .collect()
+    }
+
+    #[test]
+    fn spawned_threads_inherit_affinity() {
+        let before = allowed_cores();
+        let core = before[0];
+
+        let hints = StageThreads {
+            cores: Some(vec![core]),
+            threads: None,
+            decode_ahead: None,
+            idle_sleep_ms: None,
+        };
+
+        let inner = with_affinity(Some(&hints), || {
+            std::thread::spawn(allowed_cores).join().unwrap()
+        });
+
+        assert_eq!(inner, vec![core]);
+        assert_eq!(allowed_cores(), before);
+    }
+}
@@ -0,0 +1,166 @@
+//! Pacing of the commands written to a store
+//!
+//! During the initial sync the reducers emit commands far faster than a
+//! remote store can take them. A throttled sink stops pulling from its input
+//! while it waits, the bounded ports upstream fill up and the whole pipeline
+//! slows down to the pace of the store instead of piling up commands.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Refilled at `rate` tokens per second, holding up to a second worth of them
+///
+/// Taking more tokens than available leaves the bucket in debt and the
+/// caller waits until it's paid back, so amounts larger than the capacity
+/// still go through.
+#[derive(Debug)]
+pub struct TokenBucket {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(rate: u64, now: Instant) -> Self {
+        let rate = rate.max(1) as f64;
+
+        Self {
+            rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    /// Takes `amount` tokens as of `now`, returns how long to wait before
+    /// going ahead
+    pub fn take(&mut self, amount: u64, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.last).as_secs_f64();
+
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate);
+        self.last = now;
+        self.tokens -= amount as f64;
+
+        if self.tokens >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-self.tokens / self.rate)
+        }
+    }
+}
+
+/// Caps the commands and / or bytes per second going through a stage
+///
+/// The `rate` gauge holds the commands that went through during the last
+/// full second, whether a cap is set or not.
+pub struct Throttle {
+    commands: Option<TokenBucket>,
+    bytes: Option<TokenBucket>,
+    window_start: Instant,
+    window_count: u64,
+    rate: gasket::metrics::Gauge,
+    wait_ms: gasket::metrics::Counter,
+}
+
+impl Throttle {
+    pub fn new(max_commands_per_sec: Option<u64>, max_bytes_per_sec: Option<u64>) -> Self {
+        let now = Instant::now();
+
+        Self {
+            commands: max_commands_per_sec.map(|x| TokenBucket::new(x, now)),
+            bytes: max_bytes_per_sec.map(|x| TokenBucket::new(x, now)),
+            window_start: now,
+            window_count: 0,
+            rate: Default::default(),
+            wait_ms: Default::default(),
+        }
+    }
+
+    pub fn rate(&self) -> &gasket::metrics::Gauge {
+        &self.rate
+    }
+
+    pub fn wait_ms(&self) -> &gasket::metrics::Counter {
+        &self.wait_ms
+    }
+
+    /// How long a command of `size` bytes has to wait as of `now`
+    pub fn delay(&mut self, size: usize, now: Instant) -> Duration {
+        let commands = match &mut self.commands {
+            Some(bucket) => bucket.take(1, now),
+            None => Duration::ZERO,
+        };
+
+        let bytes = match &mut self.bytes {
+            Some(bucket) => bucket.take(size as u64, now),
+            None => Duration::ZERO,
+        };
+
+        let delay = commands.max(bytes);
+
+        self.window_count += 1;
+
+        let elapsed = (now + delay).saturating_duration_since(self.window_start);
+
+        if elapsed >= Duration::from_secs(1) {
+            self.rate
+                .set((self.window_count as f64 / elapsed.as_secs_f64()) as i64);
+
+            self.window_start = now + delay;
+            self.window_count = 0;
+        }
+
+        delay
+    }
+
+    /// Blocks the current thread until a command of `size` bytes can go
+    /// ahead
+    pub fn acquire(&mut self, size: usize) {
+        let delay = self.delay(size, Instant::now());
+
+        if !delay.is_zero() {
+            self.wait_ms.inc(delay.as_millis() as u64);
+            thread::sleep(delay);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::Throttle;
+
+    /// Time it takes for `count` commands of `size` bytes to go through
+    fn elapsed(throttle: &mut Throttle, count: usize, size: usize) -> Duration {
+        let start = Instant::now();
+        let mut now = start;
+
+        for _ in 0..count {
+            now += throttle.delay(size, now);
+        }
+
+        now - start
+    }
+
+    #[test]
+    fn rate_cap_is_honored() {
+        // the first second worth of commands goes through right away
+        let mut throttle = Throttle::new(Some(100), None);
+        let took = elapsed(&mut throttle, 500, 10);
+        assert!(took > Duration::from_millis(3990));
+        assert!(took < Duration::from_millis(4100));
+
+        let mut throttle = Throttle::new(None, Some(1_000));
+        let took = elapsed(&mut throttle, 50, 100);
+        assert!(took > Duration::from_millis(3990));
+        assert!(took < Duration::from_millis(4100));
+
+        // the strictest cap wins
+        let mut throttle = Throttle::new(Some(1_000), Some(1_000));
+        let took = elapsed(&mut throttle, 50, 100);
+        assert!(took > Duration::from_millis(3990));
+
+        let mut throttle = Throttle::new(None, None);
+        assert_eq!(elapsed(&mut throttle, 10_000, 100), Duration::ZERO);
+    }
+}
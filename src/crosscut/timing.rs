@@ -0,0 +1,96 @@
+use std::time::{Duration, Instant};
+
+/// Upper bounds (inclusive, in milliseconds) of the histogram buckets
+pub const BUCKETS_MS: [u64; 6] = [1, 10, 100, 1_000, 10_000, u64::MAX];
+
+/// Metric keys used to register a histogram, see `histogram_keys!`
+pub struct HistogramKeys {
+    pub buckets: [&'static str; 6],
+    pub sum_ms: &'static str,
+    pub count: &'static str,
+}
+
+/// Builds the set of metric keys for a histogram from a static prefix
+#[macro_export]
+macro_rules! histogram_keys {
+    ($prefix:literal) => {
+        $crate::crosscut::timing::HistogramKeys {
+            buckets: [
+                concat!($prefix, "_le_1ms"),
+                concat!($prefix, "_le_10ms"),
+                concat!($prefix, "_le_100ms"),
+                concat!($prefix, "_le_1s"),
+                concat!($prefix, "_le_10s"),
+                concat!($prefix, "_le_inf"),
+            ],
+            sum_ms: concat!($prefix, "_sum_ms"),
+            count: concat!($prefix, "_count"),
+        }
+    };
+}
+
+/// A simple latency histogram made of bucketed counters
+///
+/// Gasket's metrics registry only knows about counters and gauges, so each
+/// bucket is exposed as an independent (non-cumulative) counter, together
+/// with the total count and the sum of the observed values which can be used
+/// to derive the average.
+#[derive(Default)]
+pub struct Histogram {
+    buckets: [gasket::metrics::Counter; 6],
+    sum_ms: gasket::metrics::Counter,
+    count: gasket::metrics::Counter,
+}
+
+fn bucket_index(millis: u64) -> usize {
+    BUCKETS_MS
+        .iter()
+        .position(|bound| millis <= *bound)
+        .unwrap_or(BUCKETS_MS.len() - 1)
+}
+
+impl Histogram {
+    pub fn observe(&self, elapsed: Duration) {
+        let millis = elapsed.as_millis() as u64;
+
+        self.buckets[bucket_index(millis)].inc(1);
+        self.sum_ms.inc(millis);
+        self.count.inc(1);
+    }
+
+    /// Records the time elapsed since `start`
+    pub fn observe_since(&self, start: Instant) {
+        self.observe(start.elapsed());
+    }
+
+    pub fn register(
+        &self,
+        builder: gasket::metrics::Builder,
+        keys: &HistogramKeys,
+    ) -> gasket::metrics::Builder {
+        let builder = self
+            .buckets
+            .iter()
+            .zip(keys.buckets.iter())
+            .fold(builder, |b, (counter, key)| b.with_counter(key, counter));
+
+        builder
+            .with_counter(keys.sum_ms, &self.sum_ms)
+            .with_counter(keys.count, &self.count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::bucket_index;
+
+    #[test]
+    fn values_fall_in_expected_bucket() {
+        assert_eq!(bucket_index(0), 0);
+        assert_eq!(bucket_index(1), 0);
+        assert_eq!(bucket_index(2), 1);
+        assert_eq!(bucket_index(100), 2);
+        assert_eq!(bucket_index(5_000), 4);
+        assert_eq!(bucket_index(60_000), 5);
+    }
+}
@@ -0,0 +1,263 @@
+//! Capture of enriched blocks, to run the reducers without a node or an
+//! enrich db
+//!
+//! The sled stage appends each payload it emits to the file set as
+//! `capture_path`, one json document per line:
+//!
+//! ```text
+//! {"type":"roll_forward","block":"<cbor hex>","utxos":{"<tx hash>#<idx>":[<era>,"<cbor hex>"]},"datums":{"<hash>":"<cbor hex>"},"utxo_count":null}
+//! {"type":"roll_back","point":"<slot>,<hash>"}
+//! ```
+//!
+//! Eras are the numeric tags used by the enrich db. `scrolls replay` feeds
+//! the file back to the reducers stage, which makes reducer changes
+//! reproducible against the same input and quick to iterate on.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, LineWriter, Lines, Write};
+use std::time::Duration;
+
+use gasket::runtime::{spawn_stage, WorkOutcome};
+use pallas::ledger::traverse::Era;
+use pallas::network::miniprotocols::Point;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    bootstrap,
+    model::{self, point_serde, BlockContext},
+    prelude::*,
+};
+
+type OutputPort = gasket::messaging::OutputPort<model::EnrichedBlockPayload>;
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Record {
+    RollForward {
+        block: String,
+        utxos: HashMap<String, (u16, String)>,
+        datums: HashMap<String, String>,
+        utxo_count: Option<u64>,
+    },
+    RollBack {
+        #[serde(with = "point_serde")]
+        point: Point,
+    },
+}
+
+/// Writes a payload as a line of the capture format, without the newline
+pub fn encode(payload: &model::EnrichedBlockPayload) -> Result<String, crate::Error> {
+    let record = match payload {
+        model::EnrichedBlockPayload::RollForward(cbor, ctx) => {
+            let (utxos, datums) = ctx.parts();
+
+            Record::RollForward {
+                block: hex::encode(cbor),
+                utxos: utxos
+                    .iter()
+                    .map(|(key, (era, cbor))| (key.clone(), (u16::from(*era), hex::encode(cbor))))
+                    .collect(),
+                datums: datums
+                    .iter()
+                    .map(|(key, cbor)| (key.clone(), hex::encode(cbor)))
+                    .collect(),
+                utxo_count: ctx.utxo_count(),
+            }
+        }
+        model::EnrichedBlockPayload::RollBack(point) => Record::RollBack {
+            point: point.clone(),
+        },
+    };
+
+    serde_json::to_string(&record).map_err(crate::Error::storage)
+}
+
+/// Reads a payload from a line of the capture format
+pub fn decode(line: &str) -> Result<model::EnrichedBlockPayload, crate::Error> {
+    let record: Record = serde_json::from_str(line).map_err(crate::Error::source)?;
+
+    let payload = match record {
+        Record::RollForward {
+            block,
+            utxos,
+            datums,
+            utxo_count,
+        } => {
+            let utxos = utxos
+                .into_iter()
+                .map(|(key, (era, cbor))| {
+                    let era: Era = era.try_into().map_err(crate::Error::source)?;
+                    let cbor = hex::decode(cbor).map_err(crate::Error::source)?;
+                    Ok((key, (era, cbor)))
+                })
+                .collect::<Result<_, crate::Error>>()?;
+
+            let datums = datums
+                .into_iter()
+                .map(|(key, cbor)| Ok((key, hex::decode(cbor).map_err(crate::Error::source)?)))
+                .collect::<Result<_, crate::Error>>()?;
+
+            let block = hex::decode(block).map_err(crate::Error::source)?;
+
+            model::EnrichedBlockPayload::RollForward(
+                block,
+                BlockContext::from_parts(utxos, datums, utxo_count),
+            )
+        }
+        Record::RollBack { point } => model::EnrichedBlockPayload::RollBack(point),
+    };
+
+    Ok(payload)
+}
+
+/// Appends the payloads to a capture file
+///
+/// Each line is written as soon as it's complete. The file isn't truncated:
+/// a restarted pipeline appends the blocks it replays from its cursor after
+/// the ones already captured, which would reduce them twice on replay.
+pub struct Recorder {
+    file: LineWriter<File>,
+}
+
+impl Recorder {
+    pub fn open(path: &str) -> Result<Self, crate::Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(crate::Error::storage)?;
+
+        Ok(Self {
+            file: LineWriter::new(file),
+        })
+    }
+
+    pub fn record(&mut self, payload: &model::EnrichedBlockPayload) -> Result<(), crate::Error> {
+        let line = encode(payload)?;
+
+        writeln!(self.file, "{}", line).map_err(crate::Error::storage)
+    }
+}
+
+/// Stage replaying a capture file in place of the source and enrich stages
+///
+/// The stage is done once the whole file was sent.
+pub struct Bootstrapper {
+    path: String,
+    output: OutputPort,
+}
+
+impl Bootstrapper {
+    pub fn new(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            output: Default::default(),
+        }
+    }
+
+    pub fn borrow_output_port(&mut self) -> &'_ mut OutputPort {
+        &mut self.output
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker {
+            path: self.path,
+            lines: None,
+            output: self.output,
+            blocks_counter: Default::default(),
+        };
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            Some("replay"),
+        ));
+    }
+}
+
+pub struct Worker {
+    path: String,
+    lines: Option<Lines<BufReader<File>>>,
+    output: OutputPort,
+    blocks_counter: gasket::metrics::Counter,
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("replayed_blocks", &self.blocks_counter)
+            .build()
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        let file = File::open(&self.path).or_panic()?;
+        self.lines = Some(BufReader::new(file).lines());
+
+        Ok(())
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let line = match self.lines.as_mut().and_then(|x| x.next()) {
+            Some(x) => x.or_panic()?,
+            None => {
+                log::info!("capture {} fully replayed", self.path);
+                return Ok(WorkOutcome::Done);
+            }
+        };
+
+        if line.trim().is_empty() {
+            return Ok(WorkOutcome::Partial);
+        }
+
+        let payload = decode(&line).or_panic()?;
+
+        if let model::EnrichedBlockPayload::RollForward(..) = payload {
+            self.blocks_counter.inc(1);
+        }
+
+        self.output
+            .send(gasket::messaging::Message::from(payload))?;
+
+        Ok(WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::crypto::hash::Hash;
+    use pallas::ledger::traverse::{Era, OutputRef};
+    use pallas::network::miniprotocols::Point;
+
+    use super::{decode, encode};
+    use crate::model::{BlockContext, EnrichedBlockPayload};
+
+    #[test]
+    fn payloads_survive_a_round_trip() {
+        let mut ctx = BlockContext::default();
+        ctx.import_ref_output(
+            &OutputRef::new(Hash::new([0x11; 32]), 3),
+            Era::Alonzo,
+            vec![1, 2, 3],
+        );
+        ctx.import_datum(&Hash::new([0x22; 32]), vec![4, 5]);
+        ctx.set_utxo_count(42);
+
+        let payloads = vec![
+            EnrichedBlockPayload::RollForward(vec![0xca, 0xfe], ctx),
+            EnrichedBlockPayload::RollBack(Point::Specific(7, vec![0x33; 32])),
+            EnrichedBlockPayload::RollBack(Point::Origin),
+        ];
+
+        for payload in payloads {
+            let line = encode(&payload).unwrap();
+            assert!(!line.contains('\n'));
+
+            let decoded = decode(&line).unwrap();
+            assert_eq!(format!("{:?}", decoded), format!("{:?}", payload));
+        }
+    }
+}
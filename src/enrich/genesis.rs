@@ -0,0 +1,262 @@
+//! Utxos created by the Byron genesis
+//!
+//! The initial funds of a Byron network aren't produced by any block, they
+//! live in the genesis file: `nonAvvmBalances` maps base58 addresses to
+//! their lovelace and `avvmDistr` maps the keys of the ADA vending machine
+//! (base64url) to theirs, paid to redeem addresses derived from those keys.
+//! Each one becomes an output at index 0 of a pseudo-tx whose hash is the
+//! blake2b-256 of the address bytes.
+
+use std::collections::HashMap;
+
+use pallas::codec::minicbor::{self, data::Tag};
+use pallas::crypto::hash::{Hash, Hasher};
+use serde::Deserialize;
+use sha3::{Digest, Sha3_256};
+
+const BASE58_ALPHABET: &[u8] = b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// Byron address type of the redeem addresses
+const REDEEM_ADDRESS_TYPE: u8 = 2;
+
+/// Attribute of byron addresses holding the network magic
+const NETWORK_MAGIC_ATTRIBUTE: u8 = 2;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ProtocolConsts {
+    protocol_magic: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GenesisFile {
+    #[serde(default)]
+    avvm_distr: HashMap<String, String>,
+    #[serde(default)]
+    non_avvm_balances: HashMap<String, String>,
+    protocol_consts: ProtocolConsts,
+}
+
+fn base58_decode(value: &str) -> Option<Vec<u8>> {
+    // little-endian base 256 digits
+    let mut bytes: Vec<u8> = vec![];
+
+    for c in value.bytes() {
+        let mut carry = BASE58_ALPHABET.iter().position(|x| *x == c)? as u32;
+
+        for byte in bytes.iter_mut() {
+            let x = *byte as u32 * 58 + carry;
+            *byte = x as u8;
+            carry = x >> 8;
+        }
+
+        while carry > 0 {
+            bytes.push(carry as u8);
+            carry >>= 8;
+        }
+    }
+
+    // leading ones stand for leading zero bytes
+    let zeros = value.bytes().take_while(|x| *x == b'1').count();
+    bytes.extend(std::iter::repeat(0).take(zeros));
+    bytes.reverse();
+
+    Some(bytes)
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+
+    !crc
+}
+
+/// Wraps the payload of a byron address along with its checksum
+fn crc_protected(payload: &[u8]) -> Result<Vec<u8>, crate::Error> {
+    let mut e = minicbor::Encoder::new(vec![]);
+
+    e.array(2)
+        .and_then(|e| e.tag(Tag::Cbor))
+        .and_then(|e| e.bytes(payload))
+        .and_then(|e| e.u32(crc32(payload)))
+        .map_err(crate::Error::cbor)?;
+
+    Ok(e.into_writer())
+}
+
+/// Decodes a base58 byron address, checking its checksum
+fn byron_address(value: &str) -> Result<Vec<u8>, crate::Error> {
+    let bytes = base58_decode(value)
+        .ok_or_else(|| crate::Error::message(format!("invalid base58 address {}", value)))?;
+
+    let mut d = minicbor::Decoder::new(&bytes);
+
+    let (payload, crc) = d
+        .array()
+        .and_then(|_| d.tag())
+        .and_then(|_| d.bytes())
+        .and_then(|payload| Ok((payload, d.u32()?)))
+        .map_err(crate::Error::cbor)?;
+
+    if crc32(payload) != crc {
+        return Err(crate::Error::message(format!(
+            "invalid checksum of address {}",
+            value
+        )));
+    }
+
+    Ok(bytes)
+}
+
+fn encode_attributes(
+    e: &mut minicbor::Encoder<Vec<u8>>,
+    magic: Option<u32>,
+) -> Result<(), crate::Error> {
+    match magic {
+        Some(magic) => {
+            // attribute values are themselves cbor encoded
+            let magic = minicbor::to_vec(magic).map_err(crate::Error::cbor)?;
+
+            e.map(1)
+                .and_then(|e| e.u8(NETWORK_MAGIC_ATTRIBUTE))
+                .and_then(|e| e.bytes(&magic))
+                .map_err(crate::Error::cbor)?;
+        }
+        None => {
+            e.map(0).map_err(crate::Error::cbor)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Redeem address of an AVVM key, carrying the network magic on networks
+/// that require it
+fn redeem_address(key: &[u8], magic: Option<u32>) -> Result<Vec<u8>, crate::Error> {
+    // the root hashes the address type, its spending data and attributes
+    let mut e = minicbor::Encoder::new(vec![]);
+
+    e.array(3)
+        .and_then(|e| e.u8(REDEEM_ADDRESS_TYPE))
+        .and_then(|e| e.array(2))
+        .and_then(|e| e.u8(REDEEM_ADDRESS_TYPE))
+        .and_then(|e| e.bytes(key))
+        .map_err(crate::Error::cbor)?;
+
+    encode_attributes(&mut e, magic)?;
+
+    let root = Hasher::<224>::hash(&Sha3_256::digest(e.into_writer()));
+
+    let mut e = minicbor::Encoder::new(vec![]);
+
+    e.array(3)
+        .and_then(|e| e.bytes(&root[..]))
+        .map_err(crate::Error::cbor)?;
+
+    encode_attributes(&mut e, magic)?;
+
+    e.u8(REDEEM_ADDRESS_TYPE).map_err(crate::Error::cbor)?;
+
+    crc_protected(&e.into_writer())
+}
+
+/// Hash of the pseudo-tx holding the genesis output of an address
+pub fn genesis_tx_hash(address: &[u8]) -> Hash<32> {
+    Hasher::<256>::hash(address)
+}
+
+/// Cbor of a byron output, the address is already cbor encoded
+fn byron_output(address: &[u8], amount: u64) -> Result<Vec<u8>, crate::Error> {
+    let mut e = minicbor::Encoder::new(vec![]);
+
+    e.array(2).map_err(crate::Error::cbor)?;
+    e.writer_mut().extend_from_slice(address);
+    e.u64(amount).map_err(crate::Error::cbor)?;
+
+    Ok(e.into_writer())
+}
+
+fn parse_amount(value: &str, holder: &str) -> Result<u64, crate::Error> {
+    value
+        .parse()
+        .map_err(|_| crate::Error::message(format!("invalid balance {} of {}", value, holder)))
+}
+
+/// Reads the utxos of a Byron genesis file as `{tx hash}#0` keys along with
+/// the output cbor
+///
+/// `network_magic` puts the protocol magic of the file in the redeem
+/// addresses, as the testnets do but not mainnet.
+pub fn read_utxos(json: &str, network_magic: bool) -> Result<Vec<(String, Vec<u8>)>, crate::Error> {
+    let genesis: GenesisFile = serde_json::from_str(json).map_err(crate::Error::config)?;
+
+    let magic = match network_magic {
+        true => Some(genesis.protocol_consts.protocol_magic),
+        false => None,
+    };
+
+    let mut utxos = vec![];
+
+    for (address, amount) in genesis.non_avvm_balances.iter() {
+        let amount = parse_amount(amount, address)?;
+        let address = byron_address(address)?;
+
+        let key = format!("{}#0", genesis_tx_hash(&address));
+        utxos.push((key, byron_output(&address, amount)?));
+    }
+
+    for (key, amount) in genesis.avvm_distr.iter() {
+        let amount = parse_amount(amount, key)?;
+
+        let vk = base64::decode_config(key, base64::URL_SAFE)
+            .map_err(|_| crate::Error::message(format!("invalid avvm key {}", key)))?;
+
+        let address = redeem_address(&vk, magic)?;
+
+        let key = format!("{}#0", genesis_tx_hash(&address));
+        utxos.push((key, byron_output(&address, amount)?));
+    }
+
+    Ok(utxos)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{byron_address, crc32, redeem_address};
+
+    // redeem address of the key [7; 32] on a testnet with magic 1097911063
+    const REDEEM_ADDRESS: &str =
+        "2cWKMJemoBah9m4XyvPg69dMuoVJe2MUF6EMtmexWxJiAr8vLAoon2PrtZJBbCNqd4z4A";
+
+    #[test]
+    fn redeem_addresses_match_their_base58_form() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+
+        assert_eq!(
+            byron_address(REDEEM_ADDRESS).unwrap(),
+            redeem_address(&[7u8; 32], Some(1097911063)).unwrap()
+        );
+
+        // mainnet redeem addresses carry no magic
+        assert_ne!(
+            redeem_address(&[7u8; 32], None).unwrap(),
+            redeem_address(&[7u8; 32], Some(1097911063)).unwrap()
+        );
+
+        // a single changed character breaks the checksum
+        let tampered = REDEEM_ADDRESS.replace("ah9", "ah8");
+        assert!(byron_address(&tampered).is_err());
+    }
+}
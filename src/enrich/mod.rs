@@ -1,12 +1,16 @@
+pub mod capture;
+pub mod genesis;
 pub mod skip;
 pub mod sled;
 
 use gasket::messaging::{OutputPort, TwoPhaseInputPort};
 use serde::Deserialize;
 
-use crate::{bootstrap, crosscut, model};
+use crate::{bootstrap, crosscut, model, storage};
 
-#[derive(Deserialize)]
+use gasket::runtime::Worker as _;
+
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type")]
 pub enum Config {
     Skip,
@@ -20,10 +24,32 @@ impl Default for Config {
 }
 
 impl Config {
-    pub fn bootstrapper(self, policy: &crosscut::policies::RuntimePolicy) -> Bootstrapper {
+    /// See `sled::Config::scratch`, the skip backend keeps nothing
+    pub fn scratch(self, suffix: &str) -> Self {
+        match self {
+            Config::Skip => Config::Skip,
+            Config::Sled(c) => Config::Sled(c.scratch(suffix)),
+        }
+    }
+
+    /// See `sled::Config::remove_db`
+    pub fn remove_db(&self) -> Result<(), crate::Error> {
+        match self {
+            Config::Skip => Ok(()),
+            Config::Sled(c) => c.remove_db(),
+        }
+    }
+
+    /// Builds the stage, `pool_size` bounds the threads used for parallel
+    /// lookups and defaults to one per available core
+    pub fn bootstrapper(
+        self,
+        policy: &crosscut::policies::RuntimePolicy,
+        pool_size: Option<usize>,
+    ) -> Bootstrapper {
         match self {
             Config::Skip => Bootstrapper::Skip(skip::Bootstrapper::default()),
-            Config::Sled(c) => Bootstrapper::Sled(c.boostrapper(policy)),
+            Config::Sled(c) => Bootstrapper::Sled(c.boostrapper(policy, pool_size)),
         }
     }
 }
@@ -48,10 +74,108 @@ impl Bootstrapper {
         }
     }
 
+    /// Hands the stage a storage cursor to check its state against at
+    /// startup, only the sled backend keeps any
+    pub fn reconcile_with(&mut self, cursor: storage::Cursor) {
+        if let Bootstrapper::Sled(x) = self {
+            x.reconcile_with(cursor);
+        }
+    }
+
+    /// Gate opened once the stage is able to process blocks
+    pub fn ready_gate(&self) -> crosscut::ready::ReadyGate {
+        match self {
+            Bootstrapper::Skip(_) => crosscut::ready::ReadyGate::opened(),
+            Bootstrapper::Sled(x) => x.ready_gate(),
+        }
+    }
+
+    /// Blocks sent to the stage and not committed yet, see
+    /// `crosscut::inflight::Inflight`
+    pub fn inflight(&self) -> crosscut::inflight::Inflight {
+        match self {
+            Bootstrapper::Skip(x) => x.inflight(),
+            Bootstrapper::Sled(x) => x.inflight(),
+        }
+    }
+
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
         match self {
             Bootstrapper::Skip(x) => x.spawn_stages(pipeline),
             Bootstrapper::Sled(x) => x.spawn_stages(pipeline),
         }
     }
+
+    /// Builds the backend to be called inline by the fused reducers stage,
+    /// along with the input port that stage has to read from
+    pub(crate) fn into_inline(self) -> (TwoPhaseInputPort<model::RawBlockPayload>, Inline) {
+        match self {
+            Bootstrapper::Skip(x) => (x.into_input(), Inline::Skip),
+            Bootstrapper::Sled(x) => {
+                let (input, worker) = x.into_inline();
+                (input, Inline::Sled(Box::new(worker)))
+            }
+        }
+    }
+}
+
+/// Enrich backend called from another stage instead of running its own
+pub(crate) enum Inline {
+    Skip,
+    Sled(Box<sled::Worker>),
+}
+
+impl Inline {
+    /// Payloads ready to be reduced, see `sled::Worker::enrich`
+    pub(crate) fn enrich(
+        &mut self,
+        payload: model::RawBlockPayload,
+    ) -> Result<Option<Vec<model::EnrichedBlockPayload>>, gasket::error::Error> {
+        match (self, payload) {
+            (Inline::Sled(x), payload) => x.enrich(payload),
+            (Inline::Skip, model::RawBlockPayload::RollForward(cbor)) => {
+                Ok(Some(vec![model::EnrichedBlockPayload::RollForward(
+                    cbor,
+                    Default::default(),
+                )]))
+            }
+            (Inline::Skip, model::RawBlockPayload::RollBack(point)) => {
+                Ok(Some(vec![model::EnrichedBlockPayload::RollBack(point)]))
+            }
+        }
+    }
+
+    /// Payloads held back by a partial batch, see `sled::Worker::release`
+    pub(crate) fn release(
+        &mut self,
+    ) -> Result<Vec<model::EnrichedBlockPayload>, gasket::error::Error> {
+        match self {
+            Inline::Skip => Ok(vec![]),
+            Inline::Sled(x) => x.release(),
+        }
+    }
+
+    pub(crate) fn register_metrics(
+        &self,
+        builder: gasket::metrics::Builder,
+    ) -> gasket::metrics::Builder {
+        match self {
+            Inline::Skip => builder,
+            Inline::Sled(x) => x.register_metrics(builder),
+        }
+    }
+
+    pub(crate) fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        match self {
+            Inline::Skip => Ok(()),
+            Inline::Sled(x) => x.bootstrap(),
+        }
+    }
+
+    pub(crate) fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        match self {
+            Inline::Skip => Ok(()),
+            Inline::Sled(x) => x.teardown(),
+        }
+    }
 }
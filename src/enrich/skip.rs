@@ -3,7 +3,7 @@ use std::time::Duration;
 use gasket::runtime::{spawn_stage, WorkOutcome};
 
 use crate::{
-    bootstrap,
+    bootstrap, crosscut,
     model::{self, BlockContext},
 };
 
@@ -13,6 +13,7 @@ type OutputPort = gasket::messaging::OutputPort<model::EnrichedBlockPayload>;
 pub struct Bootstrapper {
     input: InputPort,
     output: OutputPort,
+    inflight: crosscut::inflight::Inflight,
 }
 
 impl Default for Bootstrapper {
@@ -20,6 +21,7 @@ impl Default for Bootstrapper {
         Self {
             input: Default::default(),
             output: Default::default(),
+            inflight: Default::default(),
         }
     }
 }
@@ -33,10 +35,19 @@ impl Bootstrapper {
         &mut self.output
     }
 
+    pub fn inflight(&self) -> crosscut::inflight::Inflight {
+        self.inflight.clone()
+    }
+
+    pub(crate) fn into_input(self) -> InputPort {
+        self.input
+    }
+
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
         let worker = Worker {
             input: self.input,
             output: self.output,
+            inflight: self.inflight,
         };
 
         pipeline.register_stage(spawn_stage(
@@ -53,6 +64,7 @@ impl Bootstrapper {
 pub struct Worker {
     input: InputPort,
     output: OutputPort,
+    inflight: crosscut::inflight::Inflight,
 }
 
 impl gasket::runtime::Worker for Worker {
@@ -77,6 +89,8 @@ impl gasket::runtime::Worker for Worker {
         };
 
         self.input.commit();
+        self.inflight.acknowledged();
+
         Ok(WorkOutcome::Partial)
     }
 }
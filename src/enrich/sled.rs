@@ -1,39 +1,520 @@
-use std::time::Duration;
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use gasket::{
     error::AsWorkError,
     runtime::{spawn_stage, WorkOutcome},
 };
+use lru::LruCache;
 
 use pallas::{
     codec::minicbor,
-    ledger::traverse::{Era, MultiEraBlock, MultiEraTx, OutputRef},
+    crypto::hash::{Hash, Hasher},
+    ledger::primitives::babbage::DatumOption,
+    ledger::traverse::{Era, MultiEraBlock, MultiEraOutput, MultiEraTx, OutputRef},
+    network::miniprotocols::Point,
 };
 use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
 use serde::Deserialize;
 use sled::IVec;
 
 use crate::{
-    bootstrap, crosscut,
+    bootstrap,
+    crosscut::{self, PointArg},
     model::{self, BlockContext},
     prelude::AppliesPolicy,
+    storage,
 };
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::RawBlockPayload>;
 type OutputPort = gasket::messaging::OutputPort<model::EnrichedBlockPayload>;
 
-#[derive(Deserialize, Clone)]
+const UTXOS_TREE: &str = "utxos";
+const ADDRESS_INDEX_TREE: &str = "utxos_by_address";
+const CONSUMED_RING_TREE: &str = "consumed_ring";
+const DATUMS_TREE: &str = "datums";
+const DATUM_RING_TREE: &str = "datum_ring";
+const META_TREE: &str = "enrich_meta";
+
+/// Key of the meta tree holding the last block applied to the utxo set
+const TIP_KEY: &str = "tip";
+
+/// Key of the meta tree set while the address index holds every utxo
+const ADDRESS_INDEX_READY_KEY: &str = "address_index_ready";
+
+/// Number of snapshot entries written to the db per batch
+const SNAPSHOT_BATCH_SIZE: usize = 10_000;
+
+/// Changes of a block to the utxo set: consumed (key, value) and produced keys
+type UndoEntry = (Vec<(String, Vec<u8>)>, Vec<String>);
+
+/// Outcome of undoing the blocks after a rollback point
+#[derive(Debug, PartialEq, Eq)]
+enum Undo {
+    /// The rollback window is disabled, the utxo set is left as is
+    Disabled,
+    /// Number of reverted blocks, zero if the point is at or past the tip
+    Reverted(usize),
+    /// The point is older than the blocks kept by the rollback window, the
+    /// utxo set is left as is
+    PastWindow,
+}
+
+/// Position of the enrich db relative to the storage cursor
+#[derive(Debug, PartialEq)]
+enum Drift {
+    InSync,
+    /// The db applied blocks the storage didn't commit
+    Ahead,
+    /// The storage committed blocks the db didn't apply
+    Behind,
+    /// Both are at the same slot but on different blocks
+    Forked,
+}
+
+fn drift(tip: &PointArg, cursor: &PointArg) -> Drift {
+    let slot = |x: &PointArg| match x {
+        PointArg::Origin => None,
+        PointArg::Specific(slot, _) => Some(*slot),
+    };
+
+    match slot(tip).cmp(&slot(cursor)) {
+        Ordering::Greater => Drift::Ahead,
+        Ordering::Less => Drift::Behind,
+        Ordering::Equal if tip.to_string() == cursor.to_string() => Drift::InSync,
+        Ordering::Equal => Drift::Forked,
+    }
+}
+
+/// Changes to the utxo set and its address index not yet applied to the db
+///
+/// A `None` value marks a removal. Lookups go through these first, so that
+/// the blocks of a batch see the outputs produced and spent by each other.
+#[derive(Default)]
+struct PendingWrites {
+    utxos: HashMap<String, Option<IVec>>,
+    index: HashMap<IVec, Option<IVec>>,
+    blocks: usize,
+    /// Last block of the batch, recorded as the tip once applied
+    tip: Option<Point>,
+}
+
+impl PendingWrites {
+    fn apply(&mut self, db: &sled::Tree, index: Option<&sled::Tree>) -> Result<(), crate::Error> {
+        let mut batch = sled::Batch::default();
+
+        for (key, value) in self.utxos.drain() {
+            match value {
+                Some(value) => batch.insert(key.as_bytes(), value),
+                None => batch.remove(key.as_bytes()),
+            }
+        }
+
+        db.apply_batch(batch).map_err(crate::Error::storage)?;
+
+        if let Some(index) = index {
+            let mut batch = sled::Batch::default();
+
+            for (key, value) in self.index.drain() {
+                match value {
+                    Some(value) => batch.insert(key, value),
+                    None => batch.remove(key),
+                }
+            }
+
+            index.apply_batch(batch).map_err(crate::Error::storage)?;
+        }
+
+        self.index.clear();
+        self.blocks = 0;
+
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Clone, Default)]
 pub struct Config {
-    pub db_path: String,
+    /// Path of a dedicated sled db for the utxo set
+    pub db_path: Option<String>,
+
+    /// Directory of a single sled db holding all of the enrich data as named
+    /// trees. Takes precedence over `db_path` when both are present.
+    pub data_dir: Option<String>,
+
+    /// Maintain a secondary address -> utxo refs index, required by
+    /// `UtxoStore::by_address`. It roughly doubles the writes of the stage
+    /// and requires the utxo value to be read before each removal. Enabling
+    /// it on a db that already holds utxos builds it when the db is opened.
+    pub address_index: Option<bool>,
+
+    /// Number of recent blocks whose consumed utxos are kept to undo them on
+    /// rollback. Disabled by default, in which case rollbacks leave the utxo
+    /// set untouched. Rollbacks past the window stop the stage.
+    pub rollback_window: Option<usize>,
+
+    /// Keep the datums supplied in witness sets by hash, so that outputs
+    /// carrying only a datum hash can be resolved through the block context.
+    /// Datums added by a block are forgotten when it's rolled back, as long
+    /// as the `rollback_window` is enabled.
+    pub datum_index: Option<bool>,
+
+    /// Keep a running count of the utxo set, exposed as the
+    /// `enrich_utxo_count` gauge and to reducers through the block context.
+    /// The count is taken once at startup, which walks the whole utxo tree,
+    /// and then follows the inserts and removals of each block.
+    pub utxo_count: Option<bool>,
+
+    /// Ratio (between 0 and 1) of the inputs of a block that can't be
+    /// resolved from the db above which the unresolved refs are logged. A
+    /// persistent high ratio usually means that enrich started after the
+    /// outputs were produced. Disabled by default.
+    pub mismatch_diagnostics: Option<f64>,
+
+    /// Maximum number of unresolved refs logged per block when
+    /// `mismatch_diagnostics` is enabled
+    pub mismatch_diagnostics_limit: Option<usize>,
+
+    /// Number of blocks whose changes to the utxo set are accumulated in
+    /// memory and applied to the db as a single batch, which speeds up the
+    /// initial sync. The batch is also applied before a rollback and
+    /// whenever the input runs idle, which keeps the blocks flowing at the
+    /// tip. The blocks of a batch are only sent downstream once it's applied,
+    /// so the storage cursor never gets past the db: a crash or a shutdown
+    /// drops the batch along with its blocks and the source sends them again.
+    /// Disabled by default.
+    pub batch_blocks: Option<usize>,
+
+    /// Path of a Byron genesis file whose initial utxos are loaded when the
+    /// db is empty, so that the txs spending them find their inputs. Those
+    /// outputs aren't produced by any block, without this they only show up
+    /// as mismatches.
+    pub byron_genesis: Option<String>,
+
+    /// Whether the redeem addresses of the AVVM distribution of the genesis
+    /// carry the protocol magic, as they do on the testnets. Off by default,
+    /// as on mainnet.
+    pub byron_genesis_network_magic: Option<bool>,
+
+    /// Number of recent txs whose hashes are kept to flag the inputs that
+    /// point at one of their outputs but can't be resolved, counted by the
+    /// `enrich_ordering_violations` metric. On a valid chain that only
+    /// happens if a block was enriched before the ones it spends from,
+    /// debug builds stop the stage on it. Disabled by default.
+    pub ordering_check: Option<usize>,
+
+    /// Path of a file where every payload sent to the reducers is appended,
+    /// to be replayed later through `scrolls replay`, see `capture`
+    pub capture_path: Option<String>,
+}
+
+const DEFAULT_MISMATCH_DIAGNOSTICS_LIMIT: usize = 10;
+
+/// Hashes of the txs of the recently enriched blocks
+///
+/// Their outputs were inserted before any later block was enriched, so an
+/// input pointing at one of them that can't be resolved was either spent
+/// twice or looked up too early. The former can't happen on a valid chain.
+struct RecentTxs(LruCache<Hash<32>, ()>);
+
+impl RecentTxs {
+    fn new(size: NonZeroUsize) -> Self {
+        Self(LruCache::new(size))
+    }
+
+    fn record(&mut self, txs: &[MultiEraTx]) {
+        for tx in txs {
+            self.0.put(tx.hash(), ());
+        }
+    }
+
+    fn produced(&self, utxo_ref: &OutputRef) -> bool {
+        self.0.contains(utxo_ref.hash())
+    }
+
+    /// Forgets the txs of the blocks that were rolled back along with the
+    /// others, only blocks enriched from now on are tracked
+    fn clear(&mut self) {
+        self.0.clear();
+    }
+}
+
+/// Whether the share of unresolved inputs of a block is above the threshold
+fn exceeds_mismatch_ratio(required: usize, unresolved: usize, threshold: f64) -> bool {
+    required > 0 && unresolved as f64 / required as f64 > threshold
 }
 
 impl Config {
-    pub fn boostrapper(self, policy: &crosscut::policies::RuntimePolicy) -> Bootstrapper {
+    /// Same settings, pointing at a db of its own next to the configured one
+    ///
+    /// The path gets `.{suffix}` appended, so that a replay of the chain
+    /// builds a separate utxo set and leaves the one of the daemon alone.
+    /// Nothing is captured, the capture file belongs to the daemon as well.
+    pub fn scratch(mut self, suffix: &str) -> Self {
+        self.data_dir = self.data_dir.map(|x| format!("{}.{}", x, suffix));
+        self.db_path = self.db_path.map(|x| format!("{}.{}", x, suffix));
+        self.capture_path = None;
+        self
+    }
+
+    /// Deletes the db of the config, meant for the scratch ones
+    pub fn remove_db(&self) -> Result<(), crate::Error> {
+        for path in self.data_dir.iter().chain(self.db_path.iter()) {
+            match std::fs::remove_dir_all(path) {
+                Ok(()) => (),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => return Err(crate::Error::storage(err)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Opens the sled db and the tree used to store the utxo set
+    ///
+    /// When using the legacy `db_path` layout, utxos live in the default tree
+    /// of the db. With `data_dir`, they live in a named tree so that other
+    /// enrich data can share the same db.
+    fn open_db(&self) -> Result<(sled::Db, sled::Tree), crate::Error> {
+        match (&self.data_dir, &self.db_path) {
+            (Some(dir), path) => {
+                if path.is_some() {
+                    log::warn!("both data_dir and db_path are set, using data_dir");
+                }
+
+                let db = sled::open(dir).map_err(crate::Error::storage)?;
+                let utxos = db.open_tree(UTXOS_TREE).map_err(crate::Error::storage)?;
+                Ok((db, utxos))
+            }
+            (None, Some(path)) => {
+                let db = sled::open(path).map_err(crate::Error::storage)?;
+                let utxos = (*db).clone();
+                Ok((db, utxos))
+            }
+            (None, None) => Err(crate::Error::config(
+                "sled enrich requires either data_dir or db_path",
+            )),
+        }
+    }
+
+    /// Opens the address index if enabled, building it from the utxo set
+    /// unless it was kept up to date since it was last built
+    ///
+    /// Disabling the index drops it, it would miss the utxos produced in the
+    /// meantime if it was enabled again.
+    fn open_address_index(
+        &self,
+        db: &sled::Db,
+        utxos: &sled::Tree,
+    ) -> Result<Option<sled::Tree>, crate::Error> {
+        let meta = self.open_meta(db)?;
+
+        if self.address_index != Some(true) {
+            if meta
+                .remove(ADDRESS_INDEX_READY_KEY)
+                .map_err(crate::Error::storage)?
+                .is_some()
+            {
+                log::info!("address index disabled, dropping it");
+                db.drop_tree(ADDRESS_INDEX_TREE)
+                    .map_err(crate::Error::storage)?;
+            }
+
+            return Ok(None);
+        }
+
+        let addresses = db
+            .open_tree(ADDRESS_INDEX_TREE)
+            .map_err(crate::Error::storage)?;
+
+        if !meta
+            .contains_key(ADDRESS_INDEX_READY_KEY)
+            .map_err(crate::Error::storage)?
+        {
+            if !utxos.is_empty() {
+                log::info!("building the address index, this walks the whole db");
+            }
+
+            build_address_index(utxos, &addresses)?;
+
+            meta.insert(ADDRESS_INDEX_READY_KEY, IVec::default())
+                .map_err(crate::Error::storage)?;
+        }
+
+        Ok(Some(addresses))
+    }
+
+    fn open_consumed_ring(&self, db: &sled::Db) -> Result<Option<sled::Tree>, crate::Error> {
+        match self.rollback_window {
+            Some(x) if x > 0 => db
+                .open_tree(CONSUMED_RING_TREE)
+                .map(Some)
+                .map_err(crate::Error::storage),
+            _ => Ok(None),
+        }
+    }
+
+    fn open_datum_index(&self, db: &sled::Db) -> Result<Option<sled::Tree>, crate::Error> {
+        match self.datum_index {
+            Some(true) => db
+                .open_tree(DATUMS_TREE)
+                .map(Some)
+                .map_err(crate::Error::storage),
+            _ => Ok(None),
+        }
+    }
+
+    /// Tree with the datums added by each block of the rollback window, kept
+    /// apart from the consumed ring so that its entries stay compatible
+    fn open_datum_ring(&self, db: &sled::Db) -> Result<Option<sled::Tree>, crate::Error> {
+        match (self.datum_index, self.rollback_window) {
+            (Some(true), Some(x)) if x > 0 => db
+                .open_tree(DATUM_RING_TREE)
+                .map(Some)
+                .map_err(crate::Error::storage),
+            _ => Ok(None),
+        }
+    }
+
+    fn open_meta(&self, db: &sled::Db) -> Result<sled::Tree, crate::Error> {
+        db.open_tree(META_TREE).map_err(crate::Error::storage)
+    }
+
+    /// Loads the entries of a utxo snapshot into an empty enrich db
+    ///
+    /// Each line is an unspent output as `{tx hash}#{index},{era},{cbor}`,
+    /// with the era number as stored by the db (0 for Byron up to 5 for
+    /// Babbage) and the output cbor in hex. The header with the snapshot
+    /// point is read with `parse_snapshot_point` beforehand. A malformed
+    /// entry aborts the import, leaving the db partially filled.
+    ///
+    /// Returns the number of imported utxos.
+    pub fn import_snapshot(&self, entries: impl BufRead) -> Result<usize, crate::Error> {
+        let (db, utxos) = self.open_db()?;
+
+        if !utxos.is_empty() {
+            return Err(crate::Error::config(
+                "the enrich db already holds utxos, snapshots can only be imported into an empty one",
+            ));
+        }
+
+        let addresses = self.open_address_index(&db, &utxos)?;
+
+        let mut batch = sled::Batch::default();
+        let mut index_batch = sled::Batch::default();
+        let mut batched = 0;
+        let mut imported = 0;
+
+        for line in entries.lines() {
+            let line = line.map_err(crate::Error::storage)?;
+            let line = line.trim();
+
+            if line.is_empty() {
+                continue;
+            }
+
+            let (key, value) = parse_snapshot_entry(line)?;
+
+            if addresses.is_some() {
+                let index_key = address_index_key(&utxo_address(&value)?, &key);
+                index_batch.insert(index_key, IVec::default());
+            }
+
+            batch.insert(key.as_bytes(), value);
+            batched += 1;
+            imported += 1;
+
+            if batched == SNAPSHOT_BATCH_SIZE {
+                apply_snapshot_batch(&utxos, addresses.as_ref(), &mut batch, &mut index_batch)?;
+                batched = 0;
+            }
+        }
+
+        apply_snapshot_batch(&utxos, addresses.as_ref(), &mut batch, &mut index_batch)?;
+        db.flush().map_err(crate::Error::storage)?;
+
+        Ok(imported)
+    }
+
+    /// Loads the utxos of the Byron genesis into the db if it's still empty,
+    /// returns how many
+    ///
+    /// A db holding any utxo is left alone: it was either loaded on a
+    /// previous start or built from a later point, and the genesis outputs
+    /// spent since then must not come back.
+    fn load_byron_genesis(
+        &self,
+        utxos: &sled::Tree,
+        addresses: Option<&sled::Tree>,
+    ) -> Result<usize, crate::Error> {
+        let path = match &self.byron_genesis {
+            Some(x) => x,
+            None => return Ok(0),
+        };
+
+        if !utxos.is_empty() {
+            return Ok(0);
+        }
+
+        let json = std::fs::read_to_string(path).map_err(crate::Error::config)?;
+        let entries = super::genesis::read_utxos(
+            &json,
+            self.byron_genesis_network_magic.unwrap_or_default(),
+        )?;
+
+        let mut batch = sled::Batch::default();
+        let mut index_batch = sled::Batch::default();
+
+        for (key, cbor) in entries.iter() {
+            let value: IVec = SledTxValue(Era::Byron.into(), cbor.clone()).try_into()?;
+
+            if addresses.is_some() {
+                let index_key = address_index_key(&utxo_address(&value)?, key);
+                index_batch.insert(index_key, IVec::default());
+            }
+
+            batch.insert(key.as_bytes(), value);
+        }
+
+        apply_snapshot_batch(utxos, addresses, &mut batch, &mut index_batch)?;
+
+        Ok(entries.len())
+    }
+
+    /// Opens the enrich db for direct reads of the utxo set
+    ///
+    /// Sled holds an exclusive lock on the db, this can't be used while the
+    /// pipeline is running in another process.
+    pub fn open_store(&self) -> Result<UtxoStore, crate::Error> {
+        let (db, utxos) = self.open_db()?;
+        let addresses = self.open_address_index(&db, &utxos)?;
+        let ring = self.open_consumed_ring(&db)?;
+
+        Ok(UtxoStore {
+            _db: db,
+            utxos,
+            addresses,
+            ring,
+        })
+    }
+
+    pub fn boostrapper(
+        self,
+        policy: &crosscut::policies::RuntimePolicy,
+        pool_size: Option<usize>,
+    ) -> Bootstrapper {
         Bootstrapper {
             config: self,
             policy: policy.clone(),
+            pool_size,
             input: Default::default(),
             output: Default::default(),
+            ready: Default::default(),
+            inflight: Default::default(),
+            cursor: None,
         }
     }
 }
@@ -41,8 +522,12 @@ impl Config {
 pub struct Bootstrapper {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pool_size: Option<usize>,
     input: InputPort,
     output: OutputPort,
+    ready: crosscut::ready::ReadyGate,
+    inflight: crosscut::inflight::Inflight,
+    cursor: Option<storage::Cursor>,
 }
 
 impl Bootstrapper {
@@ -54,19 +539,37 @@ impl Bootstrapper {
         &mut self.output
     }
 
+    /// Storage cursor the utxo set is reconciled with before the stage opens,
+    /// see `Worker::reconcile`
+    pub fn reconcile_with(&mut self, cursor: storage::Cursor) {
+        self.cursor = Some(cursor);
+    }
+
+    pub fn ready_gate(&self) -> crosscut::ready::ReadyGate {
+        self.ready.clone()
+    }
+
+    pub fn inflight(&self) -> crosscut::inflight::Inflight {
+        self.inflight.clone()
+    }
+
+    /// Builds the worker to be driven by another stage, which takes over the
+    /// input port
+    pub(crate) fn into_inline(self) -> (InputPort, Worker) {
+        let mut worker = Worker::new(self.config, self.policy, Default::default(), self.output);
+        worker.ready = self.ready;
+        worker.pool_size = self.pool_size;
+        worker.cursor = self.cursor;
+
+        (self.input, worker)
+    }
+
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
-        let worker = Worker {
-            config: self.config,
-            policy: self.policy,
-            db: None,
-            input: self.input,
-            output: self.output,
-            inserts_counter: Default::default(),
-            remove_counter: Default::default(),
-            matches_counter: Default::default(),
-            mismatches_counter: Default::default(),
-            blocks_counter: Default::default(),
-        };
+        let mut worker = Worker::new(self.config, self.policy, self.input, self.output);
+        worker.ready = self.ready;
+        worker.inflight = self.inflight;
+        worker.pool_size = self.pool_size;
+        worker.cursor = self.cursor;
 
         pipeline.register_stage(spawn_stage(
             worker,
@@ -79,10 +582,328 @@ impl Bootstrapper {
     }
 }
 
+/// Read access to the utxo set kept by the enrich stage
+pub struct UtxoStore {
+    _db: sled::Db,
+    utxos: sled::Tree,
+    addresses: Option<sled::Tree>,
+    ring: Option<sled::Tree>,
+}
+
+/// Outcome of a consistency check of the enrich db
+#[derive(Debug, Default)]
+pub struct DbReport {
+    pub utxo_count: usize,
+    pub address_index_count: Option<usize>,
+    pub ring_size: Option<usize>,
+    pub issues: Vec<String>,
+}
+
+impl UtxoStore {
+    pub fn utxo_count(&self) -> usize {
+        self.utxos.len()
+    }
+
+    /// Walks the whole db looking for inconsistencies, without changing it
+    ///
+    /// Reports utxos that can't be decoded, address index entries out of sync
+    /// with the utxo set and outputs recorded as consumed by the rollback ring
+    /// that are still present in the utxo set.
+    pub fn check(&self) -> Result<DbReport, crate::Error> {
+        let mut report = DbReport::default();
+
+        for entry in self.utxos.iter() {
+            let (key, value) = entry.map_err(crate::Error::storage)?;
+            let key = String::from_utf8_lossy(&key).to_string();
+
+            report.utxo_count += 1;
+
+            if let Err(err) = parse_output_ref(&key) {
+                report.issues.push(format!("invalid utxo key {}: {}", key, err));
+                continue;
+            }
+
+            match (utxo_address(&value), &self.addresses) {
+                (Err(err), _) => report
+                    .issues
+                    .push(format!("undecodable utxo {}: {}", key, err)),
+                (Ok(address), Some(index)) => {
+                    let indexed = index
+                        .contains_key(address_index_key(&address, &key))
+                        .map_err(crate::Error::storage)?;
+
+                    if !indexed {
+                        report
+                            .issues
+                            .push(format!("utxo {} is missing from the address index", key));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(index) = &self.addresses {
+            let mut count = 0;
+
+            for key in index.iter().keys() {
+                let key = key.map_err(crate::Error::storage)?;
+                let key = String::from_utf8_lossy(&key).to_string();
+
+                count += 1;
+
+                let present = match key.rsplit_once('|') {
+                    Some((_, utxo)) => self
+                        .utxos
+                        .contains_key(utxo)
+                        .map_err(crate::Error::storage)?,
+                    None => false,
+                };
+
+                if !present {
+                    report
+                        .issues
+                        .push(format!("dangling address index entry {}", key));
+                }
+            }
+
+            report.address_index_count = Some(count);
+        }
+
+        if let Some(ring) = &self.ring {
+            let mut count = 0;
+
+            for entry in ring.iter() {
+                let (key, value) = entry.map_err(crate::Error::storage)?;
+                let slot = ring_key_slot(&key);
+
+                count += 1;
+
+                let consumed = match minicbor::decode::<UndoEntry>(&value) {
+                    Ok((consumed, _)) => consumed,
+                    Err(err) => {
+                        report
+                            .issues
+                            .push(format!("undecodable ring entry at slot {}: {}", slot, err));
+                        continue;
+                    }
+                };
+
+                for (utxo, _) in consumed {
+                    let present = self
+                        .utxos
+                        .contains_key(&utxo)
+                        .map_err(crate::Error::storage)?;
+
+                    if present {
+                        report.issues.push(format!(
+                            "utxo {} consumed at slot {} is still in the utxo set",
+                            utxo, slot
+                        ));
+                    }
+                }
+            }
+
+            report.ring_size = Some(count);
+        }
+
+        Ok(report)
+    }
+
+    /// Lists the unspent outputs locked by an address (bech32 / base58)
+    pub fn by_address(&self, address: &str) -> Result<Vec<OutputRef>, crate::Error> {
+        let index = self
+            .addresses
+            .as_ref()
+            .ok_or_else(|| crate::Error::config("the enrich address_index is disabled"))?;
+
+        index
+            .scan_prefix(address_index_prefix(address))
+            .keys()
+            .map(|key| {
+                let key = key.map_err(crate::Error::storage)?;
+                let utxo = &key[address_index_prefix(address).len()..];
+                parse_output_ref(&String::from_utf8_lossy(utxo))
+            })
+            .collect()
+    }
+}
+
+fn address_index_prefix(address: &str) -> String {
+    format!("{}|", address)
+}
+
+fn address_index_key(address: &str, utxo: &str) -> IVec {
+    format!("{}{}", address_index_prefix(address), utxo)
+        .as_bytes()
+        .into()
+}
+
+fn utxo_address(value: &[u8]) -> Result<String, crate::Error> {
+    let SledTxValue(era, cbor) = IVec::from(value).try_into()?;
+    let era: Era = era.try_into().map_err(crate::Error::storage)?;
+    let output = MultiEraOutput::decode(era, &cbor).map_err(crate::Error::cbor)?;
+    let address = output.address().map_err(crate::Error::ledger)?;
+
+    Ok(address.to_string())
+}
+
+fn ring_key(slot: u64, hash: &[u8]) -> Vec<u8> {
+    // big-endian slot first so that the tree is sorted by chain order
+    let mut key = slot.to_be_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+fn ring_key_slot(key: &[u8]) -> u64 {
+    let mut slot = [0u8; 8];
+    slot.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(slot)
+}
+
+/// Datums supplied in the witness set of a tx, keyed by their hex hash
+///
+/// The hash is computed over the original bytes, re-encoding the datum
+/// could yield a different one.
+fn witness_datums(tx: &MultiEraTx) -> Vec<(String, Vec<u8>)> {
+    let datums = match tx {
+        MultiEraTx::AlonzoCompatible(x, _) => x.transaction_witness_set.plutus_data.as_ref(),
+        MultiEraTx::Babbage(x) => x.transaction_witness_set.plutus_data.as_ref(),
+        _ => None,
+    };
+
+    datums
+        .into_iter()
+        .flatten()
+        .map(|datum| {
+            let cbor = datum.raw_cbor().to_vec();
+            (Hasher::<256>::hash(&cbor).to_string(), cbor)
+        })
+        .collect()
+}
+
+fn datum_hash(output: &MultiEraOutput) -> Option<Hash<32>> {
+    match output.datum()? {
+        DatumOption::Hash(x) => Some(x),
+        DatumOption::Data(_) => None,
+    }
+}
+
+/// Parses the header of a utxo snapshot, the `{slot},{block hash}` of the
+/// last block whose changes are included
+pub fn parse_snapshot_point(line: &str) -> Result<(u64, String), crate::Error> {
+    match crosscut::PointArg::from_str(line.trim())? {
+        crosscut::PointArg::Specific(slot, hash) => Ok((slot, hash)),
+        crosscut::PointArg::Origin => Err(crate::Error::message(
+            "the snapshot point has to be a specific block",
+        )),
+    }
+}
+
+/// Parses a snapshot entry into the key and value stored in the db, checking
+/// that the output decodes for the given era
+fn parse_snapshot_entry(line: &str) -> Result<(String, IVec), crate::Error> {
+    let mut parts = line.splitn(3, ',');
+
+    let (key, era, cbor) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(key), Some(era), Some(cbor)) => (key, era, cbor),
+        _ => {
+            return Err(crate::Error::message(format!(
+                "invalid snapshot entry {}",
+                line
+            )))
+        }
+    };
+
+    let utxo = parse_output_ref(key)?;
+
+    let tag: u16 = era
+        .parse()
+        .map_err(|_| crate::Error::message(format!("invalid era {} of utxo {}", era, key)))?;
+
+    let cbor = hex::decode(cbor).map_err(crate::Error::cbor)?;
+
+    let era: Era = tag.try_into().map_err(crate::Error::storage)?;
+    MultiEraOutput::decode(era, &cbor).map_err(crate::Error::cbor)?;
+
+    let value = SledTxValue(tag, cbor).try_into()?;
+
+    Ok((utxo.to_string(), value))
+}
+
+/// Fills the address index from scratch with every utxo of the set
+fn build_address_index(utxos: &sled::Tree, addresses: &sled::Tree) -> Result<(), crate::Error> {
+    addresses.clear().map_err(crate::Error::storage)?;
+
+    let mut batch = sled::Batch::default();
+    let mut batched = 0;
+
+    for entry in utxos.iter() {
+        let (key, value) = entry.map_err(crate::Error::storage)?;
+        let key = std::str::from_utf8(&key).map_err(crate::Error::storage)?;
+
+        batch.insert(
+            address_index_key(&utxo_address(&value)?, key),
+            IVec::default(),
+        );
+        batched += 1;
+
+        if batched == SNAPSHOT_BATCH_SIZE {
+            addresses
+                .apply_batch(std::mem::take(&mut batch))
+                .map_err(crate::Error::storage)?;
+            batched = 0;
+        }
+    }
+
+    addresses.apply_batch(batch).map_err(crate::Error::storage)
+}
+
+fn apply_snapshot_batch(
+    utxos: &sled::Tree,
+    addresses: Option<&sled::Tree>,
+    batch: &mut sled::Batch,
+    index_batch: &mut sled::Batch,
+) -> Result<(), crate::Error> {
+    utxos
+        .apply_batch(std::mem::take(batch))
+        .map_err(crate::Error::storage)?;
+
+    if let Some(index) = addresses {
+        index
+            .apply_batch(std::mem::take(index_batch))
+            .map_err(crate::Error::storage)?;
+    }
+
+    Ok(())
+}
+
+fn parse_output_ref(value: &str) -> Result<OutputRef, crate::Error> {
+    let (hash, idx) = value
+        .split_once('#')
+        .ok_or_else(|| crate::Error::storage(format!("invalid utxo key {}", value)))?;
+
+    let hash = Hash::<32>::from_str(hash).map_err(crate::Error::storage)?;
+    let idx = idx.parse().map_err(crate::Error::storage)?;
+
+    Ok(OutputRef::new(hash, idx))
+}
+
 pub struct Worker {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
     db: Option<sled::Db>,
+    utxos: Option<sled::Tree>,
+    addresses: Option<sled::Tree>,
+    consumed_ring: Option<sled::Tree>,
+    datums: Option<sled::Tree>,
+    datum_ring: Option<sled::Tree>,
+    meta: Option<sled::Tree>,
+    ring_len: usize,
+    cursor: Option<storage::Cursor>,
+    pool_size: Option<usize>,
+    pool: Option<rayon::ThreadPool>,
+    ready: crosscut::ready::ReadyGate,
+    inflight: crosscut::inflight::Inflight,
     input: InputPort,
     output: OutputPort,
     inserts_counter: gasket::metrics::Counter,
@@ -90,6 +911,16 @@ pub struct Worker {
     matches_counter: gasket::metrics::Counter,
     mismatches_counter: gasket::metrics::Counter,
     blocks_counter: gasket::metrics::Counter,
+    utxo_count: Option<i64>,
+    utxo_count_gauge: gasket::metrics::Gauge,
+    pending: Option<PendingWrites>,
+    /// Payloads of the blocks in `pending`, sent once it's applied
+    held: Vec<model::EnrichedBlockPayload>,
+    recent_txs: Option<RecentTxs>,
+    ordering_violations: gasket::metrics::Counter,
+    decode_time: crosscut::timing::Histogram,
+    fetch_time: crosscut::timing::Histogram,
+    capture: Option<super::capture::Recorder>,
 }
 
 struct SledTxValue(u16, Vec<u8>);
@@ -117,13 +948,18 @@ impl TryFrom<IVec> for SledTxValue {
 
 #[inline]
 fn fetch_referenced_utxo<'a>(
-    db: &sled::Db,
+    db: &sled::Tree,
+    pending: Option<&PendingWrites>,
     utxo_ref: &OutputRef,
 ) -> Result<Option<(OutputRef, Era, Vec<u8>)>, crate::Error> {
-    if let Some(ivec) = db
-        .get(utxo_ref.to_string())
-        .map_err(crate::Error::storage)?
-    {
+    let key = crosscut::refs::ref_key(utxo_ref);
+
+    let value = match pending.and_then(|x| x.utxos.get(&key)) {
+        Some(x) => x.clone(),
+        None => db.get(key.as_bytes()).map_err(crate::Error::storage)?,
+    };
+
+    if let Some(ivec) = value {
         let SledTxValue(era, cbor) = ivec.try_into().map_err(crate::Error::storage)?;
         let era: Era = era.try_into().map_err(crate::Error::storage)?;
         Ok(Some((utxo_ref.clone(), era, cbor)))
@@ -133,34 +969,127 @@ fn fetch_referenced_utxo<'a>(
 }
 
 impl Worker {
+    pub fn new(
+        config: Config,
+        policy: crosscut::policies::RuntimePolicy,
+        input: InputPort,
+        output: OutputPort,
+    ) -> Self {
+        Self {
+            config,
+            policy,
+            db: None,
+            utxos: None,
+            addresses: None,
+            consumed_ring: None,
+            datums: None,
+            datum_ring: None,
+            meta: None,
+            ring_len: 0,
+            cursor: None,
+            pool_size: None,
+            pool: None,
+            ready: Default::default(),
+            inflight: Default::default(),
+            input,
+            output,
+            inserts_counter: Default::default(),
+            remove_counter: Default::default(),
+            matches_counter: Default::default(),
+            mismatches_counter: Default::default(),
+            blocks_counter: Default::default(),
+            utxo_count: None,
+            utxo_count_gauge: Default::default(),
+            pending: None,
+            held: vec![],
+            recent_txs: None,
+            ordering_violations: Default::default(),
+            decode_time: Default::default(),
+            fetch_time: Default::default(),
+            capture: None,
+        }
+    }
+
+    /// Inserts the utxos produced by the txs of the block, returns how many
+    /// weren't in the db yet
+    ///
+    /// A block applied again (eg: replayed after a restart) finds its outputs
+    /// already there. Telling them apart takes a lookup per output, only done
+    /// when the utxo count is tracked: otherwise every output is counted.
     #[inline]
-    fn insert_produced_utxos(&self, db: &sled::Db, txs: &[MultiEraTx]) -> Result<(), crate::Error> {
+    fn insert_produced_utxos(&mut self, db: &sled::Tree, txs: &[MultiEraTx]) -> Result<usize, crate::Error> {
         let mut insert_batch = sled::Batch::default();
+        let mut index_batch = sled::Batch::default();
+        let mut inserted = 0;
+
+        let counted = self.utxo_count.is_some();
+        let is_new = |key: &str| match counted {
+            true => db
+                .contains_key(key.as_bytes())
+                .map(|x| !x)
+                .map_err(crate::Error::storage),
+            false => Ok(true),
+        };
 
         for tx in txs.iter() {
             for (idx, output) in tx.produces() {
-                let key: IVec = format!("{}#{}", tx.hash(), idx).as_bytes().into();
+                let utxo = crosscut::refs::output_key(&tx.hash(), idx as u64);
+
+                if self.addresses.is_some() {
+                    let address = output.address().map_err(crate::Error::ledger)?;
+                    let index_key = address_index_key(&address.to_string(), &utxo);
+
+                    match &mut self.pending {
+                        Some(pending) => {
+                            pending.index.insert(index_key, Some(IVec::default()));
+                        }
+                        None => index_batch.insert(index_key, IVec::default()),
+                    }
+                }
 
                 let era = tx.era().into();
                 let body = output.encode();
                 let value: IVec = SledTxValue(era, body).try_into()?;
 
-                insert_batch.insert(key, value)
-            }
+                let new = match &mut self.pending {
+                    Some(pending) => match pending.utxos.insert(utxo.clone(), Some(value)) {
+                        Some(previous) => previous.is_none(),
+                        None => is_new(&utxo)?,
+                    },
+                    None => {
+                        let new = is_new(&utxo)?;
+                        insert_batch.insert(utxo.as_bytes(), value);
+                        new
+                    }
+                };
+
+                if new {
+                    inserted += 1;
+                }
+            }
         }
 
-        db.apply_batch(insert_batch)
-            .map_err(crate::Error::storage)?;
+        if self.pending.is_none() {
+            db.apply_batch(insert_batch)
+                .map_err(crate::Error::storage)?;
+
+            if let Some(index) = &self.addresses {
+                index
+                    .apply_batch(index_batch)
+                    .map_err(crate::Error::storage)?;
+            }
+        }
 
         self.inserts_counter.inc(txs.len() as u64);
 
-        Ok(())
+        Ok(inserted)
     }
 
     #[inline]
     fn par_fetch_referenced_utxos(
         &self,
-        db: &sled::Db,
+        db: &sled::Tree,
+        slot: u64,
         txs: &[MultiEraTx],
     ) -> Result<BlockContext, crate::Error> {
         let mut ctx = BlockContext::default();
@@ -171,103 +1100,794 @@ impl Worker {
             .map(|input| input.output_ref())
             .collect();
 
-        let matches: Result<Vec<_>, crate::Error> = required
-            .par_iter()
-            .map(|utxo_ref| fetch_referenced_utxo(db, utxo_ref))
-            .collect();
+        let pending = self.pending.as_ref();
+
+        let fetch = || -> Result<Vec<_>, crate::Error> {
+            required
+                .par_iter()
+                .map(|utxo_ref| fetch_referenced_utxo(db, pending, utxo_ref))
+                .collect()
+        };
+
+        // without a dedicated pool, lookups run on the global rayon pool
+        let matches = match &self.pool {
+            Some(pool) => pool.install(fetch),
+            None => fetch(),
+        };
 
-        for m in matches? {
+        let mut unresolved = Vec::new();
+
+        for (utxo_ref, m) in required.iter().zip(matches?) {
             if let Some((key, era, cbor)) = m {
                 ctx.import_ref_output(&key, era, cbor);
                 self.matches_counter.inc(1);
             } else {
                 self.mismatches_counter.inc(1);
+                unresolved.push(utxo_ref);
             }
         }
 
+        self.report_unresolved(slot, required.len(), &unresolved);
+        self.check_ordering(slot, &unresolved);
+
         Ok(ctx)
     }
 
-    fn remove_consumed_utxos(&self, db: &sled::Db, txs: &[MultiEraTx]) -> Result<(), crate::Error> {
+    /// Logs the inputs of a block missing from the db when they're above the
+    /// `mismatch_diagnostics` ratio, up to the configured limit
+    fn report_unresolved(&self, slot: u64, required: usize, unresolved: &[&OutputRef]) {
+        let threshold = match self.config.mismatch_diagnostics {
+            Some(x) => x,
+            None => return,
+        };
+
+        if !exceeds_mismatch_ratio(required, unresolved.len(), threshold) {
+            return;
+        }
+
+        let limit = self
+            .config
+            .mismatch_diagnostics_limit
+            .unwrap_or(DEFAULT_MISMATCH_DIAGNOSTICS_LIMIT);
+
+        let refs: Vec<_> = unresolved.iter().take(limit).map(|x| x.to_string()).collect();
+
+        log::warn!(
+            "{} of {} inputs of block at slot {} not found in the enrich db, was it started after they were produced? unresolved: {}",
+            unresolved.len(),
+            required,
+            slot,
+            refs.join(", ")
+        );
+    }
+
+    /// Flags the unresolved inputs that point at the outputs of a recently
+    /// enriched block, see `RecentTxs`
+    fn check_ordering(&self, slot: u64, unresolved: &[&OutputRef]) {
+        let recent = match &self.recent_txs {
+            Some(x) => x,
+            None => return,
+        };
+
+        let mut violations = 0;
+
+        for utxo_ref in unresolved.iter().filter(|x| recent.produced(x)) {
+            log::error!(
+                "input {} of block at slot {} was produced by an already enriched block but isn't in the db",
+                utxo_ref,
+                slot
+            );
+
+            violations += 1;
+        }
+
+        self.ordering_violations.inc(violations);
+
+        debug_assert!(
+            violations == 0,
+            "inputs of block at slot {} resolved before their block was enriched",
+            slot
+        );
+    }
+
+    /// Removes the utxos spent by the txs of the block
+    ///
+    /// Only `consumes()` is considered: reference inputs (and collateral of
+    /// valid txs) are read-only and must stay available in the db.
+    ///
+    /// Returns the removed entries so that they can be restored on rollback.
+    fn remove_consumed_utxos(
+        &mut self,
+        db: &sled::Tree,
+        txs: &[MultiEraTx],
+    ) -> Result<Vec<(String, IVec)>, crate::Error> {
         let keys: Vec<_> = txs
             .iter()
             .flat_map(|tx| tx.consumes())
-            .map(|i| i.output_ref())
+            .map(|i| crosscut::refs::ref_key(&i.output_ref()))
             .collect();
 
+        let mut removed = Vec::with_capacity(keys.len());
+
         for key in keys.iter() {
-            db.remove(key.to_string().as_bytes())
-                .map_err(crate::Error::storage)?;
+            let value = match &mut self.pending {
+                Some(pending) => match pending.utxos.insert(key.clone(), None) {
+                    Some(previous) => previous,
+                    None => db.get(key.as_bytes()).map_err(crate::Error::storage)?,
+                },
+                None => db.remove(key.as_bytes()).map_err(crate::Error::storage)?,
+            };
+
+            if let Some(value) = value {
+                if let Some(index) = &self.addresses {
+                    let index_key = address_index_key(&utxo_address(&value)?, key);
+
+                    match &mut self.pending {
+                        Some(pending) => {
+                            pending.index.insert(index_key, None);
+                        }
+                        None => {
+                            index.remove(index_key).map_err(crate::Error::storage)?;
+                        }
+                    }
+                }
+
+                removed.push((key.clone(), value));
+            }
         }
 
         self.remove_counter.inc(keys.len() as u64);
 
+        Ok(removed)
+    }
+
+    /// Stores the datums supplied in the witness sets of the block
+    ///
+    /// Returns the hashes that weren't known yet, the only ones that have to
+    /// be forgotten if the block is rolled back.
+    fn insert_witness_datums(&self, txs: &[MultiEraTx]) -> Result<Vec<String>, crate::Error> {
+        let datums = match &self.datums {
+            Some(x) => x,
+            None => return Ok(vec![]),
+        };
+
+        let mut added = Vec::new();
+
+        for (hash, cbor) in txs.iter().flat_map(witness_datums) {
+            let previous = datums
+                .insert(hash.as_bytes(), cbor)
+                .map_err(crate::Error::storage)?;
+
+            if previous.is_none() {
+                added.push(hash);
+            }
+        }
+
+        Ok(added)
+    }
+
+    /// Adds to the context the datums referenced by hash from the outputs
+    /// produced or consumed by the block
+    fn fetch_referenced_datums(
+        &self,
+        txs: &[MultiEraTx],
+        ctx: &mut BlockContext,
+    ) -> Result<(), crate::Error> {
+        let datums = match &self.datums {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let mut hashes = Vec::new();
+
+        for tx in txs.iter() {
+            for (_, output) in tx.produces() {
+                hashes.extend(datum_hash(&output));
+            }
+
+            for input in tx.consumes() {
+                if let Ok(output) = ctx.find_utxo(&input.output_ref()) {
+                    hashes.extend(datum_hash(&output));
+                }
+            }
+        }
+
+        for hash in hashes {
+            let cbor = datums
+                .get(hash.to_string().as_bytes())
+                .map_err(crate::Error::storage)?;
+
+            if let Some(cbor) = cbor {
+                ctx.import_datum(&hash, cbor.to_vec());
+            }
+        }
+
         Ok(())
     }
-}
 
-impl gasket::runtime::Worker for Worker {
-    fn metrics(&self) -> gasket::metrics::Registry {
-        gasket::metrics::Builder::new()
-            .with_counter("enrich_inserts", &self.inserts_counter)
-            .with_counter("enrich_removes", &self.remove_counter)
-            .with_counter("enrich_matches", &self.matches_counter)
-            .with_counter("enrich_mismatches", &self.mismatches_counter)
-            .with_counter("enrich_blocks", &self.blocks_counter)
-            .build()
+    fn push_datum_undo_entry(
+        &self,
+        slot: u64,
+        hash: &[u8],
+        added: Vec<String>,
+    ) -> Result<(), crate::Error> {
+        let ring = match &self.datum_ring {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let value = minicbor::to_vec(added).map_err(crate::Error::cbor)?;
+
+        ring.insert(ring_key(slot, hash), value)
+            .map_err(crate::Error::storage)?;
+
+        Ok(())
     }
 
-    fn work(&mut self) -> gasket::runtime::WorkResult {
-        let msg = self.input.recv_or_idle()?;
+    /// Forgets the datums first seen in the block of the given ring key
+    fn forget_block_datums(&self, key: &[u8]) -> Result<(), crate::Error> {
+        let (datums, ring) = match (&self.datums, &self.datum_ring) {
+            (Some(datums), Some(ring)) => (datums, ring),
+            _ => return Ok(()),
+        };
+
+        if let Some(value) = ring.get(key).map_err(crate::Error::storage)? {
+            let added: Vec<String> = minicbor::decode(&value).map_err(crate::Error::cbor)?;
+
+            for hash in added {
+                datums
+                    .remove(hash.as_bytes())
+                    .map_err(crate::Error::storage)?;
+            }
+
+            ring.remove(key).map_err(crate::Error::storage)?;
+        }
+
+        Ok(())
+    }
+
+    /// Records what a block changed so that it can be undone, dropping the
+    /// oldest entries beyond the rollback window
+    fn push_undo_entry(
+        &mut self,
+        slot: u64,
+        hash: &[u8],
+        consumed: Vec<(String, IVec)>,
+        produced: Vec<String>,
+    ) -> Result<(), crate::Error> {
+        let ring = match &self.consumed_ring {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let consumed: Vec<_> = consumed.into_iter().map(|(k, v)| (k, v.to_vec())).collect();
+        let entry: UndoEntry = (consumed, produced);
+        let value = minicbor::to_vec(entry).map_err(crate::Error::cbor)?;
+
+        ring.insert(ring_key(slot, hash), value)
+            .map_err(crate::Error::storage)?;
+        self.ring_len += 1;
+
+        let window = self.config.rollback_window.unwrap_or_default();
+
+        while self.ring_len > window {
+            let oldest = ring.pop_min().map_err(crate::Error::storage)?;
+
+            if let (Some(datum_ring), Some((key, _))) = (&self.datum_ring, oldest) {
+                datum_ring.remove(key).map_err(crate::Error::storage)?;
+            }
+
+            self.ring_len -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Reverts the changes of a single block to the utxo set
+    ///
+    /// Consumed utxos are restored before the produced ones are removed: an
+    /// output produced and spent within the same block shows up in both lists
+    /// and has to end up absent. Both steps are idempotent, so re-applying an
+    /// entry after a crash is harmless.
+    ///
+    /// Returns the change to the size of the utxo set.
+    fn replace_consumed_utxos(&self, db: &sled::Tree, entry: UndoEntry) -> Result<i64, crate::Error> {
+        let (consumed, produced) = entry;
+        let mut delta = 0;
+
+        for (key, value) in consumed {
+            if let Some(index) = &self.addresses {
+                index
+                    .insert(address_index_key(&utxo_address(&value)?, &key), IVec::default())
+                    .map_err(crate::Error::storage)?;
+            }
+
+            let previous = db
+                .insert(key.as_bytes(), value)
+                .map_err(crate::Error::storage)?;
 
-        match msg.payload {
+            if previous.is_none() {
+                delta += 1;
+            }
+        }
+
+        for key in produced {
+            let value = db.remove(key.as_bytes()).map_err(crate::Error::storage)?;
+
+            if value.is_some() {
+                delta -= 1;
+            }
+
+            if let (Some(index), Some(value)) = (&self.addresses, value) {
+                index
+                    .remove(address_index_key(&utxo_address(&value)?, &key))
+                    .map_err(crate::Error::storage)?;
+            }
+        }
+
+        Ok(delta)
+    }
+
+    /// True if the ring is full and its oldest block is after the point
+    ///
+    /// Once the window is full, the blocks evicted from the ring can't be
+    /// reverted: going past the oldest one would leave their changes behind.
+    /// Until then, the ring holds every block applied to the db.
+    fn is_past_window(&self, ring: &sled::Tree, point: &Point) -> Result<bool, crate::Error> {
+        let window = self.config.rollback_window.unwrap_or_default();
+
+        if self.ring_len < window {
+            return Ok(false);
+        }
+
+        let oldest = ring.first().map_err(crate::Error::storage)?;
+
+        let past = match (oldest, point) {
+            (None, _) => false,
+            (Some(_), Point::Origin) => true,
+            (Some((key, _)), Point::Specific(slot, _)) => ring_key_slot(&key) > *slot,
+        };
+
+        Ok(past)
+    }
+
+    /// Undoes the blocks after the rollback point, newest first
+    ///
+    /// Blocks have to be reverted in LIFO order. A utxo produced by one block
+    /// and spent by a later one is restored when undoing the latter and then
+    /// removed when undoing the former; the other way around would leave it
+    /// behind. Nothing is reverted if the point is past the window, a partial
+    /// undo would leave the db in a state that no restart can recover from.
+    fn undo_blocks(&mut self, point: &Point) -> Result<Undo, crate::Error> {
+        let (db, ring) = match (&self.utxos, &self.consumed_ring) {
+            (Some(db), Some(ring)) => (db, ring),
+            _ => return Ok(Undo::Disabled),
+        };
+
+        if self.is_past_window(ring, point)? {
+            return Ok(Undo::PastWindow);
+        }
+
+        let mut undone = 0;
+        let mut delta = 0;
+
+        while let Some((key, value)) = ring.last().map_err(crate::Error::storage)? {
+            let keep = match point {
+                Point::Origin => false,
+                Point::Specific(slot, _) => ring_key_slot(&key) <= *slot,
+            };
+
+            if keep {
+                break;
+            }
+
+            let entry: UndoEntry = minicbor::decode(&value).map_err(crate::Error::cbor)?;
+            delta += self.replace_consumed_utxos(db, entry)?;
+            self.forget_block_datums(&key)?;
+
+            // only forget the entry once it was applied
+            ring.remove(key).map_err(crate::Error::storage)?;
+            undone += 1;
+        }
+
+        self.ring_len = self.ring_len.saturating_sub(undone);
+        self.track_utxo_count(delta);
+
+        Ok(Undo::Reverted(undone))
+    }
+
+    /// Applies the changes accumulated across blocks by `batch_blocks`
+    fn flush_pending(&mut self) -> Result<(), crate::Error> {
+        let tip = match (&self.utxos, &mut self.pending) {
+            (Some(db), Some(pending)) => {
+                pending.apply(db, self.addresses.as_ref())?;
+                pending.tip.take()
+            }
+            _ => None,
+        };
+
+        match tip {
+            Some(x) => self.save_tip(&x),
+            None => Ok(()),
+        }
+    }
+
+    /// Records the last block whose changes are in the utxo set
+    fn save_tip(&self, point: &Point) -> Result<(), crate::Error> {
+        if let Some(meta) = &self.meta {
+            let value = PointArg::from(point.clone()).to_string();
+            meta.insert(TIP_KEY, value.as_bytes())
+                .map_err(crate::Error::storage)?;
+        }
+
+        Ok(())
+    }
+
+    /// Last block recorded by `save_tip`, `None` for a db written before
+    /// the tip was tracked
+    fn applied_tip(&self) -> Result<Option<PointArg>, crate::Error> {
+        let value = match &self.meta {
+            Some(meta) => meta.get(TIP_KEY).map_err(crate::Error::storage)?,
+            None => None,
+        };
+
+        value
+            .map(|x| PointArg::from_str(&String::from_utf8_lossy(&x)))
+            .transpose()
+    }
+
+    /// Brings the utxo set in line with the storage cursor the source
+    /// resumes from
+    ///
+    /// The db and the storage are flushed independently, so a restart can
+    /// find the db a few blocks ahead of the cursor. Those blocks are
+    /// reverted through the rollback window: applied twice, the inputs
+    /// they spend would no longer resolve. A db behind the cursor misses the
+    /// changes of blocks the source won't send again, and one on another
+    /// block at the cursor slot can't be told apart from a corrupted one,
+    /// both are refused. Nothing is checked without a cursor or a recorded
+    /// tip.
+    fn reconcile(&mut self, cursor: Option<PointArg>) -> Result<(), crate::Error> {
+        let (tip, cursor) = match (self.applied_tip()?, cursor) {
+            (Some(tip), Some(cursor)) => (tip, cursor),
+            _ => return Ok(()),
+        };
+
+        match drift(&tip, &cursor) {
+            Drift::InSync => Ok(()),
+            Drift::Ahead => {
+                let point: Point = cursor.clone().try_into()?;
+
+                match self.undo_blocks(&point)? {
+                    Undo::Disabled => {
+                        log::warn!(
+                            "the enrich db is at {}, ahead of the cursor {}, but can't be reverted since the rollback window is disabled",
+                            tip.to_string(),
+                            cursor.to_string()
+                        );
+
+                        Ok(())
+                    }
+                    Undo::Reverted(undone) => {
+                        log::info!(
+                            "reverted {} blocks of the enrich db to match the cursor {}",
+                            undone,
+                            cursor.to_string()
+                        );
+
+                        self.save_tip(&point)
+                    }
+                    Undo::PastWindow => Err(crate::Error::message(format!(
+                        "the enrich db is at {}, too far ahead of the cursor {} to be reverted by the rollback window. Rebuild the enrich db or reset the cursor",
+                        tip.to_string(),
+                        cursor.to_string()
+                    ))),
+                }
+            }
+            Drift::Behind => Err(crate::Error::message(format!(
+                "the enrich db is at {}, behind the cursor {}, the utxos of the blocks in between are missing. Rebuild the enrich db or reset the cursor",
+                tip.to_string(),
+                cursor.to_string()
+            ))),
+            Drift::Forked => Err(crate::Error::message(format!(
+                "the enrich db is at {}, on another block than the cursor {}. Rebuild the enrich db or reset the cursor",
+                tip.to_string(),
+                cursor.to_string()
+            ))),
+        }
+    }
+
+    /// Enriches the payload and appends the result to the capture file, if
+    /// any, see `enrich_payload`. Returns the payloads ready to be sent,
+    /// none while the block waits in a `batch_blocks` batch, `None` if the
+    /// block is skipped by the policy.
+    pub(crate) fn enrich(
+        &mut self,
+        payload: model::RawBlockPayload,
+    ) -> Result<Option<Vec<model::EnrichedBlockPayload>>, gasket::error::Error> {
+        let enriched = match self.enrich_payload(payload)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if let Some(capture) = &mut self.capture {
+            capture.record(&enriched).or_panic()?;
+        }
+
+        self.held.push(enriched);
+
+        // the blocks of a batch wait for their changes to reach the db
+        match &self.pending {
+            Some(x) if x.blocks > 0 => Ok(Some(vec![])),
+            _ => Ok(Some(self.held.drain(..).collect())),
+        }
+    }
+
+    /// Applies the pending batch, if any, returning the payloads of its
+    /// blocks. Called when the input is idle, so that a partial batch doesn't
+    /// hold the blocks back while waiting for the next one.
+    pub(crate) fn release(
+        &mut self,
+    ) -> Result<Vec<model::EnrichedBlockPayload>, gasket::error::Error> {
+        self.flush_pending().or_restart()?;
+
+        Ok(self.held.drain(..).collect())
+    }
+
+    /// Updates the utxo set with a block and resolves its context, or
+    /// reverts the rolled back blocks. `None` if the block is skipped by the
+    /// policy.
+    ///
+    /// A rollback is always forwarded once the utxo set is reverted, or left
+    /// as is when the rollback window is disabled. A rollback past the window
+    /// stops the stage instead: the blocks reduced from then on would be
+    /// enriched against utxos that were already spent.
+    fn enrich_payload(
+        &mut self,
+        payload: model::RawBlockPayload,
+    ) -> Result<Option<model::EnrichedBlockPayload>, gasket::error::Error> {
+        match payload {
             model::RawBlockPayload::RollForward(cbor) => {
+                let start = Instant::now();
+
                 let block = MultiEraBlock::decode(&cbor)
                     .map_err(crate::Error::cbor)
                     .apply_policy(&self.policy)
                     .or_panic()?;
 
+                self.decode_time.observe_since(start);
+
                 let block = match block {
                     Some(x) => x,
-                    None => return Ok(gasket::runtime::WorkOutcome::Partial),
+                    None => return Ok(None),
                 };
 
-                let db = self.db.as_ref().unwrap();
+                let db = self.utxos.clone().unwrap();
 
                 let txs = block.txs();
 
+                let start = Instant::now();
+
                 // first we insert new utxo produced in this block
-                self.insert_produced_utxos(db, &txs).or_restart()?;
+                let inserted = self.insert_produced_utxos(&db, &txs).or_restart()?;
+                let datums = self.insert_witness_datums(&txs).or_restart()?;
 
                 // then we fetch referenced utxo in this block
-                let ctx = self.par_fetch_referenced_utxos(db, &txs).or_restart()?;
+                let mut ctx = self.par_fetch_referenced_utxos(&db, block.slot(), &txs).or_restart()?;
+                self.fetch_referenced_datums(&txs, &mut ctx).or_restart()?;
 
                 // and finally we remove utxos consumed by the block
-                self.remove_consumed_utxos(db, &txs).or_restart()?;
+                let consumed = self.remove_consumed_utxos(&db, &txs).or_restart()?;
+
+                self.fetch_time.observe_since(start);
+
+                self.track_utxo_count(inserted as i64 - consumed.len() as i64);
+
+                if let Some(count) = self.utxo_count {
+                    ctx.set_utxo_count(count.max(0) as u64);
+                }
+
+                let produced = txs
+                    .iter()
+                    .flat_map(|tx| {
+                        let hash = tx.hash();
+                        tx.produces()
+                            .into_iter()
+                            .map(move |(idx, _)| crosscut::refs::output_key(&hash, idx as u64))
+                    })
+                    .collect();
+
+                // the datum entry goes first, trimming the ring drops both
+                self.push_datum_undo_entry(block.slot(), &block.hash().to_vec(), datums)
+                    .or_restart()?;
 
-                self.output
-                    .send(model::EnrichedBlockPayload::roll_forward(cbor, ctx))?;
+                self.push_undo_entry(block.slot(), &block.hash().to_vec(), consumed, produced)
+                    .or_restart()?;
+
+                let batch_blocks = self.config.batch_blocks.unwrap_or(1);
+                let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+                match &mut self.pending {
+                    Some(pending) => {
+                        pending.blocks += 1;
+                        pending.tip = Some(point);
+
+                        if pending.blocks >= batch_blocks {
+                            self.flush_pending().or_restart()?;
+                        }
+                    }
+                    None => self.save_tip(&point).or_restart()?,
+                }
+
+                if let Some(recent) = &mut self.recent_txs {
+                    recent.record(&txs);
+                }
 
                 self.blocks_counter.inc(1);
+
+                Ok(Some(model::EnrichedBlockPayload::RollForward(cbor, ctx)))
             }
             model::RawBlockPayload::RollBack(x) => {
-                self.output
-                    .send(model::EnrichedBlockPayload::roll_back(x))?;
+                self.flush_pending().or_restart()?;
+
+                if let Some(recent) = &mut self.recent_txs {
+                    recent.clear();
+                }
+
+                match self.undo_blocks(&x).or_restart()? {
+                    Undo::Disabled => log::info!(
+                        "rollback to {:?}, the utxo set is left as is since the rollback window is disabled",
+                        x
+                    ),
+                    Undo::Reverted(undone) => {
+                        log::info!("rollback to {:?} reverted {} blocks of the utxo set", x, undone);
+                        self.save_tip(&x).or_restart()?;
+                    }
+                    Undo::PastWindow => {
+                        return Err(crate::Error::message(format!(
+                            "can't rollback the utxo set to {:?}, it's older than the blocks of the rollback window. Consider increasing rollback_window and rebuilding the enrich db",
+                            x
+                        )))
+                        .or_panic();
+                    }
+                }
+
+                Ok(Some(model::EnrichedBlockPayload::RollBack(x)))
+            }
+        }
+    }
+
+    pub(crate) fn register_metrics(
+        &self,
+        builder: gasket::metrics::Builder,
+    ) -> gasket::metrics::Builder {
+        let builder = builder
+            .with_counter("enrich_inserts", &self.inserts_counter)
+            .with_counter("enrich_removes", &self.remove_counter)
+            .with_counter("enrich_matches", &self.matches_counter)
+            .with_counter("enrich_mismatches", &self.mismatches_counter)
+            .with_counter("enrich_blocks", &self.blocks_counter)
+            .with_counter("enrich_ordering_violations", &self.ordering_violations)
+            .with_gauge("enrich_utxo_count", &self.utxo_count_gauge);
+
+        let builder = self
+            .decode_time
+            .register(builder, &crate::histogram_keys!("enrich_decode_time"));
+
+        self.fetch_time
+            .register(builder, &crate::histogram_keys!("enrich_fetch_time"))
+    }
+
+    fn track_utxo_count(&mut self, delta: i64) {
+        if let Some(count) = &mut self.utxo_count {
+            *count += delta;
+            self.utxo_count_gauge.set(*count);
+        }
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        self.register_metrics(gasket::metrics::Builder::new())
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = match self.input.recv_or_idle() {
+            Err(gasket::error::Error::RecvIdle) => {
+                for x in self.release()? {
+                    self.output.send(gasket::messaging::Message::from(x))?;
+                }
+
+                return Err(gasket::error::Error::RecvIdle);
             }
+            x => x?,
         };
 
+        // a block skipped by the policy releases nothing, it's still committed
+        // so that the source gets its in-flight slot back
+        let released = self.enrich(msg.payload)?.unwrap_or_default();
+
+        for x in released {
+            self.output.send(gasket::messaging::Message::from(x))?;
+        }
+
         self.input.commit();
+        self.inflight.acknowledged();
+
         Ok(WorkOutcome::Partial)
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
-        let db = sled::open(&self.config.db_path).or_retry()?;
+        let (db, utxos) = self.config.open_db().or_retry()?;
+        self.addresses = self.config.open_address_index(&db, &utxos).or_retry()?;
+        self.consumed_ring = self.config.open_consumed_ring(&db).or_retry()?;
+        self.datums = self.config.open_datum_index(&db).or_retry()?;
+        self.datum_ring = self.config.open_datum_ring(&db).or_retry()?;
+        self.meta = Some(self.config.open_meta(&db).or_retry()?);
+        self.ring_len = self.consumed_ring.as_ref().map(|x| x.len()).unwrap_or_default();
+
+        let loaded = self
+            .config
+            .load_byron_genesis(&utxos, self.addresses.as_ref())
+            .or_panic()?;
+
+        if loaded > 0 {
+            log::info!("loaded {} utxos from the byron genesis", loaded);
+        }
+
+        if self.config.utxo_count.unwrap_or_default() {
+            log::info!("counting the utxo set, this walks the whole db");
+            self.utxo_count = Some(0);
+            self.track_utxo_count(utxos.len() as i64);
+        }
+
+        // built from the stage thread so that the pool threads inherit its
+        // affinity
+        if let Some(size) = self.pool_size {
+            let pool = rayon::ThreadPoolBuilder::new()
+                .num_threads(size)
+                .thread_name(|idx| format!("enrich-sled-{}", idx))
+                .build()
+                .or_panic()?;
+
+            self.pool = Some(pool);
+        }
+
+        if self.config.batch_blocks.unwrap_or(1) > 1 {
+            self.pending = Some(PendingWrites::default());
+        }
+
+        self.recent_txs = self
+            .config
+            .ordering_check
+            .and_then(NonZeroUsize::new)
+            .map(RecentTxs::new);
+
+        if let Some(path) = &self.config.capture_path {
+            self.capture = Some(super::capture::Recorder::open(path).or_retry()?);
+        }
+
         self.db = Some(db);
+        self.utxos = Some(utxos);
+
+        // the source waits for the gate, so it resumes from a cursor the
+        // utxo set agrees with
+        let cursor = match &mut self.cursor {
+            Some(x) => x.last_point().or_retry()?,
+            None => None,
+        };
+
+        self.reconcile(cursor).or_panic()?;
+
+        self.ready.open();
 
         Ok(())
     }
 
     fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        // the pending batch is dropped along with the blocks held for it,
+        // applying it would leave the db ahead of blocks never sent
         match &self.db {
             Some(db) => {
                 db.flush().or_panic()?;
@@ -276,4 +1896,747 @@ impl gasket::runtime::Worker for Worker {
             None => Ok(()),
         }
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use gasket::{
+        messaging::{connect_ports, OutputPort, TwoPhaseInputPort},
+        runtime::Worker as _,
+    };
+
+    use pallas::codec::minicbor;
+    use pallas::codec::utils::KeyValuePairs;
+    use pallas::ledger::primitives::alonzo;
+    use pallas::ledger::traverse::{Era, MultiEraBlock, MultiEraOutput, MultiEraTx, OutputRef};
+    use pallas::network::miniprotocols::Point;
+
+    use super::{
+        address_index_key, exceeds_mismatch_ratio, fetch_referenced_utxo, parse_output_ref,
+        parse_snapshot_entry, parse_snapshot_point, Config, PendingWrites, RecentTxs,
+        SledTxValue, Undo, UtxoStore, Worker,
+    };
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::crosscut::PointArg;
+    use crate::model;
+
+    // babbage tx spending 1111..11#0 and using 2222..22#0 as reference input
+    const TX_WITH_REFERENCE_INPUT: &str = "84a40081825820111111111111111111111111111111111111111111111111111111111111111100018182581d61000000000000000000000000000000000000000000000000000000001a001e8480021a000298101281825820222222222222222222222222222222222222222222222222222222222222222200a0f5f6";
+
+    #[test]
+    fn reference_inputs_are_not_removed() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let spent = format!("{}#0", "11".repeat(32));
+        let referenced = format!("{}#0", "22".repeat(32));
+
+        db.insert(spent.as_bytes(), vec![0u8]).unwrap();
+        db.insert(referenced.as_bytes(), vec![0u8]).unwrap();
+
+        let cbor = hex::decode(TX_WITH_REFERENCE_INPUT).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+
+        let config = Config::default();
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.remove_consumed_utxos(&db, &[tx]).unwrap();
+
+        assert!(db.get(spent.as_bytes()).unwrap().is_none());
+        assert!(db.get(referenced.as_bytes()).unwrap().is_some());
+    }
+
+    #[test]
+    fn address_index_follows_the_utxo_set() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+        let addresses = db.open_tree("utxos_by_address").unwrap();
+
+        let cbor = hex::decode(TX_WITH_REFERENCE_INPUT).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+
+        let (_, output) = tx.produces().into_iter().next().unwrap();
+        let address = output.address().unwrap().to_string();
+
+        // pretend the input spent by the tx was locked by the same address
+        let spent = format!("{}#0", "11".repeat(32));
+        let value: sled::IVec = SledTxValue(Era::Babbage.into(), output.encode())
+            .try_into()
+            .unwrap();
+        utxos.insert(spent.as_bytes(), value).unwrap();
+        addresses
+            .insert(address_index_key(&address, &spent), sled::IVec::default())
+            .unwrap();
+
+        let config = Config {
+            address_index: Some(true),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.addresses = Some(addresses.clone());
+
+        let txs = std::slice::from_ref(&tx);
+        worker.insert_produced_utxos(&utxos, txs).unwrap();
+        worker.remove_consumed_utxos(&utxos, txs).unwrap();
+
+        let store = UtxoStore {
+            _db: db.clone(),
+            utxos: utxos.clone(),
+            addresses: Some(addresses.clone()),
+            ring: None,
+        };
+
+        let refs = store.by_address(&address).unwrap();
+
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].to_string(), format!("{}#0", tx.hash()));
+
+        assert!(store.check().unwrap().issues.is_empty());
+
+        // an index entry without its utxo is reported
+        addresses
+            .insert(address_index_key(&address, &spent), sled::IVec::default())
+            .unwrap();
+
+        let report = store.check().unwrap();
+        assert_eq!(report.utxo_count, 1);
+        assert_eq!(report.address_index_count, Some(2));
+        assert_eq!(report.issues.len(), 1);
+    }
+
+    #[test]
+    fn address_index_is_built_for_existing_utxos() {
+        let dir =
+            std::env::temp_dir().join(format!("scrolls-address-index-{}", std::process::id()));
+
+        let config = |address_index| Config {
+            data_dir: Some(dir.to_string_lossy().to_string()),
+            address_index: Some(address_index),
+            ..Default::default()
+        };
+
+        let cbor = format!("82581d61{}1a004c4b40", "00".repeat(28));
+        let entry = |n: &str| format!("{}#0,{},{}", n.repeat(32), u16::from(Era::Alonzo), cbor);
+
+        // imported while the index is off
+        config(false)
+            .import_snapshot(entry("33").as_bytes())
+            .unwrap();
+
+        let output = MultiEraOutput::decode(Era::Alonzo, &hex::decode(&cbor).unwrap()).unwrap();
+        let address = output.address().unwrap().to_string();
+
+        let store = config(true).open_store().unwrap();
+        let refs: Vec<_> = store
+            .by_address(&address)
+            .unwrap()
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(refs, vec![format!("{}#0", "33".repeat(32))]);
+        drop(store);
+
+        // utxos added while it's off again are picked up once it's back on
+        let store = config(false).open_store().unwrap();
+        let value: sled::IVec = SledTxValue(Era::Alonzo.into(), hex::decode(&cbor).unwrap())
+            .try_into()
+            .unwrap();
+        store
+            .utxos
+            .insert(format!("{}#0", "44".repeat(32)).as_bytes(), value)
+            .unwrap();
+        drop(store);
+
+        let store = config(true).open_store().unwrap();
+        assert_eq!(store.by_address(&address).unwrap().len(), 2);
+        assert!(store.check().unwrap().issues.is_empty());
+        drop(store);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rollback_restores_outputs_spent_across_blocks() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+
+        let config = Config {
+            rollback_window: Some(10),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(utxos.clone());
+        worker.consumed_ring = Some(db.open_tree("consumed_ring").unwrap());
+        worker.utxo_count = Some(1);
+
+        utxos.insert("x#0", "x").unwrap();
+
+        // block at slot 10 spends x and produces y
+        let x = utxos.remove("x#0").unwrap().unwrap();
+        utxos.insert("y#0", "y").unwrap();
+        worker
+            .push_undo_entry(10, &[1u8; 32], vec![("x#0".into(), x)], vec!["y#0".into()])
+            .unwrap();
+
+        // block at slot 20 spends y and produces z, plus w which is spent
+        // within the same block
+        let y = utxos.remove("y#0").unwrap().unwrap();
+        utxos.insert("z#0", "z").unwrap();
+        worker
+            .push_undo_entry(
+                20,
+                &[2u8; 32],
+                vec![("y#0".into(), y), ("w#0".into(), "w".into())],
+                vec!["z#0".into(), "w#0".into()],
+            )
+            .unwrap();
+
+        let undone = worker.undo_blocks(&Point::Specific(10, vec![1u8; 32])).unwrap();
+        assert_eq!(undone, Undo::Reverted(1));
+
+        let keys = |tree: &sled::Tree| -> Vec<String> {
+            tree.iter()
+                .keys()
+                .map(|k| String::from_utf8(k.unwrap().to_vec()).unwrap())
+                .collect()
+        };
+
+        assert_eq!(keys(&utxos), vec!["y#0"]);
+        assert_eq!(worker.utxo_count, Some(1));
+
+        let undone = worker.undo_blocks(&Point::Specific(5, vec![0u8; 32])).unwrap();
+        assert_eq!(undone, Undo::Reverted(1));
+
+        assert_eq!(keys(&utxos), vec!["x#0"]);
+        assert_eq!(worker.utxo_count, Some(1));
+        assert_eq!(utxos.get("x#0").unwrap().unwrap(), "x".as_bytes());
+    }
+
+    /// Block right after the given alonzo one, holding a copy of its first tx
+    /// that spends the first output of the original instead
+    fn spending_block(cbor: &[u8]) -> Vec<u8> {
+        let (era, mut block): (u16, alonzo::Block) = minicbor::decode(cbor).unwrap();
+        let spent = MultiEraBlock::decode(cbor).unwrap().txs()[0].hash();
+
+        block.header.header_body.slot += 1;
+        block.header.header_body.block_number += 1;
+
+        block.transaction_bodies.truncate(1);
+        block.transaction_bodies[0].inputs = vec![alonzo::TransactionInput {
+            transaction_id: spent,
+            index: 0,
+        }];
+
+        block.transaction_witness_sets.truncate(1);
+        block.auxiliary_data_set = KeyValuePairs::from(vec![]);
+        block.invalid_transactions = Some(vec![]);
+
+        minicbor::to_vec((era, block)).unwrap()
+    }
+
+    #[test]
+    fn rolled_forward_blocks_are_undone_to_the_exact_state() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+
+        let config = Config {
+            rollback_window: Some(10),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(
+            config,
+            Default::default(),
+            Default::default(),
+            Default::default(),
+        );
+        worker.utxos = Some(utxos.clone());
+        worker.meta = Some(db.open_tree("enrich_meta").unwrap());
+        worker.consumed_ring = Some(db.open_tree("consumed_ring").unwrap());
+        worker.utxo_count = Some(1);
+
+        // the second input of the first tx of test.block, the only one of
+        // its inputs known to the db
+        let cbor = hex::decode(format!("82581d61{}1a004c4b40", "00".repeat(28))).unwrap();
+        let value: sled::IVec = SledTxValue(Era::Alonzo.into(), cbor).try_into().unwrap();
+        let spent = "4ddea0589dbb0bfa613c2c8fa974fba72d437cbb79f279c765a75a094462983b#1";
+        utxos.insert(spent, value).unwrap();
+
+        let state = |tree: &sled::Tree| -> Vec<(sled::IVec, sled::IVec)> {
+            tree.iter().map(|x| x.unwrap()).collect()
+        };
+
+        let first = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let second = spending_block(&first);
+
+        let block = MultiEraBlock::decode(&first).unwrap();
+        let first_point = Point::Specific(block.slot(), block.hash().to_vec());
+        let first_output = format!("{}#0", block.txs()[0].hash());
+
+        let initial = state(&utxos);
+
+        worker
+            .enrich(model::RawBlockPayload::RollForward(first.clone()))
+            .unwrap();
+
+        let after_first = state(&utxos);
+        assert!(utxos.get(spent).unwrap().is_none());
+        assert!(utxos.get(&first_output).unwrap().is_some());
+
+        // the output produced by the first block is spent by the second
+        worker
+            .enrich(model::RawBlockPayload::RollForward(second))
+            .unwrap();
+
+        assert!(utxos.get(&first_output).unwrap().is_none());
+
+        worker
+            .enrich(model::RawBlockPayload::RollBack(first_point))
+            .unwrap();
+
+        assert_eq!(state(&utxos), after_first);
+        assert_eq!(worker.utxo_count, Some(after_first.len() as i64));
+
+        worker
+            .enrich(model::RawBlockPayload::RollBack(Point::Specific(
+                block.slot() - 1,
+                vec![0u8; 32],
+            )))
+            .unwrap();
+
+        assert_eq!(state(&utxos), initial);
+        assert_eq!(worker.utxo_count, Some(1));
+    }
+
+    #[test]
+    fn rollback_past_window_leaves_utxos_untouched() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+
+        let config = Config {
+            rollback_window: Some(2),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(utxos.clone());
+
+        // without a ring there's nothing to undo with
+        let undone = worker.undo_blocks(&Point::Origin).unwrap();
+        assert_eq!(undone, Undo::Disabled);
+
+        worker.consumed_ring = Some(db.open_tree("consumed_ring").unwrap());
+
+        // a rollback to the intersection of a fresh db is a no-op
+        let undone = worker.undo_blocks(&Point::Specific(5, vec![0u8; 32])).unwrap();
+        assert_eq!(undone, Undo::Reverted(0));
+
+        // blocks at slots 10, 20 and 30 each produce an output, the first
+        // one is evicted from the ring
+        for (slot, key) in [(10, "x#0"), (20, "y#0"), (30, "z#0")] {
+            utxos.insert(key, key).unwrap();
+            worker
+                .push_undo_entry(slot, &[slot as u8; 32], vec![], vec![key.into()])
+                .unwrap();
+        }
+
+        let undone = worker.undo_blocks(&Point::Specific(5, vec![0u8; 32])).unwrap();
+        assert_eq!(undone, Undo::PastWindow);
+        assert_eq!(utxos.len(), 3);
+
+        let undone = worker.undo_blocks(&Point::Specific(20, vec![20u8; 32])).unwrap();
+        assert_eq!(undone, Undo::Reverted(1));
+        assert!(!utxos.contains_key("z#0").unwrap());
+    }
+
+    fn reconciled_worker(db: &sled::Db) -> Worker {
+        let config = Config {
+            rollback_window: Some(2),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(db.open_tree("utxos").unwrap());
+        worker.consumed_ring = Some(db.open_tree("consumed_ring").unwrap());
+        worker.meta = Some(db.open_tree("enrich_meta").unwrap());
+
+        // blocks at slots 10, 20 and 30 each produce an output, the first
+        // one is evicted from the ring
+        for (slot, key) in [(10, "x#0"), (20, "y#0"), (30, "z#0")] {
+            let point = Point::Specific(slot, vec![slot as u8; 32]);
+            worker.utxos.as_ref().unwrap().insert(key, key).unwrap();
+            worker
+                .push_undo_entry(slot, &[slot as u8; 32], vec![], vec![key.into()])
+                .unwrap();
+            worker.save_tip(&point).unwrap();
+        }
+
+        worker
+    }
+
+    fn cursor(slot: u64) -> Option<PointArg> {
+        Some(Point::Specific(slot, vec![slot as u8; 32]).into())
+    }
+
+    #[test]
+    fn enrich_ahead_of_cursor_is_reverted() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut worker = reconciled_worker(&db);
+
+        // without a cursor there's nothing to check against
+        worker.reconcile(None).unwrap();
+
+        // the block at slot 10 left the ring, it can't be reverted
+        assert!(worker.reconcile(cursor(5)).is_err());
+
+        let utxos = db.open_tree("utxos").unwrap();
+        assert_eq!(utxos.len(), 3);
+
+        worker.reconcile(cursor(20)).unwrap();
+
+        assert!(!utxos.contains_key("z#0").unwrap());
+        assert!(utxos.contains_key("y#0").unwrap());
+        assert_eq!(
+            worker.applied_tip().unwrap().unwrap().to_string(),
+            cursor(20).unwrap().to_string()
+        );
+
+        // once in sync, a restart leaves the db alone
+        worker.reconcile(cursor(20)).unwrap();
+        assert_eq!(utxos.len(), 2);
+    }
+
+    #[test]
+    fn enrich_behind_cursor_is_refused() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut worker = reconciled_worker(&db);
+
+        assert!(worker.reconcile(cursor(40)).is_err());
+
+        // same slot as the tip, another block
+        let forked = Some(Point::Specific(30, vec![0u8; 32]).into());
+        assert!(worker.reconcile(forked).is_err());
+
+        assert_eq!(db.open_tree("utxos").unwrap().len(), 3);
+        assert_eq!(
+            worker.applied_tip().unwrap().unwrap().to_string(),
+            cursor(30).unwrap().to_string()
+        );
+    }
+
+    #[test]
+    fn rollback_forgets_datums_first_seen_in_block() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+        let datums = db.open_tree("datums").unwrap();
+
+        let config = Config {
+            rollback_window: Some(10),
+            datum_index: Some(true),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(utxos);
+        worker.consumed_ring = Some(db.open_tree("consumed_ring").unwrap());
+        worker.datums = Some(datums.clone());
+        worker.datum_ring = Some(db.open_tree("datum_ring").unwrap());
+
+        // block at slot 10 supplies datum a, the one at slot 20 supplies a
+        // again (already known) and b
+        datums.insert("a", "a").unwrap();
+        worker.push_datum_undo_entry(10, &[1u8; 32], vec!["a".into()]).unwrap();
+        worker.push_undo_entry(10, &[1u8; 32], vec![], vec![]).unwrap();
+
+        datums.insert("b", "b").unwrap();
+        worker.push_datum_undo_entry(20, &[2u8; 32], vec!["b".into()]).unwrap();
+        worker.push_undo_entry(20, &[2u8; 32], vec![], vec![]).unwrap();
+
+        worker.undo_blocks(&Point::Specific(10, vec![1u8; 32])).unwrap();
+
+        assert!(datums.contains_key("a").unwrap());
+        assert!(!datums.contains_key("b").unwrap());
+
+        worker.undo_blocks(&Point::Origin).unwrap();
+
+        assert!(datums.is_empty());
+        assert!(worker.datum_ring.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn batched_blocks_see_each_other_outputs() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+
+        let cbor = hex::decode(TX_WITH_REFERENCE_INPUT).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+
+        let spent = format!("{}#0", "11".repeat(32));
+        let referenced = format!("{}#0", "22".repeat(32));
+
+        let config = Config {
+            batch_blocks: Some(10),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(utxos.clone());
+        worker.pending = Some(PendingWrites::default());
+
+        // a first block of the batch produces the outputs used by the tx
+        let (_, output) = tx.produces().into_iter().next().unwrap();
+        let value: sled::IVec = SledTxValue(Era::Babbage.into(), output.encode())
+            .try_into()
+            .unwrap();
+        let pending = worker.pending.as_mut().unwrap();
+        pending.utxos.insert(spent.clone(), Some(value.clone()));
+        pending.utxos.insert(referenced.clone(), Some(value));
+
+        let txs = std::slice::from_ref(&tx);
+        worker.insert_produced_utxos(&utxos, txs).unwrap();
+
+        let ctx = worker.par_fetch_referenced_utxos(&utxos, 0, txs).unwrap();
+        assert!(ctx.find_utxo(&tx.consumes()[0].output_ref()).is_ok());
+
+        let removed = worker.remove_consumed_utxos(&utxos, txs).unwrap();
+        assert_eq!(removed.len(), 1);
+
+        // nothing reaches the db until the batch is flushed
+        assert!(utxos.is_empty());
+
+        worker.flush_pending().unwrap();
+
+        let mut keys: Vec<_> = utxos
+            .iter()
+            .keys()
+            .map(|k| String::from_utf8(k.unwrap().to_vec()).unwrap())
+            .collect();
+
+        let mut expected = vec![referenced, format!("{}#0", tx.hash())];
+
+        keys.sort();
+        expected.sort();
+        assert_eq!(keys, expected);
+    }
+
+    #[test]
+    fn skipped_blocks_are_acknowledged() {
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<model::EnrichedBlockPayload>::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        let policy = RuntimePolicy {
+            cbor_errors: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(Config::default(), policy, input, output);
+
+        upstream
+            .send(model::RawBlockPayload::roll_forward(vec![0xff, 0xff]))
+            .unwrap();
+        worker.inflight.emitted();
+
+        worker.work().unwrap();
+
+        // nothing to send, the slot of the source is freed all the same
+        assert_eq!(worker.inflight.count(), 0);
+        assert!(matches!(
+            downstream.recv_or_idle(),
+            Err(gasket::error::Error::RecvIdle)
+        ));
+    }
+
+    #[test]
+    fn batched_blocks_are_held_until_applied() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+
+        let config = Config {
+            batch_blocks: Some(3),
+            ..Default::default()
+        };
+
+        let mut worker = Worker::new(config, Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(db.open_tree("utxos").unwrap());
+        worker.meta = Some(db.open_tree("enrich_meta").unwrap());
+        worker.pending = Some(PendingWrites::default());
+
+        for _ in 0..2 {
+            let released = worker
+                .enrich(model::RawBlockPayload::RollForward(cbor.clone()))
+                .unwrap();
+
+            assert_eq!(released.unwrap().len(), 0);
+        }
+
+        // nothing is sent before the db has the changes of the blocks
+        assert!(worker.applied_tip().unwrap().is_none());
+
+        // an idle input applies the partial batch
+        let released = worker.release().unwrap();
+        assert_eq!(released.len(), 2);
+        assert!(worker.applied_tip().unwrap().is_some());
+
+        // a rollback applies the batch first, its blocks go out before it
+        worker
+            .enrich(model::RawBlockPayload::RollForward(cbor.clone()))
+            .unwrap();
+
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        let released = worker
+            .enrich(model::RawBlockPayload::RollBack(point))
+            .unwrap()
+            .unwrap();
+
+        assert!(matches!(
+            released.as_slice(),
+            [
+                model::EnrichedBlockPayload::RollForward(..),
+                model::EnrichedBlockPayload::RollBack(_)
+            ]
+        ));
+        assert!(worker.release().unwrap().is_empty());
+    }
+
+    #[test]
+    fn replayed_block_keeps_utxo_count() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+
+        let mut worker = Worker::new(Config::default(), Default::default(), Default::default(), Default::default());
+        worker.utxos = Some(utxos.clone());
+        worker.meta = Some(db.open_tree("enrich_meta").unwrap());
+        worker.utxo_count = Some(0);
+
+        // the second time, the outputs are already there and the inputs
+        // are already gone
+        for _ in 0..2 {
+            worker
+                .enrich(model::RawBlockPayload::RollForward(cbor.clone()))
+                .unwrap();
+
+            assert!(!utxos.is_empty());
+            assert_eq!(worker.utxo_count, Some(utxos.len() as i64));
+        }
+    }
+
+    #[test]
+    fn snapshot_entries_are_validated() {
+        let (slot, hash) = parse_snapshot_point(&format!("4492800,{}\n", "aa".repeat(32))).unwrap();
+        assert_eq!(slot, 4492800);
+        assert_eq!(hash, "aa".repeat(32));
+        assert!(parse_snapshot_point("origin").is_err());
+
+        let cbor = hex::decode(TX_WITH_REFERENCE_INPUT).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+        let (_, output) = tx.produces().into_iter().next().unwrap();
+
+        let key = format!("{}#0", "11".repeat(32));
+        let era: u16 = Era::Babbage.into();
+        let line = format!("{},{},{}", key, era, hex::encode(output.encode()));
+
+        let (parsed, value) = parse_snapshot_entry(&line).unwrap();
+        assert_eq!(parsed, key);
+
+        let SledTxValue(tag, body) = SledTxValue::try_from(value).unwrap();
+        assert_eq!(tag, era);
+        assert_eq!(body, output.encode());
+
+        assert!(parse_snapshot_entry(&format!("{},{},00ff", key, era)).is_err());
+        assert!(parse_snapshot_entry(&format!("{},{}", key, era)).is_err());
+    }
+
+    #[test]
+    fn genesis_utxos_resolve_spent_inputs() {
+        let dir = std::env::temp_dir().join(format!("scrolls-genesis-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // a testnet redeem address, along with an avvm key
+        let genesis = dir.join("byron-genesis.json");
+        let json = r#"{
+            "protocolConsts": { "k": 2160, "protocolMagic": 1097911063 },
+            "nonAvvmBalances": {
+                "2cWKMJemoBah9m4XyvPg69dMuoVJe2MUF6EMtmexWxJiAr8vLAoon2PrtZJBbCNqd4z4A": "1000000"
+            },
+            "avvmDistr": { "CQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQkJCQk=": "2000000" }
+        }"#;
+        std::fs::write(&genesis, json).unwrap();
+
+        let config = Config {
+            address_index: Some(true),
+            byron_genesis: Some(genesis.to_string_lossy().to_string()),
+            byron_genesis_network_magic: Some(true),
+            ..Default::default()
+        };
+
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let utxos = db.open_tree("utxos").unwrap();
+        let addresses = db.open_tree("utxos_by_address").unwrap();
+
+        assert_eq!(config.load_byron_genesis(&utxos, Some(&addresses)).unwrap(), 2);
+
+        // the hash of the address is the id of its genesis tx
+        let input = parse_output_ref(
+            "5b4ee65a04f03d73e5250c4677f45c4c31e43f8ed3e9dcca4b1813ed60867d9f#0",
+        )
+        .unwrap();
+
+        let (_, era, cbor) = fetch_referenced_utxo(&utxos, None, &input).unwrap().unwrap();
+        assert!(matches!(era, Era::Byron));
+
+        let output = MultiEraOutput::decode(era, &cbor).unwrap();
+        assert_eq!(output.lovelace_amount(), 1_000_000);
+
+        let store = UtxoStore {
+            _db: db.clone(),
+            utxos: utxos.clone(),
+            addresses: Some(addresses.clone()),
+            ring: None,
+        };
+
+        let address = output.address().unwrap().to_string();
+        let refs = store.by_address(&address).unwrap();
+        assert_eq!(refs.len(), 1);
+        assert_eq!(refs[0].to_string(), input.to_string());
+
+        // a db that already holds utxos is left alone
+        assert_eq!(config.load_byron_genesis(&utxos, Some(&addresses)).unwrap(), 0);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn recent_txs_follow_rollbacks() {
+        let cbor = hex::decode(TX_WITH_REFERENCE_INPUT).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+
+        let mut recent = RecentTxs::new(NonZeroUsize::new(10).unwrap());
+        recent.record(std::slice::from_ref(&tx));
+
+        assert!(recent.produced(&OutputRef::new(tx.hash(), 0)));
+        assert!(!recent.produced(&tx.consumes()[0].output_ref()));
+
+        recent.clear();
+        assert!(!recent.produced(&OutputRef::new(tx.hash(), 0)));
+    }
+
+    #[test]
+    fn mismatch_ratio_threshold() {
+        assert!(!exceeds_mismatch_ratio(0, 0, 0.0));
+        assert!(!exceeds_mismatch_ratio(10, 0, 0.0));
+        assert!(exceeds_mismatch_ratio(10, 1, 0.0));
+        assert!(!exceeds_mismatch_ratio(10, 1, 0.1));
+        assert!(exceeds_mismatch_ratio(10, 2, 0.1));
+    }
+}
@@ -31,12 +31,18 @@ pub enum Error {
     #[error("missing utxo: {0}")]
     MissingUtxo(String),
 
+    #[error("missing datum: {0}")]
+    MissingDatum(String),
+
     #[error("source error: {0}")]
     SourceError(String),
 
     #[error("storage error: {0}")]
     StorageError(String),
 
+    #[error("reducer panicked: {0}")]
+    ReducerPanic(String),
+
     #[error("chain-sync intersect not found")]
     IntersectNotFound,
 
@@ -76,6 +82,10 @@ impl Error {
         Error::MissingUtxo(utxo_key.to_string())
     }
 
+    pub fn missing_datum(datum_hash: impl Display) -> Error {
+        Error::MissingDatum(datum_hash.to_string())
+    }
+
     pub fn source(error: impl Display) -> Error {
         Error::SourceError(error.to_string())
     }
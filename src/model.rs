@@ -1,11 +1,17 @@
 use std::{collections::HashMap, fmt::Debug};
 
 use pallas::{
+    codec::minicbor,
+    ledger::primitives::alonzo::PlutusData,
     ledger::traverse::{Era, MultiEraBlock, MultiEraOutput, MultiEraTx, OutputRef},
     network::miniprotocols::Point,
     crypto::hash::Hash,
 };
 
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::crosscut::{self, keys::Prefix};
 use crate::prelude::*;
 
 #[derive(Debug, Clone)]
@@ -31,22 +37,70 @@ impl RawBlockPayload {
 #[derive(Default, Debug, Clone)]
 pub struct BlockContext {
     utxos: HashMap<String, (Era, Vec<u8>)>,
+    datums: HashMap<String, Vec<u8>>,
+    utxo_count: Option<u64>,
 }
 
 impl BlockContext {
     pub fn import_ref_output(&mut self, key: &OutputRef, era: Era, cbor: Vec<u8>) {
-        self.utxos.insert(key.to_string(), (era, cbor));
+        self.utxos.insert(crosscut::refs::ref_key(key), (era, cbor));
     }
 
     pub fn find_utxo(&self, key: &OutputRef) -> Result<MultiEraOutput, Error> {
         let (era, cbor) = self
             .utxos
-            .get(&key.to_string())
+            .get(&crosscut::refs::ref_key(key))
             .ok_or_else(|| Error::missing_utxo(key))?;
 
         MultiEraOutput::decode(*era, cbor).map_err(crate::Error::cbor)
     }
 
+    pub fn import_datum(&mut self, hash: &Hash<32>, cbor: Vec<u8>) {
+        self.datums.insert(hash.to_string(), cbor);
+    }
+
+    /// Resolves a datum referenced by hash from the ones seen in witness sets
+    ///
+    /// Only available when the enrich stage indexes datums, and only for the
+    /// hashes referenced by the outputs produced or consumed in the block.
+    pub fn find_datum(&self, hash: &Hash<32>) -> Result<PlutusData, Error> {
+        let cbor = self
+            .datums
+            .get(&hash.to_string())
+            .ok_or_else(|| Error::missing_datum(hash))?;
+
+        minicbor::decode(cbor).map_err(crate::Error::cbor)
+    }
+
+    /// Rebuilds a context from its raw utxos and datums, by key, as kept by
+    /// `enrich::capture`
+    pub(crate) fn from_parts(
+        utxos: HashMap<String, (Era, Vec<u8>)>,
+        datums: HashMap<String, Vec<u8>>,
+        utxo_count: Option<u64>,
+    ) -> Self {
+        Self {
+            utxos,
+            datums,
+            utxo_count,
+        }
+    }
+
+    /// Raw utxos and datums of the context, by key
+    pub(crate) fn parts(&self) -> (&HashMap<String, (Era, Vec<u8>)>, &HashMap<String, Vec<u8>>) {
+        (&self.utxos, &self.datums)
+    }
+
+    pub fn set_utxo_count(&mut self, count: u64) {
+        self.utxo_count = Some(count);
+    }
+
+    /// Size of the utxo set once the block is applied, only available when
+    /// the enrich stage keeps count of it
+    pub fn utxo_count(&self) -> Option<u64> {
+        self.utxo_count
+    }
+
     pub fn get_all_keys(&self) -> Vec<String> {
         self.utxos.keys().map(|x| x.clone()).collect()
     }
@@ -97,7 +151,7 @@ pub type Key = String;
 pub type Delta = i64;
 pub type Timestamp = u64;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Value {
     String(String),
     BigInt(i128),
@@ -123,14 +177,77 @@ impl From<serde_json::Value> for Value {
     }
 }
 
-#[derive(Clone, Debug)]
+impl From<Value> for serde_json::Value {
+    fn from(other: Value) -> serde_json::Value {
+        match other {
+            Value::Json(x) => x,
+            other => serde_json::Value::from(&other),
+        }
+    }
+}
+
+impl From<&Value> for serde_json::Value {
+    fn from(other: &Value) -> serde_json::Value {
+        match other {
+            Value::String(x) => json!(x),
+            Value::Cbor(x) => json!(hex::encode(x)),
+            Value::BigInt(x) => json!(x),
+            Value::Json(x) => x.clone(),
+        }
+    }
+}
+
+/// Json representation of a chain point, as used by `CRDTCommand::to_json`
+pub fn point_to_json(point: &Point) -> serde_json::Value {
+    match point {
+        Point::Origin => json!("origin"),
+        Point::Specific(slot, hash) => json!({ "slot": slot, "hash": hex::encode(hash) }),
+    }
+}
+
+/// Serde adapter for chain points, written in the `slot,hash` cursor format
+pub(crate) mod point_serde {
+    use std::str::FromStr;
+
+    use pallas::network::miniprotocols::Point;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::crosscut::PointArg;
+
+    pub fn serialize<S: Serializer>(point: &Point, serializer: S) -> Result<S::Ok, S::Error> {
+        PointArg::from(point.clone())
+            .to_string()
+            .serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Point, D::Error> {
+        let raw = String::deserialize(deserializer)?;
+
+        PointArg::from_str(&raw)
+            .and_then(|x| x.try_into())
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// A storage-agnostic instruction emitted by reducers
+///
+/// Commands are applied by the storage stage in the order they were emitted,
+/// framed by `BlockStarting` / `BlockFinished`. Some commands are idempotent
+/// (applying them twice yields the same state, eg: sets and write-wins
+/// registers) while others are not (counters). Sinks must make sure that
+/// non-idempotent commands are applied exactly once, see `is_idempotent`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 #[non_exhaustive]
 pub enum CRDTCommand {
-    BlockStarting(Point),
+    BlockStarting(#[serde(with = "point_serde")] Point),
     SetAdd(Set, Member),
     SetRemove(Set, Member),
     SortedSetAdd(Set, Member, Delta),
     SortedSetRemove(Set, Member, Delta),
+    /// Keeps only the `n` members of the sorted set with the highest scores
+    ///
+    /// Members dropped by the trim are gone, so a rollback can't restore them.
+    SortedSetTrim(Set, usize),
     TwoPhaseSetAdd(Set, Member),
     TwoPhaseSetRemove(Set, Member),
     GrowOnlySetAdd(Set, Member),
@@ -138,13 +255,202 @@ pub enum CRDTCommand {
     AnyWriteWins(Key, Value),
     // TODO make sure Value is a generic not stringly typed
     PNCounter(Key, Delta),
+    /// Keeps the greatest value ever written to the key
+    ///
+    /// Max / min registers can't be reverted on rollback: once a larger value
+    /// was applied, the previous one is lost. Prefer keys scoped to a bounded
+    /// period (eg: an epoch) so that a rollback can only affect the current
+    /// bucket.
+    MaxWins(Key, i64),
+    /// Keeps the smallest value ever written to the key, see `MaxWins`
+    MinWins(Key, i64),
+    /// Pushes the value at the head of the list, keeping only the first `n`
+    /// items
+    ///
+    /// Items dropped by the trim are gone, so a rollback can't restore them.
+    ListPrepend(Key, Value, usize),
+    /// Removes the item at the head of the list, undoing a `ListPrepend`
+    ListPop(Key),
     HashCounter(Key, Member, Delta),
     HashSetValue(Key, Member, Value),
     HashUnsetKey(Key, Member),
-    BlockFinished(Point),
+    /// Removes the key along with whatever it holds
+    DeleteKey(Key),
+    BlockFinished(#[serde(with = "point_serde")] Point),
+    /// Signals that the chain was rolled back to the given point
+    ///
+    /// Emitted outside of any block, before the commands that revert the
+    /// rolled back blocks. Key-value storages can ignore it, it's meant for
+    /// stream consumers that need to know a reorg happened.
+    RollbackMarker(#[serde(with = "point_serde")] Point),
 }
 
 impl CRDTCommand {
+    /// Returns true if re-applying the command leaves the store unchanged
+    ///
+    /// Set membership and write-wins registers converge to the same value no
+    /// matter how many times they are applied. Counters and score increments
+    /// accumulate, so a replayed block would double-count them.
+    pub fn is_idempotent(&self) -> bool {
+        match self {
+            CRDTCommand::BlockStarting(_) => true,
+            CRDTCommand::SetAdd(_, _) => true,
+            CRDTCommand::SetRemove(_, _) => true,
+            CRDTCommand::TwoPhaseSetAdd(_, _) => true,
+            CRDTCommand::TwoPhaseSetRemove(_, _) => true,
+            CRDTCommand::GrowOnlySetAdd(_, _) => true,
+            CRDTCommand::LastWriteWins(_, _, _) => true,
+            CRDTCommand::AnyWriteWins(_, _) => true,
+            CRDTCommand::HashSetValue(_, _, _) => true,
+            CRDTCommand::HashUnsetKey(_, _) => true,
+            CRDTCommand::MaxWins(_, _) => true,
+            CRDTCommand::MinWins(_, _) => true,
+            CRDTCommand::SortedSetTrim(_, _) => true,
+            CRDTCommand::BlockFinished(_) => true,
+            CRDTCommand::RollbackMarker(_) => true,
+            CRDTCommand::SortedSetAdd(_, _, _) => false,
+            CRDTCommand::SortedSetRemove(_, _, _) => false,
+            CRDTCommand::PNCounter(_, _) => false,
+            CRDTCommand::HashCounter(_, _, _) => false,
+            CRDTCommand::ListPrepend(_, _, _) => false,
+            CRDTCommand::ListPop(_) => false,
+            // deleting twice is harmless, but not once the writes that
+            // followed the first delete were applied
+            CRDTCommand::DeleteKey(_) => false,
+        }
+    }
+
+    /// Returns the storage key affected by the command, if any
+    ///
+    /// For hash commands this is the key of the hash as written by the redis
+    /// storage, which doesn't always match the position of the field.
+    pub fn key(&self) -> Option<&str> {
+        match self {
+            CRDTCommand::BlockStarting(_) => None,
+            CRDTCommand::SetAdd(key, _) => Some(key),
+            CRDTCommand::SetRemove(key, _) => Some(key),
+            CRDTCommand::SortedSetAdd(key, _, _) => Some(key),
+            CRDTCommand::SortedSetRemove(key, _, _) => Some(key),
+            CRDTCommand::SortedSetTrim(key, _) => Some(key),
+            CRDTCommand::TwoPhaseSetAdd(key, _) => Some(key),
+            CRDTCommand::TwoPhaseSetRemove(key, _) => Some(key),
+            CRDTCommand::GrowOnlySetAdd(key, _) => Some(key),
+            CRDTCommand::LastWriteWins(key, _, _) => Some(key),
+            CRDTCommand::AnyWriteWins(key, _) => Some(key),
+            CRDTCommand::PNCounter(key, _) => Some(key),
+            CRDTCommand::MaxWins(key, _) => Some(key),
+            CRDTCommand::MinWins(key, _) => Some(key),
+            CRDTCommand::ListPrepend(key, _, _) => Some(key),
+            CRDTCommand::ListPop(key) => Some(key),
+            CRDTCommand::HashCounter(_, key, _) => Some(key),
+            CRDTCommand::HashSetValue(key, _, _) => Some(key),
+            CRDTCommand::HashUnsetKey(_, key) => Some(key),
+            CRDTCommand::DeleteKey(key) => Some(key),
+            CRDTCommand::BlockFinished(_) => None,
+            CRDTCommand::RollbackMarker(_) => None,
+        }
+    }
+
+    /// Mutable access to the storage key of the command, see `key`
+    pub(crate) fn key_mut(&mut self) -> Option<&mut String> {
+        match self {
+            CRDTCommand::BlockStarting(_) => None,
+            CRDTCommand::SetAdd(key, _) => Some(key),
+            CRDTCommand::SetRemove(key, _) => Some(key),
+            CRDTCommand::SortedSetAdd(key, _, _) => Some(key),
+            CRDTCommand::SortedSetRemove(key, _, _) => Some(key),
+            CRDTCommand::SortedSetTrim(key, _) => Some(key),
+            CRDTCommand::TwoPhaseSetAdd(key, _) => Some(key),
+            CRDTCommand::TwoPhaseSetRemove(key, _) => Some(key),
+            CRDTCommand::GrowOnlySetAdd(key, _) => Some(key),
+            CRDTCommand::LastWriteWins(key, _, _) => Some(key),
+            CRDTCommand::AnyWriteWins(key, _) => Some(key),
+            CRDTCommand::PNCounter(key, _) => Some(key),
+            CRDTCommand::MaxWins(key, _) => Some(key),
+            CRDTCommand::MinWins(key, _) => Some(key),
+            CRDTCommand::ListPrepend(key, _, _) => Some(key),
+            CRDTCommand::ListPop(key) => Some(key),
+            CRDTCommand::HashCounter(_, key, _) => Some(key),
+            CRDTCommand::HashSetValue(key, _, _) => Some(key),
+            CRDTCommand::HashUnsetKey(_, key) => Some(key),
+            CRDTCommand::DeleteKey(key) => Some(key),
+            CRDTCommand::BlockFinished(_) => None,
+            CRDTCommand::RollbackMarker(_) => None,
+        }
+    }
+
+    /// Json representation of the command used by streaming storages
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            CRDTCommand::BlockStarting(point) => {
+                json!({ "type": "block_starting", "point": point_to_json(point) })
+            }
+            CRDTCommand::SetAdd(key, member) => {
+                json!({ "type": "set_add", "key": key, "member": member })
+            }
+            CRDTCommand::SetRemove(key, member) => {
+                json!({ "type": "set_remove", "key": key, "member": member })
+            }
+            CRDTCommand::SortedSetAdd(key, member, delta) => {
+                json!({ "type": "sorted_set_add", "key": key, "member": member, "delta": delta })
+            }
+            CRDTCommand::SortedSetRemove(key, member, delta) => {
+                json!({ "type": "sorted_set_remove", "key": key, "member": member, "delta": delta })
+            }
+            CRDTCommand::SortedSetTrim(key, limit) => {
+                json!({ "type": "sorted_set_trim", "key": key, "limit": limit })
+            }
+            CRDTCommand::TwoPhaseSetAdd(key, member) => {
+                json!({ "type": "two_phase_set_add", "key": key, "member": member })
+            }
+            CRDTCommand::TwoPhaseSetRemove(key, member) => {
+                json!({ "type": "two_phase_set_remove", "key": key, "member": member })
+            }
+            CRDTCommand::GrowOnlySetAdd(key, member) => {
+                json!({ "type": "grow_only_set_add", "key": key, "member": member })
+            }
+            CRDTCommand::LastWriteWins(key, value, ts) => {
+                json!({ "type": "last_write_wins", "key": key, "value": serde_json::Value::from(value), "timestamp": ts })
+            }
+            CRDTCommand::AnyWriteWins(key, value) => {
+                json!({ "type": "any_write_wins", "key": key, "value": serde_json::Value::from(value) })
+            }
+            CRDTCommand::PNCounter(key, delta) => {
+                json!({ "type": "pn_counter", "key": key, "delta": delta })
+            }
+            CRDTCommand::MaxWins(key, value) => {
+                json!({ "type": "max_wins", "key": key, "value": value })
+            }
+            CRDTCommand::MinWins(key, value) => {
+                json!({ "type": "min_wins", "key": key, "value": value })
+            }
+            CRDTCommand::ListPrepend(key, value, limit) => {
+                json!({ "type": "list_prepend", "key": key, "value": serde_json::Value::from(value), "limit": limit })
+            }
+            CRDTCommand::ListPop(key) => {
+                json!({ "type": "list_pop", "key": key })
+            }
+            CRDTCommand::HashCounter(member, key, delta) => {
+                json!({ "type": "hash_counter", "key": key, "member": member, "delta": delta })
+            }
+            CRDTCommand::HashSetValue(key, member, value) => {
+                json!({ "type": "hash_set_value", "key": key, "member": member, "value": serde_json::Value::from(value) })
+            }
+            CRDTCommand::HashUnsetKey(member, key) => {
+                json!({ "type": "hash_unset_key", "key": key, "member": member })
+            }
+            CRDTCommand::DeleteKey(key) => {
+                json!({ "type": "delete_key", "key": key })
+            }
+            CRDTCommand::BlockFinished(point) => {
+                json!({ "type": "block_finished", "point": point_to_json(point) })
+            }
+            CRDTCommand::RollbackMarker(point) => {
+                json!({ "type": "rollback", "point": point_to_json(point) })
+            }
+        }
+    }
+
     pub fn block_starting(block: &MultiEraBlock) -> CRDTCommand {
         let hash = block.hash();
         let slot = block.slot();
@@ -152,67 +458,52 @@ impl CRDTCommand {
         CRDTCommand::BlockStarting(point)
     }
 
-    pub fn set_add(prefix: Option<&str>, key: &str, member: String) -> CRDTCommand {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key),
-            None => key.to_string(),
-        };
+    pub fn set_add(prefix: Prefix, key: &str, member: String) -> CRDTCommand {
+        let key = prefix.key(&[key]);
 
         CRDTCommand::SetAdd(key, member)
     }
 
-    pub fn set_remove(prefix: Option<&str>, key: &str, member: String) -> CRDTCommand {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key),
-            None => key.to_string(),
-        };
+    pub fn set_remove(prefix: Prefix, key: &str, member: String) -> CRDTCommand {
+        let key = prefix.key(&[key]);
 
         CRDTCommand::SetRemove(key, member)
     }
 
     pub fn sorted_set_add(
-        prefix: Option<&str>,
+        prefix: Prefix,
         key: &str,
         member: String,
         delta: i64,
     ) -> CRDTCommand {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key),
-            None => key.to_string(),
-        };
+        let key = prefix.key(&[key]);
 
         CRDTCommand::SortedSetAdd(key, member, delta)
     }
 
     pub fn sorted_set_remove(
-        prefix: Option<&str>,
+        prefix: Prefix,
         key: &str,
         member: String,
         delta: i64,
     ) -> CRDTCommand {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key),
-            None => key.to_string(),
-        };
+        let key = prefix.key(&[key]);
 
         CRDTCommand::SortedSetRemove(key, member, delta)
     }
 
-    pub fn any_write_wins<K, V>(prefix: Option<&str>, key: K, value: V) -> CRDTCommand
+    pub fn any_write_wins<K, V>(prefix: Prefix, key: K, value: V) -> CRDTCommand
     where
         K: ToString,
         V: Into<Value>,
     {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key.to_string()),
-            None => key.to_string(),
-        };
+        let key = prefix.key(&[&key.to_string()]);
 
         CRDTCommand::AnyWriteWins(key, value.into())
     }
 
     pub fn last_write_wins<V>(
-        prefix: Option<&str>,
+        prefix: Prefix,
         key: &str,
         value: V,
         ts: Timestamp,
@@ -220,16 +511,13 @@ impl CRDTCommand {
     where
         V: Into<Value>,
     {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key),
-            None => key.to_string(),
-        };
+        let key = prefix.key(&[key]);
 
         CRDTCommand::LastWriteWins(key, value.into(), ts)
     }
 
     pub fn hash_set_value<V>(
-        prefix: Option<&str>,
+        prefix: Prefix,
         key: &str,
         member: String,
         value: V,
@@ -237,33 +525,24 @@ impl CRDTCommand {
         where
             V: Into<Value>,
     {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key.to_string()),
-            None => key.to_string(),
-        };
+        let key = prefix.key(&[key]);
 
         CRDTCommand::HashSetValue(member, key, value.into())
     }
 
-    pub fn hash_del_key(prefix: Option<&str>, member: String, key: &str) -> CRDTCommand {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key.to_string()),
-            None => key.to_string(),
-        };
+    pub fn hash_del_key(prefix: Prefix, member: String, key: &str) -> CRDTCommand {
+        let key = prefix.key(&[key]);
 
         CRDTCommand::HashUnsetKey(member, key)
     }
 
     pub fn hash_counter(
-        prefix: Option<&str>,
+        prefix: Prefix,
         key: &str,
         member: String,
         delta: i64,
     ) -> CRDTCommand {
-        let key = match prefix {
-            Some(prefix) => format!("{}.{}", prefix, key.to_string()),
-            None => key.to_string(),
-        };
+        let key = prefix.key(&[key]);
 
         CRDTCommand::HashCounter(member, key, delta)
     }
@@ -275,3 +554,30 @@ impl CRDTCommand {
         CRDTCommand::BlockFinished(point)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::CRDTCommand;
+
+    #[test]
+    fn counters_are_not_idempotent() {
+        assert!(!CRDTCommand::PNCounter("a".into(), 1).is_idempotent());
+        assert!(!CRDTCommand::HashCounter("a".into(), "b".into(), 1).is_idempotent());
+        assert!(CRDTCommand::SetAdd("a".into(), "b".into()).is_idempotent());
+        assert!(CRDTCommand::AnyWriteWins("a".into(), "b".to_string().into()).is_idempotent());
+        assert!(CRDTCommand::MaxWins("a".into(), 1).is_idempotent());
+        assert!(!CRDTCommand::ListPrepend("a".into(), "b".to_string().into(), 5).is_idempotent());
+        assert!(!CRDTCommand::ListPop("a".into()).is_idempotent());
+        assert!(CRDTCommand::SortedSetTrim("a".into(), 5).is_idempotent());
+        assert!(!CRDTCommand::DeleteKey("a".into()).is_idempotent());
+    }
+
+    #[test]
+    fn json_uses_storage_key_for_hashes() {
+        let cmd = CRDTCommand::hash_counter(Some("p").into(), "key", "member".into(), 2);
+
+        assert_eq!(cmd.key(), Some("p.key"));
+        assert_eq!(cmd.to_json()["key"], "p.key");
+        assert_eq!(cmd.to_json()["member"], "member");
+    }
+}
@@ -0,0 +1,241 @@
+use std::collections::HashSet;
+
+use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::Journal;
+use crate::crosscut::addresses::EnterpriseHandling;
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+    pub filter: Option<crosscut::filters::Predicate>,
+
+    /// How enterprise addresses are keyed, each by itself by default
+    pub enterprise_handling: Option<EnterpriseHandling>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    time: crosscut::time::NaiveProvider,
+    journal: Journal,
+}
+
+impl Reducer {
+    fn prefix(&self) -> crosscut::keys::Prefix<'_> {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("address_activity")
+    }
+
+    fn send_activity(
+        &self,
+        soa: &str,
+        slot: u64,
+        ts: u64,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = self.prefix();
+
+        let crdts = [
+            model::CRDTCommand::MinWins(prefix.key(&[soa, "first_seen"]), slot as i64),
+            model::CRDTCommand::LastWriteWins(
+                prefix.key(&[soa, "last_seen"]),
+                slot.to_string().into(),
+                ts,
+            ),
+        ];
+
+        for crdt in crdts {
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    /// Tracks the slot in which each address was first and last active
+    ///
+    /// An address is active in a tx when it owns one of the consumed or
+    /// produced outputs. There's no set-if-absent register, the first slot is
+    /// kept in a min-wins register instead, which amounts to the same as long
+    /// as the value is the slot.
+    ///
+    /// The write-wins register of the last slot is stored as a set of slots
+    /// scored by timestamp, the one with the highest score wins. On
+    /// rollback, the slots of the undone blocks are taken out of it, which
+    /// leaves the last slot the address was active in before them. The
+    /// min-wins register can't be reverted: an address first seen in an
+    /// undone block keeps that slot, even if the fork includes its tx later.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let slot = block.slot();
+        let ts = self.time.slot_to_wallclock(slot);
+        let enterprise = self.config.enterprise_handling.unwrap_or_default();
+
+        // addresses active in the block, each slot is only written once
+        let mut seen = HashSet::new();
+
+        for tx in block.txs().into_iter() {
+            if filter_matches!(self, block, &tx, ctx) {
+                let mut active = HashSet::new();
+
+                for consumed in tx.consumes().iter().map(|i| i.output_ref()) {
+                    let utxo = ctx
+                        .find_utxo(&consumed)
+                        .apply_policy(&self.policy)
+                        .or_panic()?;
+
+                    if let Some(utxo) = utxo {
+                        active.extend(self.addresses.stake_key(&utxo, enterprise));
+                    }
+                }
+
+                for (_, produced) in tx.produces() {
+                    active.extend(self.addresses.stake_key(&produced, enterprise));
+                }
+
+                for soa in active {
+                    self.send_activity(&soa, slot, ts, output)?;
+                    seen.insert(soa);
+                }
+            }
+        }
+
+        let prefix = self.prefix();
+
+        let undo = seen
+            .into_iter()
+            .map(|soa| {
+                model::CRDTCommand::SortedSetRemove(
+                    prefix.key(&[&soa, "last_seen"]),
+                    slot.to_string(),
+                    -(ts as i64),
+                )
+            })
+            .collect();
+
+        self.journal.record(slot, undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Takes the slots of the blocks past the point out of the last seen
+    /// registers
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            time: crosscut::time::NaiveProvider::new(chain.clone()),
+            journal,
+        };
+
+        super::Reducer::AddressActivity(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::Config;
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn rollback_takes_slot_out_of_last_seen() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 10_000);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            filter: None,
+            enterprise_handling: None,
+            rollback_window: None,
+        };
+
+        // the consumed utxos aren't in the context, only outputs count
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = match config.plugin(&ChainWellKnownInfo::mainnet(), &policy) {
+            Reducer::AddressActivity(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer
+            .reduce_block(&block, &BlockContext::default(), &mut output)
+            .unwrap();
+
+        let written: HashSet<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .filter_map(|x| match x.payload {
+                CRDTCommand::LastWriteWins(key, _, ts) => Some((key, ts)),
+                _ => None,
+            })
+            .collect();
+
+        assert!(!written.is_empty());
+
+        assert!(reducer.covers_rollback(&before));
+        let undo = reducer.roll_back(&before);
+
+        // once per address, even when active in several txs
+        assert_eq!(undo.len(), written.len());
+
+        for cmd in undo {
+            match cmd {
+                CRDTCommand::SortedSetRemove(key, member, delta) => {
+                    assert_eq!(member, block.slot().to_string());
+                    assert!(written.contains(&(key, -delta as u64)));
+                }
+                x => panic!("unexpected command {:?}", x),
+            }
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraOutput, OutputRef};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::{inverse, Journal};
+use crate::crosscut::addresses::EnterpriseHandling;
+use crate::{crosscut, model, prelude::*};
+
+const SECONDS_PER_DAY: u64 = 86_400;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+    pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Key by the bucket of the address, out of `2^bits` buckets, instead of
+    /// the address itself. Lossy, see `crosscut::buckets`.
+    pub address_bucket_bits: Option<u8>,
+
+    /// How enterprise addresses are keyed, each by itself by default
+    pub enterprise_handling: Option<EnterpriseHandling>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    time: crosscut::time::NaiveProvider,
+    journal: Journal,
+    /// Inverse deltas of the block being reduced
+    undo: Vec<model::CRDTCommand>,
+}
+
+impl Reducer {
+    fn send_flow(
+        &mut self,
+        soa: &str,
+        day: u64,
+        direction: &str,
+        amount: u64,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("ada_flow");
+
+        let crdt = model::CRDTCommand::HashCounter(
+            direction.to_string(),
+            prefix.key(&[soa, &day.to_string()]),
+            amount as i64,
+        );
+
+        self.undo.extend(inverse(&crdt));
+        output.send(gasket::messaging::Message::from(crdt))
+    }
+
+    fn process_consumed_txo(
+        &mut self,
+        ctx: &model::BlockContext,
+        input: &OutputRef,
+        day: u64,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let utxo = ctx.find_utxo(input).apply_policy(&self.policy).or_panic()?;
+
+        let utxo = match utxo {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let enterprise = self.config.enterprise_handling.unwrap_or_default();
+
+        let soa = match self.addresses.stake_key(&utxo, enterprise) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let soa = crosscut::buckets::address_key(&soa, self.config.address_bucket_bits);
+
+        self.send_flow(&soa, day, "out", utxo.lovelace_amount(), output)
+    }
+
+    fn process_produced_txo(
+        &mut self,
+        tx_output: &MultiEraOutput,
+        day: u64,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let enterprise = self.config.enterprise_handling.unwrap_or_default();
+
+        let soa = match self.addresses.stake_key(tx_output, enterprise) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let soa = crosscut::buckets::address_key(&soa, self.config.address_bucket_bits);
+
+        self.send_flow(&soa, day, "in", tx_output.lovelace_amount(), output)
+    }
+
+    /// Accumulates lovelace flows per address and day (days since unix epoch)
+    ///
+    /// Inflows and outflows are kept as separate members of the same hash
+    /// instead of being netted, so both can be queried.
+    ///
+    /// On rollback, the flows of the undone blocks are sent again negated.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let day = self.time.slot_to_wallclock(block.slot()) / SECONDS_PER_DAY;
+
+        // leftovers of a block that failed half way
+        self.undo.clear();
+
+        for tx in block.txs().into_iter() {
+            if filter_matches!(self, block, &tx, ctx) {
+                for consumed in tx.consumes().iter().map(|i| i.output_ref()) {
+                    self.process_consumed_txo(ctx, &consumed, day, output)?;
+                }
+
+                for (_, produced) in tx.produces() {
+                    self.process_produced_txo(&produced, day, output)?;
+                }
+            }
+        }
+
+        let undo = std::mem::take(&mut self.undo);
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Sends the inverse flows of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            time: crosscut::time::NaiveProvider::new(chain.clone()),
+            journal,
+            undo: vec![],
+        };
+
+        super::Reducer::AddressAdaFlow(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::Config;
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn rollback_sends_inverse_flows() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 10_000);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            filter: None,
+            address_bucket_bits: None,
+            enterprise_handling: None,
+            rollback_window: None,
+        };
+
+        // the consumed utxos aren't in the context, only inflows are sent
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = match config.plugin(&ChainWellKnownInfo::mainnet(), &policy) {
+            Reducer::AddressAdaFlow(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer
+            .reduce_block(&block, &BlockContext::default(), &mut output)
+            .unwrap();
+
+        let sent: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        assert!(!sent.is_empty());
+
+        assert!(reducer.covers_rollback(&before));
+        let undo = reducer.roll_back(&before);
+
+        assert_eq!(undo.len(), sent.len());
+
+        for (flow, inverse) in sent.iter().zip(undo.iter()) {
+            match (flow, inverse) {
+                (
+                    CRDTCommand::HashCounter(a, x, delta),
+                    CRDTCommand::HashCounter(b, y, inverse),
+                ) => {
+                    assert_eq!((a, x), (b, y));
+                    assert_eq!(*delta, -inverse);
+                }
+                x => panic!("unexpected commands {:?}", x),
+            }
+        }
+    }
+}
@@ -2,30 +2,37 @@ use pallas::ledger::traverse::MultiEraOutput;
 use pallas::ledger::traverse::{Asset, MultiEraBlock};
 use serde::Deserialize;
 
-use crate::{model, prelude::*};
+use crate::crosscut::assets::{decode_name, AssetLimit, NameEncoding};
+use crate::{crosscut, model};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<Vec<String>>,
     pub policy_id_hex: String,
     // bool convert to ascii, default true
     pub convert_to_ascii: Option<bool>,
+    /// Takes precedence over `convert_to_ascii` when set
+    pub asset_name_encoding: Option<NameEncoding>,
+
+    /// Outputs carrying more native assets than this are skipped, see
+    /// `AssetLimit`
+    pub max_assets_per_output: Option<usize>,
 }
 
 pub struct Reducer {
     config: Config,
-    convert_to_ascii: bool,
+    name_encoding: NameEncoding,
+    pub(crate) asset_limit: AssetLimit,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 impl Reducer {
     fn to_string_output(&self, asset: Asset) -> Option<String> {
         match asset.policy_hex() {
             Some(policy_id) if policy_id.eq(&self.config.policy_id_hex) => match asset {
-                Asset::NativeAsset(_, name, _) => match self.convert_to_ascii {
-                    true => String::from_utf8(name).ok(),
-                    false => Some(hex::encode(name)),
-                },
+                Asset::NativeAsset(_, name, _) => decode_name(&name, self.name_encoding),
                 _ => None,
             },
             _ => None,
@@ -37,6 +44,10 @@ impl Reducer {
         txo: &MultiEraOutput,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
+        if !self.asset_limit.admits(txo) {
+            return Ok(());
+        }
+
         let asset_names: Vec<_> = txo
             .non_ada_assets()
             .into_iter()
@@ -47,13 +58,19 @@ impl Reducer {
             return Ok(());
         }
 
-        let address = txo.address().map(|x| x.to_string()).or_panic()?;
+        let address = match self.addresses.key(txo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
 
         for asset in asset_names {
             log::debug!("asset match found: ${asset}=>{address}");
 
             let crdt = model::CRDTCommand::any_write_wins(
-                self.config.key_prefix.as_deref(),
+                crosscut::keys::Prefix::new(
+                    self.config.key_prefix.as_deref(),
+                    self.config.key_separator.as_deref(),
+                ),
                 asset,
                 address.clone(),
             );
@@ -81,11 +98,20 @@ impl Reducer {
 }
 
 impl Config {
-    pub fn plugin(self) -> super::Reducer {
-        let convert_to_ascii = self.convert_to_ascii.unwrap_or(false);
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let name_encoding = match (self.asset_name_encoding, self.convert_to_ascii) {
+            (Some(x), _) => x,
+            (None, Some(true)) => NameEncoding::Ascii,
+            (None, _) => NameEncoding::Hex,
+        };
+
+        let asset_limit = AssetLimit::new(self.max_assets_per_output);
+
         let reducer = Reducer {
             config: self,
-            convert_to_ascii,
+            name_encoding,
+            asset_limit,
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::AddressByAsset(reducer)
@@ -9,15 +9,27 @@ use crate::{crosscut, model};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Also index the txs that use each output as a reference input
+    pub referenced_by: Option<bool>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 impl Reducer {
+    fn prefix(&self) -> crosscut::keys::Prefix {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+    }
+
     fn send(
         &mut self,
         slot: u64,
@@ -27,8 +39,8 @@ impl Reducer {
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         let crdt = model::CRDTCommand::last_write_wins(
-            self.config.key_prefix.as_deref(),
-            &format!("{}#{}", tx_hash, output_idx),
+            self.prefix(),
+            &crosscut::refs::output_key(&tx_hash, output_idx as u64),
             address.to_string(),
             slot,
         );
@@ -38,6 +50,22 @@ impl Reducer {
         Ok(())
     }
 
+    fn send_reference(
+        &mut self,
+        tx_hash: Hash<32>,
+        input_ref: &str,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let crdt = model::CRDTCommand::SetAdd(
+            self.prefix().key(&["referenced_by", input_ref]),
+            tx_hash.to_string(),
+        );
+
+        output.send(gasket::messaging::Message::from(crdt))?;
+
+        Ok(())
+    }
+
     pub fn reduce_block(
         &mut self,
         block: &MultiEraBlock,
@@ -51,9 +79,16 @@ impl Reducer {
                 let tx_hash = tx.hash();
 
                 for (output_idx, tx_out) in tx.outputs().iter().enumerate() {
-                    let address = tx_out.address().map(|x| x.to_string()).or_panic()?;
+                    if let Some(address) = self.addresses.key(tx_out, |x| x.to_string()) {
+                        self.send(slot, &address, tx_hash, output_idx, output)?;
+                    }
+                }
 
-                    self.send(slot, &address, tx_hash, output_idx, output)?;
+                if self.config.referenced_by.unwrap_or(false) {
+                    for input in tx.reference_inputs() {
+                        let input_ref = crosscut::refs::ref_key(&input.output_ref());
+                        self.send_reference(tx_hash, &input_ref, output)?;
+                    }
                 }
             }
         }
@@ -67,6 +102,7 @@ impl Config {
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::AddressByTxo(reducer)
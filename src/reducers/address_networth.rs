@@ -0,0 +1,273 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraOutput};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use crate::{crosscut, model, prelude::*};
+
+const BALANCES_TREE: &str = "networth_balances";
+const UNDO_RING_TREE: &str = "networth_undo_ring";
+
+/// Unit of the lovelace balance, assets use their policy id and name in hex
+const LOVELACE: &str = "lovelace";
+
+/// Net quantity change per (address, unit) accumulated over a block
+type BalanceOffsets = BTreeMap<(String, String), i128>;
+
+/// Source of the prices used to value native assets
+///
+/// Prices live off-chain and none ships with scrolls: library users plug
+/// their own feed through `Config::plugin_with_pricer`. Assets are valued
+/// when a block moves them, price changes alone don't update the net worth
+/// of an address.
+pub trait AssetPricer: Send {
+    /// Lovelace worth of `quantity` of the asset, by its unit (policy id
+    /// followed by the asset name, in hex), none if it has no known price
+    fn lovelace_value(&self, unit: &str, quantity: i128) -> Option<i128>;
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Path of the sled db where the holdings of every address are kept,
+    /// see `crosscut::balances`
+    pub db_path: String,
+
+    /// Number of recent blocks whose balance changes are kept to be undone
+    /// on a rollback. Defaults to 2160.
+    pub rollback_window: Option<usize>,
+}
+
+fn balance_key(address: &str, unit: &str) -> String {
+    format!("{} {}", address, unit)
+}
+
+/// Lovelace worth of the holdings of an address, as (unit, quantity)
+///
+/// Assets are only tracked with a pricer, those it can't value are left out.
+fn net_worth(holdings: &[(String, i128)], pricer: Option<&dyn AssetPricer>) -> i128 {
+    holdings
+        .iter()
+        .filter_map(|(unit, quantity)| match (unit.as_str(), pricer) {
+            (LOVELACE, _) => Some(*quantity),
+            (_, Some(pricer)) => pricer.lovelace_value(unit, *quantity),
+            (_, None) => None,
+        })
+        .sum()
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    pricer: Option<Box<dyn AssetPricer>>,
+    balances: Option<crosscut::balances::Balances>,
+}
+
+impl Reducer {
+    /// Opens the balances db on first use
+    fn balances(&mut self) -> Result<&mut crosscut::balances::Balances, crate::Error> {
+        if self.balances.is_none() {
+            let db = sled::open(&self.config.db_path).map_err(crate::Error::storage)?;
+            let window = self.config.rollback_window.unwrap_or(2160);
+
+            self.balances = Some(crosscut::balances::Balances::open(
+                &db,
+                BALANCES_TREE,
+                UNDO_RING_TREE,
+                window,
+            )?);
+        }
+
+        Ok(self.balances.as_mut().unwrap())
+    }
+
+    fn add_offsets(&self, utxo: &MultiEraOutput, sign: i128, offsets: &mut BalanceOffsets) {
+        let address = match self.addresses.key(utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return,
+        };
+
+        *offsets
+            .entry((address.clone(), LOVELACE.to_string()))
+            .or_default() += sign * utxo.lovelace_amount() as i128;
+
+        // assets are worthless without a price feed, no need to track them
+        if self.pricer.is_none() {
+            return;
+        }
+
+        for asset in utxo.non_ada_assets() {
+            if let Asset::NativeAsset(policy_id, name, quantity) = asset {
+                let unit = format!("{}{}", policy_id, hex::encode(name));
+                *offsets.entry((address.clone(), unit)).or_default() += sign * quantity as i128;
+            }
+        }
+    }
+
+    /// Keeps the net worth of each address, in lovelace, as a last write
+    /// wins register under `{prefix}.{address}`
+    ///
+    /// Only the lovelace balance counts unless a pricer values the native
+    /// assets. Balances are read from the local db at `db_path`, so they're
+    /// only right if the reducer ran since the origin: the outputs spent from
+    /// before that are counted as empty balances.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let mut offsets = BalanceOffsets::new();
+
+        for tx in block.txs().iter() {
+            for input in tx.consumes() {
+                let utxo = ctx
+                    .find_utxo(&input.output_ref())
+                    .apply_policy(&self.policy)
+                    .or_panic()?;
+
+                if let Some(utxo) = utxo {
+                    self.add_offsets(&utxo, -1, &mut offsets);
+                }
+            }
+
+            for (_, produced) in tx.produces() {
+                self.add_offsets(&produced, 1, &mut offsets);
+            }
+        }
+
+        let balances = self.balances().or_retry()?;
+        let mut changes = vec![];
+        let mut touched = BTreeSet::new();
+
+        for ((address, unit), delta) in offsets {
+            if delta == 0 {
+                continue;
+            }
+
+            let key = balance_key(&address, &unit);
+            let prior = balances.get(&key).or_panic()?;
+            let new = (prior + delta).max(0);
+
+            if prior + delta < 0 {
+                log::warn!(
+                    "negative {} balance for {}, counted as empty",
+                    unit,
+                    address
+                );
+            }
+
+            changes.push((key, prior, new));
+            touched.insert(address);
+        }
+
+        balances
+            .apply(block.slot(), &block.hash().to_vec(), &changes)
+            .or_panic()?;
+
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("address_networth");
+
+        for address in touched {
+            let holdings: Vec<_> = self
+                .balances()
+                .or_retry()?
+                .scan(&balance_key(&address, ""))
+                .or_panic()?
+                .into_iter()
+                .map(|(key, quantity)| (key[address.len() + 1..].to_string(), quantity))
+                .collect();
+
+            let worth = net_worth(&holdings, self.pricer.as_deref());
+
+            let crdt = model::CRDTCommand::LastWriteWins(
+                prefix.key(&[&address]),
+                model::Value::BigInt(worth),
+                block.slot(),
+            );
+
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    /// Restores the local balances as of the rollback point, see
+    /// `holding_distribution` for why no command is emitted
+    pub fn roll_back(&mut self, point: &Point) -> Result<(), crate::Error> {
+        let undone = self.balances()?.undo(point)?;
+        log::info!("reverted address holdings of {} blocks", undone);
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        self.build(policy, None)
+    }
+
+    /// Same as `plugin`, valuing native assets with the given price feed
+    pub fn plugin_with_pricer(
+        self,
+        policy: &crosscut::policies::RuntimePolicy,
+        pricer: Box<dyn AssetPricer>,
+    ) -> super::Reducer {
+        self.build(policy, Some(pricer))
+    }
+
+    fn build(
+        self,
+        policy: &crosscut::policies::RuntimePolicy,
+        pricer: Option<Box<dyn AssetPricer>>,
+    ) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            pricer,
+            balances: None,
+        };
+
+        super::Reducer::AddressNetworth(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{net_worth, AssetPricer, LOVELACE};
+
+    /// Values every asset at 2 lovelace per unit, except `ff..`
+    struct FixedPrice;
+
+    impl AssetPricer for FixedPrice {
+        fn lovelace_value(&self, unit: &str, quantity: i128) -> Option<i128> {
+            match unit.starts_with("ff") {
+                true => None,
+                false => Some(quantity * 2),
+            }
+        }
+    }
+
+    #[test]
+    fn assets_count_only_with_a_price() {
+        let holdings = vec![
+            (LOVELACE.to_string(), 5_000_000),
+            ("aa".repeat(28), 100),
+            ("ff".repeat(28), 100),
+        ];
+
+        assert_eq!(net_worth(&holdings, None), 5_000_000);
+        assert_eq!(
+            net_worth(&holdings, Some(&FixedPrice as &dyn AssetPricer)),
+            5_000_200
+        );
+    }
+}
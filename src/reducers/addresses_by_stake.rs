@@ -2,17 +2,23 @@ use pallas::ledger::addresses::{Address, StakeAddress};
 use pallas::ledger::traverse::MultiEraBlock;
 use serde::Deserialize;
 
-use crate::{crosscut, model, prelude::*};
+use crate::{crosscut, model};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<Vec<String>>,
+
+    /// Key of the set collecting addresses without a stake part (Byron,
+    /// enterprise). These addresses are skipped if not set.
+    pub unstaked_key: Option<String>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 fn any_address_to_stake_bech32(address: Address) -> Option<String> {
@@ -32,17 +38,13 @@ impl Reducer {
         address: Address,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        // exit early since we don't care about Byron
-        if matches!(address, Address::Byron(_)) {
-            return Ok(());
-        }
-
         let full_address = address.to_string();
         let stake_address = any_address_to_stake_bech32(address);
 
-        let stake_address = match stake_address {
-            Some(x) => x,
-            None => return Ok(()),
+        let stake_address = match (stake_address, &self.config.unstaked_key) {
+            (Some(x), _) => x,
+            (None, Some(bucket)) => bucket.clone(),
+            (None, None) => return Ok(()),
         };
 
         if let Some(stake_addresses) = &self.config.filter {
@@ -52,7 +54,10 @@ impl Reducer {
         }
 
         let crdt = model::CRDTCommand::set_add(
-            self.config.key_prefix.as_deref(),
+            crosscut::keys::Prefix::new(
+                self.config.key_prefix.as_deref(),
+                self.config.key_separator.as_deref(),
+            ),
             &stake_address,
             full_address,
         );
@@ -68,8 +73,10 @@ impl Reducer {
     ) -> Result<(), gasket::error::Error> {
         for tx in block.txs().into_iter() {
             for (idx, produced) in tx.produces() {
-                let address = produced.address().or_panic()?;
-                self.process_address(address, output)?;
+                // unknown addresses have no stake part to be grouped by
+                if let Some(address) = self.addresses.address(&produced) {
+                    self.process_address(address, output)?;
+                }
             }
         }
 
@@ -82,6 +89,7 @@ impl Config {
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::AddressesByStake(reducer)
@@ -6,8 +6,12 @@ use crate::{crosscut, model, prelude::*};
 use pallas::crypto::hash::Hash;
 
 use crate::crosscut::epochs::block_epoch;
+use std::collections::BTreeMap;
 use std::str::FromStr;
 
+/// Net asset delta per (key, address) accumulated over a block
+type BalanceOffsets = BTreeMap<(String, String), i64>;
+
 #[derive(Deserialize, Copy, Clone)]
 pub enum AggrType {
     Epoch,
@@ -16,6 +20,7 @@ pub enum AggrType {
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
     pub aggr_by: Option<AggrType>,
 
@@ -24,6 +29,19 @@ pub struct Config {
     /// If specified only those policy ids as hex will be taken into account, if
     /// not all policy ids will be indexed.
     pub policy_ids_hex: Option<Vec<String>>,
+
+    /// Minimum absolute net delta per block to be written
+    ///
+    /// Movements below this value are dropped, which means the stored
+    /// balances will drift by the suppressed amounts. Zero deltas are never
+    /// written regardless of this value.
+    pub min_delta: Option<u64>,
+
+    pub asset_name_encoding: Option<crosscut::assets::NameEncoding>,
+
+    /// Outputs carrying more native assets than this are skipped, see
+    /// `crosscut::assets::AssetLimit`
+    pub max_assets_per_output: Option<usize>,
 }
 
 pub struct Reducer {
@@ -31,24 +49,28 @@ pub struct Reducer {
     policy: crosscut::policies::RuntimePolicy,
     chain: crosscut::ChainWellKnownInfo,
     policy_ids: Option<Vec<Hash<28>>>,
+    pub(crate) asset_limit: crosscut::assets::AssetLimit,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 impl Reducer {
     fn config_key(&self, subject: String, epoch_no: u64) -> String {
         let def_key_prefix = "asset_holders_by_asset_id";
 
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        );
+
         match &self.config.aggr_by {
             Some(aggr_type) if matches!(aggr_type, AggrType::Epoch) => {
                 return match &self.config.key_prefix {
-                    Some(prefix) => format!("{}.{}.{}", prefix, subject, epoch_no),
-                    None => format!("{}.{}", def_key_prefix.to_string(), subject),
+                    Some(_) => prefix.key(&[&subject, &epoch_no.to_string()]),
+                    None => prefix.or(def_key_prefix).key(&[&subject]),
                 };
             }
             _ => {
-                return match &self.config.key_prefix {
-                    Some(prefix) => format!("{}.{}", prefix, subject),
-                    None => format!("{}.{}", def_key_prefix.to_string(), subject),
-                };
+                return prefix.or(def_key_prefix).key(&[&subject]);
             }
         };
     }
@@ -65,7 +87,7 @@ impl Reducer {
         ctx: &model::BlockContext,
         input: &OutputRef,
         epoch_no: u64,
-        output: &mut super::OutputPort,
+        offsets: &mut BalanceOffsets,
     ) -> Result<(), gasket::error::Error> {
         let utxo = ctx.find_utxo(input).apply_policy(&self.policy).or_panic()?;
 
@@ -74,20 +96,31 @@ impl Reducer {
             None => return Ok(()),
         };
 
-        let address = utxo.address().map(|addr| addr.to_string()).or_panic()?;
+        if !self.asset_limit.admits_spent(&utxo) {
+            return Ok(());
+        }
+
+        let address = match self.addresses.key(&utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
 
         for asset in utxo.assets() {
             match asset {
-                Asset::NativeAsset(policy_id, _, quantity) => {
+                Asset::NativeAsset(policy_id, name, quantity) => {
                     if self.is_policy_id_accepted(&policy_id) {
-                        let subject = asset.subject();
+                        let encoding = self.config.asset_name_encoding.unwrap_or_default();
+
+                        let subject = match crosscut::assets::asset_id(&policy_id, &name, encoding)
+                        {
+                            Some(x) => x,
+                            None => continue,
+                        };
+
                         let key = self.config_key(subject, epoch_no);
                         let delta = quantity as i64 * (-1);
 
-                        let crdt =
-                            model::CRDTCommand::SortedSetRemove(key, address.to_string(), delta);
-
-                        output.send(gasket::messaging::Message::from(crdt))?;
+                        *offsets.entry((key, address.to_string())).or_default() += delta;
                     }
                 }
                 _ => (),
@@ -101,25 +134,33 @@ impl Reducer {
         &mut self,
         tx_output: &MultiEraOutput,
         epoch_no: u64,
-        output: &mut super::OutputPort,
+        offsets: &mut BalanceOffsets,
     ) -> Result<(), gasket::error::Error> {
-        let address = tx_output
-            .address()
-            .map(|addr| addr.to_string())
-            .or_panic()?;
+        if !self.asset_limit.admits(tx_output) {
+            return Ok(());
+        }
+
+        let address = match self.addresses.key(tx_output, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
 
         for asset in tx_output.assets() {
             match asset {
-                Asset::NativeAsset(policy_id, _, quantity) => {
+                Asset::NativeAsset(policy_id, name, quantity) => {
                     if self.is_policy_id_accepted(&policy_id) {
-                        let subject = asset.subject();
+                        let encoding = self.config.asset_name_encoding.unwrap_or_default();
+
+                        let subject = match crosscut::assets::asset_id(&policy_id, &name, encoding)
+                        {
+                            Some(x) => x,
+                            None => continue,
+                        };
+
                         let key = self.config_key(subject, epoch_no);
                         let delta = quantity as i64;
 
-                        let crdt =
-                            model::CRDTCommand::SortedSetAdd(key, address.to_string(), delta);
-
-                        output.send(gasket::messaging::Message::from(crdt))?;
+                        *offsets.entry((key, address.to_string())).or_default() += delta;
                     }
                 }
                 _ => {}
@@ -135,24 +176,45 @@ impl Reducer {
         ctx: &model::BlockContext,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
+        let mut offsets = BalanceOffsets::new();
+
         for tx in block.txs().into_iter() {
             if filter_matches!(self, block, &tx, ctx) {
                 let epoch_no = block_epoch(&self.chain, block);
 
                 for consumed in tx.consumes().iter().map(|i| i.output_ref()) {
-                    self.process_consumed_txo(&ctx, &consumed, epoch_no, output)?;
+                    self.process_consumed_txo(&ctx, &consumed, epoch_no, &mut offsets)?;
                 }
 
                 for (_, meo) in tx.produces() {
-                    self.process_produced_txo(&meo, epoch_no, output)?;
+                    self.process_produced_txo(&meo, epoch_no, &mut offsets)?;
                 }
             }
         }
 
+        for crdt in offset_commands(offsets, self.config.min_delta.unwrap_or_default()) {
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
         Ok(())
     }
 }
 
+/// Turns the net offsets of a block into sorted set commands
+///
+/// Assets received and spent within the same block cancel out, those and
+/// any movement below `min_delta` don't produce a write.
+fn offset_commands(offsets: BalanceOffsets, min_delta: u64) -> Vec<model::CRDTCommand> {
+    offsets
+        .into_iter()
+        .filter(|(_, delta)| *delta != 0 && delta.unsigned_abs() >= min_delta)
+        .map(|((key, address), delta)| match delta {
+            x if x > 0 => model::CRDTCommand::SortedSetAdd(key, address, x),
+            x => model::CRDTCommand::SortedSetRemove(key, address, x),
+        })
+        .collect()
+}
+
 impl Config {
     pub fn plugin(
         self,
@@ -171,17 +233,64 @@ impl Config {
             None => None,
         };
 
+        let asset_limit = crosscut::assets::AssetLimit::new(self.max_assets_per_output);
+
         let reducer = Reducer {
             config: self,
             chain: chain.clone(),
             policy: policy.clone(),
             policy_ids: policy_ids.clone(),
+            asset_limit,
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::AssetHoldersByAssetId(reducer)
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use super::{offset_commands, BalanceOffsets};
+    use crate::model::CRDTCommand;
+
+    fn offsets() -> BalanceOffsets {
+        let mut offsets = BalanceOffsets::new();
+        offsets.insert(("asset".into(), "addr_spent_back".into()), 0);
+        offsets.insert(("asset".into(), "addr_dust".into()), 3);
+        offsets.insert(("asset".into(), "addr_in".into()), 100);
+        offsets.insert(("asset".into(), "addr_out".into()), -100);
+        offsets
+    }
+
+    #[test]
+    fn zero_deltas_are_not_emitted() {
+        let commands = offset_commands(offsets(), 0);
+
+        assert_eq!(commands.len(), 3);
+
+        for cmd in commands {
+            match cmd {
+                CRDTCommand::SortedSetAdd(_, _, delta) => assert!(delta > 0),
+                CRDTCommand::SortedSetRemove(_, _, delta) => assert!(delta < 0),
+                x => panic!("unexpected command {:?}", x),
+            }
+        }
+    }
+
+    #[test]
+    fn dust_is_suppressed() {
+        let commands = offset_commands(offsets(), 10);
+
+        assert_eq!(commands.len(), 2);
+
+        assert!(commands.iter().all(|cmd| match cmd {
+            CRDTCommand::SortedSetAdd(_, addr, _) => addr == "addr_in",
+            CRDTCommand::SortedSetRemove(_, addr, _) => addr == "addr_out",
+            _ => false,
+        }));
+    }
+}
+
 // How to query
 // 127.0.0.1:6379> ZRANGEBYSCORE
 // "asset_holders_by_asset_id.
@@ -1,9 +1,4 @@
 use std::collections::HashMap;
-use std::ops::Deref;
-
-use bech32::{ToBase32, Variant};
-use blake2::digest::{Update, VariableOutput};
-use blake2::Blake2bVar;
 
 use pallas::ledger::primitives::alonzo::{Metadata, Metadatum, MetadatumLabel};
 use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
@@ -15,6 +10,7 @@ use serde_json::{Value};
 
 use hex::{self};
 
+use crate::crosscut::metadata::{kv_pairs_to_hashmap, metadatum_to_value};
 use crate::{crosscut, model};
 use crate::model::CRDTCommand;
 
@@ -33,66 +29,95 @@ impl Default for Projection {
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub historical_metadata: Option<bool>,
     pub policy_asset_index: Option<bool>,
     pub royalty_metadata: Option<bool>,
+    pub raw_fingerprint_index: Option<bool>,
+    /// Also write the metadata under `{prefix}.{policy}.{asset_name_hex}`,
+    /// for lookups that don't go through the fingerprint
+    pub policy_asset_name_key: Option<bool>,
     pub projection: Option<Projection>,
     pub filter: Option<crosscut::filters::Predicate>,
+    pub metadata_labels: Option<Vec<u64>>,
+    /// Hand the `image` / `files` URIs of CIP-25 mints to a pinner and keep
+    /// what it answered under `{prefix}.pin.{fingerprint}`, one hash member
+    /// per URI, see `crosscut::pinning`
+    pub pin_media: Option<crosscut::pinning::Config>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
     time: crosscut::time::NaiveProvider,
+    pinner: Option<Box<dyn crosscut::pinning::Pinner>>,
 }
 
 const CIP25_META_NFT: u64 = 721;
 const U_20_META_TOKEN: u64 = 20;
 const CIP27_META_ROYALTIES: u64 = 777;
 
-fn kv_pairs_to_hashmap(kv_pairs: &KeyValuePairs<Metadatum, Metadatum>
-) -> serde_json::Map<String, Value> {
-    fn metadatum_to_value(m: &Metadatum) -> Value {
-        match m {
-            Metadatum::Int(int_value) => {
-                Value::String(int_value.to_string())
-            },
-            Metadatum::Bytes(bytes) => Value::String(hex::encode(bytes.as_slice())),
-            Metadatum::Text(text) => Value::String(text.clone()),
-            Metadatum::Array(array) => {
-                let json_array: Vec<Value> = array.iter().map(metadatum_to_value).collect();
-                Value::Array(json_array)
-            },
-            Metadatum::Map(kv_pairs) => {
-                let json_object = kv_pairs_to_hashmap(kv_pairs);
-                Value::Object(json_object)
-            },
+/// Labels scanned when the config doesn't set `metadata_labels`
+const DEFAULT_METADATA_LABELS: [u64; 3] = [CIP25_META_NFT, U_20_META_TOKEN, CIP27_META_ROYALTIES];
 
-        }
+fn is_known_label(label: u64) -> bool {
+    DEFAULT_METADATA_LABELS.contains(&label)
+}
 
+/// Bytes of the hex policy id the fingerprint is derived from, `None` with a
+/// warning if it isn't valid hex: the asset would get the fingerprint of an
+/// empty policy
+fn policy_id_raw(policy_id: &str) -> Option<Vec<u8>> {
+    match hex::decode(policy_id) {
+        Ok(x) => Some(x),
+        Err(err) => {
+            log::warn!(
+                "skipping asset of policy {}, invalid policy id: {}",
+                policy_id,
+                err
+            );
+            None
+        }
     }
+}
 
-    let mut hashmap = serde_json::Map::new();
-    for (key, value) in kv_pairs.deref() {
-        if let Metadatum::Text(key_str) = key {
-            hashmap.insert(key_str.clone(), metadatum_to_value(value));
+/// Version of the CIP-25 map, from its `version` entry (1 when absent)
+///
+/// Version 2 keys policies and asset names by their raw bytes instead of
+/// their hex / utf-8 text.
+fn cip25_version(metadata: &Metadatum) -> u64 {
+    if let Metadatum::Map(kv) = metadata {
+        for (key, value) in kv.iter() {
+            if let (Metadatum::Text(key), Metadatum::Int(version)) = (key, value) {
+                if key == "version" {
+                    return version.to_string().parse().unwrap_or(1);
+                }
+            }
         }
-
     }
 
-    hashmap
+    1
 }
 
 impl Reducer {
+    fn metadata_labels(&self) -> &[u64] {
+        self.config.metadata_labels.as_deref().unwrap_or(&DEFAULT_METADATA_LABELS)
+    }
+
     fn find_metadata_policy_assets(&self, metadata: &Metadatum, target_policy_id: &str) -> Option<KeyValuePairs<Metadatum, Metadatum>> {
+        let version = cip25_version(metadata);
+
         if let Metadatum::Map(kv) = metadata {
             for (policy_label, policy_contents) in kv.iter() {
-                if let Metadatum::Text(policy_label) = policy_label {
-                    if policy_label == target_policy_id {
-                        if let Metadatum::Map(policy_inner_map) = policy_contents {
-                            return Some(policy_inner_map.clone());
-                        }
-
+                let matches = match (version, policy_label) {
+                    (2, Metadatum::Bytes(policy_label)) => hex::encode(policy_label.as_slice()) == target_policy_id,
+                    (_, Metadatum::Text(policy_label)) => policy_label == target_policy_id,
+                    _ => false,
+                };
+
+                if matches {
+                    if let Metadatum::Map(policy_inner_map) = policy_contents {
+                        return Some(policy_inner_map.clone());
                     }
 
                 }
@@ -104,18 +129,8 @@ impl Reducer {
         None
     }
 
-    fn asset_fingerprint(&self, data_list: [&str; 2]) -> Result<String, bech32::Error> {
-        let combined_parts = data_list.join("");
-        let raw = hex::decode(combined_parts).unwrap();
-
-        let mut hasher = Blake2bVar::new(20).unwrap();
-        hasher.update(&raw);
-        let mut buf = [0u8; 20];
-        hasher.finalize_variable(&mut buf).unwrap();
-        let base32_combined = buf.to_base32();
-        bech32::encode("asset", base32_combined, Variant::Bech32)
-    }
-
+    /// Text of the key of an asset entry, integer keys are matched by their
+    /// decimal text as `metadatum_to_value` writes them
     fn get_asset_label (&self, l: Metadatum) -> Result<String, &str> {
         match l {
             Metadatum::Text(l) => Ok(l),
@@ -167,11 +182,18 @@ impl Reducer {
         asset_name_str: String,
         slot_no: u64
     ) {
-        let prefix = self.config.key_prefix.as_deref().unwrap_or("m");
+        let prefix = crosscut::keys::Prefix::new(self.config.key_prefix.as_deref(), self.config.key_separator.as_deref()).or("m");
         let projection = self.config.projection.unwrap_or_default();
         let should_keep_asset_index = self.config.policy_asset_index.unwrap_or(false);
         let should_keep_historical_metadata = self.config.historical_metadata.unwrap_or(false);
         let should_store_royalty_metadata = self.config.royalty_metadata.unwrap_or(true);
+        let should_index_raw_fingerprint = self.config.raw_fingerprint_index.unwrap_or(false);
+        let should_key_by_policy_asset_name = self.config.policy_asset_name_key.unwrap_or(false);
+
+        let policy_id_raw = match policy_id_raw(&policy_id_str) {
+            Some(x) => x,
+            None => return,
+        };
 
         if let Some(policy_assets) = self.find_metadata_policy_assets(&policy_map, &policy_id_str) {
             let filtered_policy_assets = policy_assets.iter().find(|(l, _)| {
@@ -180,7 +202,7 @@ impl Reducer {
             });
 
             if let Some((_, Metadatum::Map(asset_metadata))) = filtered_policy_assets {
-                if let Ok(fingerprint_str) = self.asset_fingerprint([&policy_id_str.clone(), hex::encode(&asset_name_str).as_str()]) {
+                if let Ok(fingerprint_str) = crosscut::assets::asset_fingerprint(&policy_id_raw, asset_name_str.as_bytes()) {
                     let timestamp = self.time.slot_to_wallclock(slot_no);
                     let metadata_final: Metadata = self.get_wrapped_metadata_fragment(cip, asset_name_str.clone(), policy_id_str.clone(), asset_metadata);
 
@@ -202,37 +224,70 @@ impl Reducer {
 
                         if should_store_royalty_metadata && cip == CIP27_META_ROYALTIES {
                             minted_a.push(model::CRDTCommand::LastWriteWins(
-                                format!("{}.{}.{}", prefix, "r", policy_id_str.clone()),
+                                prefix.key(&["r", &policy_id_str]),
                                 meta_payload.clone().into(),
                                 timestamp,
                             ));
 
                         }
 
-                        if should_keep_historical_metadata {
-                            minted_a.push(model::CRDTCommand::LastWriteWins(
-                                format!("{}.{}", prefix, fingerprint_str.clone()),
-                                meta_payload.clone().into(),
-                                timestamp,
-                            ));
+                        let mut meta_keys = vec![prefix.key(&[&fingerprint_str])];
+
+                        if should_key_by_policy_asset_name {
+                            meta_keys.push(prefix.key(&[&policy_id_str, &hex::encode(asset_name_str.as_bytes())]));
+                        }
+
+                        for meta_key in meta_keys {
+                            if should_keep_historical_metadata {
+                                minted_a.push(model::CRDTCommand::LastWriteWins(
+                                    meta_key,
+                                    meta_payload.clone().into(),
+                                    timestamp,
+                                ));
+
+                            } else {
+                                minted_a.push(model::CRDTCommand::AnyWriteWins(
+                                    meta_key,
+                                    model::Value::String(meta_payload.clone()),
+                                ));
+
+                            };
+
+                        }
+
+                        if should_index_raw_fingerprint {
+                            let raw_hash = crosscut::assets::fingerprint_hash(&policy_id_raw, asset_name_str.as_bytes());
 
-                        } else {
                             minted_a.push(model::CRDTCommand::AnyWriteWins(
-                                format!("{}.{}", prefix, fingerprint_str.clone()),
-                                model::Value::String(meta_payload.clone()),
+                                prefix.key(&["h", &hex::encode(raw_hash)]),
+                                fingerprint_str.clone().into(),
                             ));
 
-                        };
+                        }
 
                         if should_keep_asset_index {
                             minted_a.push(model::CRDTCommand::LastWriteWins(
-                                format!("{}.{}", prefix, policy_id_str),
+                                prefix.key(&[&policy_id_str]),
                                 fingerprint_str.clone().into(),
                                 timestamp,
                             ));
 
                         }
 
+                        if let (Some(pinner), CIP25_META_NFT) = (&self.pinner, cip) {
+                            for uri in crosscut::pinning::media_uris(asset_metadata) {
+                                let status = pinner.pin(&uri);
+
+                                minted_a.push(model::CRDTCommand::HashSetValue(
+                                    prefix.key(&["pin", &fingerprint_str]),
+                                    uri,
+                                    status.as_str().to_string().into(),
+                                ));
+
+                            }
+
+                        }
+
                     }
 
                 }
@@ -243,6 +298,64 @@ impl Reducer {
 
     }
 
+    /// Json of a label outside of the known standards, stored under
+    /// `{prefix}.{label}.{fingerprint}`
+    ///
+    /// Labels following the CIP-25 policy / asset layout are narrowed down to
+    /// the entry of the asset, other layouts are kept whole.
+    fn prepare_generic_meta_cmds(
+        &self,
+        label: u64,
+        minted_assets_unique: &mut HashMap<String, Vec<model::CRDTCommand>>,
+        label_map: &Metadatum,
+        policy_id_str: String,
+        asset_name_str: String,
+        slot_no: u64
+    ) {
+        let prefix = crosscut::keys::Prefix::new(self.config.key_prefix.as_deref(), self.config.key_separator.as_deref()).or("m");
+
+        let policy_id_raw = match policy_id_raw(&policy_id_str) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let fingerprint_str = match crosscut::assets::asset_fingerprint(&policy_id_raw, asset_name_str.as_bytes()) {
+            Ok(x) => x,
+            Err(_) => return,
+        };
+
+        let asset_metadata = self.find_metadata_policy_assets(label_map, &policy_id_str).and_then(|policy_assets| {
+            policy_assets.iter().find_map(|(l, contents)| match (self.get_asset_label(l.clone()), contents) {
+                (Ok(l), Metadatum::Map(asset_metadata)) if l == asset_name_str => Some(asset_metadata.clone()),
+                _ => None,
+            })
+        });
+
+        let meta_payload = match asset_metadata {
+            Some(asset_metadata) => self.get_metadata_fragment(asset_name_str, policy_id_str, &asset_metadata, label),
+            None => {
+                let mut label_wrap_map = serde_json::Map::new();
+                label_wrap_map.insert(label.to_string(), metadatum_to_value(label_map));
+                Value::Object(label_wrap_map).to_string()
+            },
+        };
+
+        let key = prefix.key(&[&label.to_string(), &fingerprint_str]);
+        let minted_a = minted_assets_unique.entry(fingerprint_str).or_default();
+
+        if self.config.historical_metadata.unwrap_or(false) {
+            minted_a.push(model::CRDTCommand::LastWriteWins(
+                key,
+                meta_payload.into(),
+                self.time.slot_to_wallclock(slot_no),
+            ));
+
+        } else {
+            minted_a.push(model::CRDTCommand::AnyWriteWins(key, model::Value::String(meta_payload)));
+        }
+
+    }
+
     fn send(
         &mut self,
         block: &MultiEraBlock,
@@ -262,16 +375,29 @@ impl Reducer {
                     if let Ok(asset_name_str) = String::from_utf8(asset_name.to_vec()) {
                         if !policy_id_str.is_empty() {
                             let metadata = tx.metadata();
-                            for supported_metadata_cip in vec![CIP25_META_NFT, U_20_META_TOKEN, CIP27_META_ROYALTIES] {
-                                if let Some(policy_map) = metadata.find(MetadatumLabel::from(supported_metadata_cip)) {
-                                    self.prepare_meta_agg_cmds(
-                                        supported_metadata_cip,
-                                        &mut minted_assets_unique,
-                                        policy_map,
-                                        policy_id_str.to_owned(),
-                                        asset_name_str.to_owned(),
-                                        block.slot().to_owned(),
-                                    );
+                            for &label in self.metadata_labels() {
+                                if let Some(policy_map) = metadata.find(MetadatumLabel::from(label)) {
+                                    if is_known_label(label) {
+                                        self.prepare_meta_agg_cmds(
+                                            label,
+                                            &mut minted_assets_unique,
+                                            policy_map,
+                                            policy_id_str.to_owned(),
+                                            asset_name_str.to_owned(),
+                                            block.slot().to_owned(),
+                                        );
+
+                                    } else {
+                                        self.prepare_generic_meta_cmds(
+                                            label,
+                                            &mut minted_assets_unique,
+                                            policy_map,
+                                            policy_id_str.to_owned(),
+                                            asset_name_str.to_owned(),
+                                            block.slot().to_owned(),
+                                        );
+
+                                    }
 
                                 }
 
@@ -300,13 +426,14 @@ impl Reducer {
     pub fn reduce_block<'b>(
         &mut self,
         block: &'b MultiEraBlock<'b>,
+        txs: &[&MultiEraTx],
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        for tx in &block.txs() {
-            // Make sure the TX is worth processing for the use-case (metadata extraction). It should have minted at least one asset with the CIP25_META key present in metadata.
+        for tx in txs {
+            // Make sure the TX is worth processing for the use-case (metadata extraction). It should have minted at least one asset with one of the scanned labels present in metadata.
             // Currently this will send thru a TX that is just a burn with no mint, but it will be handled in the reducer.
             // Todo: could be cleaner using a filter
-            if tx.mint().len() > 0 && tx.metadata().as_alonzo().iter().any(|meta| meta.iter().any(|(key, _)| *key == U_20_META_TOKEN || *key == CIP25_META_NFT || *key == CIP27_META_ROYALTIES)) {
+            if tx.mint().len() > 0 && tx.metadata().as_alonzo().iter().any(|meta| meta.iter().any(|(key, _)| self.metadata_labels().iter().any(|label| *key == *label))) {
                 self.send(block, tx, output)?;
             }
 
@@ -319,17 +446,272 @@ impl Reducer {
 
 impl Config {
     pub fn plugin(
-        self,
+        mut self,
         chain: &crosscut::ChainWellKnownInfo,
         policy: &crosscut::policies::RuntimePolicy,
     ) -> super::Reducer {
+        let pinner = self.pin_media.take().map(|x| x.pinner());
+
         let worker = Reducer {
             config: self,
             policy: policy.clone(),
             time: crosscut::time::NaiveProvider::new(chain.clone()),
+            pinner,
         };
 
         super::Reducer::AssetMetadata(worker)
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use pallas::codec::minicbor;
+    use pallas::codec::utils::KeyValuePairs;
+    use pallas::ledger::primitives::alonzo::Metadatum;
+
+    use super::{cip25_version, Config, Reducer, CIP25_META_NFT};
+    use crate::crosscut::pinning::{PinStatus, Pinner};
+    use crate::crosscut::{self, time::NaiveProvider, ChainWellKnownInfo};
+    use crate::model::{CRDTCommand, Value};
+
+    const POLICY: &str = "d5e6bf0500378d4f0da4e8dde6becec7621cd8cbf5cbb9b87013d4cc";
+
+    // asset entry with a supply past i64: { "supply": 2^64 - 1 }
+    const BIG_SUPPLY: &str = "a166737570706c791bffffffffffffffff";
+
+    // 721 map of a v2 mint: { h'd5e6..d4cc': { h'SpaceBud1': { "name": "SpaceBud #1" } }, "version": 2 }
+    const CIP25_V2: &str = "a2581cd5e6bf0500378d4f0da4e8dde6becec7621cd8cbf5cbb9b87013d4cca149537061636542756431a1646e616d656b53706163654275642023316776657273696f6e02";
+
+    // 721 map of a v1 mint with media: { "d5e6..d4cc": { "SpaceBud1": {
+    // "name": "SpaceBud #1", "image": ["ipfs://QmRh..VFw", "/1.png"],
+    // "files": [{ "src": "ar://bXlfbWVkaWE", .. }, { "src": "data:..", .. }] } } }
+    const CIP25_MEDIA: &str = "a178386435653662663035303033373864346630646134653864646536626563656337363231636438636266356362623962383730313364346363a169537061636542756431a3646e616d656b537061636542756420233165696d616765827835697066733a2f2f516d5268545462557250594577336d4a4747685171515354396b38367631445042695454574a474b444a73564677662f312e706e676566696c657382a2637372637061723a2f2f62586c666257566b615745696d656469615479706569696d6167652f706e67a2637372637822646174613a696d6167652f7376672b786d6c3b6261736536342c50484e325a7a343d696d65646961547970656d696d6167652f7376672b786d6c";
+
+    struct RecordingPinner(Arc<Mutex<Vec<String>>>);
+
+    impl Pinner for RecordingPinner {
+        fn pin(&self, uri: &str) -> PinStatus {
+            self.0.lock().unwrap().push(uri.to_string());
+            PinStatus::Queued
+        }
+    }
+
+    fn reducer(metadata_labels: Option<Vec<u64>>) -> Reducer {
+        Reducer {
+            config: Config {
+                key_prefix: None,
+                key_separator: None,
+                historical_metadata: None,
+                policy_asset_index: None,
+                royalty_metadata: None,
+                raw_fingerprint_index: None,
+                policy_asset_name_key: None,
+                projection: None,
+                filter: None,
+                metadata_labels,
+                pin_media: None,
+            },
+            policy: Default::default(),
+            time: NaiveProvider::new(ChainWellKnownInfo::mainnet()),
+            pinner: None,
+        }
+    }
+
+    #[test]
+    fn bytes_keyed_v2_assets_are_indexed() {
+        let metadata: Metadatum = minicbor::decode(&hex::decode(CIP25_V2).unwrap()).unwrap();
+        assert_eq!(cip25_version(&metadata), 2);
+
+        let reducer = reducer(None);
+        let mut minted = HashMap::new();
+
+        reducer.prepare_meta_agg_cmds(
+            CIP25_META_NFT,
+            &mut minted,
+            &metadata,
+            POLICY.to_string(),
+            "SpaceBud1".to_string(),
+            0,
+        );
+
+        let fingerprint =
+            crosscut::assets::asset_fingerprint(&hex::decode(POLICY).unwrap(), b"SpaceBud1").unwrap();
+
+        match minted.get(&fingerprint).map(|x| &x[0]) {
+            Some(CRDTCommand::AnyWriteWins(key, Value::String(json))) => {
+                assert_eq!(*key, format!("m.{}", fingerprint));
+
+                // normalized to the text-keyed v1 layout
+                assert!(json.contains(POLICY));
+                assert!(json.contains("SpaceBud #1"));
+            }
+            x => panic!("expected the asset to be indexed, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn metadata_is_also_keyed_by_policy_and_name() {
+        let metadata: Metadatum = minicbor::decode(&hex::decode(CIP25_V2).unwrap()).unwrap();
+
+        let mut reducer = reducer(None);
+        reducer.config.policy_asset_name_key = Some(true);
+
+        let mut minted = HashMap::new();
+
+        reducer.prepare_meta_agg_cmds(
+            CIP25_META_NFT,
+            &mut minted,
+            &metadata,
+            POLICY.to_string(),
+            "SpaceBud1".to_string(),
+            0,
+        );
+
+        let fingerprint =
+            crosscut::assets::asset_fingerprint(&hex::decode(POLICY).unwrap(), b"SpaceBud1").unwrap();
+
+        let writes: Vec<_> = minted[&fingerprint]
+            .iter()
+            .map(|x| match x {
+                CRDTCommand::AnyWriteWins(key, Value::String(json)) => (key.clone(), json.clone()),
+                x => panic!("unexpected command {:?}", x),
+            })
+            .collect();
+
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].0, format!("m.{}", fingerprint));
+        assert_eq!(writes[1].0, format!("m.{}.{}", POLICY, hex::encode("SpaceBud1")));
+        assert_eq!(writes[0].1, writes[1].1);
+    }
+
+    #[test]
+    fn media_uris_are_handed_to_the_pinner() {
+        let metadata: Metadatum = minicbor::decode(&hex::decode(CIP25_MEDIA).unwrap()).unwrap();
+
+        let pinned = Arc::new(Mutex::new(vec![]));
+        let mut reducer = reducer(None);
+        reducer.pinner = Some(Box::new(RecordingPinner(pinned.clone())));
+
+        let mut minted = HashMap::new();
+
+        reducer.prepare_meta_agg_cmds(
+            CIP25_META_NFT,
+            &mut minted,
+            &metadata,
+            POLICY.to_string(),
+            "SpaceBud1".to_string(),
+            0,
+        );
+
+        // the chunked image is joined, the inline svg isn't worth pinning
+        let expected = vec![
+            "ipfs://QmRhTTbUrPYEw3mJGGhQqQST9k86v1DPBiTTWJGKDJsVFw/1.png".to_string(),
+            "ar://bXlfbWVkaWE".to_string(),
+        ];
+
+        assert_eq!(*pinned.lock().unwrap(), expected);
+
+        let fingerprint =
+            crosscut::assets::asset_fingerprint(&hex::decode(POLICY).unwrap(), b"SpaceBud1").unwrap();
+
+        let pins: Vec<_> = minted[&fingerprint]
+            .iter()
+            .filter_map(|x| match x {
+                CRDTCommand::HashSetValue(key, uri, Value::String(status)) => {
+                    assert_eq!(*key, format!("m.pin.{}", fingerprint));
+                    assert_eq!(status, "queued");
+                    Some(uri.clone())
+                }
+                _ => None,
+            })
+            .collect();
+
+        assert_eq!(pins, expected);
+    }
+
+    #[test]
+    fn big_ints_are_projected_as_strings() {
+        let reducer = reducer(None);
+
+        let asset: Metadatum = minicbor::decode(&hex::decode(BIG_SUPPLY).unwrap()).unwrap();
+        let asset = match asset {
+            Metadatum::Map(x) => x,
+            _ => unreachable!(),
+        };
+
+        let json = reducer.get_metadata_fragment("Bud".into(), POLICY.into(), &asset, CIP25_META_NFT);
+        assert!(json.contains(r#""supply":"18446744073709551615""#));
+
+        let label = match minicbor::decode::<Metadatum>(&hex::decode("3bffffffffffffffff").unwrap()).unwrap() {
+            x @ Metadatum::Int(_) => reducer.get_asset_label(x),
+            _ => unreachable!(),
+        };
+        assert_eq!(label, Ok("-18446744073709551616".to_string()));
+    }
+
+    #[test]
+    fn custom_labels_are_kept_whole() {
+        let reducer = reducer(Some(vec![CIP25_META_NFT, 1000]));
+        assert_eq!(reducer.metadata_labels(), &[CIP25_META_NFT, 1000]);
+
+        // a custom standard that doesn't follow the policy / asset layout
+        let metadata = Metadatum::Map(KeyValuePairs::from(vec![(
+            Metadatum::Text("standard".into()),
+            Metadatum::Text("custom".into()),
+        )]));
+
+        let mut minted = HashMap::new();
+
+        reducer.prepare_generic_meta_cmds(
+            1000,
+            &mut minted,
+            &metadata,
+            POLICY.to_string(),
+            "SpaceBud1".to_string(),
+            0,
+        );
+
+        let fingerprint =
+            crosscut::assets::asset_fingerprint(&hex::decode(POLICY).unwrap(), b"SpaceBud1").unwrap();
+
+        match minted.get(&fingerprint).map(|x| &x[0]) {
+            Some(CRDTCommand::AnyWriteWins(key, Value::String(json))) => {
+                assert_eq!(*key, format!("m.1000.{}", fingerprint));
+                assert_eq!(json, r#"{"1000":{"standard":"custom"}}"#);
+            }
+            x => panic!("expected the label to be indexed, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn invalid_policy_ids_are_skipped() {
+        let reducer = reducer(Some(vec![CIP25_META_NFT, 1000]));
+        let metadata: Metadatum = minicbor::decode(&hex::decode(CIP25_V2).unwrap()).unwrap();
+
+        let mut minted = HashMap::new();
+
+        reducer.prepare_meta_agg_cmds(
+            CIP25_META_NFT,
+            &mut minted,
+            &metadata,
+            "not hex".to_string(),
+            "SpaceBud1".to_string(),
+            0,
+        );
+
+        reducer.prepare_generic_meta_cmds(
+            1000,
+            &mut minted,
+            &metadata,
+            "not hex".to_string(),
+            "SpaceBud1".to_string(),
+            0,
+        );
+
+        assert!(minted.is_empty());
+    }
+}
@@ -0,0 +1,333 @@
+use std::collections::BTreeMap;
+
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraOutput, MultiEraTx};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::journal::Journal;
+use crate::{crosscut, model, prelude::*};
+
+/// Number of transfers kept per asset when the config doesn't say
+const DEFAULT_LIMIT: usize = 1000;
+
+/// Net change over a tx of the holdings of each address, by asset
+/// fingerprint. The supply of the asset is kept under `None`: it gives out
+/// what's minted and takes what's burnt.
+type TxOffsets = BTreeMap<String, BTreeMap<Option<String>, i128>>;
+
+/// A quantity of an asset moved from one holder to another, `None` being
+/// the supply (a mint or a burn)
+type Transfer = (Option<String>, Option<String>, i128);
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Number of transfers kept in the list of each asset, defaults to 1000
+    pub limit: Option<usize>,
+
+    /// Number of blocks whose transfers can be popped on rollback, defaults
+    /// to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    journal: Journal,
+}
+
+/// Matches what the senders gave with what the receivers got, in order
+///
+/// A tx only says how much each address gave or got, not who paid whom: when
+/// it has several senders and receivers, the quantities are paired greedily,
+/// which yields the fewest transfers that add up to the same changes.
+fn pair_transfers(offsets: &BTreeMap<Option<String>, i128>) -> Vec<Transfer> {
+    let mut senders: Vec<_> = offsets
+        .iter()
+        .filter(|(_, x)| **x < 0)
+        .map(|(holder, x)| (holder.clone(), -x))
+        .collect();
+
+    let mut receivers: Vec<_> = offsets
+        .iter()
+        .filter(|(_, x)| **x > 0)
+        .map(|(holder, x)| (holder.clone(), *x))
+        .collect();
+
+    let mut transfers = vec![];
+    let (mut i, mut j) = (0, 0);
+
+    // whatever is left on one side belongs to outputs we couldn't resolve
+    while i < senders.len() && j < receivers.len() {
+        let quantity = senders[i].1.min(receivers[j].1);
+
+        transfers.push((senders[i].0.clone(), receivers[j].0.clone(), quantity));
+
+        senders[i].1 -= quantity;
+        receivers[j].1 -= quantity;
+
+        if senders[i].1 == 0 {
+            i += 1;
+        }
+
+        if receivers[j].1 == 0 {
+            j += 1;
+        }
+    }
+
+    transfers
+}
+
+impl Reducer {
+    fn add_offsets(
+        &self,
+        utxo: &MultiEraOutput,
+        sign: i128,
+        offsets: &mut TxOffsets,
+    ) -> Result<(), gasket::error::Error> {
+        let holder = match self.addresses.key(utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        for asset in utxo.non_ada_assets() {
+            if let Asset::NativeAsset(policy_id, name, quantity) = asset {
+                let fingerprint =
+                    crosscut::assets::asset_fingerprint(&policy_id[..], &name).or_panic()?;
+
+                *offsets
+                    .entry(fingerprint)
+                    .or_default()
+                    .entry(Some(holder.clone()))
+                    .or_default() += sign * quantity as i128;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Asset changes of the tx: what its inputs held, what its outputs hold
+    /// and what it minted or burnt
+    fn tx_offsets(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<TxOffsets, gasket::error::Error> {
+        let mut offsets = TxOffsets::new();
+
+        for input in tx.consumes() {
+            let utxo = ctx
+                .find_utxo(&input.output_ref())
+                .apply_policy(&self.policy)
+                .or_panic()?;
+
+            if let Some(utxo) = utxo {
+                self.add_offsets(&utxo, -1, &mut offsets)?;
+            }
+        }
+
+        for (_, produced) in tx.produces() {
+            self.add_offsets(&produced, 1, &mut offsets)?;
+        }
+
+        if let Some(mints) = tx.mint().as_alonzo() {
+            for (policy, assets) in mints.iter() {
+                for (name, amount) in assets.iter() {
+                    let fingerprint =
+                        crosscut::assets::asset_fingerprint(&policy[..], &name[..]).or_panic()?;
+
+                    *offsets
+                        .entry(fingerprint)
+                        .or_default()
+                        .entry(None)
+                        .or_default() -= *amount as i128;
+                }
+            }
+        }
+
+        Ok(offsets)
+    }
+
+    /// Keeps a log of the transfers of each asset, newest first, under
+    /// `{prefix}.{fingerprint}`
+    ///
+    /// Each transfer is a json document with the sending and receiving
+    /// addresses (null for a mint or a burn), the fingerprint, the quantity,
+    /// the tx hash and the slot. Streaming storages (Kafka, webhooks) get
+    /// one command per transfer, in chain order. On rollback, the transfers
+    /// of the undone blocks are popped from their lists, newest first, and
+    /// stream consumers get a `list_pop` of the asset for each of them.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("asset_transfers");
+        let limit = self.config.limit.unwrap_or(DEFAULT_LIMIT).max(1);
+        let mut undo = vec![];
+
+        for tx in block.txs().iter() {
+            let tx_hash = tx.hash().to_string();
+
+            for (fingerprint, offsets) in self.tx_offsets(tx, ctx)? {
+                for (from, to, quantity) in pair_transfers(&offsets) {
+                    let event = json!({
+                        "from": from,
+                        "to": to,
+                        "fingerprint": fingerprint,
+                        "quantity": quantity as u64,
+                        "tx": tx_hash,
+                        "slot": block.slot(),
+                    });
+
+                    let key = prefix.key(&[&fingerprint]);
+                    undo.push(model::CRDTCommand::ListPop(key.clone()));
+
+                    let crdt = model::CRDTCommand::ListPrepend(key, event.into(), limit);
+
+                    output.send(gasket::messaging::Message::from(crdt))?;
+                }
+            }
+        }
+
+        undo.reverse();
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Pops the transfers of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            journal,
+        };
+
+        super::Reducer::AssetTransfers(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::{pair_transfers, Config};
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn transfers_add_up_to_the_changes() {
+        let holder = |x: &str| Some(x.to_string());
+
+        // a pays 10 and b pays 5, c gets 12 and 3 are burnt
+        let offsets = BTreeMap::from([
+            (holder("a"), -10),
+            (holder("b"), -5),
+            (holder("c"), 12),
+            (None, 3),
+        ]);
+
+        assert_eq!(
+            pair_transfers(&offsets),
+            vec![
+                (holder("a"), None, 3),
+                (holder("a"), holder("c"), 7),
+                (holder("b"), holder("c"), 5),
+            ]
+        );
+
+        // a mint shows up as a transfer from the supply
+        let offsets = BTreeMap::from([(None, -100), (holder("a"), 100)]);
+        assert_eq!(pair_transfers(&offsets), vec![(None, holder("a"), 100)]);
+
+        // an address that spends and gets back the same isn't a transfer
+        let offsets = BTreeMap::from([(holder("a"), 0)]);
+        assert!(pair_transfers(&offsets).is_empty());
+    }
+
+    #[test]
+    fn rollback_pops_transfers_of_undone_blocks() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 1000);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            limit: None,
+            rollback_window: None,
+        };
+
+        // without the consumed outputs, only the mint of the block is a
+        // transfer
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = match config.plugin(&policy) {
+            Reducer::AssetTransfers(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer
+            .reduce_block(&block, &BlockContext::default(), &mut output)
+            .unwrap();
+
+        let prepended: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .filter_map(|x| match x.payload {
+                CRDTCommand::ListPrepend(key, _, _) => Some(key),
+                _ => None,
+            })
+            .collect();
+
+        assert!(!prepended.is_empty());
+
+        assert!(reducer.covers_rollback(&before));
+
+        let popped: Vec<_> = reducer
+            .roll_back(&before)
+            .into_iter()
+            .map(|x| match x {
+                CRDTCommand::ListPop(key) => key,
+                x => panic!("unexpected undo command {:?}", x),
+            })
+            .collect();
+
+        assert_eq!(popped, prepended.into_iter().rev().collect::<Vec<_>>());
+    }
+}
@@ -7,15 +7,30 @@ use crate::{crosscut, model, prelude::*};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Key by the bucket of the address, out of `2^bits` buckets, instead of
+    /// the address itself. Lossy, see `crosscut::buckets`.
+    pub address_bucket_bits: Option<u8>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 impl Reducer {
+    fn address_key(&self, address: &str) -> String {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("balance_by_address")
+        .key(&[address])
+    }
+
     fn process_consumed_txo(
         &mut self,
         ctx: &model::BlockContext,
@@ -29,13 +44,15 @@ impl Reducer {
             None => return Ok(()),
         };
 
-        let address = utxo.address().map(|addr| addr.to_string()).or_panic()?;
-
-        let key = match &self.config.key_prefix {
-            Some(prefix) => format!("{}.{}", prefix, address),
-            None => format!("{}.{}", "balance_by_address".to_string(), address),
+        let address = match self.addresses.key(&utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
         };
 
+        let address = crosscut::buckets::address_key(&address, self.config.address_bucket_bits);
+
+        let key = self.address_key(&address);
+
         let crdt = model::CRDTCommand::PNCounter(key, -1 * utxo.lovelace_amount() as i64);
 
         output.send(gasket::messaging::Message::from(crdt))?;
@@ -48,13 +65,15 @@ impl Reducer {
         tx_output: &MultiEraOutput,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let address = tx_output.address().map(|x| x.to_string()).or_panic()?;
-
-        let key = match &self.config.key_prefix {
-            Some(prefix) => format!("{}.{}", prefix, address),
-            None => format!("{}.{}", "balance_by_address".to_string(), address),
+        let address = match self.addresses.key(tx_output, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
         };
 
+        let address = crosscut::buckets::address_key(&address, self.config.address_bucket_bits);
+
+        let key = self.address_key(&address);
+
         let crdt = model::CRDTCommand::PNCounter(key, tx_output.lovelace_amount() as i64);
 
         output.send(gasket::messaging::Message::from(crdt))?;
@@ -89,6 +108,7 @@ impl Config {
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::BalanceByAddress(reducer)
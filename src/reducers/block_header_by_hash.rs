@@ -7,6 +7,7 @@ use crate::{crosscut, model};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
 }
 
@@ -29,7 +30,10 @@ impl Reducer {
                 .to_vec();
                 
             let crdt = model::CRDTCommand::any_write_wins(
-                self.config.key_prefix.as_deref(),
+                crosscut::keys::Prefix::new(
+                    self.config.key_prefix.as_deref(),
+                    self.config.key_separator.as_deref(),
+                ),
                 block.hash(),
                 value
             );
@@ -0,0 +1,300 @@
+use pallas::crypto::hash::Hasher;
+use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use crate::{crosscut, model, prelude::*};
+
+const DIGEST_RING_TREE: &str = "digest_ring";
+
+/// Digest of the chain before its first block
+const INITIAL_DIGEST: [u8; 32] = [0u8; 32];
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Path of the sled db where the digests of the recent blocks are kept,
+    /// to carry on after a restart and to go back on a rollback
+    pub db_path: String,
+
+    /// Number of recent digests kept to roll back to. Defaults to 2160.
+    pub rollback_window: Option<usize>,
+
+    /// Also keep the digest of every block whose height is a multiple of
+    /// this, under `{prefix}.checkpoint.{height}`
+    pub checkpoint_every: Option<u64>,
+}
+
+/// Folds the hash of the next block into the running digest
+fn fold(digest: &[u8; 32], block_hash: &[u8]) -> [u8; 32] {
+    let mut data = digest.to_vec();
+    data.extend_from_slice(block_hash);
+
+    *Hasher::<256>::hash(&data)
+}
+
+fn ring_key(slot: u64, hash: &[u8]) -> Vec<u8> {
+    // big-endian slot first so that the tree is sorted by chain order
+    let mut key = slot.to_be_bytes().to_vec();
+    key.extend_from_slice(hash);
+    key
+}
+
+fn ring_key_slot(key: &[u8]) -> u64 {
+    let mut slot = [0u8; 8];
+    slot.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(slot)
+}
+
+/// Digests of the recent blocks, by chain order, along with their height
+struct Digests {
+    ring: sled::Tree,
+    ring_len: usize,
+    window: usize,
+}
+
+impl Digests {
+    fn open(db: &sled::Db, window: usize) -> Result<Self, crate::Error> {
+        let ring = db
+            .open_tree(DIGEST_RING_TREE)
+            .map_err(crate::Error::storage)?;
+
+        Ok(Self {
+            ring_len: ring.len(),
+            ring,
+            // the latest digest is needed to carry on
+            window: window.max(1),
+        })
+    }
+
+    /// Height and digest of the latest block, none before the first one
+    fn last(&self) -> Result<Option<(u64, [u8; 32])>, crate::Error> {
+        let (_, value) = match self.ring.last().map_err(crate::Error::storage)? {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+        if value.len() != 40 {
+            return Err(crate::Error::storage("invalid digest entry"));
+        }
+
+        let mut height = [0u8; 8];
+        height.copy_from_slice(&value[..8]);
+
+        let mut digest = [0u8; 32];
+        digest.copy_from_slice(&value[8..]);
+
+        Ok(Some((u64::from_be_bytes(height), digest)))
+    }
+
+    fn push(
+        &mut self,
+        slot: u64,
+        hash: &[u8],
+        height: u64,
+        digest: &[u8; 32],
+    ) -> Result<(), crate::Error> {
+        let mut value = height.to_be_bytes().to_vec();
+        value.extend_from_slice(digest);
+
+        self.ring
+            .insert(ring_key(slot, hash), value)
+            .map_err(crate::Error::storage)?;
+
+        self.ring_len += 1;
+
+        while self.ring_len > self.window {
+            self.ring.pop_min().map_err(crate::Error::storage)?;
+            self.ring_len -= 1;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the digests of the blocks after the point, returns how many
+    ///
+    /// Fails without touching anything if the point is older than the blocks
+    /// kept in the ring, the digest at that point is lost.
+    fn undo(&mut self, point: &Point) -> Result<usize, crate::Error> {
+        if self.ring_len >= self.window {
+            let oldest = self.ring.first().map_err(crate::Error::storage)?;
+
+            let past = match (oldest, point) {
+                (None, _) => false,
+                (Some(_), Point::Origin) => true,
+                (Some((key, _)), Point::Specific(slot, _)) => ring_key_slot(&key) > *slot,
+            };
+
+            if past {
+                return Err(crate::Error::message(format!(
+                    "can't roll back the chain digest to {:?}, it's older than the rollback window",
+                    point
+                )));
+            }
+        }
+
+        let mut undone = 0;
+
+        while let Some((key, _)) = self.ring.last().map_err(crate::Error::storage)? {
+            let keep = match point {
+                Point::Origin => false,
+                Point::Specific(slot, _) => ring_key_slot(&key) <= *slot,
+            };
+
+            if keep {
+                break;
+            }
+
+            self.ring.remove(key).map_err(crate::Error::storage)?;
+            undone += 1;
+        }
+
+        self.ring_len = self.ring_len.saturating_sub(undone);
+
+        Ok(undone)
+    }
+}
+
+pub struct Reducer {
+    config: Config,
+    digests: Option<Digests>,
+}
+
+impl Reducer {
+    /// Opens the digests db on first use
+    fn digests(&mut self) -> Result<&mut Digests, crate::Error> {
+        if self.digests.is_none() {
+            let db = sled::open(&self.config.db_path).map_err(crate::Error::storage)?;
+            let window = self.config.rollback_window.unwrap_or(2160);
+
+            self.digests = Some(Digests::open(&db, window)?);
+        }
+
+        Ok(self.digests.as_mut().unwrap())
+    }
+
+    /// Folds the hash of each block into a running digest, kept along with
+    /// the point and height under `{prefix}.digest`
+    ///
+    /// Instances that start at the same block and reduce the same chain end
+    /// up with the same digest at every point, which checkpoints make easy to
+    /// compare. Every block has to go through: sampling (`sample_every`) or
+    /// blocks skipped by the policy make the digest diverge from the one of
+    /// other instances.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let digests = self.digests().or_retry()?;
+
+        let previous = digests
+            .last()
+            .or_panic()?
+            .map(|(_, digest)| digest)
+            .unwrap_or(INITIAL_DIGEST);
+
+        let hash = block.hash();
+        let height = block.number();
+        let digest = fold(&previous, &hash[..]);
+
+        digests
+            .push(block.slot(), &hash[..], height, &digest)
+            .or_panic()?;
+
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("chain_digest");
+
+        let key = prefix.key(&["digest"]);
+        let digest = hex::encode(digest);
+
+        let mut crdts = vec![
+            model::CRDTCommand::HashSetValue(key.clone(), "digest".into(), digest.clone().into()),
+            model::CRDTCommand::HashSetValue(
+                key.clone(),
+                "height".into(),
+                model::Value::BigInt(height as i128),
+            ),
+            model::CRDTCommand::HashSetValue(
+                key.clone(),
+                "slot".into(),
+                model::Value::BigInt(block.slot() as i128),
+            ),
+            model::CRDTCommand::HashSetValue(key, "hash".into(), hash.to_string().into()),
+        ];
+
+        if matches!(self.config.checkpoint_every, Some(n) if n > 0 && height % n == 0) {
+            crdts.push(model::CRDTCommand::AnyWriteWins(
+                prefix.key(&["checkpoint", &height.to_string()]),
+                digest.into(),
+            ));
+        }
+
+        for crdt in crdts {
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    /// Goes back to the digest as of the rollback point
+    ///
+    /// No command is emitted: the reducers stage only rolls back to the last
+    /// point it reduced, which happens after a restart, and the store never
+    /// committed the digests of the blocks past its cursor. The next block
+    /// overwrites them.
+    pub fn roll_back(&mut self, point: &Point) -> Result<(), crate::Error> {
+        let undone = self.digests()?.undo(point)?;
+        log::info!("dropped the chain digest of {} blocks", undone);
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            digests: None,
+        };
+
+        super::Reducer::ChainDigest(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::{fold, Digests, INITIAL_DIGEST};
+
+    #[test]
+    fn rollback_restores_previous_digest() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut digests = Digests::open(&db, 10).unwrap();
+
+        let a = fold(&INITIAL_DIGEST, &[1u8; 32]);
+        let b = fold(&a, &[2u8; 32]);
+
+        // the order of the blocks matters
+        assert_ne!(b, fold(&fold(&INITIAL_DIGEST, &[2u8; 32]), &[1u8; 32]));
+
+        digests.push(10, &[1u8; 32], 1, &a).unwrap();
+        digests.push(20, &[2u8; 32], 2, &b).unwrap();
+        assert_eq!(digests.last().unwrap(), Some((2, b)));
+
+        assert_eq!(
+            digests.undo(&Point::Specific(10, vec![1u8; 32])).unwrap(),
+            1
+        );
+        assert_eq!(digests.last().unwrap(), Some((1, a)));
+
+        // replaying the same block yields the same digest
+        assert_eq!(fold(&a, &[2u8; 32]), b);
+    }
+}
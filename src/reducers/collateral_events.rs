@@ -0,0 +1,402 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::journal::{inverse, Journal};
+use crate::{crosscut, model, prelude::*};
+
+/// Number of events kept per address when the config doesn't say
+const DEFAULT_LIMIT: usize = 100;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Number of events kept in the list of each address, defaults to 100
+    pub limit: Option<usize>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    journal: Journal,
+}
+
+/// Lovelace lost by each address providing collateral
+///
+/// The collateral return gives back part of it: it's deducted from the
+/// address it goes to first, then from the others in order when it exceeds
+/// what that address provided. Addresses getting everything back are left
+/// out.
+fn forfeited_by_address(
+    collateral: &[(String, u64)],
+    returned: Option<(String, u64)>,
+) -> BTreeMap<String, u64> {
+    let mut provided = BTreeMap::<String, u64>::new();
+
+    for (address, lovelace) in collateral {
+        *provided.entry(address.clone()).or_default() += lovelace;
+    }
+
+    if let Some((address, mut left)) = returned {
+        let order = std::iter::once(address.clone())
+            .chain(provided.keys().filter(|x| **x != address).cloned())
+            .collect::<Vec<_>>();
+
+        for key in order {
+            if let Some(lovelace) = provided.get_mut(&key) {
+                let deducted = left.min(*lovelace);
+                *lovelace -= deducted;
+                left -= deducted;
+            }
+        }
+    }
+
+    provided.retain(|_, x| *x > 0);
+    provided
+}
+
+impl Reducer {
+    fn resolve_collateral(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<Vec<(String, u64)>, gasket::error::Error> {
+        let mut collateral = vec![];
+
+        for input in tx.collateral() {
+            let utxo = ctx
+                .find_utxo(&input.output_ref())
+                .apply_policy(&self.policy)
+                .or_panic()?;
+
+            if let Some(utxo) = utxo {
+                if let Some(address) = self.addresses.key(&utxo, |x| x.to_string()) {
+                    collateral.push((address, utxo.lovelace_amount()));
+                }
+            }
+        }
+
+        Ok(collateral)
+    }
+
+    /// Scripts the tx failed to run: the ones locking its regular inputs and
+    /// its minting policies
+    fn failed_scripts(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<BTreeSet<String>, gasket::error::Error> {
+        let mut scripts = BTreeSet::new();
+
+        for input in tx.inputs() {
+            let utxo = ctx
+                .find_utxo(&input.output_ref())
+                .apply_policy(&self.policy)
+                .or_panic()?;
+
+            if let Some(address) = utxo.and_then(|x| self.addresses.address(&x)) {
+                scripts.extend(crosscut::addresses::script_hash(&address));
+            }
+        }
+
+        if let Some(mints) = tx.mint().as_alonzo() {
+            for (policy, _) in mints.iter() {
+                scripts.insert(policy.to_string());
+            }
+        }
+
+        Ok(scripts)
+    }
+
+    /// Indexes the collateral forfeited by the txs that failed phase-2
+    /// validation
+    ///
+    /// Each address losing collateral gets the lovelace added to the counter
+    /// `{prefix}.forfeited.{address}` and an event prepended to the list
+    /// `{prefix}.events.{address}`, newest first: a json document with the
+    /// address as `from`, a null `to`, the lovelace, the tx hash, the slot and
+    /// the scripts that failed. Each of those scripts gets the counter
+    /// `{prefix}.scripts.{script hash}` incremented.
+    ///
+    /// On rollback, the counters of the undone blocks are decremented and
+    /// their events popped from the lists, newest first.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        txs: &[&MultiEraTx],
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("collateral_events");
+        let limit = self.config.limit.unwrap_or(DEFAULT_LIMIT).max(1);
+        let mut undo = vec![];
+
+        for tx in txs.iter().filter(|tx| !tx.is_valid()) {
+            let collateral = self.resolve_collateral(tx, ctx)?;
+
+            let returned = tx.collateral_return().and_then(|x| {
+                let address = self.addresses.key(&x, |x| x.to_string())?;
+                Some((address, x.lovelace_amount()))
+            });
+
+            let scripts = self.failed_scripts(tx, ctx)?;
+            let tx_hash = tx.hash().to_string();
+
+            for (address, lovelace) in forfeited_by_address(&collateral, returned) {
+                let event = json!({
+                    "from": address,
+                    "to": null,
+                    "lovelace": lovelace,
+                    "tx": tx_hash,
+                    "slot": block.slot(),
+                    "scripts": scripts,
+                });
+
+                let events = prefix.key(&["events", &address]);
+
+                let crdts = [
+                    model::CRDTCommand::PNCounter(
+                        prefix.key(&["forfeited", &address]),
+                        lovelace as i64,
+                    ),
+                    model::CRDTCommand::ListPrepend(events.clone(), event.into(), limit),
+                ];
+
+                for crdt in crdts {
+                    undo.extend(inverse(&crdt));
+                    output.send(gasket::messaging::Message::from(crdt))?;
+                }
+
+                undo.push(model::CRDTCommand::ListPop(events));
+            }
+
+            for script in scripts.iter() {
+                let crdt = model::CRDTCommand::PNCounter(prefix.key(&["scripts", script]), 1);
+                undo.extend(inverse(&crdt));
+                output.send(gasket::messaging::Message::from(crdt))?;
+            }
+        }
+
+        undo.reverse();
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Decrements the counters and pops the events of the blocks past the
+    /// point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            journal,
+        };
+
+        super::Reducer::CollateralEvents(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use gasket::runtime::Worker as _;
+    use pallas::ledger::traverse::{Era, MultiEraBlock};
+    use pallas::network::miniprotocols::Point;
+
+    use super::{forfeited_by_address, Config, Reducer};
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::model::{self, CRDTCommand};
+    use crate::{enrich, reducers};
+
+    // the first tx of the test block, made invalid and given 3333..33#0 as
+    // collateral
+    fn invalid_tx_block() -> Vec<u8> {
+        let input = "4ddea0589dbb0bfa613c2c8fa974fba72d437cbb79f279c765a75a094462983b01";
+        let collateral = format!("0d81825820{}00", "33".repeat(32));
+
+        let block = include_str!("../../assets/test.block")
+            .replacen(
+                &format!("{}0d80", input),
+                &format!("{}{}", input, collateral),
+                1,
+            )
+            .strip_suffix("80")
+            .map(|x| format!("{}8100", x))
+            .unwrap();
+
+        hex::decode(block).unwrap()
+    }
+
+    /// Enriches the block through a sled db holding the collateral, an
+    /// enterprise address output of 5 ada, then reduces it
+    fn reduce_invalid_tx_block(name: &str) -> (Reducer, Point, Vec<CRDTCommand>) {
+        let dir = std::env::temp_dir().join(format!("scrolls-{}-{}", name, std::process::id()));
+
+        let config = enrich::sled::Config {
+            db_path: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+
+        let output = format!("82581d61{}1a004c4b40", "00".repeat(28));
+        let snapshot = format!(
+            "{}#0,{},{}",
+            "33".repeat(32),
+            u16::from(Era::Alonzo),
+            output
+        );
+        config.import_snapshot(snapshot.as_bytes()).unwrap();
+
+        // the regular inputs of the tx aren't in the db
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let (_, mut enrich) = config.boostrapper(&policy, None).into_inline();
+        enrich.bootstrap().unwrap();
+
+        let enriched = enrich
+            .enrich(model::RawBlockPayload::RollForward(invalid_tx_block()))
+            .unwrap()
+            .unwrap();
+
+        let (cbor, ctx) = match enriched.into_iter().next() {
+            Some(model::EnrichedBlockPayload::RollForward(cbor, ctx)) => (cbor, ctx),
+            _ => panic!("the block wasn't enriched"),
+        };
+
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        assert!(!block.txs()[0].is_valid());
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 100);
+        let mut output = reducers::dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            limit: None,
+            rollback_window: None,
+        };
+
+        let mut reducer = match config.plugin(&policy) {
+            reducers::Reducer::CollateralEvents(x) => x,
+            _ => unreachable!(),
+        };
+
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let txs = block.txs();
+        let txs: Vec<_> = txs.iter().collect();
+
+        reducer.roll_back(&before);
+        reducer
+            .reduce_block(&block, &txs, &ctx, &mut output)
+            .unwrap();
+
+        let sent = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        drop(enrich);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        (reducer, before, sent)
+    }
+
+    #[test]
+    fn collateral_return_is_deducted_from_its_address_first() {
+        let collateral = vec![
+            ("a".to_string(), 5_000_000),
+            ("b".to_string(), 2_000_000),
+            ("a".to_string(), 1_000_000),
+        ];
+
+        // no return, everything is lost
+        assert_eq!(
+            forfeited_by_address(&collateral, None),
+            BTreeMap::from([("a".to_string(), 6_000_000), ("b".to_string(), 2_000_000)])
+        );
+
+        // b gets back more than it provided, the rest comes off a
+        assert_eq!(
+            forfeited_by_address(&collateral, Some(("b".to_string(), 3_000_000))),
+            BTreeMap::from([("a".to_string(), 5_000_000)])
+        );
+
+        // a return to an address that provided nothing still counts
+        assert_eq!(
+            forfeited_by_address(&collateral, Some(("c".to_string(), 1_500_000))),
+            BTreeMap::from([("a".to_string(), 4_500_000), ("b".to_string(), 2_000_000)])
+        );
+    }
+
+    #[test]
+    fn collateral_of_invalid_tx_is_resolved_through_enrich() {
+        let (_, _, sent) = reduce_invalid_tx_block("collateral-enrich");
+
+        // the collateral is only found if enrich fetched it along with the
+        // inputs of the tx
+        assert_eq!(sent.len(), 2);
+
+        assert!(matches!(
+            &sent[0],
+            CRDTCommand::PNCounter(key, 5_000_000) if key.starts_with("collateral_events.forfeited.")
+        ));
+
+        match &sent[1] {
+            CRDTCommand::ListPrepend(key, model::Value::Json(event), 100) => {
+                assert!(key.starts_with("collateral_events.events."));
+                assert_eq!(event["lovelace"], 5_000_000);
+                assert!(event["to"].is_null());
+            }
+            x => panic!("unexpected command {:?}", x),
+        }
+    }
+
+    #[test]
+    fn rollback_undoes_forfeited_collateral() {
+        let (mut reducer, before, sent) = reduce_invalid_tx_block("collateral-rollback");
+
+        assert!(reducer.covers_rollback(&before));
+        let undo = reducer.roll_back(&before);
+
+        let events = match &sent[1] {
+            CRDTCommand::ListPrepend(key, _, _) => key.clone(),
+            x => panic!("unexpected command {:?}", x),
+        };
+
+        assert_eq!(undo.len(), 2);
+        assert!(matches!(&undo[0], CRDTCommand::ListPop(key) if *key == events));
+        assert!(matches!(&undo[1], CRDTCommand::PNCounter(_, -5_000_000)));
+    }
+}
@@ -0,0 +1,314 @@
+//! Separate views of the confirmed and volatile state
+//!
+//! The source holds blocks back until they're `min_depth` deep, so that a
+//! rollback rarely reaches the reducers, at the cost of lagging behind the
+//! tip. When enabled, the reducers stage keeps that depth itself instead: the
+//! commands of each block are written right away under a volatile prefix
+//! (`volatile.{key}` by default) and written again under their own key once
+//! `depth` blocks were reduced on top of it. Clients trusting only confirmed
+//! data read the plain keys, those wanting the tip read the volatile ones.
+//!
+//! A rollback within the last `depth` blocks drops the blocks it undoes
+//! before they are confirmed, and reverts their volatile writes: counter
+//! deltas are written back with the opposite sign, the keys of any other
+//! command (sets, registers, lists...) can't be restored without knowing
+//! what they held before and are deleted from the volatile view. The storage
+//! cursor follows the confirmed blocks, so a restart
+//! reduces the unconfirmed ones again: the volatile keys can count their
+//! non-idempotent commands twice, the confirmed ones never do. The source
+//! `min_depth` is meant to be lowered when this is enabled.
+
+use std::collections::{BTreeSet, VecDeque};
+
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::inverse;
+use crate::crosscut;
+use crate::model::CRDTCommand;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    /// Number of blocks on top of a block before its commands are written to
+    /// the confirmed keys
+    pub depth: usize,
+
+    /// Prefix prepended to the keys of the volatile writes, defaults to
+    /// `volatile`
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+}
+
+pub struct Confirmation {
+    config: Config,
+    /// Commands of the reduced blocks not confirmed yet, oldest first
+    ring: VecDeque<(Point, Vec<CRDTCommand>)>,
+    /// Block being reduced
+    current: Option<(Point, Vec<CRDTCommand>)>,
+    /// Volatile writes waiting for a confirmed block to frame them, only
+    /// while the first `depth` blocks fill the ring
+    held: Vec<CRDTCommand>,
+    confirmed: Option<Point>,
+}
+
+impl Confirmation {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            ring: VecDeque::new(),
+            current: None,
+            held: vec![],
+            confirmed: None,
+        }
+    }
+
+    fn volatile(&self, cmd: &CRDTCommand) -> CRDTCommand {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("volatile");
+
+        let mut cmd = cmd.clone();
+
+        if let Some(key) = cmd.key_mut() {
+            *key = prefix.key(&[key.as_str()]);
+        }
+
+        cmd
+    }
+
+    /// True if a rollback to the point only undoes blocks that aren't
+    /// confirmed yet
+    pub fn covers(&self, point: &Point) -> bool {
+        self.confirmed.as_ref() == Some(point) || self.ring.iter().any(|(x, _)| x == point)
+    }
+
+    /// Volatile writes undoing the commands of the dropped blocks, oldest
+    /// first
+    fn revert(&self, dropped: &[Vec<CRDTCommand>]) -> Vec<CRDTCommand> {
+        let commands = dropped.iter().flatten().rev();
+
+        // a key written by anything but counters is deleted, whatever the
+        // counters did to it
+        let deleted: BTreeSet<_> = commands
+            .clone()
+            .filter(|x| inverse(x).is_none())
+            .filter_map(|x| x.key())
+            .collect();
+
+        let mut writes: Vec<_> = commands
+            .filter(|x| x.key().map_or(false, |x| !deleted.contains(x)))
+            .filter_map(inverse)
+            .collect();
+
+        writes.extend(
+            deleted
+                .into_iter()
+                .map(|x| CRDTCommand::DeleteKey(x.to_string())),
+        );
+
+        writes.iter().map(|x| self.volatile(x)).collect()
+    }
+
+    fn roll_back(&mut self, point: &Point) {
+        let keep = match self.ring.iter().position(|(x, _)| x == point) {
+            Some(idx) => idx + 1,
+            None if self.confirmed.as_ref() == Some(point) => 0,
+            None => self.ring.len(),
+        };
+
+        let mut dropped: Vec<_> = self
+            .ring
+            .split_off(keep)
+            .into_iter()
+            .map(|(_, x)| x)
+            .collect();
+
+        // the volatile writes of the unfinished block are held already
+        dropped.extend(self.current.take().map(|(_, x)| x));
+
+        let reverts = self.revert(&dropped);
+        self.held.extend(reverts);
+    }
+
+    /// Confirms the oldest block once the ring is past the depth, framing
+    /// its commands along with the volatile writes held so far
+    fn finish(&mut self, point: Point) -> Vec<CRDTCommand> {
+        let commands = self.current.take().map(|(_, x)| x).unwrap_or_default();
+        self.ring.push_back((point, commands));
+
+        if self.ring.len() <= self.config.depth {
+            return vec![];
+        }
+
+        let (confirmed, commands) = self.ring.pop_front().unwrap();
+
+        let mut frame = vec![CRDTCommand::BlockStarting(confirmed.clone())];
+        frame.append(&mut self.held);
+        frame.extend(commands);
+        frame.push(CRDTCommand::BlockFinished(confirmed.clone()));
+
+        self.confirmed = Some(confirmed);
+
+        frame
+    }
+
+    /// Takes a command of the reducers, returns the ones to send downstream
+    pub fn push(&mut self, cmd: CRDTCommand) -> Vec<CRDTCommand> {
+        match cmd {
+            CRDTCommand::BlockStarting(point) => {
+                self.current = Some((point, vec![]));
+                vec![]
+            }
+            CRDTCommand::BlockFinished(point) => self.finish(point),
+            CRDTCommand::RollbackMarker(point) => {
+                self.roll_back(&point);
+                vec![CRDTCommand::RollbackMarker(point)]
+            }
+            cmd if self.current.is_some() => {
+                let volatile = self.volatile(&cmd);
+                self.held.push(volatile);
+
+                if let Some((_, commands)) = &mut self.current {
+                    commands.push(cmd);
+                }
+
+                vec![]
+            }
+            cmd => vec![cmd],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::{Config, Confirmation};
+    use crate::model::CRDTCommand;
+
+    fn point(n: u8) -> Point {
+        Point::Specific(n as u64, vec![n; 32])
+    }
+
+    fn reduce(confirmation: &mut Confirmation, n: u8) -> Vec<String> {
+        [
+            CRDTCommand::BlockStarting(point(n)),
+            CRDTCommand::PNCounter(format!("c{}", n), 1),
+            CRDTCommand::BlockFinished(point(n)),
+        ]
+        .into_iter()
+        .flat_map(|x| confirmation.push(x))
+        .map(|x| match x {
+            CRDTCommand::BlockStarting(Point::Specific(slot, _)) => format!("start {}", slot),
+            CRDTCommand::BlockFinished(Point::Specific(slot, _)) => format!("finish {}", slot),
+            x => x.key().unwrap().to_string(),
+        })
+        .collect()
+    }
+
+    #[test]
+    fn blocks_are_confirmed_once_deep_enough() {
+        let mut confirmation = Confirmation::new(Config {
+            depth: 2,
+            key_prefix: None,
+            key_separator: None,
+        });
+
+        // volatile writes are held until the first block is confirmed
+        assert!(reduce(&mut confirmation, 1).is_empty());
+        assert!(reduce(&mut confirmation, 2).is_empty());
+
+        assert_eq!(
+            reduce(&mut confirmation, 3),
+            vec![
+                "start 1",
+                "volatile.c1",
+                "volatile.c2",
+                "volatile.c3",
+                "c1",
+                "finish 1"
+            ]
+        );
+
+        assert_eq!(
+            reduce(&mut confirmation, 4),
+            vec!["start 2", "volatile.c4", "c2", "finish 2"]
+        );
+
+        // block 4 is undone before being confirmed, its volatile write is
+        // reverted along with the next ones
+        assert!(confirmation.covers(&point(3)));
+        assert!(!confirmation.covers(&point(1)));
+        confirmation.push(CRDTCommand::RollbackMarker(point(3)));
+
+        assert!(reduce(&mut confirmation, 5).is_empty());
+
+        assert_eq!(
+            reduce(&mut confirmation, 6),
+            vec![
+                "start 3",
+                "volatile.c4",
+                "volatile.c5",
+                "volatile.c6",
+                "c3",
+                "finish 3"
+            ]
+        );
+
+        assert_eq!(
+            reduce(&mut confirmation, 7),
+            vec!["start 5", "volatile.c7", "c5", "finish 5"]
+        );
+    }
+
+    #[test]
+    fn rollback_reverts_volatile_writes() {
+        let mut confirmation = Confirmation::new(Config {
+            depth: 1,
+            key_prefix: None,
+            key_separator: None,
+        });
+
+        let block = |n: u8| {
+            vec![
+                CRDTCommand::BlockStarting(point(n)),
+                CRDTCommand::PNCounter("c".into(), 2),
+                CRDTCommand::HashCounter("m".into(), "h".into(), 3),
+                CRDTCommand::SortedSetAdd("z".into(), "m".into(), 4),
+                CRDTCommand::PNCounter("w".into(), 1),
+                CRDTCommand::AnyWriteWins("w".into(), "x".to_string().into()),
+                CRDTCommand::SetAdd("s".into(), "m".into()),
+                CRDTCommand::BlockFinished(point(n)),
+            ]
+        };
+
+        // block 1 is confirmed once block 2 is reduced
+        for cmd in [block(1), block(2)].concat() {
+            confirmation.push(cmd);
+        }
+
+        let output = confirmation.push(CRDTCommand::RollbackMarker(point(1)));
+        assert_eq!(output.len(), 1);
+
+        // the reverts are framed along with the volatile writes of the next
+        // block to be confirmed
+        let frame: Vec<_> = [block(3), block(4)]
+            .concat()
+            .into_iter()
+            .flat_map(|x| confirmation.push(x))
+            .map(|x| format!("{:?}", x))
+            .collect();
+
+        let reverts = [
+            CRDTCommand::SortedSetRemove("volatile.z".into(), "m".into(), -4),
+            CRDTCommand::HashCounter("m".into(), "volatile.h".into(), -3),
+            CRDTCommand::PNCounter("volatile.c".into(), -2),
+            CRDTCommand::DeleteKey("volatile.s".into()),
+            CRDTCommand::DeleteKey("volatile.w".into()),
+        ];
+
+        assert_eq!(&frame[1..6], reverts.map(|x| format!("{:?}", x)).as_slice());
+    }
+}
@@ -0,0 +1,284 @@
+//! Per-block buffer collapsing redundant commands
+//!
+//! Reducers emit their commands tx by tx and reducer by reducer, so the same
+//! write often shows up several times within a block (eg: an address added
+//! to the same set by each of its outputs). When enabled, the commands of a
+//! block are held until it finishes: identical `SetAdd` / `AnyWriteWins`
+//! commands are sent once, at the position of the last one, and the deltas
+//! of `PNCounter` / `HashCounter` commands on the same key are added up into
+//! the first one. The store ends up in the same state, streaming storages
+//! just see fewer commands.
+
+use std::collections::HashMap;
+
+use gasket::messaging::Message;
+use pallas::network::miniprotocols::Point;
+
+use super::confirmation::Confirmation;
+use super::epoch_commit::EpochCommit;
+use crate::model::CRDTCommand;
+
+#[derive(Default)]
+pub struct BlockBuffer {
+    commands: Vec<Option<CRDTCommand>>,
+    /// Position of the last occurrence of each collapsible command
+    writes: HashMap<String, usize>,
+    /// Position of the counter each delta gets merged into, by storage key
+    /// and hash member
+    counters: HashMap<String, HashMap<Option<String>, usize>>,
+    merged: u64,
+}
+
+impl BlockBuffer {
+    pub fn push(&mut self, cmd: CRDTCommand) {
+        let idx = self.commands.len();
+
+        match &cmd {
+            CRDTCommand::SetAdd(_, _) | CRDTCommand::AnyWriteWins(_, _) => {
+                if let Some(prior) = self.writes.insert(format!("{:?}", cmd), idx) {
+                    self.commands[prior] = None;
+                    self.merged += 1;
+                }
+            }
+            CRDTCommand::PNCounter(key, delta) => {
+                if self.merge_counter(key, None, *delta) {
+                    return;
+                }
+
+                self.counters
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(None, idx);
+            }
+            CRDTCommand::HashCounter(member, key, delta) => {
+                if self.merge_counter(key, Some(member), *delta) {
+                    return;
+                }
+
+                self.counters
+                    .entry(key.clone())
+                    .or_default()
+                    .insert(Some(member.clone()), idx);
+            }
+            _ => (),
+        };
+
+        // a counter can't be merged across another write to its key, the
+        // order between both matters
+        if !matches!(
+            cmd,
+            CRDTCommand::PNCounter(_, _) | CRDTCommand::HashCounter(_, _, _)
+        ) {
+            if let Some(key) = cmd.key() {
+                self.counters.remove(key);
+            }
+        }
+
+        self.commands.push(Some(cmd));
+    }
+
+    /// Adds the delta to a pending counter of the same key, if any
+    fn merge_counter(&mut self, key: &str, member: Option<&String>, delta: i64) -> bool {
+        let idx = match self.counters.get(key).and_then(|x| x.get(&member.cloned())) {
+            Some(x) => *x,
+            None => return false,
+        };
+
+        match &mut self.commands[idx] {
+            Some(CRDTCommand::PNCounter(_, total)) => *total += delta,
+            Some(CRDTCommand::HashCounter(_, _, total)) => *total += delta,
+            _ => return false,
+        };
+
+        self.merged += 1;
+
+        true
+    }
+
+    /// Takes the buffered commands, in order, along with how many were
+    /// collapsed into others
+    pub fn drain(&mut self) -> (Vec<CRDTCommand>, u64) {
+        self.writes.clear();
+        self.counters.clear();
+
+        let commands = self.commands.drain(..).flatten().collect();
+        let merged = std::mem::take(&mut self.merged);
+
+        (commands, merged)
+    }
+
+    pub fn clear(&mut self) {
+        self.drain();
+    }
+}
+
+/// Output of the reducers, sending commands through a `BlockBuffer` when
+/// enabled
+///
+/// Frame commands (`BlockFinished`, `RollbackMarker`) send the buffered
+/// commands ahead of them. `BlockStarting` drops whatever is left of a block
+/// that didn't finish, as the storages do. The commands leaving the buffer
+/// go through the `Confirmation` of the volatile writes or the `EpochCommit`,
+/// if any.
+pub struct Output {
+    port: gasket::messaging::OutputPort<CRDTCommand>,
+    buffer: Option<BlockBuffer>,
+    confirmation: Option<Confirmation>,
+    epoch_commit: Option<EpochCommit>,
+    merged: gasket::metrics::Counter,
+}
+
+impl Output {
+    pub fn new(port: gasket::messaging::OutputPort<CRDTCommand>, dedup: bool) -> Self {
+        Self {
+            port,
+            buffer: dedup.then(BlockBuffer::default),
+            confirmation: None,
+            epoch_commit: None,
+            merged: Default::default(),
+        }
+    }
+
+    pub fn confirmed(mut self, confirmation: Option<Confirmation>) -> Self {
+        self.confirmation = confirmation;
+        self
+    }
+
+    pub fn epoch_aligned(mut self, epoch_commit: Option<EpochCommit>) -> Self {
+        self.epoch_commit = epoch_commit;
+        self
+    }
+
+    /// True if a rollback to the point only undoes blocks not written for
+    /// good, see `Confirmation::covers` and `EpochCommit::covers`
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        match (&self.confirmation, &self.epoch_commit) {
+            (Some(x), _) => x.covers(point),
+            (None, Some(x)) => x.covers(point),
+            (None, None) => false,
+        }
+    }
+
+    /// True if blocks are held until written for good, rollbacks past them
+    /// can't be undone by the reducers
+    pub fn holds_blocks(&self) -> bool {
+        self.confirmation.is_some() || self.epoch_commit.is_some()
+    }
+
+    pub fn merged(&self) -> &gasket::metrics::Counter {
+        &self.merged
+    }
+
+    pub fn into_port(self) -> gasket::messaging::OutputPort<CRDTCommand> {
+        self.port
+    }
+
+    fn forward(&mut self, cmd: CRDTCommand) -> Result<(), gasket::error::Error> {
+        let commands = match (&mut self.confirmation, &mut self.epoch_commit) {
+            (Some(x), _) => x.push(cmd),
+            (None, Some(x)) => x.push(cmd),
+            (None, None) => return self.port.send(Message::from(cmd)),
+        };
+
+        for cmd in commands {
+            self.port.send(Message::from(cmd))?;
+        }
+
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), gasket::error::Error> {
+        let (commands, merged) = match &mut self.buffer {
+            Some(buffer) => buffer.drain(),
+            None => return Ok(()),
+        };
+
+        self.merged.inc(merged);
+
+        for cmd in commands {
+            self.forward(cmd)?;
+        }
+
+        Ok(())
+    }
+
+    pub fn send(&mut self, msg: Message<CRDTCommand>) -> Result<(), gasket::error::Error> {
+        let buffer = match &mut self.buffer {
+            Some(x) => x,
+            None => return self.forward(msg.payload),
+        };
+
+        match &msg.payload {
+            CRDTCommand::BlockStarting(_) => {
+                buffer.clear();
+                self.forward(msg.payload)
+            }
+            CRDTCommand::BlockFinished(_) | CRDTCommand::RollbackMarker(_) => {
+                self.flush()?;
+                self.forward(msg.payload)
+            }
+            _ => {
+                buffer.push(msg.payload);
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, Message, OutputPort, TwoPhaseInputPort};
+    use pallas::network::miniprotocols::Point;
+
+    use super::Output;
+    use crate::model::CRDTCommand;
+
+    #[test]
+    fn duplicates_are_merged_at_block_end() {
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut port, &mut downstream, 20);
+
+        let mut output = Output::new(port, true);
+        let point = Point::Specific(1, vec![1u8; 32]);
+
+        let block = vec![
+            CRDTCommand::BlockStarting(point.clone()),
+            CRDTCommand::SetAdd("s".into(), "x".into()),
+            CRDTCommand::PNCounter("c".into(), 1),
+            CRDTCommand::HashCounter("m".into(), "h".into(), 2),
+            CRDTCommand::SetAdd("s".into(), "y".into()),
+            CRDTCommand::PNCounter("c".into(), 1),
+            CRDTCommand::SetAdd("s".into(), "x".into()),
+            CRDTCommand::HashCounter("m".into(), "h".into(), -1),
+            // the counter can't be moved across the write
+            CRDTCommand::AnyWriteWins("c".into(), "0".to_string().into()),
+            CRDTCommand::PNCounter("c".into(), 5),
+            CRDTCommand::BlockFinished(point),
+        ];
+
+        for cmd in block {
+            output.send(Message::from(cmd)).unwrap();
+        }
+
+        let received: Vec<_> = (0..8)
+            .map(|_| format!("{:?}", downstream.recv_or_idle().unwrap().payload))
+            .collect();
+
+        let expected: Vec<_> = [
+            CRDTCommand::PNCounter("c".into(), 2),
+            CRDTCommand::HashCounter("m".into(), "h".into(), 1),
+            CRDTCommand::SetAdd("s".into(), "y".into()),
+            CRDTCommand::SetAdd("s".into(), "x".into()),
+            CRDTCommand::AnyWriteWins("c".into(), "0".to_string().into()),
+            CRDTCommand::PNCounter("c".into(), 5),
+        ]
+        .iter()
+        .map(|x| format!("{:?}", x))
+        .collect();
+
+        assert!(received[0].starts_with("BlockStarting"));
+        assert_eq!(&received[1..7], &expected[..]);
+        assert!(received[7].starts_with("BlockFinished"));
+    }
+}
@@ -0,0 +1,539 @@
+//! Writes committed at epoch boundaries
+//!
+//! Reducers bucketing by epoch (`epoch_stats`, `fee_stats`, ...) send a few
+//! small updates per block to the same keys. When enabled, the reducers stage
+//! holds the commands of the blocks of the current epoch and sends them as a
+//! single frame once the epoch is over, merging the counters on the same key
+//! (see `dedup::BlockBuffer`). The storage cursor only moves at those
+//! commits, so an epoch's aggregates are written at once, when they're final.
+//!
+//! An epoch is committed once its last block is `depth` blocks deep: the
+//! first blocks of the next epoch are held along with it, and a rollback to
+//! any held block just drops the ones it undoes. A fork in the first blocks of
+//! an epoch doesn't have to undo a committed one. A rollback reaching further
+//! back is refused like any rollback of reduced blocks and stops the pipeline.
+//!
+//! Meanwhile, the held blocks are written under an in-progress prefix
+//! (`in_progress.{key}` by default), framed by the last committed block so
+//! that the cursor stays put. Clients wanting the aggregates of the running
+//! epoch read those keys. Whenever the blocks an in-progress key was written
+//! from change (a rollback, a commit, or a restart reducing them again), the
+//! key is deleted and written again from the held blocks: it never counts a
+//! block twice and the keys of a committed epoch go away.
+//!
+//! The commands of the held blocks are kept in memory, this is meant for
+//! pipelines running epoch-aggregating reducers only. Past
+//! `max_held_commands`, the blocks `depth` deep are committed without waiting
+//! for the end of the epoch, moving the cursor mid-epoch.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::dedup::BlockBuffer;
+use crate::crosscut::{self, epochs::EpochTracker};
+use crate::model::CRDTCommand;
+
+const DEFAULT_DEPTH: usize = 2160;
+
+const DEFAULT_MAX_HELD_COMMANDS: usize = 1_000_000;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    /// Prefix prepended to the keys of the in-progress writes, defaults to
+    /// `in_progress`
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Number of blocks reduced on top of the last block of an epoch before
+    /// the epoch is committed. Defaults to 2160.
+    pub depth: Option<usize>,
+
+    /// Number of held commands past which the blocks `depth` deep are
+    /// committed before the epoch is over. Defaults to 1000000.
+    pub max_held_commands: Option<usize>,
+}
+
+pub struct EpochCommit {
+    config: Config,
+    tracker: EpochTracker,
+    /// Commands of the reduced blocks not committed yet, oldest first
+    blocks: VecDeque<(Point, Vec<CRDTCommand>)>,
+    /// Number of held blocks that belong to an epoch that's over
+    boundary: Option<usize>,
+    /// Number of commands in `blocks`
+    held: usize,
+    /// Block being reduced
+    current: Option<(Point, Vec<CRDTCommand>)>,
+    /// Last block of the last commit, framing the in-progress writes
+    committed: Option<Point>,
+    /// Keys whose in-progress value matches the held blocks
+    synced: HashSet<String>,
+    /// Keys whose in-progress value has to be written again from the held
+    /// blocks
+    stale: BTreeSet<String>,
+}
+
+fn point_slot(point: &Point) -> Option<u64> {
+    match point {
+        Point::Origin => None,
+        Point::Specific(slot, _) => Some(*slot),
+    }
+}
+
+impl EpochCommit {
+    pub fn new(config: Config, chain: &crosscut::ChainWellKnownInfo) -> Self {
+        Self {
+            config,
+            tracker: EpochTracker::new(chain),
+            blocks: VecDeque::new(),
+            boundary: None,
+            held: 0,
+            current: None,
+            committed: None,
+            synced: HashSet::new(),
+            stale: BTreeSet::new(),
+        }
+    }
+
+    fn in_progress_key(&self, key: &str) -> String {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("in_progress")
+        .key(&[key])
+    }
+
+    fn in_progress(&self, cmd: &CRDTCommand) -> CRDTCommand {
+        let mut cmd = cmd.clone();
+
+        if let Some(key) = cmd.key_mut() {
+            *key = self.in_progress_key(key);
+        }
+
+        cmd
+    }
+
+    /// True if a rollback to the point only undoes blocks not committed yet
+    pub fn covers(&self, point: &Point) -> bool {
+        self.committed.as_ref() == Some(point) || self.blocks.iter().any(|(x, _)| x == point)
+    }
+
+    /// Marks the in-progress keys of commands no longer held as stale
+    fn forget(&mut self, commands: &[CRDTCommand]) {
+        self.held -= commands.len();
+
+        for key in commands.iter().filter_map(|x| x.key()) {
+            self.synced.remove(key);
+            self.stale.insert(key.to_string());
+        }
+    }
+
+    fn roll_back(&mut self, point: &Point) {
+        self.current = None;
+
+        let keep = match self.blocks.iter().position(|(x, _)| x == point) {
+            Some(idx) => idx + 1,
+            None if self.committed.as_ref() == Some(point) => 0,
+            // the intersection found on startup, where the cursor is
+            None if self.blocks.is_empty() && point_slot(point).is_some() => {
+                self.committed = Some(point.clone());
+                0
+            }
+            None => self.blocks.len(),
+        };
+
+        for (_, commands) in self.blocks.split_off(keep) {
+            self.forget(&commands);
+        }
+
+        // the epoch that was over is the running one again
+        if self.boundary.map_or(false, |x| x >= self.blocks.len()) {
+            self.boundary = None;
+        }
+
+        let last = match self.blocks.back() {
+            Some((x, _)) => Some(x),
+            None => self.committed.as_ref(),
+        };
+
+        self.tracker.reset(last.and_then(point_slot));
+    }
+
+    /// Number of the oldest held blocks to commit now
+    fn due(&self) -> usize {
+        let depth = self.config.depth.unwrap_or(DEFAULT_DEPTH);
+        let max_held = self
+            .config
+            .max_held_commands
+            .unwrap_or(DEFAULT_MAX_HELD_COMMANDS);
+
+        // blocks with at least `depth` blocks on top of them
+        let deep = self.blocks.len().saturating_sub(depth);
+
+        match self.boundary {
+            _ if self.held > max_held => deep,
+            Some(x) if x <= deep => x,
+            _ => 0,
+        }
+    }
+
+    /// Frames the oldest `n` held blocks as a single one, ending at the last
+    /// of them
+    fn commit(&mut self, n: usize) -> Vec<CRDTCommand> {
+        let blocks: Vec<_> = self.blocks.drain(..n).collect();
+
+        let last = match blocks.last() {
+            Some((x, _)) => x.clone(),
+            None => return vec![],
+        };
+
+        let mut buffer = BlockBuffer::default();
+
+        for (_, commands) in blocks {
+            self.forget(&commands);
+            commands.into_iter().for_each(|x| buffer.push(x));
+        }
+
+        self.boundary = self
+            .boundary
+            .and_then(|x| x.checked_sub(n))
+            .filter(|x| *x > 0);
+
+        let (commands, _) = buffer.drain();
+
+        let mut frame = vec![CRDTCommand::BlockStarting(last.clone())];
+        frame.extend(commands);
+        frame.push(CRDTCommand::BlockFinished(last.clone()));
+
+        self.committed = Some(last);
+
+        frame
+    }
+
+    /// Frames the in-progress writes along with the stale keys, deleted and
+    /// written again from the held blocks
+    fn sync(&mut self, writes: Vec<CRDTCommand>) -> Vec<CRDTCommand> {
+        let committed = match &self.committed {
+            Some(x) => x.clone(),
+            // nothing to frame them with until the first commit, the keys
+            // stay stale until then
+            None => return vec![],
+        };
+
+        let stale = std::mem::take(&mut self.stale);
+
+        let mut frame = vec![CRDTCommand::BlockStarting(committed.clone())];
+
+        for key in stale.iter() {
+            frame.push(CRDTCommand::DeleteKey(self.in_progress_key(key)));
+        }
+
+        let mut buffer = BlockBuffer::default();
+
+        self.blocks
+            .iter()
+            .flat_map(|(_, x)| x)
+            .filter(|x| x.key().map_or(false, |x| stale.contains(x)))
+            .for_each(|x| buffer.push(self.in_progress(x)));
+
+        let (rewrites, _) = buffer.drain();
+
+        frame.extend(rewrites);
+        frame.extend(writes);
+        frame.push(CRDTCommand::BlockFinished(committed));
+
+        self.synced.extend(stale);
+
+        frame
+    }
+
+    fn finish(&mut self, point: Point) -> Vec<CRDTCommand> {
+        let commands = self.current.take().map(|(_, x)| x).unwrap_or_default();
+
+        self.held += commands.len();
+        self.blocks.push_back((point, commands));
+
+        let mut frame = self.commit(self.due());
+
+        // the block may have been committed right away
+        let mut writes = vec![];
+        let mut stale = vec![];
+
+        if let Some((_, commands)) = self.blocks.back() {
+            for cmd in commands {
+                match cmd.key() {
+                    Some(key) if !self.synced.contains(key) => stale.push(key.to_string()),
+                    _ => writes.push(self.in_progress(cmd)),
+                }
+            }
+        }
+
+        self.stale.extend(stale);
+        frame.extend(self.sync(writes));
+
+        frame
+    }
+
+    /// Takes a command of the reducers, returns the ones to send downstream
+    pub fn push(&mut self, cmd: CRDTCommand) -> Vec<CRDTCommand> {
+        match cmd {
+            CRDTCommand::BlockStarting(point) => {
+                let rollover = point_slot(&point).and_then(|x| self.tracker.advance(x));
+
+                let mut frame = vec![];
+
+                if rollover.is_some() {
+                    // an epoch shorter than the depth, over anyway
+                    if let Some(x) = self.boundary {
+                        frame = self.commit(x);
+                    }
+
+                    self.boundary = Some(self.blocks.len()).filter(|x| *x > 0);
+                    frame.extend(self.commit(self.due()));
+                }
+
+                self.current = Some((point, vec![]));
+                frame
+            }
+            CRDTCommand::BlockFinished(point) => self.finish(point),
+            CRDTCommand::RollbackMarker(point) => {
+                self.roll_back(&point);
+
+                let mut frame = vec![CRDTCommand::RollbackMarker(point)];
+
+                // the in-progress keys of the undone blocks
+                if !self.stale.is_empty() {
+                    frame.extend(self.sync(vec![]));
+                }
+
+                frame
+            }
+            cmd if self.current.is_some() => {
+                if let Some((_, commands)) = &mut self.current {
+                    commands.push(cmd);
+                }
+
+                vec![]
+            }
+            cmd => vec![cmd],
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::{Config, EpochCommit};
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::CRDTCommand;
+
+    /// Point at the nth slot of a mainnet shelley epoch, 432000 slots each
+    fn point(epoch: u64, n: u8) -> Point {
+        let slot = 4492800 + (epoch - 208) * 432000 + n as u64;
+        Point::Specific(slot, vec![n; 32])
+    }
+
+    fn epoch_commit(depth: usize, max_held_commands: Option<usize>) -> EpochCommit {
+        EpochCommit::new(
+            Config {
+                key_prefix: None,
+                key_separator: None,
+                depth: Some(depth),
+                max_held_commands,
+            },
+            &ChainWellKnownInfo::mainnet(),
+        )
+    }
+
+    fn describe(commands: Vec<CRDTCommand>) -> Vec<String> {
+        commands
+            .into_iter()
+            .map(|x| match x {
+                CRDTCommand::BlockStarting(Point::Specific(_, hash)) => {
+                    format!("start {}", hash[0])
+                }
+                CRDTCommand::BlockFinished(Point::Specific(_, hash)) => {
+                    format!("finish {}", hash[0])
+                }
+                CRDTCommand::RollbackMarker(Point::Specific(_, hash)) => {
+                    format!("rollback {}", hash[0])
+                }
+                CRDTCommand::PNCounter(key, delta) => format!("{} {}", key, delta),
+                CRDTCommand::DeleteKey(key) => format!("delete {}", key),
+                x => format!("{:?}", x),
+            })
+            .collect()
+    }
+
+    fn reduce(commit: &mut EpochCommit, point: Point) -> Vec<String> {
+        let commands = [
+            CRDTCommand::BlockStarting(point.clone()),
+            CRDTCommand::PNCounter("c".into(), 1),
+            CRDTCommand::BlockFinished(point),
+        ]
+        .into_iter()
+        .flat_map(|x| commit.push(x))
+        .collect();
+
+        describe(commands)
+    }
+
+    fn roll_back(commit: &mut EpochCommit, point: Point) -> Vec<String> {
+        describe(commit.push(CRDTCommand::RollbackMarker(point)))
+    }
+
+    #[test]
+    fn epochs_are_committed_at_rollover() {
+        let mut commit = epoch_commit(0, None);
+
+        // the intersection found on startup frames the in-progress writes
+        roll_back(&mut commit, point(299, 9));
+
+        // the key is written from scratch the first time it's touched
+        assert_eq!(
+            reduce(&mut commit, point(300, 1)),
+            vec![
+                "start 9",
+                "delete in_progress.c",
+                "in_progress.c 1",
+                "finish 9"
+            ]
+        );
+
+        assert_eq!(
+            reduce(&mut commit, point(300, 2)),
+            vec!["start 9", "in_progress.c 1", "finish 9"]
+        );
+
+        // block 3 is undone before the epoch is over
+        reduce(&mut commit, point(300, 3));
+        assert!(commit.covers(&point(300, 2)));
+        assert!(!commit.covers(&point(299, 8)));
+
+        assert_eq!(
+            roll_back(&mut commit, point(300, 2)),
+            vec![
+                "rollback 2",
+                "start 9",
+                "delete in_progress.c",
+                "in_progress.c 2",
+                "finish 9"
+            ]
+        );
+
+        // the next epoch commits the counters of the previous one at once,
+        // its in-progress keys only hold the new epoch
+        assert_eq!(
+            reduce(&mut commit, point(301, 4)),
+            vec![
+                "start 2",
+                "c 2",
+                "finish 2",
+                "start 2",
+                "delete in_progress.c",
+                "in_progress.c 1",
+                "finish 2"
+            ]
+        );
+
+        // the committed epoch can't be rolled back anymore
+        assert!(!commit.covers(&point(300, 1)));
+        assert!(commit.covers(&point(300, 2)));
+
+        // after a restart, the blocks reduced again replace what the
+        // in-progress keys held
+        let mut commit = epoch_commit(0, None);
+        roll_back(&mut commit, point(300, 2));
+
+        assert_eq!(
+            reduce(&mut commit, point(301, 4)),
+            vec![
+                "start 2",
+                "delete in_progress.c",
+                "in_progress.c 1",
+                "finish 2"
+            ]
+        );
+    }
+
+    #[test]
+    fn fork_after_boundary_is_undone() {
+        let mut commit = epoch_commit(2, None);
+
+        roll_back(&mut commit, point(299, 9));
+        reduce(&mut commit, point(300, 1));
+        reduce(&mut commit, point(300, 2));
+
+        // the epoch is over, but its last block isn't deep enough yet
+        assert_eq!(
+            reduce(&mut commit, point(301, 3)),
+            vec!["start 9", "in_progress.c 1", "finish 9"]
+        );
+
+        assert!(commit.covers(&point(300, 2)));
+
+        assert_eq!(
+            roll_back(&mut commit, point(300, 2)),
+            vec![
+                "rollback 2",
+                "start 9",
+                "delete in_progress.c",
+                "in_progress.c 2",
+                "finish 9"
+            ]
+        );
+
+        assert_eq!(
+            reduce(&mut commit, point(301, 4)),
+            vec!["start 9", "in_progress.c 1", "finish 9"]
+        );
+
+        // two blocks on top of the boundary
+        assert_eq!(
+            reduce(&mut commit, point(301, 5)),
+            vec![
+                "start 2",
+                "c 2",
+                "finish 2",
+                "start 2",
+                "delete in_progress.c",
+                "in_progress.c 2",
+                "finish 2"
+            ]
+        );
+
+        assert!(!commit.covers(&point(300, 1)));
+        assert!(commit.covers(&point(301, 4)));
+    }
+
+    #[test]
+    fn held_commands_are_capped() {
+        let mut commit = epoch_commit(1, Some(2));
+
+        roll_back(&mut commit, point(300, 0));
+        reduce(&mut commit, point(300, 1));
+        reduce(&mut commit, point(300, 2));
+
+        // past the cap, the blocks deep enough are committed mid-epoch
+        assert_eq!(
+            reduce(&mut commit, point(300, 3)),
+            vec![
+                "start 2",
+                "c 2",
+                "finish 2",
+                "start 2",
+                "delete in_progress.c",
+                "in_progress.c 1",
+                "finish 2"
+            ]
+        );
+
+        assert!(!commit.covers(&point(300, 1)));
+        assert!(commit.covers(&point(300, 2)));
+        assert!(commit.covers(&point(300, 3)));
+    }
+}
@@ -0,0 +1,215 @@
+use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::{inverse, Journal};
+use crate::crosscut::epochs::block_epoch;
+use crate::{crosscut, model};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Also accumulate the deposits taken and refunded by certificates into
+    /// the `deposits` and `refunds` members
+    pub deposits: Option<crosscut::deposits::Config>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    chain: crosscut::ChainWellKnownInfo,
+    journal: Journal,
+}
+
+impl Reducer {
+    /// Accumulates the block totals into a hash keyed by epoch
+    ///
+    /// Members are `blocks`, `transactions`, `fees` and `output_lovelace`,
+    /// plus `deposits` and `refunds` when enabled. Fees, outputs and deposits
+    /// are only counted for valid txs, invalid ones don't produce their
+    /// outputs and pay with collateral instead.
+    ///
+    /// On rollback, the totals of the undone blocks are subtracted from the
+    /// hash of their epoch.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("epoch_stats");
+        let epoch_no = block_epoch(&self.chain, block).to_string();
+
+        let txs = block.txs();
+
+        let mut fees = 0u64;
+        let mut output_lovelace = 0u64;
+        let mut deposits = 0u64;
+        let mut refunds = 0u64;
+
+        for tx in txs.iter().filter(|tx| tx.is_valid()) {
+            fees += tx.fee().unwrap_or_default();
+
+            output_lovelace += tx
+                .outputs()
+                .iter()
+                .map(|o| o.lovelace_amount())
+                .sum::<u64>();
+
+            if let Some(config) = &self.config.deposits {
+                let (deposit, refund) = config.tx_amounts(tx);
+                deposits += deposit;
+                refunds += refund;
+            }
+        }
+
+        let mut totals = vec![
+            ("blocks", 1),
+            ("transactions", txs.len() as i64),
+            ("fees", fees as i64),
+            ("output_lovelace", output_lovelace as i64),
+        ];
+
+        if self.config.deposits.is_some() {
+            totals.push(("deposits", deposits as i64));
+            totals.push(("refunds", refunds as i64));
+        }
+
+        let mut undo = vec![];
+
+        for (member, delta) in totals {
+            let crdt =
+                model::CRDTCommand::hash_counter(prefix, &epoch_no, member.to_string(), delta);
+
+            undo.extend(inverse(&crdt));
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Subtracts the totals of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, chain: &crosscut::ChainWellKnownInfo) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            chain: chain.clone(),
+            journal,
+        };
+
+        super::Reducer::EpochStats(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::Config;
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::CRDTCommand;
+    use crate::reducers::{dedup, Reducer};
+
+    /// Totals by member of the hash counters
+    fn totals<'a>(cmds: impl Iterator<Item = &'a CRDTCommand>) -> HashMap<String, i64> {
+        let mut totals = HashMap::new();
+
+        for cmd in cmds {
+            if let CRDTCommand::HashCounter(member, _, delta) = cmd {
+                *totals.entry(member.clone()).or_default() += delta;
+            }
+        }
+
+        totals
+    }
+
+    fn reduce(config: Config) -> (Vec<CRDTCommand>, Vec<CRDTCommand>) {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 100);
+        let mut output = dedup::Output::new(port, false);
+
+        let mut reducer = match config.plugin(&ChainWellKnownInfo::mainnet()) {
+            Reducer::EpochStats(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer.reduce_block(&block, &mut output).unwrap();
+
+        let sent = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        assert!(reducer.covers_rollback(&before));
+
+        (sent, reducer.roll_back(&before))
+    }
+
+    #[test]
+    fn rollback_subtracts_deposits_and_refunds() {
+        let (sent, undo) = reduce(Config {
+            key_prefix: None,
+            key_separator: None,
+            deposits: Some(Default::default()),
+            rollback_window: None,
+        });
+
+        assert!(totals(sent.iter()).contains_key("deposits"));
+        assert!(totals(sent.iter()).contains_key("refunds"));
+
+        let reverted = totals(sent.iter().chain(undo.iter()));
+
+        assert_eq!(reverted.len(), 6);
+        assert!(reverted.values().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn rollback_subtracts_block_totals() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let (sent, undo) = reduce(Config {
+            key_prefix: None,
+            key_separator: None,
+            deposits: None,
+            rollback_window: None,
+        });
+
+        assert_eq!(totals(sent.iter())["blocks"], 1);
+        assert_eq!(totals(sent.iter())["transactions"], block.tx_count() as i64);
+
+        let reverted = totals(sent.iter().chain(undo.iter()));
+
+        assert_eq!(reverted.len(), 4);
+        assert!(reverted.values().all(|x| *x == 0));
+    }
+}
@@ -0,0 +1,265 @@
+use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::journal::{inverse, Journal};
+use crate::crosscut::epochs::block_epoch;
+use crate::{crosscut, model};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Also accumulate the deposits taken and refunded by certificates into
+    /// the `deposits` and `refunds` members
+    pub deposits: Option<crosscut::deposits::Config>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    chain: crosscut::ChainWellKnownInfo,
+    time: crosscut::time::NaiveProvider,
+    journal: Journal,
+}
+
+impl Reducer {
+    /// Sums the fees of the block, into a value per block and running totals
+    /// per epoch
+    ///
+    /// The block value is kept under `{prefix}.block.{hash}`. The epoch hash
+    /// `{prefix}.epoch.{epoch}` has `fees` and `transactions` members, their
+    /// ratio is the average fee of the epoch. When enabled, `deposits` and
+    /// `refunds` are added to both. Like `epoch_stats`, only valid txs are
+    /// counted, invalid ones pay with collateral instead.
+    ///
+    /// On rollback, the value of each undone block is deleted and its totals
+    /// are subtracted from the hash of its epoch.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("fee_stats");
+
+        let mut fees = 0u64;
+        let mut transactions = 0u64;
+        let mut deposits = 0u64;
+        let mut refunds = 0u64;
+
+        for tx in block.txs().iter().filter(|tx| tx.is_valid()) {
+            fees += tx.fee().unwrap_or_default();
+            transactions += 1;
+
+            if let Some(config) = &self.config.deposits {
+                let (deposit, refund) = config.tx_amounts(tx);
+                deposits += deposit;
+                refunds += refund;
+            }
+        }
+
+        let timestamp = self.time.slot_to_wallclock(block.slot());
+
+        let mut value = json!({
+            "slot": block.slot(),
+            "timestamp": timestamp,
+            "fees": fees,
+            "transactions": transactions,
+        });
+
+        if self.config.deposits.is_some() {
+            value["deposits"] = json!(deposits);
+            value["refunds"] = json!(refunds);
+        }
+
+        let block_key = prefix.key(&["block", &block.hash().to_string()]);
+        let mut undo = vec![model::CRDTCommand::DeleteKey(block_key.clone())];
+
+        let crdt = model::CRDTCommand::LastWriteWins(block_key, value.into(), timestamp);
+
+        output.send(gasket::messaging::Message::from(crdt))?;
+
+        let epoch = prefix.key(&["epoch", &block_epoch(&self.chain, block).to_string()]);
+
+        let mut totals = vec![("fees", fees as i64), ("transactions", transactions as i64)];
+
+        if self.config.deposits.is_some() {
+            totals.push(("deposits", deposits as i64));
+            totals.push(("refunds", refunds as i64));
+        }
+
+        for (member, delta) in totals {
+            let crdt = model::CRDTCommand::HashCounter(member.to_string(), epoch.clone(), delta);
+
+            undo.extend(inverse(&crdt));
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Deletes the values of the blocks past the point and subtracts their
+    /// totals
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, chain: &crosscut::ChainWellKnownInfo) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            chain: chain.clone(),
+            time: crosscut::time::NaiveProvider::new(chain.clone()),
+            journal,
+        };
+
+        super::Reducer::FeeStats(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::Config;
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::CRDTCommand;
+    use crate::reducers::{dedup, Reducer};
+
+    fn reduce(config: Config) -> (Vec<CRDTCommand>, Vec<CRDTCommand>) {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 100);
+        let mut output = dedup::Output::new(port, false);
+
+        let mut reducer = match config.plugin(&ChainWellKnownInfo::mainnet()) {
+            Reducer::FeeStats(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer.reduce_block(&block, &mut output).unwrap();
+
+        let sent = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        assert!(reducer.covers_rollback(&before));
+
+        (sent, reducer.roll_back(&before))
+    }
+
+    /// Totals by member of the epoch hash counters
+    fn totals<'a>(cmds: impl Iterator<Item = &'a CRDTCommand>) -> HashMap<String, i64> {
+        let mut totals = HashMap::new();
+
+        for cmd in cmds {
+            if let CRDTCommand::HashCounter(member, _, delta) = cmd {
+                *totals.entry(member.clone()).or_default() += delta;
+            }
+        }
+
+        totals
+    }
+
+    #[test]
+    fn block_fees_are_summed() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let (sent, _) = reduce(Config {
+            key_prefix: None,
+            key_separator: None,
+            deposits: None,
+            rollback_window: None,
+        });
+
+        let fees: u64 = block
+            .txs()
+            .iter()
+            .filter(|tx| tx.is_valid())
+            .map(|tx| tx.fee().unwrap_or_default())
+            .sum();
+
+        let block_key = format!("fee_stats.block.{}", block.hash());
+
+        match &sent[0] {
+            CRDTCommand::LastWriteWins(key, crate::model::Value::Json(value), _) => {
+                assert_eq!(*key, block_key);
+                assert_eq!(value["fees"], fees);
+                assert_eq!(value["slot"], block.slot());
+            }
+            x => panic!("expected the block value, got {:?}", x),
+        }
+
+        let totals = totals(sent.iter());
+
+        assert_eq!(totals["fees"], fees as i64);
+        assert_eq!(totals.len(), 2);
+    }
+
+    #[test]
+    fn rollback_deletes_block_value_and_subtracts_totals() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let (sent, undo) = reduce(Config {
+            key_prefix: None,
+            key_separator: None,
+            deposits: None,
+            rollback_window: None,
+        });
+
+        let block_key = format!("fee_stats.block.{}", block.hash());
+
+        assert!(matches!(&undo[0], CRDTCommand::DeleteKey(key) if *key == block_key));
+
+        let reverted = totals(sent.iter().chain(undo.iter()));
+
+        assert_eq!(reverted.len(), 2);
+        assert!(reverted.values().all(|x| *x == 0));
+    }
+
+    #[test]
+    fn rollback_subtracts_deposits_and_refunds() {
+        let (sent, undo) = reduce(Config {
+            key_prefix: None,
+            key_separator: None,
+            deposits: Some(Default::default()),
+            rollback_window: None,
+        });
+
+        assert!(totals(sent.iter()).contains_key("deposits"));
+        assert!(totals(sent.iter()).contains_key("refunds"));
+
+        let reverted = totals(sent.iter().chain(undo.iter()));
+
+        assert_eq!(reverted.len(), 4);
+        assert!(reverted.values().all(|x| *x == 0));
+    }
+}
@@ -13,12 +13,23 @@ use crate::{crosscut, model, prelude::*};
 pub struct Config {
     pub filter: Vec<String>,
     pub prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub address_as_key: Option<bool>,
+
+    /// Outputs carrying more native assets than this are skipped, see
+    /// `crosscut::assets::AssetLimit`
+    pub max_assets_per_output: Option<usize>,
+
+    /// Store the datum rendered by `crosscut::plutus::datum_to_json` under
+    /// `datum_json`, along with its hex cbor. Changing this on an existing
+    /// collection leaves members that can no longer be removed.
+    pub include_datum_json: Option<bool>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) asset_limit: crosscut::assets::AssetLimit,
 }
 
 pub fn resolve_datum(utxo: &MultiEraOutput, tx: &MultiEraTx) -> Result<PlutusData, ()> {
@@ -43,9 +54,19 @@ impl Reducer {
         utxo: &MultiEraOutput,
         tx: &MultiEraTx,
         output_ref: &(Hash<32>, u64),
+        produced: bool,
     ) -> Option<(String, String)> {
         if let Some(address) = utxo.address().map(|addr| addr.to_string()).ok() {
             if self.config.filter.iter().any(|addr| address.eq(addr)) {
+                let admitted = match produced {
+                    true => self.asset_limit.admits(utxo),
+                    false => self.asset_limit.admits_spent(utxo),
+                };
+
+                if !admitted {
+                    return None;
+                }
+
                 let mut data = serde_json::Value::Object(serde_json::Map::new());
                 let address_as_key = self.config.address_as_key.unwrap_or(false);
                 let key: String;
@@ -56,11 +77,15 @@ impl Reducer {
                     data["output_index"] =
                         serde_json::Value::from(serde_json::Number::from(output_ref.1));
                 } else {
-                    key = format!("{}#{}", hex::encode(output_ref.0.to_vec()), output_ref.1);
+                    key = crosscut::refs::output_key(&output_ref.0, output_ref.1);
                     data["address"] = serde_json::Value::String(address);
                 }
 
                 if let Some(datum) = resolve_datum(utxo, tx).ok() {
+                    if self.config.include_datum_json.unwrap_or(false) {
+                        data["datum_json"] = crosscut::plutus::datum_to_json(&datum);
+                    }
+
                     data["datum"] = serde_json::Value::String(hex::encode(
                         datum.encode_fragment().ok().unwrap(),
                     ));
@@ -101,13 +126,19 @@ impl Reducer {
         ctx: &model::BlockContext,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let prefix = self.config.prefix.as_deref();
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        );
         for tx in block.txs().into_iter() {
             for consumed in tx.consumes().iter().map(|i| i.output_ref()) {
                 if let Some(Some(utxo)) = ctx.find_utxo(&consumed).apply_policy(&self.policy).ok() {
-                    if let Some((key, value)) =
-                        self.get_key_value(&utxo, &tx, &(consumed.hash().clone(), consumed.index()))
-                    {
+                    if let Some((key, value)) = self.get_key_value(
+                        &utxo,
+                        &tx,
+                        &(consumed.hash().clone(), consumed.index()),
+                        false,
+                    ) {
                         output.send(
                             model::CRDTCommand::set_remove(prefix, &key.as_str(), value).into(),
                         )?;
@@ -117,8 +148,8 @@ impl Reducer {
 
             for (index, produced) in tx.produces() {
                 let output_ref = (tx.hash().clone(), index as u64);
-                if let Some((key, value)) = self.get_key_value(&produced, &tx, &output_ref) {
-                    output.send(model::CRDTCommand::set_add(None, &key, value).into())?;
+                if let Some((key, value)) = self.get_key_value(&produced, &tx, &output_ref, true) {
+                    output.send(model::CRDTCommand::set_add(crosscut::keys::Prefix::new(None, None), &key, value).into())?;
                 }
             }
         }
@@ -129,9 +160,12 @@ impl Reducer {
 
 impl Config {
     pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let asset_limit = crosscut::assets::AssetLimit::new(self.max_assets_per_output);
+
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            asset_limit,
         };
 
         super::Reducer::FullUtxosByAddress(reducer)
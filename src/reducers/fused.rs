@@ -0,0 +1,125 @@
+use gasket::runtime::{WorkOutcome, Worker as _};
+
+use crate::{crosscut, enrich, model};
+
+use super::worker;
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::RawBlockPayload>;
+
+/// Enrich and reducers in a single stage
+///
+/// The context of each block is resolved by calling the enrich backend
+/// inline, right before the block is reduced, which saves the channel hop
+/// between both stages and the payloads buffered in it. Lookups and reducers
+/// no longer overlap, so this only pays off when the stages wouldn't run in
+/// parallel anyway, i.e. on machines with few cores.
+pub struct Worker {
+    input: InputPort,
+    enrich: enrich::Inline,
+    reducers: worker::Worker,
+    pub(crate) inflight: crosscut::inflight::Inflight,
+}
+
+impl Worker {
+    pub(crate) fn new(input: InputPort, enrich: enrich::Inline, reducers: worker::Worker) -> Self {
+        Worker {
+            input,
+            enrich,
+            reducers,
+            inflight: Default::default(),
+        }
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        let builder = self
+            .enrich
+            .register_metrics(gasket::metrics::Builder::new());
+
+        self.reducers.register_metrics(builder).build()
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        self.enrich.bootstrap()?;
+        self.reducers.bootstrap()
+    }
+
+    fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        self.enrich.teardown()?;
+        self.reducers.teardown()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = match self.input.recv_or_idle() {
+            Err(gasket::error::Error::RecvIdle) => {
+                for payload in self.enrich.release()? {
+                    self.reducers.process(payload)?;
+                }
+
+                return Err(self.reducers.idle());
+            }
+            x => x?,
+        };
+
+        // blocks skipped by the policy are committed as well, see
+        // `sled::Worker::work`
+        let released = self.enrich.enrich(msg.payload)?.unwrap_or_default();
+
+        for payload in released {
+            self.reducers.process(payload)?;
+        }
+
+        self.input.commit();
+        self.inflight.acknowledged();
+
+        Ok(WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::{
+        messaging::{connect_ports, OutputPort, TwoPhaseInputPort},
+        runtime::Worker as _,
+    };
+
+    use super::Worker;
+    use crate::{enrich, model, reducers::worker};
+
+    #[test]
+    fn blocks_are_enriched_and_reduced_in_one_step() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        let reducers = worker::Worker::new(
+            vec![],
+            Default::default(),
+            output,
+            Default::default(),
+            None,
+            None,
+        );
+
+        let mut worker = Worker::new(input, enrich::Inline::Skip, reducers);
+
+        upstream
+            .send(model::RawBlockPayload::roll_forward(cbor))
+            .unwrap();
+
+        worker.work().unwrap();
+
+        let msg = downstream.recv_or_idle().unwrap();
+        assert!(matches!(msg.payload, model::CRDTCommand::BlockStarting(_)));
+
+        let msg = downstream.recv_or_idle().unwrap();
+        assert!(matches!(msg.payload, model::CRDTCommand::BlockFinished(_)));
+    }
+}
@@ -0,0 +1,322 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use pallas::crypto::hash::Hash;
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraOutput};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use crate::{crosscut, model, prelude::*};
+
+const BALANCES_TREE: &str = "holding_balances";
+const UNDO_RING_TREE: &str = "holding_undo_ring";
+
+/// Upper bounds of the ranges used when the config doesn't set any
+const DEFAULT_BOUNDS: [u64; 7] = [1, 10, 100, 1_000, 10_000, 100_000, 1_000_000];
+
+/// Net quantity change per (subject, holder) accumulated over a block
+type BalanceOffsets = BTreeMap<(String, String), i128>;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Path of the sled db where the balance of every holder is kept
+    ///
+    /// The store can't be read back by reducers, so the balances needed to
+    /// tell which range a holder moves out of live in this local db. It must
+    /// grow along with the store: wipe both when re-syncing from scratch.
+    pub db_path: String,
+
+    /// Inclusive upper bounds of the balance ranges, ascending. Defaults to
+    /// 1, 10, 100... up to 1M, which yields ranges `1`, `2-10`, `11-100`...
+    /// and `1000001+`.
+    pub bounds: Option<Vec<u64>>,
+
+    /// Count holders by policy, adding up the assets of the policy, instead
+    /// of by asset fingerprint
+    pub aggregate_by_policy: Option<bool>,
+
+    /// Policies to match
+    ///
+    /// If specified only those policy ids as hex will be taken into account, if
+    /// not all policy ids will be indexed.
+    pub policy_ids_hex: Option<Vec<String>>,
+
+    /// Number of recent blocks whose balance changes are kept to be undone
+    /// on a rollback. Defaults to 2160.
+    pub rollback_window: Option<usize>,
+}
+
+/// Label of the range the balance falls in, none for an empty balance
+fn range_label(bounds: &[u64], balance: i128) -> Option<String> {
+    if balance <= 0 {
+        return None;
+    }
+
+    let mut lower = 1i128;
+
+    for bound in bounds.iter().map(|x| *x as i128) {
+        if balance <= bound {
+            let label = match lower == bound {
+                true => bound.to_string(),
+                false => format!("{}-{}", lower, bound),
+            };
+
+            return Some(label);
+        }
+
+        lower = bound + 1;
+    }
+
+    Some(format!("{}+", lower))
+}
+
+/// Commands moving a holder from the range of its prior balance to the one
+/// of its new balance, none if the range stays the same
+fn range_moves(
+    prefix: crosscut::keys::Prefix,
+    bounds: &[u64],
+    subject: &str,
+    prior: i128,
+    new: i128,
+) -> Vec<model::CRDTCommand> {
+    let from = range_label(bounds, prior);
+    let to = range_label(bounds, new);
+
+    if from == to {
+        return vec![];
+    }
+
+    let mut crdts = vec![];
+
+    if let Some(from) = from {
+        crdts.push(model::CRDTCommand::hash_counter(prefix, subject, from, -1));
+    }
+
+    if let Some(to) = to {
+        crdts.push(model::CRDTCommand::hash_counter(prefix, subject, to, 1));
+    }
+
+    crdts
+}
+
+fn balance_key(subject: &str, holder: &str) -> String {
+    format!("{} {}", subject, holder)
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    policy_ids: Option<Vec<Hash<28>>>,
+    bounds: Vec<u64>,
+    balances: Option<crosscut::balances::Balances>,
+}
+
+impl Reducer {
+    fn is_policy_id_accepted(&self, policy_id: &Hash<28>) -> bool {
+        match &self.policy_ids {
+            Some(pids) => pids.contains(policy_id),
+            None => true,
+        }
+    }
+
+    /// Opens the balances db on first use
+    fn balances(&mut self) -> Result<&mut crosscut::balances::Balances, crate::Error> {
+        if self.balances.is_none() {
+            let db = sled::open(&self.config.db_path).map_err(crate::Error::storage)?;
+            let window = self.config.rollback_window.unwrap_or(2160);
+
+            self.balances = Some(crosscut::balances::Balances::open(
+                &db,
+                BALANCES_TREE,
+                UNDO_RING_TREE,
+                window,
+            )?);
+        }
+
+        Ok(self.balances.as_mut().unwrap())
+    }
+
+    fn add_offsets(
+        &self,
+        utxo: &MultiEraOutput,
+        sign: i128,
+        offsets: &mut BalanceOffsets,
+    ) -> Result<(), gasket::error::Error> {
+        let holder = match self.addresses.key(utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        for asset in utxo.non_ada_assets() {
+            if let Asset::NativeAsset(policy_id, name, quantity) = asset {
+                if !self.is_policy_id_accepted(&policy_id) {
+                    continue;
+                }
+
+                let subject = match self.config.aggregate_by_policy.unwrap_or(false) {
+                    true => policy_id.to_string(),
+                    false => {
+                        crosscut::assets::asset_fingerprint(&policy_id[..], &name).or_panic()?
+                    }
+                };
+
+                *offsets.entry((subject, holder.clone())).or_default() += sign * quantity as i128;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Counts the holders of each asset (or policy) by balance range, as a
+    /// hash of range label to holder count under `{prefix}.{fingerprint}`
+    /// (or `{prefix}.{policy_id}`)
+    ///
+    /// The range a holder leaves depends on its balance before the block,
+    /// which is read from the local db at `db_path`. Balances are only right
+    /// if the reducer ran since before the first mint of the tracked assets,
+    /// negative ones (outputs that predate the db) are counted as empty.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let mut offsets = BalanceOffsets::new();
+
+        for tx in block.txs().iter() {
+            for input in tx.consumes() {
+                let utxo = ctx
+                    .find_utxo(&input.output_ref())
+                    .apply_policy(&self.policy)
+                    .or_panic()?;
+
+                if let Some(utxo) = utxo {
+                    self.add_offsets(&utxo, -1, &mut offsets)?;
+                }
+            }
+
+            for (_, produced) in tx.produces() {
+                self.add_offsets(&produced, 1, &mut offsets)?;
+            }
+        }
+
+        let balances = self.balances().or_retry()?;
+        let mut changes = vec![];
+        let mut moves = vec![];
+
+        for ((subject, holder), delta) in offsets {
+            if delta == 0 {
+                continue;
+            }
+
+            let key = balance_key(&subject, &holder);
+            let prior = balances.get(&key).or_panic()?;
+            let new = (prior + delta).max(0);
+
+            if prior + delta < 0 {
+                log::warn!(
+                    "negative balance of {} for {}, counted as empty",
+                    subject,
+                    holder
+                );
+            }
+
+            moves.push((subject, prior, new));
+            changes.push((key, prior, new));
+        }
+
+        balances
+            .apply(block.slot(), &block.hash().to_vec(), &changes)
+            .or_panic()?;
+
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("holding_distribution");
+
+        for (subject, prior, new) in moves {
+            for crdt in range_moves(prefix, &self.bounds, &subject, prior, new) {
+                output.send(gasket::messaging::Message::from(crdt))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Restores the local balances as of the rollback point
+    ///
+    /// No command is emitted: the reducers stage only rolls back to the last
+    /// point it reduced, which happens after a restart, and the store never
+    /// committed the commands of the blocks past its cursor. Those blocks are
+    /// replayed against the restored balances.
+    pub fn roll_back(&mut self, point: &Point) -> Result<(), crate::Error> {
+        let undone = self.balances()?.undo(point)?;
+        log::info!("reverted holder balances of {} blocks", undone);
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let policy_ids = self.policy_ids_hex.as_ref().map(|pids| {
+            pids.iter()
+                .map(|pid| Hash::<28>::from_str(pid).expect("invalid policy_id"))
+                .collect()
+        });
+
+        let mut bounds = self
+            .bounds
+            .clone()
+            .unwrap_or_else(|| DEFAULT_BOUNDS.to_vec());
+        bounds.sort_unstable();
+        bounds.dedup();
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            policy_ids,
+            bounds,
+            balances: None,
+        };
+
+        super::Reducer::HoldingDistribution(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{range_label, range_moves, DEFAULT_BOUNDS};
+    use crate::crosscut::keys::Prefix;
+    use crate::model::CRDTCommand;
+
+    #[test]
+    fn holders_move_between_ranges() {
+        assert_eq!(range_label(&DEFAULT_BOUNDS, 0), None);
+        assert_eq!(range_label(&DEFAULT_BOUNDS, 1).unwrap(), "1");
+        assert_eq!(range_label(&DEFAULT_BOUNDS, 10).unwrap(), "2-10");
+        assert_eq!(range_label(&DEFAULT_BOUNDS, 11).unwrap(), "11-100");
+        assert_eq!(range_label(&DEFAULT_BOUNDS, 5_000_000).unwrap(), "1000001+");
+
+        let prefix = Prefix::new(Some("hd"), None);
+
+        assert!(range_moves(prefix, &DEFAULT_BOUNDS, "asset", 20, 30).is_empty());
+
+        let crdts = range_moves(prefix, &DEFAULT_BOUNDS, "asset", 5, 500);
+        assert_eq!(crdts.len(), 2);
+        assert!(
+            matches!(&crdts[0], CRDTCommand::HashCounter(m, k, -1) if m == "2-10" && k == "hd.asset")
+        );
+        assert!(matches!(&crdts[1], CRDTCommand::HashCounter(m, _, 1) if m == "101-1000"));
+
+        // a holder spending everything leaves the distribution
+        let crdts = range_moves(prefix, &DEFAULT_BOUNDS, "asset", 5, 0);
+        assert!(matches!(&crdts[..], [CRDTCommand::HashCounter(_, _, -1)]));
+    }
+}
@@ -0,0 +1,150 @@
+//! Pre-screening of the txs handed to each reducer
+//!
+//! Most reducers only act on a specific kind of tx (mints, certificates...)
+//! and do nothing for the others. Each reducer declares the kinds it cares
+//! about, the worker tells the kinds of each tx once using cheap
+//! `MultiEraTx` accessors, hands each reducer the txs of its kinds and skips
+//! it when there's none.
+
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxInterest {
+    /// Every block is reduced, the default
+    Any,
+    Mints,
+    Certs,
+    Metadata,
+    Withdrawals,
+    /// Txs that failed phase-2 validation and forfeited their collateral
+    Invalid,
+}
+
+impl TxInterest {
+    pub fn matches(&self, tx: &MultiEraTx) -> bool {
+        match self {
+            TxInterest::Any => true,
+            TxInterest::Mints => tx.mint().len() > 0,
+            TxInterest::Certs => !tx.certs().is_empty(),
+            TxInterest::Metadata => tx
+                .metadata()
+                .as_alonzo()
+                .map(|x| !x.is_empty())
+                .unwrap_or(false),
+            TxInterest::Withdrawals => !tx.withdrawals().collect::<Vec<_>>().is_empty(),
+            TxInterest::Invalid => !tx.is_valid(),
+        }
+    }
+}
+
+/// Kinds of a tx
+#[derive(Debug, Default, Clone, Copy)]
+pub struct TxKinds {
+    mints: bool,
+    certs: bool,
+    metadata: bool,
+    withdrawals: bool,
+    invalid: bool,
+}
+
+impl TxKinds {
+    pub fn of(tx: &MultiEraTx) -> Self {
+        Self {
+            mints: TxInterest::Mints.matches(tx),
+            certs: TxInterest::Certs.matches(tx),
+            metadata: TxInterest::Metadata.matches(tx),
+            withdrawals: TxInterest::Withdrawals.matches(tx),
+            invalid: TxInterest::Invalid.matches(tx),
+        }
+    }
+
+    /// True if the tx is of any of the kinds
+    pub fn wants(&self, interests: &[TxInterest]) -> bool {
+        interests.iter().any(|x| match x {
+            TxInterest::Any => true,
+            TxInterest::Mints => self.mints,
+            TxInterest::Certs => self.certs,
+            TxInterest::Metadata => self.metadata,
+            TxInterest::Withdrawals => self.withdrawals,
+            TxInterest::Invalid => self.invalid,
+        })
+    }
+}
+
+/// Txs of a block along with their kinds, told once for every reducer
+pub struct ScreenedTxs<'b> {
+    txs: Vec<MultiEraTx<'b>>,
+    kinds: Vec<TxKinds>,
+}
+
+impl<'b> ScreenedTxs<'b> {
+    pub fn of(block: &MultiEraBlock<'b>) -> Self {
+        let txs = block.txs();
+        let kinds = txs.iter().map(TxKinds::of).collect();
+
+        Self { txs, kinds }
+    }
+
+    /// Txs of any of the kinds, in block order
+    pub fn pick(&self, interests: &[TxInterest]) -> Vec<&MultiEraTx<'b>> {
+        self.txs
+            .iter()
+            .zip(self.kinds.iter())
+            .filter(|(_, kinds)| kinds.wants(interests))
+            .map(|(tx, _)| tx)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+
+    use super::{ScreenedTxs, TxInterest, TxKinds};
+
+    #[test]
+    fn metadata_reducers_skip_plain_payments() {
+        // a plain payment: no mint, cert, metadata or withdrawal
+        let payment = TxKinds::default();
+
+        assert!(!payment.wants(&[TxInterest::Metadata]));
+        assert!(!payment.wants(&[TxInterest::Withdrawals, TxInterest::Certs]));
+        assert!(payment.wants(&[TxInterest::Any]));
+    }
+
+    #[test]
+    fn reducers_only_get_the_txs_of_their_kinds() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let txs = block.txs();
+
+        let screened = ScreenedTxs::of(&block);
+
+        let hashes = |txs: Vec<&MultiEraTx>| -> Vec<_> { txs.iter().map(|x| x.hash()).collect() };
+
+        // the block mixes txs with and without metadata
+        let with_metadata: Vec<_> = txs
+            .iter()
+            .filter(|x| TxInterest::Metadata.matches(x))
+            .collect();
+
+        assert!(!with_metadata.is_empty());
+        assert!(with_metadata.len() < txs.len());
+
+        assert_eq!(
+            hashes(screened.pick(&[TxInterest::Metadata])),
+            hashes(with_metadata)
+        );
+
+        // the one mint of the block
+        let mints = screened.pick(&[TxInterest::Mints]);
+        assert_eq!(mints.len(), 1);
+        assert!(mints[0].mint().len() > 0);
+
+        // any kind gets every tx, in order
+        assert_eq!(
+            hashes(screened.pick(&[TxInterest::Any])),
+            hashes(txs.iter().collect())
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Commands undoing the latest blocks of a reducer
+//!
+//! Reducers handling rollbacks record, along with each block they reduce, the
+//! commands that undo its writes (counters negated, list items popped, ...).
+//! On a rollback, the reducers stage sends the ones of the blocks past the
+//! rollback point, newest first, in a block of their own right after the
+//! `RollbackMarker`. It only does so when every reducer covers the point and
+//! the output doesn't hold the blocks itself, see `Worker::roll_back`.
+
+use std::collections::VecDeque;
+
+use pallas::network::miniprotocols::Point;
+
+use crate::model::CRDTCommand;
+
+/// Number of blocks kept when the reducer config doesn't say
+pub const DEFAULT_WINDOW: usize = 2160;
+
+/// Command undoing a counter delta, none for the commands that can't be
+/// undone without knowing what the key held before
+pub fn inverse(cmd: &CRDTCommand) -> Option<CRDTCommand> {
+    match cmd.clone() {
+        CRDTCommand::PNCounter(key, delta) => Some(CRDTCommand::PNCounter(key, -delta)),
+        CRDTCommand::HashCounter(member, key, delta) => {
+            Some(CRDTCommand::HashCounter(member, key, -delta))
+        }
+        CRDTCommand::SortedSetAdd(key, member, delta) => {
+            Some(CRDTCommand::SortedSetRemove(key, member, -delta))
+        }
+        CRDTCommand::SortedSetRemove(key, member, delta) => {
+            Some(CRDTCommand::SortedSetAdd(key, member, -delta))
+        }
+        _ => None,
+    }
+}
+
+pub struct Journal {
+    /// Slot of each recorded block along with the commands undoing it,
+    /// oldest first
+    blocks: VecDeque<(u64, Vec<CRDTCommand>)>,
+    /// Slot past which every block was recorded, if known
+    since: Option<u64>,
+    window: usize,
+}
+
+impl Journal {
+    pub fn new(window: Option<usize>) -> Self {
+        Self {
+            blocks: VecDeque::new(),
+            since: None,
+            window: window.unwrap_or(DEFAULT_WINDOW).max(1),
+        }
+    }
+
+    /// Records the commands undoing the block at the slot
+    ///
+    /// Blocks with nothing to undo have to be recorded too, the journal can't
+    /// tell them from the blocks it missed.
+    pub fn record(&mut self, slot: u64, undo: Vec<CRDTCommand>) {
+        self.blocks.push_back((slot, undo));
+
+        while self.blocks.len() > self.window {
+            if let Some((dropped, _)) = self.blocks.pop_front() {
+                self.since = Some(dropped);
+            }
+        }
+    }
+
+    /// True if every block past the point was recorded
+    ///
+    /// Blocks are reduced in order: past the rollback the journal started
+    /// from, or else its oldest block, all of them are there.
+    pub fn covers(&self, point: &Point) -> bool {
+        match self.since.or_else(|| self.blocks.front().map(|(x, _)| *x)) {
+            Some(since) => since <= point.slot_or_default(),
+            None => false,
+        }
+    }
+
+    /// Takes the commands undoing the blocks past the point, newest block
+    /// first
+    ///
+    /// Once the rollback is honored, the store is back at the point and the
+    /// journal covers it from then on, which is what happens on startup when
+    /// the source finds the intersection.
+    pub fn roll_back(&mut self, point: &Point) -> Vec<CRDTCommand> {
+        let slot = point.slot_or_default();
+        let keep = self.blocks.iter().take_while(|(x, _)| *x <= slot).count();
+
+        self.since = Some(self.since.map_or(slot, |x| x.min(slot)));
+
+        self.blocks
+            .split_off(keep)
+            .into_iter()
+            .rev()
+            .flat_map(|(_, undo)| undo)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::Journal;
+    use crate::model::CRDTCommand;
+
+    fn undo(slot: u64) -> Vec<CRDTCommand> {
+        vec![CRDTCommand::PNCounter(format!("c{}", slot), -1)]
+    }
+
+    #[test]
+    fn blocks_past_the_point_are_undone_newest_first() {
+        let mut journal = Journal::new(Some(3));
+
+        for slot in 1..=4 {
+            journal.record(slot, undo(slot));
+        }
+
+        // block 1 fell out of the window, it can't be undone anymore
+        assert!(!journal.covers(&Point::Origin));
+        assert!(journal.covers(&Point::Specific(1, vec![1u8; 32])));
+
+        let undone: Vec<_> = journal
+            .roll_back(&Point::Specific(2, vec![2u8; 32]))
+            .iter()
+            .map(|x| format!("{:?}", x))
+            .collect();
+
+        let expected: Vec<_> = undo(4)
+            .iter()
+            .chain(undo(3).iter())
+            .map(|x| format!("{:?}", x))
+            .collect();
+
+        assert_eq!(undone, expected);
+
+        // the undone blocks are gone, a second rollback has nothing to send
+        assert!(journal
+            .roll_back(&Point::Specific(2, vec![2u8; 32]))
+            .is_empty());
+        assert!(journal.covers(&Point::Specific(2, vec![2u8; 32])));
+    }
+
+    #[test]
+    fn journal_covers_blocks_since_intersection() {
+        let mut journal = Journal::new(None);
+
+        assert!(!journal.covers(&Point::Origin));
+        assert!(!journal.covers(&Point::Specific(10, vec![1u8; 32])));
+
+        // found on startup, nothing to undo yet
+        assert!(journal
+            .roll_back(&Point::Specific(10, vec![1u8; 32]))
+            .is_empty());
+
+        journal.record(11, undo(11));
+
+        assert!(!journal.covers(&Point::Specific(9, vec![1u8; 32])));
+
+        let undone = journal.roll_back(&Point::Specific(10, vec![1u8; 32]));
+        assert_eq!(undone.len(), 1);
+    }
+}
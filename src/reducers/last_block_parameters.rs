@@ -1,4 +1,4 @@
-use pallas::ledger::traverse::MultiEraBlock;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraHeader};
 use serde::Deserialize;
 
 use crate::crosscut::epochs::block_epoch;
@@ -8,6 +8,7 @@ use crate::{crosscut, model};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
 }
 
 pub struct Reducer {
@@ -15,17 +16,27 @@ pub struct Reducer {
     chain: crosscut::ChainWellKnownInfo,
 }
 
+/// Protocol (major, minor) version announced by the block header, only
+/// available from Shelley onwards
+fn protocol_version(block: &MultiEraBlock) -> Option<(u64, u64)> {
+    match block.header() {
+        MultiEraHeader::AlonzoCompatible(x) => Some((x.header_body.protocol_major, x.header_body.protocol_minor)),
+        MultiEraHeader::Babbage(x) => Some(x.header_body.protocol_version),
+        _ => None,
+    }
+}
+
 impl Reducer {
 
     pub fn current_epoch(
-        &mut self,
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         let epoch_no = block_epoch(&self.chain, block);
 
-        let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "epoch_no"), Value::BigInt(epoch_no as i128));
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["epoch_no"]), Value::BigInt(epoch_no as i128));
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
@@ -33,12 +44,12 @@ impl Reducer {
     } 
 
     pub fn current_height(
-        &mut self,
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "height"), Value::BigInt(block.number() as i128));
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["height"]), Value::BigInt(block.number() as i128));
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
@@ -46,12 +57,12 @@ impl Reducer {
     } 
 
     pub fn current_slot(
-        &mut self,
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "slot_no"), Value::BigInt(block.slot() as i128));
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["slot_no"]), Value::BigInt(block.slot() as i128));
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
@@ -59,12 +70,12 @@ impl Reducer {
     } 
 
     pub fn current_block_hash(
-        &mut self,
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "block_hash"), Value::String(block.hash().to_string()));
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["block_hash"]), Value::String(block.hash().to_string()));
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
@@ -72,30 +83,49 @@ impl Reducer {
     } 
 
     pub fn current_block_era(
-        &mut self,
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "block_era"), Value::String(block.era().to_string()));
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["block_era"]), Value::String(block.era().to_string()));
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
         Result::Ok(())
     } 
 
+    pub fn current_protocol_version(
+        &self,
+        block: &MultiEraBlock,
+        prefix: crosscut::keys::Prefix,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        if let Some((major, minor)) = protocol_version(block) {
+            let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["protocol_major"]), Value::BigInt(major as i128));
+
+            output.send(gasket::messaging::Message::from(crdt))?;
+
+            let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["protocol_minor"]), Value::BigInt(minor as i128));
+
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Result::Ok(())
+    }
+
     pub fn current_block_last_tx_hash(
-        &mut self,
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         if !block.is_empty() {
-            let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "first_transaction_hash"), Value::String(block.txs().first().unwrap().hash().to_string()));
+            let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["first_transaction_hash"]), Value::String(block.txs().first().unwrap().hash().to_string()));
     
             output.send(gasket::messaging::Message::from(crdt))?;
 
-            let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "last_transaction_hash"), Value::String(block.txs().last().unwrap().hash().to_string()));
+            let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["last_transaction_hash"]), Value::String(block.txs().last().unwrap().hash().to_string()));
     
             output.send(gasket::messaging::Message::from(crdt))?;
         }
@@ -104,18 +134,52 @@ impl Reducer {
     }
 
     pub fn current_block_last_tx_count(
-        &mut self,
+        &self,
+        block: &MultiEraBlock,
+        prefix: crosscut::keys::Prefix,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["transactions_count"]), Value::BigInt(block.tx_count() as i128));
+
+        output.send(gasket::messaging::Message::from(crdt))?;
+
+        Result::Ok(())
+    }
+
+    pub fn current_block_size(
+        &self,
+        block: &MultiEraBlock,
+        prefix: crosscut::keys::Prefix,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["block_size"]), Value::BigInt(block.encode().len() as i128));
+
+        output.send(gasket::messaging::Message::from(crdt))?;
+
+        Result::Ok(())
+    }
+
+    pub fn current_block_fees(
+        &self,
         block: &MultiEraBlock,
-        key: &str,
+        prefix: crosscut::keys::Prefix,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let crdt = model::CRDTCommand::AnyWriteWins(format!("{}.{}", key, "transactions_count"), Value::BigInt(block.tx_count() as i128));
+        let fees: u64 = block.txs().iter().map(|tx| tx.fee().unwrap_or_default()).sum();
+
+        let crdt = model::CRDTCommand::AnyWriteWins(prefix.key(&["fees"]), Value::BigInt(fees as i128));
 
         output.send(gasket::messaging::Message::from(crdt))?;
 
         Result::Ok(())
     }
 
+    /// Overwrites the parameters of the last block, each under
+    /// `{prefix}.{member}`: `epoch_no`, `height`, `slot_no`, `block_hash`,
+    /// `block_era`, `protocol_major` / `protocol_minor` (Shelley onwards),
+    /// `first_transaction_hash` / `last_transaction_hash` (non-empty blocks),
+    /// `transactions_count`, `block_size` (bytes of the block cbor) and `fees`
+    /// (lovelace, summed over the txs of the block).
     pub fn reduce_block<'b>(
         &mut self,
         block: &'b MultiEraBlock<'b>,
@@ -124,18 +188,18 @@ impl Reducer {
 
         let def_key_prefix = "last_block";
 
-        let key = match &self.config.key_prefix {
-            Some(prefix) => format!("{}", prefix),
-            None => format!("{}", def_key_prefix.to_string()),
-        };
-
-        self.current_epoch(block, &key, output)?;
-        self.current_height(block, &key, output)?;
-        self.current_slot(block, &key, output)?;
-        self.current_block_hash(block, &key, output)?;
-        self.current_block_era(block, &key, output)?;
-        self.current_block_last_tx_hash(block, &key, output)?;
-        self.current_block_last_tx_count(block, &key, output)?;
+        let prefix = crosscut::keys::Prefix::new(self.config.key_prefix.as_deref(), self.config.key_separator.as_deref()).or(def_key_prefix);
+
+        self.current_epoch(block, prefix, output)?;
+        self.current_height(block, prefix, output)?;
+        self.current_slot(block, prefix, output)?;
+        self.current_block_hash(block, prefix, output)?;
+        self.current_block_era(block, prefix, output)?;
+        self.current_protocol_version(block, prefix, output)?;
+        self.current_block_last_tx_hash(block, prefix, output)?;
+        self.current_block_last_tx_count(block, prefix, output)?;
+        self.current_block_size(block, prefix, output)?;
+        self.current_block_fees(block, prefix, output)?;
 
         Ok(())
     }
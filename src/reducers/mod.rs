@@ -1,88 +1,181 @@
 use std::time::Duration;
 
 use gasket::runtime::spawn_stage;
-use pallas::ledger::traverse::MultiEraBlock;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use pallas::network::miniprotocols::Point;
 use serde::Deserialize;
 
-use crate::{bootstrap, crosscut, model};
+use crate::{bootstrap, crosscut, enrich, model, storage};
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::EnrichedBlockPayload>;
-type OutputPort = gasket::messaging::OutputPort<model::CRDTCommand>;
+type OutputPort = dedup::Output;
 
+pub mod confirmation;
+pub mod dedup;
+pub mod epoch_commit;
+#[cfg(feature = "full_utxos_by_address")]
 pub mod full_utxos_by_address;
+mod fused;
+pub mod interest;
+pub mod journal;
 pub mod macros;
+#[cfg(feature = "point_by_tx")]
 pub mod point_by_tx;
+#[cfg(feature = "policy_tx_count")]
+pub mod policy_tx_count;
+#[cfg(feature = "pool_by_stake")]
 pub mod pool_by_stake;
+#[cfg(feature = "utxo_by_address")]
 pub mod utxo_by_address;
 mod worker;
 
-#[cfg(feature = "unstable")]
+#[cfg(feature = "address_activity")]
+pub mod address_activity;
+#[cfg(feature = "address_ada_flow")]
+pub mod address_ada_flow;
+#[cfg(feature = "address_by_asset")]
 pub mod address_by_asset;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "address_by_txo")]
 pub mod address_by_txo;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "address_networth")]
+pub mod address_networth;
+#[cfg(feature = "addresses_by_stake")]
 pub mod addresses_by_stake;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "asset_holders_by_asset_id")]
 pub mod asset_holders_by_asset_id;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "asset_transfers")]
+pub mod asset_transfers;
+#[cfg(feature = "balance_by_address")]
 pub mod balance_by_address;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "block_header_by_hash")]
 pub mod block_header_by_hash;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "chain_digest")]
+pub mod chain_digest;
+#[cfg(feature = "collateral_events")]
+pub mod collateral_events;
+#[cfg(feature = "epoch_stats")]
+pub mod epoch_stats;
+#[cfg(feature = "fee_stats")]
+pub mod fee_stats;
+#[cfg(feature = "holding_distribution")]
+pub mod holding_distribution;
+#[cfg(feature = "last_block_parameters")]
 pub mod last_block_parameters;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "native_script_addresses")]
+pub mod native_script_addresses;
+#[cfg(feature = "passthrough")]
+pub mod passthrough;
+#[cfg(feature = "pool_reserves")]
+pub mod pool_reserves;
+#[cfg(feature = "recent_blocks")]
+pub mod recent_blocks;
+#[cfg(feature = "reward_activity")]
+pub mod reward_activity;
+#[cfg(feature = "script_activity")]
+pub mod script_activity;
+#[cfg(feature = "supply_by_asset")]
 pub mod supply_by_asset;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "token_registry")]
+pub mod token_registry;
+#[cfg(feature = "tx_by_hash")]
 pub mod tx_by_hash;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "tx_count_by_address")]
 pub mod tx_count_by_address;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "tx_count_by_native_token_policy_id")]
 pub mod tx_count_by_native_token_policy_id;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "tx_graph")]
+pub mod tx_graph;
+#[cfg(feature = "tx_size_stats")]
+pub mod tx_size_stats;
+#[cfg(feature = "utxo_by_stake")]
 pub mod utxo_by_stake;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "utxo_count")]
+pub mod utxo_count;
+#[cfg(feature = "utxos_by_asset")]
 pub mod utxos_by_asset;
-#[cfg(feature = "unstable")]
-pub mod addresses_by_stake;
-#[cfg(feature = "unstable")]
+#[cfg(feature = "asset_metadata")]
 pub mod asset_metadata;
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 pub enum Config {
+    #[cfg(feature = "full_utxos_by_address")]
     FullUtxosByAddress(full_utxos_by_address::Config),
+    #[cfg(feature = "utxo_by_address")]
     UtxoByAddress(utxo_by_address::Config),
+    #[cfg(feature = "point_by_tx")]
     PointByTx(point_by_tx::Config),
+    #[cfg(feature = "pool_by_stake")]
     PoolByStake(pool_by_stake::Config),
 
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "address_by_txo")]
     AddressByTxo(address_by_txo::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "balance_by_address")]
     BalanceByAddress(balance_by_address::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_by_hash")]
     TxByHash(tx_by_hash::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_count_by_address")]
     TxCountByAddress(tx_count_by_address::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "block_header_by_hash")]
     BlockHeaderByHash(block_header_by_hash::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "address_by_asset")]
     AddressByAsset(address_by_asset::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "address_ada_flow")]
+    AddressAdaFlow(address_ada_flow::Config),
+    #[cfg(feature = "last_block_parameters")]
     LastBlockParameters(last_block_parameters::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_count_by_native_token_policy_id")]
     TxCountByNativeTokenPolicyId(tx_count_by_native_token_policy_id::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_size_stats")]
+    TxSizeStats(tx_size_stats::Config),
+    #[cfg(feature = "epoch_stats")]
+    EpochStats(epoch_stats::Config),
+    #[cfg(feature = "asset_holders_by_asset_id")]
     AssetHoldersByAsset(asset_holders_by_asset_id::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "utxos_by_asset")]
     UtxosByAsset(utxos_by_asset::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "utxo_by_stake")]
     UtxoByStake(utxo_by_stake::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "supply_by_asset")]
     SupplyByAsset(supply_by_asset::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "addresses_by_stake")]
     AddressesByStake(addresses_by_stake::Config),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "asset_metadata")]
     AssetMetadata(asset_metadata::Config),
+    #[cfg(feature = "pool_reserves")]
+    PoolReserves(pool_reserves::Config),
+    #[cfg(feature = "script_activity")]
+    ScriptActivity(script_activity::Config),
+    #[cfg(feature = "reward_activity")]
+    RewardActivity(reward_activity::Config),
+    #[cfg(feature = "utxo_count")]
+    UtxoCount(utxo_count::Config),
+    #[cfg(feature = "fee_stats")]
+    FeeStats(fee_stats::Config),
+    #[cfg(feature = "address_activity")]
+    AddressActivity(address_activity::Config),
+    #[cfg(feature = "recent_blocks")]
+    RecentBlocks(recent_blocks::Config),
+    #[cfg(feature = "token_registry")]
+    TokenRegistry(token_registry::Config),
+    #[cfg(feature = "native_script_addresses")]
+    NativeScriptAddresses(native_script_addresses::Config),
+    #[cfg(feature = "passthrough")]
+    Passthrough(passthrough::Config),
+    #[cfg(feature = "holding_distribution")]
+    HoldingDistribution(holding_distribution::Config),
+    #[cfg(feature = "chain_digest")]
+    ChainDigest(chain_digest::Config),
+    #[cfg(feature = "address_networth")]
+    AddressNetworth(address_networth::Config),
+    #[cfg(feature = "tx_graph")]
+    TxGraph(tx_graph::Config),
+    #[cfg(feature = "policy_tx_count")]
+    PolicyTxCount(policy_tx_count::Config),
+    #[cfg(feature = "asset_transfers")]
+    AssetTransfers(asset_transfers::Config),
+    #[cfg(feature = "collateral_events")]
+    CollateralEvents(collateral_events::Config),
 }
 
 impl Config {
@@ -92,48 +185,102 @@ impl Config {
         policy: &crosscut::policies::RuntimePolicy,
     ) -> Reducer {
         match self {
+            #[cfg(feature = "full_utxos_by_address")]
             Config::FullUtxosByAddress(c) => c.plugin(policy),
+            #[cfg(feature = "utxo_by_address")]
             Config::UtxoByAddress(c) => c.plugin(policy),
+            #[cfg(feature = "point_by_tx")]
             Config::PointByTx(c) => c.plugin(),
+            #[cfg(feature = "pool_by_stake")]
             Config::PoolByStake(c) => c.plugin(),
 
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "address_by_txo")]
             Config::AddressByTxo(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "balance_by_address")]
             Config::BalanceByAddress(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_by_hash")]
             Config::TxByHash(c) => c.plugin(chain, policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_count_by_address")]
             Config::TxCountByAddress(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "block_header_by_hash")]
             Config::BlockHeaderByHash(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
-            Config::AddressByAsset(c) => c.plugin(),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "address_by_asset")]
+            Config::AddressByAsset(c) => c.plugin(policy),
+            #[cfg(feature = "address_ada_flow")]
+            Config::AddressAdaFlow(c) => c.plugin(chain, policy),
+            #[cfg(feature = "last_block_parameters")]
             Config::LastBlockParameters(c) => c.plugin(chain),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_count_by_native_token_policy_id")]
             Config::TxCountByNativeTokenPolicyId(c) => c.plugin(chain),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_size_stats")]
+            Config::TxSizeStats(c) => c.plugin(chain),
+            #[cfg(feature = "epoch_stats")]
+            Config::EpochStats(c) => c.plugin(chain),
+            #[cfg(feature = "asset_holders_by_asset_id")]
             Config::AssetHoldersByAsset(c) => c.plugin(chain, policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "utxos_by_asset")]
             Config::UtxosByAsset(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "utxo_by_stake")]
             Config::UtxoByStake(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "supply_by_asset")]
             Config::SupplyByAsset(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "addresses_by_stake")]
             Config::AddressesByStake(c) => c.plugin(policy),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "asset_metadata")]
             Config::AssetMetadata(c) => c.plugin(chain, policy),
+            #[cfg(feature = "pool_reserves")]
+            Config::PoolReserves(c) => c.plugin(policy),
+            #[cfg(feature = "script_activity")]
+            Config::ScriptActivity(c) => c.plugin(chain, policy),
+            #[cfg(feature = "reward_activity")]
+            Config::RewardActivity(c) => c.plugin(policy),
+            #[cfg(feature = "utxo_count")]
+            Config::UtxoCount(c) => c.plugin(chain),
+            #[cfg(feature = "fee_stats")]
+            Config::FeeStats(c) => c.plugin(chain),
+            #[cfg(feature = "address_activity")]
+            Config::AddressActivity(c) => c.plugin(chain, policy),
+            #[cfg(feature = "recent_blocks")]
+            Config::RecentBlocks(c) => c.plugin(),
+            #[cfg(feature = "token_registry")]
+            Config::TokenRegistry(c) => c.plugin(),
+            #[cfg(feature = "native_script_addresses")]
+            Config::NativeScriptAddresses(c) => c.plugin(policy),
+            #[cfg(feature = "passthrough")]
+            Config::Passthrough(c) => c.plugin(policy),
+            #[cfg(feature = "holding_distribution")]
+            Config::HoldingDistribution(c) => c.plugin(policy),
+            #[cfg(feature = "chain_digest")]
+            Config::ChainDigest(c) => c.plugin(),
+            #[cfg(feature = "address_networth")]
+            Config::AddressNetworth(c) => c.plugin(policy),
+            #[cfg(feature = "tx_graph")]
+            Config::TxGraph(c) => c.plugin(policy),
+            #[cfg(feature = "policy_tx_count")]
+            Config::PolicyTxCount(c) => c.plugin(chain, policy),
+            #[cfg(feature = "asset_transfers")]
+            Config::AssetTransfers(c) => c.plugin(policy),
+            #[cfg(feature = "collateral_events")]
+            Config::CollateralEvents(c) => c.plugin(policy),
         }
     }
 }
 
 pub struct Bootstrapper {
     input: InputPort,
-    output: OutputPort,
+    output: gasket::messaging::OutputPort<model::CRDTCommand>,
     reducers: Vec<Reducer>,
+    chain: crosscut::ChainWellKnownInfo,
     policy: crosscut::policies::RuntimePolicy,
+    decode_ahead: Option<usize>,
+    decode_pool_size: Option<usize>,
+    sample_every: Option<u64>,
+    dedup_block_commands: bool,
+    confirmation: Option<confirmation::Config>,
+    epoch_commit: Option<epoch_commit::Config>,
+    idle_sleep: Option<Duration>,
+    duplicate_block_window: Option<usize>,
+    recent_blocks_cursor: Option<storage::Cursor>,
 }
 
 impl Bootstrapper {
@@ -141,6 +288,8 @@ impl Bootstrapper {
         configs: Vec<Config>,
         chain: &crosscut::ChainWellKnownInfo,
         policy: &crosscut::policies::RuntimePolicy,
+        decode_ahead: Option<usize>,
+        decode_pool_size: Option<usize>,
     ) -> Self {
         Self {
             reducers: configs
@@ -149,7 +298,86 @@ impl Bootstrapper {
                 .collect(),
             input: Default::default(),
             output: Default::default(),
+            chain: chain.clone(),
             policy: policy.clone(),
+            decode_ahead,
+            decode_pool_size,
+            sample_every: None,
+            dedup_block_commands: false,
+            confirmation: None,
+            epoch_commit: None,
+            idle_sleep: None,
+            duplicate_block_window: None,
+            recent_blocks_cursor: None,
+        }
+    }
+
+    /// Reduces one block out of every `n`, by height. Cumulative collections
+    /// are invalid under sampling, see `worker::Worker::sampled`.
+    pub fn sample_every(&mut self, n: Option<u64>) {
+        self.sample_every = n;
+    }
+
+    /// Collapses the redundant commands of each block before sending them,
+    /// see `dedup::BlockBuffer`
+    pub fn dedup_block_commands(&mut self, enabled: bool) {
+        self.dedup_block_commands = enabled;
+    }
+
+    /// Writes the commands of the blocks not yet confirmed under a volatile
+    /// prefix, see `confirmation`
+    pub fn confirm_blocks(&mut self, config: Option<confirmation::Config>) {
+        self.confirmation = config;
+    }
+
+    /// Sends the commands of each epoch at once when the next one starts, see
+    /// `epoch_commit`. Fails along with `confirm_blocks`, both hold the
+    /// blocks back their own way.
+    pub fn commit_by_epoch(
+        &mut self,
+        config: Option<epoch_commit::Config>,
+    ) -> Result<(), crate::Error> {
+        if config.is_some() && self.confirmation.is_some() {
+            return Err(crate::Error::config(
+                "the volatile and epoch_commit sections can't be used together",
+            ));
+        }
+
+        self.epoch_commit = config;
+
+        Ok(())
+    }
+
+    /// Skips the blocks already reduced among the last `window` ones, see
+    /// `worker::Worker::skipping_duplicates`
+    pub fn skip_duplicate_blocks(&mut self, window: Option<usize>) {
+        self.duplicate_block_window = window;
+    }
+
+    /// Storage cursor the window of `skip_duplicate_blocks` is seeded from,
+    /// see `worker::Worker::seeded_from`
+    pub fn seed_recent_blocks(&mut self, cursor: storage::Cursor) {
+        self.recent_blocks_cursor = Some(cursor);
+    }
+
+    /// Time the stage sleeps each time its input is idle, see
+    /// `worker::Worker::idle`
+    pub fn idle_sleep(&mut self, sleep: Option<Duration>) {
+        self.idle_sleep = sleep;
+    }
+
+    /// Keeps only the reducer with the given name, so that it can be run in
+    /// isolation. Fails unless exactly one reducer has that name.
+    pub fn retain_only(&mut self, name: &str) -> Result<(), crate::Error> {
+        self.reducers.retain(|x| x.name() == name);
+
+        match self.reducers.len() {
+            1 => Ok(()),
+            0 => Err(crate::Error::config(format!("no {} reducer configured", name))),
+            n => Err(crate::Error::config(format!(
+                "{} {} reducers configured, can't pick one",
+                n, name
+            ))),
         }
     }
 
@@ -157,12 +385,29 @@ impl Bootstrapper {
         &mut self.input
     }
 
-    pub fn borrow_output_port(&mut self) -> &'_ mut OutputPort {
+    pub fn borrow_output_port(
+        &mut self,
+    ) -> &'_ mut gasket::messaging::OutputPort<model::CRDTCommand> {
         &mut self.output
     }
 
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
-        let worker = worker::Worker::new(self.reducers, self.input, self.output, self.policy);
+        let worker = worker::Worker::new(
+            self.reducers,
+            self.input,
+            self.output,
+            self.policy,
+            self.decode_ahead,
+            self.decode_pool_size,
+        )
+        .sampled(self.sample_every)
+        .deduped(self.dedup_block_commands)
+        .confirmed(self.confirmation.clone())
+        .epoch_aligned(self.epoch_commit.clone(), &self.chain)
+        .idle_sleep(self.idle_sleep)
+        .skipping_duplicates(self.duplicate_block_window)
+        .seeded_from(self.recent_blocks_cursor);
+
         pipeline.register_stage(spawn_stage(
             worker,
             gasket::runtime::Policy {
@@ -172,85 +417,448 @@ impl Bootstrapper {
             Some("reducers"),
         ));
     }
+
+    /// Spawns a single stage running the enrich backend inline before the
+    /// reducers, see `fused::Worker`. Blocks aren't decoded ahead in this
+    /// mode.
+    pub fn spawn_fused(self, enrich: enrich::Bootstrapper, pipeline: &mut bootstrap::Pipeline) {
+        let inflight = enrich.inflight();
+        let (input, enrich) = enrich.into_inline();
+
+        let reducers = worker::Worker::new(
+            self.reducers,
+            self.input,
+            self.output,
+            self.policy,
+            None,
+            None,
+        )
+        .sampled(self.sample_every)
+        .deduped(self.dedup_block_commands)
+        .confirmed(self.confirmation.clone())
+        .epoch_aligned(self.epoch_commit.clone(), &self.chain)
+        .idle_sleep(self.idle_sleep)
+        .skipping_duplicates(self.duplicate_block_window)
+        .seeded_from(self.recent_blocks_cursor);
+
+        let mut worker = fused::Worker::new(input, enrich, reducers);
+        worker.inflight = inflight;
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            Some("enrich-reducers"),
+        ));
+    }
 }
 
 pub enum Reducer {
+    #[cfg(feature = "full_utxos_by_address")]
     FullUtxosByAddress(full_utxos_by_address::Reducer),
+    #[cfg(feature = "utxo_by_address")]
     UtxoByAddress(utxo_by_address::Reducer),
+    #[cfg(feature = "point_by_tx")]
     PointByTx(point_by_tx::Reducer),
+    #[cfg(feature = "pool_by_stake")]
     PoolByStake(pool_by_stake::Reducer),
 
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "address_by_txo")]
     AddressByTxo(address_by_txo::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "balance_by_address")]
     BalanceByAddress(balance_by_address::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_by_hash")]
     TxByHash(tx_by_hash::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_count_by_address")]
     TxCountByAddress(tx_count_by_address::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "block_header_by_hash")]
     BlockHeaderByHash(block_header_by_hash::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "address_by_asset")]
     AddressByAsset(address_by_asset::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "address_ada_flow")]
+    AddressAdaFlow(address_ada_flow::Reducer),
+    #[cfg(feature = "last_block_parameters")]
     LastBlockParameters(last_block_parameters::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_count_by_native_token_policy_id")]
     TxCountByNativeTokenPolicyId(tx_count_by_native_token_policy_id::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "tx_size_stats")]
+    TxSizeStats(tx_size_stats::Reducer),
+    #[cfg(feature = "epoch_stats")]
+    EpochStats(epoch_stats::Reducer),
+    #[cfg(feature = "asset_holders_by_asset_id")]
     AssetHoldersByAssetId(asset_holders_by_asset_id::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "utxos_by_asset")]
     UtxosByAsset(utxos_by_asset::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "utxo_by_stake")]
     UtxoByStake(utxo_by_stake::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "supply_by_asset")]
     SupplyByAsset(supply_by_asset::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "addresses_by_stake")]
     AddressesByStake(addresses_by_stake::Reducer),
-    #[cfg(feature = "unstable")]
+    #[cfg(feature = "asset_metadata")]
     AssetMetadata(asset_metadata::Reducer),
+    #[cfg(feature = "pool_reserves")]
+    PoolReserves(pool_reserves::Reducer),
+    #[cfg(feature = "script_activity")]
+    ScriptActivity(script_activity::Reducer),
+    #[cfg(feature = "reward_activity")]
+    RewardActivity(reward_activity::Reducer),
+    #[cfg(feature = "utxo_count")]
+    UtxoCount(utxo_count::Reducer),
+    #[cfg(feature = "fee_stats")]
+    FeeStats(fee_stats::Reducer),
+    #[cfg(feature = "address_activity")]
+    AddressActivity(address_activity::Reducer),
+    #[cfg(feature = "recent_blocks")]
+    RecentBlocks(recent_blocks::Reducer),
+    #[cfg(feature = "token_registry")]
+    TokenRegistry(token_registry::Reducer),
+    #[cfg(feature = "native_script_addresses")]
+    NativeScriptAddresses(native_script_addresses::Reducer),
+    #[cfg(feature = "passthrough")]
+    Passthrough(passthrough::Reducer),
+    #[cfg(feature = "holding_distribution")]
+    HoldingDistribution(holding_distribution::Reducer),
+    #[cfg(feature = "chain_digest")]
+    ChainDigest(chain_digest::Reducer),
+    #[cfg(feature = "address_networth")]
+    AddressNetworth(address_networth::Reducer),
+    #[cfg(feature = "tx_graph")]
+    TxGraph(tx_graph::Reducer),
+    #[cfg(feature = "policy_tx_count")]
+    PolicyTxCount(policy_tx_count::Reducer),
+    #[cfg(feature = "asset_transfers")]
+    AssetTransfers(asset_transfers::Reducer),
+    #[cfg(feature = "collateral_events")]
+    CollateralEvents(collateral_events::Reducer),
 }
 
 impl Reducer {
+    /// Name of the reducer as used in metrics and logs
+    pub fn name(&self) -> &'static str {
+        match self {
+            #[cfg(feature = "full_utxos_by_address")]
+            Reducer::FullUtxosByAddress(_) => "full_utxos_by_address",
+            #[cfg(feature = "utxo_by_address")]
+            Reducer::UtxoByAddress(_) => "utxo_by_address",
+            #[cfg(feature = "point_by_tx")]
+            Reducer::PointByTx(_) => "point_by_tx",
+            #[cfg(feature = "pool_by_stake")]
+            Reducer::PoolByStake(_) => "pool_by_stake",
+            #[cfg(feature = "address_by_txo")]
+            Reducer::AddressByTxo(_) => "address_by_txo",
+            #[cfg(feature = "balance_by_address")]
+            Reducer::BalanceByAddress(_) => "balance_by_address",
+            #[cfg(feature = "tx_by_hash")]
+            Reducer::TxByHash(_) => "tx_by_hash",
+            #[cfg(feature = "tx_count_by_address")]
+            Reducer::TxCountByAddress(_) => "tx_count_by_address",
+            #[cfg(feature = "block_header_by_hash")]
+            Reducer::BlockHeaderByHash(_) => "block_header_by_hash",
+            #[cfg(feature = "address_by_asset")]
+            Reducer::AddressByAsset(_) => "address_by_asset",
+            #[cfg(feature = "address_ada_flow")]
+            Reducer::AddressAdaFlow(_) => "address_ada_flow",
+            #[cfg(feature = "last_block_parameters")]
+            Reducer::LastBlockParameters(_) => "last_block_parameters",
+            #[cfg(feature = "tx_count_by_native_token_policy_id")]
+            Reducer::TxCountByNativeTokenPolicyId(_) => "tx_count_by_native_token_policy_id",
+            #[cfg(feature = "tx_size_stats")]
+            Reducer::TxSizeStats(_) => "tx_size_stats",
+            #[cfg(feature = "epoch_stats")]
+            Reducer::EpochStats(_) => "epoch_stats",
+            #[cfg(feature = "asset_holders_by_asset_id")]
+            Reducer::AssetHoldersByAssetId(_) => "asset_holders_by_asset_id",
+            #[cfg(feature = "utxos_by_asset")]
+            Reducer::UtxosByAsset(_) => "utxos_by_asset",
+            #[cfg(feature = "utxo_by_stake")]
+            Reducer::UtxoByStake(_) => "utxo_by_stake",
+            #[cfg(feature = "supply_by_asset")]
+            Reducer::SupplyByAsset(_) => "supply_by_asset",
+            #[cfg(feature = "addresses_by_stake")]
+            Reducer::AddressesByStake(_) => "addresses_by_stake",
+            #[cfg(feature = "asset_metadata")]
+            Reducer::AssetMetadata(_) => "asset_metadata",
+            #[cfg(feature = "pool_reserves")]
+            Reducer::PoolReserves(_) => "pool_reserves",
+            #[cfg(feature = "script_activity")]
+            Reducer::ScriptActivity(_) => "script_activity",
+            #[cfg(feature = "reward_activity")]
+            Reducer::RewardActivity(_) => "reward_activity",
+            #[cfg(feature = "utxo_count")]
+            Reducer::UtxoCount(_) => "utxo_count",
+            #[cfg(feature = "fee_stats")]
+            Reducer::FeeStats(_) => "fee_stats",
+            #[cfg(feature = "address_activity")]
+            Reducer::AddressActivity(_) => "address_activity",
+            #[cfg(feature = "recent_blocks")]
+            Reducer::RecentBlocks(_) => "recent_blocks",
+            #[cfg(feature = "token_registry")]
+            Reducer::TokenRegistry(_) => "token_registry",
+            #[cfg(feature = "native_script_addresses")]
+            Reducer::NativeScriptAddresses(_) => "native_script_addresses",
+            #[cfg(feature = "passthrough")]
+            Reducer::Passthrough(_) => "passthrough",
+            #[cfg(feature = "holding_distribution")]
+            Reducer::HoldingDistribution(_) => "holding_distribution",
+            #[cfg(feature = "chain_digest")]
+            Reducer::ChainDigest(_) => "chain_digest",
+            #[cfg(feature = "address_networth")]
+            Reducer::AddressNetworth(_) => "address_networth",
+            #[cfg(feature = "tx_graph")]
+            Reducer::TxGraph(_) => "tx_graph",
+            #[cfg(feature = "policy_tx_count")]
+            Reducer::PolicyTxCount(_) => "policy_tx_count",
+            #[cfg(feature = "asset_transfers")]
+            Reducer::AssetTransfers(_) => "asset_transfers",
+            #[cfg(feature = "collateral_events")]
+            Reducer::CollateralEvents(_) => "collateral_events",
+        }
+    }
+
+    /// Counter of the outputs skipped for exceeding `max_assets_per_output`,
+    /// only for the reducers that index outputs asset by asset
+    pub fn skipped_outputs(&self) -> Option<&gasket::metrics::Counter> {
+        match self {
+            #[cfg(feature = "full_utxos_by_address")]
+            Reducer::FullUtxosByAddress(x) => Some(x.asset_limit.skipped()),
+            #[cfg(feature = "address_by_asset")]
+            Reducer::AddressByAsset(x) => Some(x.asset_limit.skipped()),
+            #[cfg(feature = "asset_holders_by_asset_id")]
+            Reducer::AssetHoldersByAssetId(x) => Some(x.asset_limit.skipped()),
+            #[cfg(feature = "utxos_by_asset")]
+            Reducer::UtxosByAsset(x) => Some(x.asset_limit.skipped()),
+            _ => None,
+        }
+    }
+
+    /// Counter of the outputs whose address couldn't be decoded, only for the
+    /// reducers that read addresses. See `crosscut::addresses::AddressResolver`.
+    pub fn unknown_addresses(&self) -> Option<&gasket::metrics::Counter> {
+        match self {
+            #[cfg(feature = "utxo_by_address")]
+            Reducer::UtxoByAddress(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "address_by_txo")]
+            Reducer::AddressByTxo(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "balance_by_address")]
+            Reducer::BalanceByAddress(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "tx_count_by_address")]
+            Reducer::TxCountByAddress(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "address_by_asset")]
+            Reducer::AddressByAsset(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "address_ada_flow")]
+            Reducer::AddressAdaFlow(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "asset_holders_by_asset_id")]
+            Reducer::AssetHoldersByAssetId(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "utxo_by_stake")]
+            Reducer::UtxoByStake(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "addresses_by_stake")]
+            Reducer::AddressesByStake(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "script_activity")]
+            Reducer::ScriptActivity(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "address_activity")]
+            Reducer::AddressActivity(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "native_script_addresses")]
+            Reducer::NativeScriptAddresses(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "passthrough")]
+            Reducer::Passthrough(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "holding_distribution")]
+            Reducer::HoldingDistribution(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "address_networth")]
+            Reducer::AddressNetworth(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "tx_graph")]
+            Reducer::TxGraph(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "asset_transfers")]
+            Reducer::AssetTransfers(x) => Some(x.addresses.unknown()),
+            #[cfg(feature = "collateral_events")]
+            Reducer::CollateralEvents(x) => Some(x.addresses.unknown()),
+            _ => None,
+        }
+    }
+
+    /// Kinds of txs the reducer acts on, it only gets those and blocks
+    /// without any are skipped. See `interest::ScreenedTxs`.
+    pub fn interest(&self) -> &'static [interest::TxInterest] {
+        use interest::TxInterest;
+
+        match self {
+            #[cfg(feature = "pool_by_stake")]
+            Reducer::PoolByStake(_) => &[TxInterest::Certs],
+            #[cfg(feature = "supply_by_asset")]
+            Reducer::SupplyByAsset(_) => &[TxInterest::Mints],
+            #[cfg(feature = "tx_count_by_native_token_policy_id")]
+            Reducer::TxCountByNativeTokenPolicyId(_) => &[TxInterest::Mints],
+            #[cfg(feature = "asset_metadata")]
+            Reducer::AssetMetadata(_) => &[TxInterest::Mints],
+            #[cfg(feature = "reward_activity")]
+            Reducer::RewardActivity(_) => &[TxInterest::Withdrawals, TxInterest::Certs],
+            #[cfg(feature = "token_registry")]
+            Reducer::TokenRegistry(_) => &[TxInterest::Mints],
+            #[cfg(feature = "collateral_events")]
+            Reducer::CollateralEvents(_) => &[TxInterest::Invalid],
+            _ => &[TxInterest::Any],
+        }
+    }
+
+    /// Reduces the block, `txs` being the ones of the kinds the reducer acts
+    /// on, see `interest`
     pub fn reduce_block<'b>(
         &mut self,
         block: &'b MultiEraBlock<'b>,
+        txs: &[&MultiEraTx],
         ctx: &model::BlockContext,
         output: &mut OutputPort,
     ) -> Result<(), gasket::error::Error> {
         match self {
+            #[cfg(feature = "full_utxos_by_address")]
             Reducer::FullUtxosByAddress(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "utxo_by_address")]
             Reducer::UtxoByAddress(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "point_by_tx")]
             Reducer::PointByTx(x) => x.reduce_block(block, output),
-            Reducer::PoolByStake(x) => x.reduce_block(block, output),
+            #[cfg(feature = "pool_by_stake")]
+            Reducer::PoolByStake(x) => x.reduce_block(block, txs, output),
 
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "address_by_txo")]
             Reducer::AddressByTxo(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "balance_by_address")]
             Reducer::BalanceByAddress(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_by_hash")]
             Reducer::TxByHash(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_count_by_address")]
             Reducer::TxCountByAddress(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "block_header_by_hash")]
             Reducer::BlockHeaderByHash(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "address_by_asset")]
             Reducer::AddressByAsset(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "address_ada_flow")]
+            Reducer::AddressAdaFlow(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "last_block_parameters")]
             Reducer::LastBlockParameters(x) => x.reduce_block(block, output),
-            #[cfg(feature = "unstable")]
-            Reducer::TxCountByNativeTokenPolicyId(x) => x.reduce_block(block, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "tx_count_by_native_token_policy_id")]
+            Reducer::TxCountByNativeTokenPolicyId(x) => x.reduce_block(block, txs, output),
+            #[cfg(feature = "tx_size_stats")]
+            Reducer::TxSizeStats(x) => x.reduce_block(block, output),
+            #[cfg(feature = "epoch_stats")]
+            Reducer::EpochStats(x) => x.reduce_block(block, output),
+            #[cfg(feature = "asset_holders_by_asset_id")]
             Reducer::AssetHoldersByAssetId(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "utxos_by_asset")]
             Reducer::UtxosByAsset(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "utxo_by_stake")]
             Reducer::UtxoByStake(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
-            Reducer::SupplyByAsset(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
+            #[cfg(feature = "supply_by_asset")]
+            Reducer::SupplyByAsset(x) => x.reduce_block(txs, ctx, output),
+            #[cfg(feature = "addresses_by_stake")]
             Reducer::AddressesByStake(x) => x.reduce_block(block, ctx, output),
-            #[cfg(feature = "unstable")]
-            Reducer::AssetMetadata(x) => x.reduce_block(block, output),
+            #[cfg(feature = "asset_metadata")]
+            Reducer::AssetMetadata(x) => x.reduce_block(block, txs, output),
+            #[cfg(feature = "pool_reserves")]
+            Reducer::PoolReserves(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "script_activity")]
+            Reducer::ScriptActivity(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "reward_activity")]
+            Reducer::RewardActivity(x) => x.reduce_block(block, txs, ctx, output),
+            #[cfg(feature = "utxo_count")]
+            Reducer::UtxoCount(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "fee_stats")]
+            Reducer::FeeStats(x) => x.reduce_block(block, output),
+            #[cfg(feature = "address_activity")]
+            Reducer::AddressActivity(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "recent_blocks")]
+            Reducer::RecentBlocks(x) => x.reduce_block(block, output),
+            #[cfg(feature = "token_registry")]
+            Reducer::TokenRegistry(x) => x.reduce_block(txs, output),
+            #[cfg(feature = "native_script_addresses")]
+            Reducer::NativeScriptAddresses(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "passthrough")]
+            Reducer::Passthrough(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "holding_distribution")]
+            Reducer::HoldingDistribution(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "chain_digest")]
+            Reducer::ChainDigest(x) => x.reduce_block(block, output),
+            #[cfg(feature = "address_networth")]
+            Reducer::AddressNetworth(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "tx_graph")]
+            Reducer::TxGraph(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "policy_tx_count")]
+            Reducer::PolicyTxCount(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "asset_transfers")]
+            Reducer::AssetTransfers(x) => x.reduce_block(block, ctx, output),
+            #[cfg(feature = "collateral_events")]
+            Reducer::CollateralEvents(x) => x.reduce_block(block, txs, ctx, output),
+        }
+    }
+
+    /// True if the reducer can undo the writes of every block past the
+    /// point, see `journal::Journal`
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        match self {
+            #[cfg(feature = "address_ada_flow")]
+            Reducer::AddressAdaFlow(x) => x.covers_rollback(point),
+            #[cfg(feature = "tx_size_stats")]
+            Reducer::TxSizeStats(x) => x.covers_rollback(point),
+            #[cfg(feature = "epoch_stats")]
+            Reducer::EpochStats(x) => x.covers_rollback(point),
+            #[cfg(feature = "pool_reserves")]
+            Reducer::PoolReserves(x) => x.covers_rollback(point),
+            #[cfg(feature = "script_activity")]
+            Reducer::ScriptActivity(x) => x.covers_rollback(point),
+            #[cfg(feature = "reward_activity")]
+            Reducer::RewardActivity(x) => x.covers_rollback(point),
+            #[cfg(feature = "fee_stats")]
+            Reducer::FeeStats(x) => x.covers_rollback(point),
+            #[cfg(feature = "address_activity")]
+            Reducer::AddressActivity(x) => x.covers_rollback(point),
+            #[cfg(feature = "recent_blocks")]
+            Reducer::RecentBlocks(x) => x.covers_rollback(point),
+            #[cfg(feature = "native_script_addresses")]
+            Reducer::NativeScriptAddresses(x) => x.covers_rollback(point),
+            #[cfg(feature = "asset_transfers")]
+            Reducer::AssetTransfers(x) => x.covers_rollback(point),
+            #[cfg(feature = "collateral_events")]
+            Reducer::CollateralEvents(x) => x.covers_rollback(point),
+            _ => false,
+        }
+    }
+
+    /// Reverts the local state of the reducer to the rollback point,
+    /// returning the commands that undo the writes of the blocks past it
+    pub fn roll_back(&mut self, point: &Point) -> Result<Vec<model::CRDTCommand>, crate::Error> {
+        match self {
+            #[cfg(feature = "holding_distribution")]
+            Reducer::HoldingDistribution(x) => x.roll_back(point).map(|_| vec![]),
+            #[cfg(feature = "chain_digest")]
+            Reducer::ChainDigest(x) => x.roll_back(point).map(|_| vec![]),
+            #[cfg(feature = "address_networth")]
+            Reducer::AddressNetworth(x) => x.roll_back(point).map(|_| vec![]),
+            #[cfg(feature = "address_ada_flow")]
+            Reducer::AddressAdaFlow(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "tx_size_stats")]
+            Reducer::TxSizeStats(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "epoch_stats")]
+            Reducer::EpochStats(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "pool_reserves")]
+            Reducer::PoolReserves(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "script_activity")]
+            Reducer::ScriptActivity(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "reward_activity")]
+            Reducer::RewardActivity(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "fee_stats")]
+            Reducer::FeeStats(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "address_activity")]
+            Reducer::AddressActivity(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "recent_blocks")]
+            Reducer::RecentBlocks(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "native_script_addresses")]
+            Reducer::NativeScriptAddresses(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "asset_transfers")]
+            Reducer::AssetTransfers(x) => Ok(x.roll_back(point)),
+            #[cfg(feature = "collateral_events")]
+            Reducer::CollateralEvents(x) => Ok(x.roll_back(point)),
+            _ => Ok(vec![]),
         }
     }
 }
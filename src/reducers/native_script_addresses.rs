@@ -0,0 +1,341 @@
+use std::collections::{BTreeSet, HashMap};
+
+use pallas::codec::minicbor;
+use pallas::crypto::hash::Hasher;
+use pallas::ledger::addresses::{Address, ShelleyPaymentPart};
+use pallas::ledger::primitives::alonzo::NativeScript;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::journal::Journal;
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Number of blocks that can be rolled back, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    journal: Journal,
+}
+
+/// Hash of a native script, over its tagged cbor
+///
+/// Witness scripts aren't kept in their original bytes, so the hash is taken
+/// over the re-encoded script. Scripts encoded in a non-canonical way by the
+/// submitter would get a different hash and never match an address.
+fn script_hash(script: &NativeScript) -> Option<String> {
+    let mut cbor = vec![0u8];
+    cbor.extend(minicbor::to_vec(script).ok()?);
+
+    Some(Hasher::<224>::hash(&cbor).to_string())
+}
+
+/// Json rendering of the script tree
+fn script_json(script: &NativeScript) -> serde_json::Value {
+    match script {
+        NativeScript::ScriptPubkey(x) => json!({ "sig": x.to_string() }),
+        NativeScript::ScriptAll(x) => {
+            json!({ "all": x.iter().map(script_json).collect::<Vec<_>>() })
+        }
+        NativeScript::ScriptAny(x) => {
+            json!({ "any": x.iter().map(script_json).collect::<Vec<_>>() })
+        }
+        NativeScript::ScriptNOfK(n, x) => {
+            json!({ "required": n, "of": x.iter().map(script_json).collect::<Vec<_>>() })
+        }
+        NativeScript::InvalidBefore(x) => json!({ "invalid_before": x }),
+        NativeScript::InvalidHereafter(x) => json!({ "invalid_hereafter": x }),
+    }
+}
+
+/// Signers and timelock bounds found anywhere in the script
+#[derive(Default, Debug, PartialEq)]
+struct Summary {
+    signers: BTreeSet<String>,
+    invalid_before: Option<u64>,
+    invalid_hereafter: Option<u64>,
+}
+
+impl Summary {
+    fn of(script: &NativeScript) -> Self {
+        let mut summary = Self::default();
+        summary.visit(script);
+        summary
+    }
+
+    fn visit(&mut self, script: &NativeScript) {
+        match script {
+            NativeScript::ScriptPubkey(x) => {
+                self.signers.insert(x.to_string());
+            }
+            NativeScript::ScriptAll(x) | NativeScript::ScriptAny(x) => {
+                x.iter().for_each(|x| self.visit(x));
+            }
+            NativeScript::ScriptNOfK(_, x) => x.iter().for_each(|x| self.visit(x)),
+            NativeScript::InvalidBefore(x) => {
+                self.invalid_before = self.invalid_before.max(Some(*x));
+            }
+            NativeScript::InvalidHereafter(x) => {
+                self.invalid_hereafter = Some(self.invalid_hereafter.map_or(*x, |y| y.min(*x)));
+            }
+        }
+    }
+}
+
+/// Native scripts revealed in the witness set of the tx, by hash
+fn witness_scripts(tx: &MultiEraTx) -> HashMap<String, &NativeScript> {
+    let scripts = match tx {
+        MultiEraTx::AlonzoCompatible(x, _) => x.transaction_witness_set.native_script.as_ref(),
+        MultiEraTx::Babbage(x) => x.transaction_witness_set.native_script.as_ref(),
+        _ => None,
+    };
+
+    scripts
+        .into_iter()
+        .flatten()
+        .filter_map(|script| Some((script_hash(script)?, script)))
+        .collect()
+}
+
+fn payment_script(address: &Address) -> Option<String> {
+    match address {
+        Address::Shelley(x) => match x.payment() {
+            ShelleyPaymentPart::Script(hash) => Some(hash.to_string()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+impl Reducer {
+    fn send_script(
+        &self,
+        hash: &str,
+        script: &NativeScript,
+        address: &Address,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("native_script");
+
+        let key = prefix.key(&[hash]);
+        let summary = Summary::of(script);
+
+        let mut crdts = vec![
+            model::CRDTCommand::SetAdd(prefix.key(&[hash, "addresses"]), address.to_string()),
+            model::CRDTCommand::HashSetValue(
+                key.clone(),
+                "script".into(),
+                script_json(script).into(),
+            ),
+            model::CRDTCommand::HashSetValue(
+                key.clone(),
+                "signers".into(),
+                json!(summary.signers).into(),
+            ),
+        ];
+
+        if let Some(x) = summary.invalid_before {
+            crdts.push(model::CRDTCommand::HashSetValue(
+                key.clone(),
+                "invalid_before".into(),
+                model::Value::BigInt(x as i128),
+            ));
+        }
+
+        if let Some(x) = summary.invalid_hereafter {
+            crdts.push(model::CRDTCommand::HashSetValue(
+                key,
+                "invalid_hereafter".into(),
+                model::Value::BigInt(x as i128),
+            ));
+        }
+
+        for crdt in crdts {
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    fn process_tx(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let scripts = witness_scripts(tx);
+
+        if scripts.is_empty() {
+            return Ok(());
+        }
+
+        let mut addresses = vec![];
+
+        for input in tx.consumes() {
+            let utxo = ctx
+                .find_utxo(&input.output_ref())
+                .apply_policy(&self.policy)
+                .or_panic()?;
+
+            // unknown addresses can't be locked by a script
+            if let Some(utxo) = utxo {
+                addresses.extend(self.addresses.address(&utxo));
+            }
+        }
+
+        for (_, produced) in tx.produces() {
+            addresses.extend(self.addresses.address(&produced));
+        }
+
+        for address in addresses {
+            let script = payment_script(&address).and_then(|x| scripts.get_key_value(&x));
+
+            if let Some((hash, script)) = script {
+                self.send_script(hash, script, &address, output)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Maps native scripts to the addresses they lock, along with their
+    /// signers and timelock bounds
+    ///
+    /// A script is only known once revealed in a witness set, which usually
+    /// happens when funds locked by it are spent. Both the consumed and the
+    /// produced outputs of the tx are matched against the revealed scripts.
+    ///
+    /// On rollback, there's nothing to undo: entries are derived from the
+    /// script alone, so a rolled back reveal only leaves a mapping that is
+    /// still true. The blocks are journaled all the same, with no commands,
+    /// for the reducers stage to accept the rollbacks within the window.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in block.txs().iter() {
+            self.process_tx(tx, ctx, output)?;
+        }
+
+        self.journal.record(block.slot(), vec![]);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            journal,
+        };
+
+        super::Reducer::NativeScriptAddresses(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::crypto::hash::Hash;
+    use pallas::ledger::primitives::alonzo::NativeScript;
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::{Config, Summary};
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn rollback_is_covered_with_nothing_to_undo() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 1000);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            rollback_window: None,
+        };
+
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = match config.plugin(&policy) {
+            Reducer::NativeScriptAddresses(x) => x,
+            _ => unreachable!(),
+        };
+
+        assert!(!reducer.covers_rollback(&before));
+
+        reducer.roll_back(&before);
+        reducer
+            .reduce_block(&block, &BlockContext::default(), &mut output)
+            .unwrap();
+
+        assert!(reducer.covers_rollback(&before));
+        assert!(reducer.roll_back(&before).is_empty());
+    }
+
+    #[test]
+    fn signers_and_bounds_are_flattened() {
+        let alice = Hash::new([1u8; 28]);
+        let bob = Hash::new([2u8; 28]);
+
+        let script = NativeScript::ScriptAll(vec![
+            NativeScript::ScriptNOfK(
+                1,
+                vec![
+                    NativeScript::ScriptPubkey(alice),
+                    NativeScript::ScriptPubkey(bob),
+                ],
+            ),
+            NativeScript::InvalidBefore(100),
+            NativeScript::InvalidHereafter(500),
+            NativeScript::ScriptAny(vec![NativeScript::InvalidHereafter(300)]),
+        ]);
+
+        let summary = Summary::of(&script);
+
+        assert_eq!(summary.signers.len(), 2);
+        assert!(summary.signers.contains(&alice.to_string()));
+        assert_eq!(summary.invalid_before, Some(100));
+        assert_eq!(summary.invalid_hereafter, Some(300));
+    }
+}
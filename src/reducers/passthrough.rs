@@ -0,0 +1,264 @@
+use pallas::codec::minicbor;
+use pallas::ledger::primitives::alonzo::Certificate;
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraCert, MultiEraOutput, MultiEraTx};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::crosscut::metadata::metadatum_to_value;
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize, Default)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Include the inputs of each tx, resolved through the enrich context.
+    /// Defaults to true.
+    pub include_inputs: Option<bool>,
+
+    /// Include the outputs of each tx. Defaults to true.
+    pub include_outputs: Option<bool>,
+
+    /// Include the native assets of inputs and outputs
+    pub include_assets: Option<bool>,
+
+    /// Include the certificates of each tx, as their kind and hex cbor
+    pub include_certs: Option<bool>,
+
+    /// Include the metadata of each tx, by label
+    pub include_metadata: Option<bool>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+}
+
+fn cert_kind(cert: &Certificate) -> &'static str {
+    match cert {
+        Certificate::StakeRegistration(..) => "stake_registration",
+        Certificate::StakeDeregistration(..) => "stake_deregistration",
+        Certificate::StakeDelegation(..) => "stake_delegation",
+        Certificate::PoolRegistration { .. } => "pool_registration",
+        Certificate::PoolRetirement(..) => "pool_retirement",
+        Certificate::GenesisKeyDelegation(..) => "genesis_key_delegation",
+        Certificate::MoveInstantaneousRewardsCert(..) => "move_instantaneous_rewards",
+    }
+}
+
+fn cert_json(cert: &MultiEraCert) -> Option<Value> {
+    let cert = cert.as_alonzo()?;
+    let cbor = minicbor::to_vec(cert).ok()?;
+
+    Some(json!({ "kind": cert_kind(cert), "cbor": hex::encode(cbor) }))
+}
+
+fn metadata_json(tx: &MultiEraTx) -> Value {
+    let entries = tx
+        .metadata()
+        .as_alonzo()
+        .into_iter()
+        .flat_map(|x| x.iter())
+        .map(|(label, datum)| (label.to_string(), metadatum_to_value(datum)))
+        .collect();
+
+    Value::Object(entries)
+}
+
+impl Reducer {
+    fn output_json(&self, utxo: &MultiEraOutput) -> Result<Value, gasket::error::Error> {
+        let mut data = json!({
+            // null for unknown addresses skipped by the policy
+            "address": self.addresses.key(utxo, |x| x.to_string()),
+            "lovelace": utxo.lovelace_amount(),
+        });
+
+        if self.config.include_assets.unwrap_or(false) {
+            let assets: Vec<_> = utxo
+                .non_ada_assets()
+                .into_iter()
+                .filter_map(|asset| match asset {
+                    Asset::NativeAsset(policy, name, quantity) => Some(json!({
+                        "unit": format!("{}{}", policy, hex::encode(name)),
+                        "quantity": quantity.to_string(),
+                    })),
+                    _ => None,
+                })
+                .collect();
+
+            data["assets"] = json!(assets);
+        }
+
+        Ok(data)
+    }
+
+    /// Json document of the tx, inputs the enrich stage couldn't resolve are
+    /// left with their ref only when the policy skips missing data
+    fn tx_json(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<Value, gasket::error::Error> {
+        let mut data = json!({
+            "hash": tx.hash().to_string(),
+            "fee": tx.fee(),
+        });
+
+        if self.config.include_inputs.unwrap_or(true) {
+            let mut inputs = vec![];
+
+            for input in tx.consumes() {
+                let output_ref = input.output_ref();
+
+                let utxo = ctx
+                    .find_utxo(&output_ref)
+                    .apply_policy(&self.policy)
+                    .or_panic()?;
+
+                let mut input = match utxo {
+                    Some(utxo) => self.output_json(&utxo)?,
+                    None => json!({}),
+                };
+
+                input["ref"] = json!(crosscut::refs::ref_key(&output_ref));
+                inputs.push(input);
+            }
+
+            data["inputs"] = json!(inputs);
+        }
+
+        if self.config.include_outputs.unwrap_or(true) {
+            let mut outputs = vec![];
+
+            for (_, produced) in tx.produces() {
+                outputs.push(self.output_json(&produced)?);
+            }
+
+            data["outputs"] = json!(outputs);
+        }
+
+        if self.config.include_certs.unwrap_or(false) {
+            let certs: Vec<_> = tx.certs().iter().filter_map(cert_json).collect();
+            data["certs"] = json!(certs);
+        }
+
+        if self.config.include_metadata.unwrap_or(false) {
+            data["metadata"] = metadata_json(tx);
+        }
+
+        Ok(data)
+    }
+
+    fn block_json(
+        &self,
+        block: &MultiEraBlock,
+        ctx: &model::BlockContext,
+    ) -> Result<Value, gasket::error::Error> {
+        let mut txs = vec![];
+
+        for tx in block.txs().iter() {
+            txs.push(self.tx_json(tx, ctx)?);
+        }
+
+        Ok(json!({
+            "hash": block.hash().to_string(),
+            "slot": block.slot(),
+            "height": block.number(),
+            "txs": txs,
+        }))
+    }
+
+    /// Emits the whole block as a single json document
+    ///
+    /// Documents grow quickly with the included fields; assets, certs and
+    /// metadata are opt-in.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let key = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("passthrough")
+        .key(&["block", &block.hash().to_string()]);
+
+        let crdt = model::CRDTCommand::AnyWriteWins(key, self.block_json(block, ctx)?.into());
+
+        output.send(gasket::messaging::Message::from(crdt))
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+        };
+
+        super::Reducer::Passthrough(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::traverse::MultiEraBlock;
+
+    use super::{Config, Reducer};
+    use crate::crosscut::addresses::AddressResolver;
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+
+    #[test]
+    fn fields_are_opt_in() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let reducer = Reducer {
+            config: Config::default(),
+            policy: policy.clone(),
+            addresses: AddressResolver::new(None),
+        };
+
+        let json = reducer.block_json(&block, &Default::default()).unwrap();
+
+        assert_eq!(json["hash"], block.hash().to_string());
+        assert_eq!(json["txs"].as_array().unwrap().len(), block.tx_count());
+
+        let txs = block.txs();
+        let tx = &json["txs"][0];
+        let first = &txs[0];
+        assert_eq!(
+            tx["outputs"].as_array().unwrap().len(),
+            first.produces().len()
+        );
+        assert!(tx["inputs"][0]["ref"].is_string());
+        assert!(tx.get("metadata").is_none());
+
+        let reducer = Reducer {
+            config: Config {
+                include_inputs: Some(false),
+                include_outputs: Some(false),
+                include_metadata: Some(true),
+                ..Default::default()
+            },
+            policy,
+            addresses: AddressResolver::new(None),
+        };
+
+        let json = reducer.block_json(&block, &Default::default()).unwrap();
+        let tx = &json["txs"][0];
+
+        assert!(tx.get("inputs").is_none());
+        assert!(tx.get("outputs").is_none());
+        assert!(tx["metadata"].is_object());
+    }
+}
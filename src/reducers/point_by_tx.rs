@@ -2,11 +2,12 @@ use pallas::crypto::hash::Hash;
 use pallas::ledger::traverse::MultiEraBlock;
 use serde::Deserialize;
 
-use crate::model;
+use crate::{crosscut, model};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
 }
 
 pub struct Reducer {
@@ -21,10 +22,11 @@ impl Reducer {
         block_hash: Hash<32>,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let key = match &self.config.key_prefix {
-            Some(prefix) => format!("{}.{}", prefix, tx_hash),
-            None => format!("{}", tx_hash),
-        };
+        let key = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .key(&[&tx_hash.to_string()]);
 
         let member = format!("{},{}", block_slot, block_hash);
         let crdt = model::CRDTCommand::GrowOnlySetAdd(key, member);
@@ -0,0 +1,193 @@
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+use pallas::crypto::hash::Hash;
+use pallas::ledger::traverse::{Asset, MultiEraBlock, MultiEraOutput, MultiEraTx};
+use serde::Deserialize;
+
+use crate::crosscut::epochs::block_epoch;
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Policies to match
+    ///
+    /// If specified only those policy ids as hex will be taken into account, if
+    /// not all policy ids will be indexed.
+    pub policy_ids_hex: Option<Vec<String>>,
+
+    /// Keep a separate counter per epoch, under `{prefix}.{policy}.{epoch}`
+    pub by_epoch: Option<bool>,
+}
+
+pub struct Reducer {
+    config: Config,
+    chain: crosscut::ChainWellKnownInfo,
+    policy: crosscut::policies::RuntimePolicy,
+    policy_ids: Option<Vec<Hash<28>>>,
+}
+
+impl Reducer {
+    fn is_policy_id_accepted(&self, policy_id: &Hash<28>) -> bool {
+        match &self.policy_ids {
+            Some(pids) => pids.contains(policy_id),
+            None => true,
+        }
+    }
+
+    fn add_policies(&self, utxo: &MultiEraOutput, policies: &mut BTreeSet<Hash<28>>) {
+        for asset in utxo.non_ada_assets() {
+            if let Asset::NativeAsset(policy_id, _, _) = asset {
+                if self.is_policy_id_accepted(&policy_id) {
+                    policies.insert(policy_id);
+                }
+            }
+        }
+    }
+
+    /// Policies of the assets the tx consumed or produced
+    fn touched_policies(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<BTreeSet<Hash<28>>, gasket::error::Error> {
+        let mut policies = BTreeSet::new();
+
+        for input in tx.consumes() {
+            let utxo = ctx
+                .find_utxo(&input.output_ref())
+                .apply_policy(&self.policy)
+                .or_panic()?;
+
+            if let Some(utxo) = utxo {
+                self.add_policies(&utxo, &mut policies);
+            }
+        }
+
+        for (_, produced) in tx.produces() {
+            self.add_policies(&produced, &mut policies);
+        }
+
+        Ok(policies)
+    }
+
+    /// Counts the txs moving assets of each policy, a tx moving several
+    /// assets of the same policy is counted once
+    ///
+    /// Burnt assets are found through the consumed outputs, so the enrich
+    /// stage is required.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("policy_tx_count");
+
+        let epoch = match self.config.by_epoch {
+            Some(true) => Some(block_epoch(&self.chain, block)),
+            _ => None,
+        };
+
+        for tx in block.txs().into_iter() {
+            for policy_id in self.touched_policies(&tx, ctx)? {
+                let policy_id = policy_id.to_string();
+
+                let key = match epoch {
+                    Some(epoch) => prefix.key(&[&policy_id, &epoch.to_string()]),
+                    None => prefix.key(&[&policy_id]),
+                };
+
+                let crdt = model::CRDTCommand::PNCounter(key, 1);
+                output.send(gasket::messaging::Message::from(crdt))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> super::Reducer {
+        let policy_ids = self.policy_ids_hex.as_ref().map(|pids| {
+            pids.iter()
+                .map(|pid| Hash::<28>::from_str(pid).expect("invalid policy_id"))
+                .collect()
+        });
+
+        let reducer = Reducer {
+            config: self,
+            chain: chain.clone(),
+            policy: policy.clone(),
+            policy_ids,
+        };
+
+        super::Reducer::PolicyTxCount(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::crypto::hash::Hash;
+    use pallas::ledger::traverse::{Era, MultiEraTx, OutputRef};
+
+    use super::{Config, Reducer};
+    use crate::model::BlockContext;
+
+    // babbage tx spending 1111..11#0, its output holds one unit of aa..aa.01
+    const TX: &str = "84a40081825820111111111111111111111111111111111111111111111111111111111111111100018182581d6100000000000000000000000000000000000000000000000000000000821a001e8480a1581caaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa1410101021a000298101281825820222222222222222222222222222222222222222222222222222222222222222200a0f5f6";
+
+    // the spent output, holding 5 units of bb..bb.02 and 3 of bb..bb.03
+    const SPENT: &str = "82581d6100000000000000000000000000000000000000000000000000000000821a001e8480a1581cbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbba2410205410303";
+
+    fn reducer(policy_ids: Option<Vec<Hash<28>>>) -> Reducer {
+        Reducer {
+            config: Config {
+                key_prefix: None,
+                key_separator: None,
+                policy_ids_hex: None,
+                by_epoch: None,
+            },
+            chain: Default::default(),
+            policy: Default::default(),
+            policy_ids,
+        }
+    }
+
+    #[test]
+    fn consumed_and_produced_policies_count_once() {
+        let cbor = hex::decode(TX).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+
+        let mut ctx = BlockContext::default();
+        ctx.import_ref_output(
+            &OutputRef::new(Hash::new([0x11; 32]), 0),
+            Era::Babbage,
+            hex::decode(SPENT).unwrap(),
+        );
+
+        let policies = reducer(None).touched_policies(&tx, &ctx).unwrap();
+        assert_eq!(
+            policies.into_iter().collect::<Vec<_>>(),
+            vec![Hash::new([0xaa; 28]), Hash::new([0xbb; 28])]
+        );
+
+        let only = reducer(Some(vec![Hash::new([0xbb; 28])]));
+        let policies = only.touched_policies(&tx, &ctx).unwrap();
+        assert_eq!(
+            policies.into_iter().collect::<Vec<_>>(),
+            vec![Hash::new([0xbb; 28])]
+        );
+    }
+}
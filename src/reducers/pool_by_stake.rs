@@ -1,13 +1,14 @@
 use pallas::ledger::primitives::alonzo;
 use pallas::ledger::primitives::alonzo::{PoolKeyhash, StakeCredential};
-use pallas::ledger::traverse::MultiEraBlock;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
 use serde::Deserialize;
 
-use crate::model;
+use crate::{crosscut, model};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
 }
 
 pub struct Reducer {
@@ -30,7 +31,10 @@ impl Reducer {
         let value = pool.to_string();
 
         let crdt = model::CRDTCommand::last_write_wins(
-            self.config.key_prefix.as_deref(),
+            crosscut::keys::Prefix::new(
+                self.config.key_prefix.as_deref(),
+                self.config.key_separator.as_deref(),
+            ),
             &key,
             value,
             slot,
@@ -44,11 +48,12 @@ impl Reducer {
     pub fn reduce_block<'b>(
         &mut self,
         block: &'b MultiEraBlock<'b>,
+        txs: &[&MultiEraTx],
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         let slot = block.slot();
 
-        for tx in block.txs() {
+        for tx in txs {
             if tx.is_valid() {
                 for cert in tx.certs() {
                     if let Some(cert) = cert.as_alonzo() {
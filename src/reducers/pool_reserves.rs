@@ -0,0 +1,417 @@
+use pallas::crypto::hash::Hash;
+use pallas::ledger::addresses::{Address, ShelleyPaymentPart};
+use pallas::ledger::primitives::alonzo::{BigInt, PlutusData};
+use pallas::ledger::primitives::babbage::DatumOption;
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraOutput, OutputRef};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::Journal;
+use crate::{crosscut, model, prelude::*};
+
+/// Members of the hash of each pool
+const MEMBERS: [&str; 3] = ["reserve_a", "reserve_b", "utxo"];
+
+#[derive(Deserialize, Clone)]
+pub struct PoolConfig {
+    /// Identifier of the pool, used as the key of the hash
+    pub id: String,
+
+    /// Bech32 address of the pool
+    pub address: Option<String>,
+
+    /// Hex encoded payment script hash of the pool, matches any address
+    /// locked by the script regardless of its delegation part
+    pub script_hash: Option<String>,
+
+    /// Position of the reserve of the first token inside the inline datum,
+    /// as a list of field indexes walked through nested constructors
+    pub reserve_a: Vec<usize>,
+
+    /// Position of the reserve of the second token inside the inline datum
+    pub reserve_b: Vec<usize>,
+}
+
+impl PoolConfig {
+    fn matches(&self, address: &Address) -> bool {
+        if let Some(expected) = &self.address {
+            if address.to_string() == *expected {
+                return true;
+            }
+        }
+
+        if let (Some(expected), Address::Shelley(s)) = (&self.script_hash, address) {
+            if let ShelleyPaymentPart::Script(hash) = s.payment() {
+                return hash.to_string() == expected.to_lowercase();
+            }
+        }
+
+        false
+    }
+}
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+    pub pools: Vec<PoolConfig>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    journal: Journal,
+    /// Commands undoing the writes of the block being reduced, in the order
+    /// of the writes
+    undo: Vec<model::CRDTCommand>,
+}
+
+/// Walks the given field indexes through nested constructors or lists
+fn datum_field<'a>(data: &'a PlutusData, path: &[usize]) -> Option<&'a PlutusData> {
+    match path.split_first() {
+        None => Some(data),
+        Some((idx, rest)) => {
+            let inner = match data {
+                PlutusData::Constr(x) => x.fields.get(*idx)?,
+                PlutusData::Array(x) => x.get(*idx)?,
+                _ => return None,
+            };
+
+            datum_field(inner, rest)
+        }
+    }
+}
+
+/// Reads an integer datum, big unsigned ints are accepted up to 128 bits
+fn datum_int(data: &PlutusData) -> Option<i128> {
+    match data {
+        PlutusData::BigInt(BigInt::Int(x)) => Some(i128::from(x.0)),
+        PlutusData::BigInt(BigInt::BigUInt(bytes)) if bytes.len() <= 15 => {
+            let value = bytes.iter().fold(0i128, |acc, b| (acc << 8) | *b as i128);
+            Some(value)
+        }
+        _ => None,
+    }
+}
+
+/// Inline datum of the output, or the one it references by hash when the
+/// enrich stage indexes datums
+fn output_datum(output: &MultiEraOutput, ctx: &model::BlockContext) -> Option<PlutusData> {
+    match output.datum()? {
+        DatumOption::Data(x) => Some(x.0),
+        DatumOption::Hash(x) => ctx.find_datum(&x).ok(),
+    }
+}
+
+/// Values of the members of the hash of a pool for one of its outputs, none
+/// if the reserves can't be read from its datum
+fn pool_values(
+    pool: &PoolConfig,
+    output: &MultiEraOutput,
+    output_ref: &OutputRef,
+    ctx: &model::BlockContext,
+) -> Option<[model::Value; 3]> {
+    let datum = match output_datum(output, ctx) {
+        Some(x) => x,
+        None => {
+            log::warn!("can't resolve the datum of pool {}", pool.id);
+            return None;
+        }
+    };
+
+    let reserve_a = datum_field(&datum, &pool.reserve_a).and_then(datum_int);
+    let reserve_b = datum_field(&datum, &pool.reserve_b).and_then(datum_int);
+
+    match (reserve_a, reserve_b) {
+        (Some(a), Some(b)) => Some([
+            model::Value::BigInt(a),
+            model::Value::BigInt(b),
+            crosscut::refs::ref_key(output_ref).into(),
+        ]),
+        _ => {
+            log::warn!("can't read reserves from datum of pool {}", pool.id);
+            None
+        }
+    }
+}
+
+impl Reducer {
+    fn find_pool(&self, output: &MultiEraOutput) -> Option<&PoolConfig> {
+        let address = output.address().ok()?;
+
+        self.config.pools.iter().find(|p| p.matches(&address))
+    }
+
+    fn pool_key(&self, pool: &PoolConfig) -> String {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("pool_reserves")
+        .key(&[&pool.id])
+    }
+
+    fn process_consumed_txo(
+        &mut self,
+        ctx: &model::BlockContext,
+        input: &OutputRef,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let utxo = ctx.find_utxo(input).apply_policy(&self.policy).or_panic()?;
+
+        let utxo = match utxo {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let pool = match self.find_pool(&utxo) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let key = self.pool_key(pool);
+
+        // the consumed output holds the reserves from before the block
+        if let Some(values) = pool_values(pool, &utxo, input, ctx) {
+            for (member, value) in MEMBERS.into_iter().zip(values) {
+                self.undo.push(model::CRDTCommand::HashSetValue(
+                    key.clone(),
+                    member.to_string(),
+                    value,
+                ));
+            }
+        }
+
+        for member in MEMBERS {
+            let crdt = model::CRDTCommand::HashUnsetKey(member.to_string(), key.clone());
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    fn process_produced_txo(
+        &mut self,
+        ctx: &model::BlockContext,
+        tx_hash: &Hash<32>,
+        tx_idx: usize,
+        tx_output: &MultiEraOutput,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let pool = match self.find_pool(tx_output) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let output_ref = OutputRef::new(*tx_hash, tx_idx as u64);
+
+        let values = match pool_values(pool, tx_output, &output_ref, ctx) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let key = self.pool_key(pool);
+
+        for (member, value) in MEMBERS.into_iter().zip(values) {
+            self.undo.push(model::CRDTCommand::HashUnsetKey(
+                member.to_string(),
+                key.clone(),
+            ));
+
+            let crdt = model::CRDTCommand::HashSetValue(key.clone(), member.to_string(), value);
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    /// Tracks the reserves of the configured pools as members of a hash per
+    /// pool
+    ///
+    /// Consumed pool outputs are resolved through the enrich stage and clear
+    /// the hash, so a pool that is closed doesn't keep stale reserves. They
+    /// also hold the reserves from before the block, which is what a
+    /// rollback writes back.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        // leftovers of a block that failed half way
+        self.undo.clear();
+
+        for tx in block.txs().into_iter() {
+            for consumed in tx.consumes().iter().map(|i| i.output_ref()) {
+                self.process_consumed_txo(ctx, &consumed, output)?;
+            }
+
+            let tx_hash = tx.hash();
+
+            for (idx, produced) in tx.produces() {
+                self.process_produced_txo(ctx, &tx_hash, idx, &produced, output)?;
+            }
+        }
+
+        // undone from the last write back to the first
+        let mut undo = std::mem::take(&mut self.undo);
+        undo.reverse();
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Writes back the reserves the pools had before the blocks past the
+    /// point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            journal,
+            undo: vec![],
+        };
+
+        super::Reducer::PoolReserves(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{datum_field, datum_int, Config, PoolConfig};
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::codec::minicbor;
+    use pallas::ledger::primitives::alonzo::PlutusData;
+    use pallas::ledger::traverse::{Era, MultiEraBlock};
+    use pallas::network::miniprotocols::Point;
+
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::model::{BlockContext, CRDTCommand, Value};
+    use crate::reducers::{dedup, Reducer};
+
+    fn decode(hex_cbor: &str) -> PlutusData {
+        minicbor::decode(&hex::decode(hex_cbor).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn reads_reserves_from_datum() {
+        // Constr 0 [Constr 0 [], 1000, 2000]
+        let datum = decode("d87983d879801903e81907d0");
+
+        assert_eq!(datum_field(&datum, &[1]).and_then(datum_int), Some(1000));
+        assert_eq!(datum_field(&datum, &[2]).and_then(datum_int), Some(2000));
+        assert_eq!(datum_field(&datum, &[0]).and_then(datum_int), None);
+        assert!(datum_field(&datum, &[3]).is_none());
+
+        // Constr 0 [Constr 0 [bignum 256], 5]
+        let datum = decode("d87982d87981c242010005");
+
+        assert_eq!(datum_field(&datum, &[0, 0]).and_then(datum_int), Some(256));
+    }
+
+    #[test]
+    fn rollback_restores_reserves_of_consumed_output() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let script_hash = "11".repeat(28);
+
+        // babbage output locked by the pool script, with the inline datum
+        // Constr 0 [Constr 0 [], 1000, 2000]
+        let pool_output = hex::decode(format!(
+            "a300581d71{}011a001e8480028201d8184cd87983d879801903e81907d0",
+            script_hash
+        ))
+        .unwrap();
+
+        // the block spends the pool output
+        let consumed = block.txs()[0].consumes()[0].output_ref();
+        let mut ctx = BlockContext::default();
+        ctx.import_ref_output(&consumed, Era::Babbage, pool_output);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 100);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            pools: vec![PoolConfig {
+                id: "p1".into(),
+                address: None,
+                script_hash: Some(script_hash),
+                reserve_a: vec![1],
+                reserve_b: vec![2],
+            }],
+            rollback_window: None,
+        };
+
+        // the other inputs of the block aren't in the context
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = match config.plugin(&policy) {
+            Reducer::PoolReserves(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer.reduce_block(&block, &ctx, &mut output).unwrap();
+
+        let sent: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        // spending the pool output clears the hash
+        assert_eq!(sent.len(), 3);
+        assert!(sent
+            .iter()
+            .all(|x| matches!(x, CRDTCommand::HashUnsetKey(_, key) if key == "pool_reserves.p1")));
+
+        assert!(reducer.covers_rollback(&before));
+
+        let restored: Vec<_> = reducer
+            .roll_back(&before)
+            .into_iter()
+            .map(|x| match x {
+                CRDTCommand::HashSetValue(key, member, value) => {
+                    assert_eq!(key, "pool_reserves.p1");
+                    (member, value)
+                }
+                x => panic!("unexpected command {:?}", x),
+            })
+            .collect();
+
+        assert_eq!(restored.len(), 3);
+
+        let value = |member: &str| {
+            restored
+                .iter()
+                .find(|(x, _)| x == member)
+                .map(|(_, x)| x.clone())
+                .unwrap()
+        };
+
+        assert!(matches!(value("reserve_a"), Value::BigInt(1000)));
+        assert!(matches!(value("reserve_b"), Value::BigInt(2000)));
+        assert!(matches!(value("utxo"), Value::String(x) if x == consumed.to_string()));
+    }
+}
@@ -0,0 +1,158 @@
+use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use super::journal::Journal;
+use crate::{crosscut, model};
+
+/// Number of blocks kept when the config doesn't say
+const DEFAULT_LIMIT: usize = 10;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Number of blocks kept in the list, defaults to 10
+    pub limit: Option<usize>,
+
+    /// Number of blocks that can be popped from the list on rollback,
+    /// defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    journal: Journal,
+}
+
+/// Summary of the block as stored in the list
+fn block_summary(block: &MultiEraBlock) -> serde_json::Value {
+    json!({
+        "hash": block.hash().to_string(),
+        "slot": block.slot(),
+        "height": block.number(),
+        "tx_count": block.tx_count(),
+    })
+}
+
+impl Reducer {
+    /// Keeps a summary of the latest blocks, newest first
+    ///
+    /// On rollback, the summaries of the undone blocks are popped. Blocks
+    /// trimmed out of the list meanwhile can't come back, the list is short
+    /// of them until new blocks fill it again.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("recent_blocks");
+        let limit = self.config.limit.unwrap_or(DEFAULT_LIMIT).max(1);
+
+        let key = prefix.key(&["recent"]);
+
+        self.journal
+            .record(block.slot(), vec![model::CRDTCommand::ListPop(key.clone())]);
+
+        let crdt = model::CRDTCommand::ListPrepend(key, block_summary(block).into(), limit);
+
+        output.send(gasket::messaging::Message::from(crdt))
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Pops the summaries of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            journal,
+        };
+
+        super::Reducer::RecentBlocks(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::{block_summary, Config};
+    use crate::model::CRDTCommand;
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn summary_has_block_fields() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let summary = block_summary(&block);
+
+        assert_eq!(summary["hash"], block.hash().to_string());
+        assert_eq!(summary["slot"], block.slot());
+        assert_eq!(summary["height"], block.number());
+        assert_eq!(summary["tx_count"], block.tx_count());
+    }
+
+    #[test]
+    fn rollback_pops_undone_blocks() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 10);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            limit: Some(3),
+            rollback_window: None,
+        };
+
+        let mut reducer = match config.plugin() {
+            Reducer::RecentBlocks(x) => x,
+            _ => unreachable!(),
+        };
+
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        // nothing reduced yet, the reducer can't tell what came before
+        assert!(!reducer.covers_rollback(&before));
+
+        reducer.reduce_block(&block, &mut output).unwrap();
+
+        assert!(matches!(
+            downstream.recv_or_idle().unwrap().payload,
+            CRDTCommand::ListPrepend(key, _, 3) if key == "recent_blocks.recent"
+        ));
+
+        // rolling back to the block itself leaves it in the list
+        assert!(reducer.covers_rollback(&point));
+        assert!(reducer.roll_back(&point).is_empty());
+
+        let undo = reducer.roll_back(&before);
+
+        assert_eq!(undo.len(), 1);
+        assert!(matches!(&undo[0], CRDTCommand::ListPop(key) if key == "recent_blocks.recent"));
+    }
+}
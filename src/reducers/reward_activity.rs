@@ -0,0 +1,265 @@
+//! Withdrawals and MIR transfers by reward account
+//!
+//! Blocks only carry the movements that are requested on-chain: withdrawals
+//! out of a reward account and instantaneous reward (MIR) certificates into
+//! it. Rewards paid out at each epoch boundary are computed by the ledger and
+//! never appear in a block, so these counters are a partial view and don't
+//! match the actual balance of the account.
+//!
+//! On rollback, the amounts of the undone blocks are subtracted from the
+//! counters of their accounts.
+
+use pallas::ledger::primitives::alonzo::{self, InstantaneousRewardTarget, StakeCredential};
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::{inverse, Journal};
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+    pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    journal: Journal,
+}
+
+/// Hex credential of a reward account, the header byte is dropped so that
+/// withdrawals and certificates share the same key
+fn account_credential(reward_account: &[u8]) -> Option<String> {
+    match reward_account.len() {
+        29 => Some(hex::encode(&reward_account[1..])),
+        _ => None,
+    }
+}
+
+fn credential_hex(cred: &StakeCredential) -> String {
+    match cred {
+        StakeCredential::AddrKeyhash(x) => x.to_string(),
+        StakeCredential::Scripthash(x) => x.to_string(),
+    }
+}
+
+impl Reducer {
+    fn send_counter(
+        &self,
+        kind: &str,
+        credential: &str,
+        delta: i64,
+        undo: &mut Vec<model::CRDTCommand>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let key = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("reward_activity")
+        .key(&[kind, credential]);
+
+        let crdt = model::CRDTCommand::PNCounter(key, delta);
+
+        undo.extend(inverse(&crdt));
+        output.send(gasket::messaging::Message::from(crdt))?;
+
+        Ok(())
+    }
+
+    fn process_withdrawals(
+        &self,
+        tx: &MultiEraTx,
+        undo: &mut Vec<model::CRDTCommand>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for (account, amount) in tx.withdrawals().collect::<Vec<_>>() {
+            match account_credential(account) {
+                Some(cred) => {
+                    self.send_counter("withdrawals", &cred, amount as i64, undo, output)?
+                }
+                None => log::warn!("unexpected reward account {}", hex::encode(account)),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Transfers to other accounting pots don't target any account and are
+    /// ignored
+    fn process_mir_certs(
+        &self,
+        tx: &MultiEraTx,
+        undo: &mut Vec<model::CRDTCommand>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for cert in tx.certs() {
+            if let Some(alonzo::Certificate::MoveInstantaneousRewardsCert(mir)) = cert.as_alonzo() {
+                if let InstantaneousRewardTarget::StakeCredentials(targets) = &mir.target {
+                    for (cred, delta) in targets.iter() {
+                        self.send_counter("mir", &credential_hex(cred), *delta, undo, output)?;
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        txs: &[&MultiEraTx],
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let mut undo = vec![];
+
+        for tx in txs.iter().copied() {
+            if tx.is_valid() && filter_matches!(self, block, tx, ctx) {
+                self.process_withdrawals(tx, &mut undo, output)?;
+                self.process_mir_certs(tx, &mut undo, output)?;
+            }
+        }
+
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Subtracts the amounts of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            journal,
+        };
+
+        super::Reducer::RewardActivity(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{account_credential, Config};
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::addresses::Address;
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn withdrawal_key_is_the_stake_credential() {
+        let account =
+            Address::from_bech32("stake1uyehkck0lajq8gr28t9uxnuvgcqrc6070x3k9r8048z8y5gh6ffgw")
+                .unwrap();
+
+        assert_eq!(
+            account_credential(&account.to_vec()).as_deref(),
+            Some("337b62cfff6403a06a3acbc34f8c46003c69fe79a3628cefa9c47251")
+        );
+
+        assert_eq!(account_credential(&[0xe1, 0x00]), None);
+    }
+
+    fn reducer() -> super::Reducer {
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            filter: None,
+            rollback_window: None,
+        };
+
+        match config.plugin(&Default::default()) {
+            Reducer::RewardActivity(x) => x,
+            _ => unreachable!(),
+        }
+    }
+
+    fn output() -> (dedup::Output, TwoPhaseInputPort<CRDTCommand>) {
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut port, &mut downstream, 1000);
+
+        (dedup::Output::new(port, false), downstream)
+    }
+
+    #[test]
+    fn counters_are_undone_per_account() {
+        let (mut output, mut downstream) = output();
+        let reducer = reducer();
+        let mut undo = vec![];
+
+        reducer
+            .send_counter("withdrawals", "ab", 5, &mut undo, &mut output)
+            .unwrap();
+
+        assert!(matches!(
+            downstream.recv_or_idle().unwrap().payload,
+            CRDTCommand::PNCounter(key, 5) if key == "reward_activity.withdrawals.ab"
+        ));
+
+        assert_eq!(undo.len(), 1);
+        assert!(matches!(
+            &undo[0],
+            CRDTCommand::PNCounter(key, -5) if key == "reward_activity.withdrawals.ab"
+        ));
+    }
+
+    #[test]
+    fn rollback_subtracts_block_amounts() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let (mut output, mut downstream) = output();
+        let mut reducer = reducer();
+
+        reducer.roll_back(&before);
+        let txs = block.txs();
+        let txs: Vec<_> = txs.iter().collect();
+
+        reducer
+            .reduce_block(&block, &txs, &BlockContext::default(), &mut output)
+            .unwrap();
+
+        let sent: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        assert!(reducer.covers_rollback(&before));
+        let undo = reducer.roll_back(&before);
+
+        assert_eq!(undo.len(), sent.len());
+
+        for (sent, undo) in sent.iter().zip(undo.iter()) {
+            match (sent, undo) {
+                (CRDTCommand::PNCounter(a, x), CRDTCommand::PNCounter(b, y)) => {
+                    assert_eq!(a, b);
+                    assert_eq!(*x, -y);
+                }
+                x => panic!("unexpected commands {:?}", x),
+            }
+        }
+    }
+}
@@ -0,0 +1,213 @@
+use std::collections::BTreeSet;
+
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::{inverse, Journal};
+use crate::crosscut::epochs::block_epoch;
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+    pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Keep a separate counter per epoch, under `{prefix}.{script}.{epoch}`
+    pub by_epoch: Option<bool>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    chain: crosscut::ChainWellKnownInfo,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+    journal: Journal,
+}
+
+impl Reducer {
+    /// Scripts run by the tx: the ones locking the spent inputs and the
+    /// minting policies
+    fn executed_scripts(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<BTreeSet<String>, gasket::error::Error> {
+        let mut scripts = BTreeSet::new();
+
+        for input in tx.consumes() {
+            let utxo = ctx
+                .find_utxo(&input.output_ref())
+                .apply_policy(&self.policy)
+                .or_panic()?;
+
+            if let Some(address) = utxo.and_then(|x| self.addresses.address(&x)) {
+                scripts.extend(crosscut::addresses::script_hash(&address));
+            }
+        }
+
+        if let Some(mints) = tx.mint().as_alonzo() {
+            for (policy, _) in mints.iter() {
+                scripts.insert(policy.to_string());
+            }
+        }
+
+        Ok(scripts)
+    }
+
+    /// Counts the txs running each script, a script used several times by
+    /// the same tx is counted once
+    ///
+    /// On rollback, the counters are decremented for the txs of the undone
+    /// blocks.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("script_activity");
+
+        let epoch = match self.config.by_epoch {
+            Some(true) => Some(block_epoch(&self.chain, block)),
+            _ => None,
+        };
+
+        let mut undo = vec![];
+
+        for tx in block.txs().into_iter() {
+            if filter_matches!(self, block, &tx, ctx) {
+                for script in self.executed_scripts(&tx, ctx)? {
+                    let key = match epoch {
+                        Some(epoch) => prefix.key(&[&script, &epoch.to_string()]),
+                        None => prefix.key(&[&script]),
+                    };
+
+                    let crdt = model::CRDTCommand::PNCounter(key, 1);
+
+                    undo.extend(inverse(&crdt));
+                    output.send(gasket::messaging::Message::from(crdt))?;
+                }
+            }
+        }
+
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Decrements the counters of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            chain: chain.clone(),
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+            journal,
+        };
+
+        super::Reducer::ScriptActivity(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::{Era, MultiEraBlock};
+    use pallas::network::miniprotocols::Point;
+
+    use super::Config;
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn rollback_decrements_script_counters() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let script_hash = "11".repeat(28);
+
+        // the block spends an output locked by the script
+        let locked = hex::decode(format!("a200581d71{}011a001e8480", script_hash)).unwrap();
+        let consumed = block.txs()[0].consumes()[0].output_ref();
+        let mut ctx = BlockContext::default();
+        ctx.import_ref_output(&consumed, Era::Babbage, locked);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 1000);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            filter: None,
+            by_epoch: None,
+            rollback_window: None,
+        };
+
+        // the other inputs of the block aren't in the context
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = match config.plugin(&ChainWellKnownInfo::mainnet(), &policy) {
+            Reducer::ScriptActivity(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer.reduce_block(&block, &ctx, &mut output).unwrap();
+
+        let key = format!("script_activity.{}", script_hash);
+
+        let count = |cmds: &[CRDTCommand]| -> i64 {
+            cmds.iter()
+                .map(|x| match x {
+                    CRDTCommand::PNCounter(x, delta) if *x == key => *delta,
+                    _ => 0,
+                })
+                .sum()
+        };
+
+        let sent: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        assert_eq!(count(&sent), 1);
+
+        assert!(reducer.covers_rollback(&before));
+        let undo = reducer.roll_back(&before);
+
+        assert_eq!(count(&undo), -1);
+        assert_eq!(undo.len(), sent.len());
+    }
+}
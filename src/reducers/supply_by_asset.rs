@@ -3,7 +3,7 @@ use std::str::FromStr;
 use gasket::error::AsWorkError;
 use pallas::crypto::hash::Hash;
 use pallas::ledger::traverse::Asset;
-use pallas::ledger::traverse::MultiEraBlock;
+use pallas::ledger::traverse::MultiEraTx;
 use serde::Deserialize;
 
 use crate::{crosscut, model};
@@ -11,7 +11,9 @@ use crate::{crosscut, model};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub policy_ids_hex: Option<Vec<String>>,
+    pub asset_name_encoding: Option<crosscut::assets::NameEncoding>,
 }
 
 pub struct Reducer {
@@ -39,25 +41,32 @@ impl Reducer {
             return Ok(());
         }
 
-        let asset_id = &format!("{}{}", policy, hex::encode(asset));
+        let encoding = self.config.asset_name_encoding.unwrap_or_default();
 
-        let key = match &self.config.key_prefix {
-            Some(prefix) => format!("{}.{}", prefix, asset_id),
-            None => format!("{}.{}", "supply_by_asset".to_string(), asset_id),
+        let asset_id = match crosscut::assets::asset_id(policy, asset, encoding) {
+            Some(x) => x,
+            None => return Ok(()),
         };
 
+        let key = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("supply_by_asset")
+        .key(&[&asset_id]);
+
         let crdt = model::CRDTCommand::PNCounter(key, qty);
 
         output.send(crdt.into())
     }
 
-    pub fn reduce_block<'b>(
+    pub fn reduce_block(
         &mut self,
-        block: &'b MultiEraBlock<'b>,
+        txs: &[&MultiEraTx],
         ctx: &model::BlockContext,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        for tx in block.txs().into_iter() {
+        for tx in txs {
             if let Some(mints) = tx.mint().as_alonzo() {
                 for (policy, assets) in mints.iter() {
                     for (name, amount) in assets.iter() {
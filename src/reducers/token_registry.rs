@@ -0,0 +1,187 @@
+use pallas::ledger::primitives::alonzo::{Metadatum, MetadatumLabel};
+use pallas::ledger::traverse::MultiEraTx;
+use serde::Deserialize;
+
+use crate::{crosscut, model};
+
+/// Metadata label of the token registry entries embedded on-chain
+const TOKEN_REGISTRY_LABEL: u64 = 20;
+
+/// Fields of a registry entry kept by the reducer, any other is ignored
+const FIELDS: [&str; 6] = ["name", "description", "ticker", "decimals", "url", "logo"];
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+}
+
+pub struct Reducer {
+    config: Config,
+}
+
+/// Value of a registry field: integers stay integers, arrays of text (logos
+/// split in 64 bytes chunks) are joined back together
+fn field_value(value: &Metadatum) -> Option<model::Value> {
+    match value {
+        Metadatum::Text(x) => Some(model::Value::String(x.clone())),
+        Metadatum::Int(x) => x.to_string().parse().ok().map(model::Value::BigInt),
+        Metadatum::Array(items) => items
+            .iter()
+            .map(|x| match x {
+                Metadatum::Text(x) => Some(x.as_str()),
+                _ => None,
+            })
+            .collect::<Option<String>>()
+            .map(model::Value::String),
+        _ => None,
+    }
+}
+
+fn find_entry<'a>(map: &'a Metadatum, key: &Metadatum) -> Option<&'a Metadatum> {
+    match map {
+        Metadatum::Map(kv) => kv.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+        _ => None,
+    }
+}
+
+/// Known fields of the registry entry of the asset, out of the label 20 map
+///
+/// The map follows the CIP-25 layout (policy id as hex text, then asset
+/// name), asset names are looked up as utf-8 text or raw bytes.
+fn registry_fields(
+    metadata: &Metadatum,
+    policy_id: &str,
+    asset_name: &[u8],
+) -> Vec<(&'static str, model::Value)> {
+    let assets = match find_entry(metadata, &Metadatum::Text(policy_id.to_string())) {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    let by_text = String::from_utf8(asset_name.to_vec())
+        .ok()
+        .and_then(|x| find_entry(assets, &Metadatum::Text(x)));
+
+    let entry = match by_text
+        .or_else(|| find_entry(assets, &Metadatum::Bytes(asset_name.to_vec().into())))
+    {
+        Some(x) => x,
+        None => return vec![],
+    };
+
+    FIELDS
+        .iter()
+        .filter_map(|field| {
+            find_entry(entry, &Metadatum::Text(field.to_string()))
+                .and_then(field_value)
+                .map(|value| (*field, value))
+        })
+        .collect()
+}
+
+impl Reducer {
+    fn process_tx(
+        &mut self,
+        tx: &MultiEraTx,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let metadata = tx.metadata();
+
+        let registry = match metadata.find(MetadatumLabel::from(TOKEN_REGISTRY_LABEL)) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let mint = match tx.mint().as_alonzo() {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("token_registry");
+
+        for (policy_id, assets) in mint.iter() {
+            let policy_id = hex::encode(policy_id);
+
+            for (asset_name, quantity) in assets.iter() {
+                if *quantity < 1 {
+                    continue;
+                }
+
+                let subject = format!("{}{}", policy_id, hex::encode(asset_name.to_vec()));
+                let key = prefix.key(&[&subject]);
+
+                for (field, value) in registry_fields(registry, &policy_id, asset_name) {
+                    let crdt =
+                        model::CRDTCommand::HashSetValue(key.clone(), field.to_string(), value);
+                    output.send(gasket::messaging::Message::from(crdt))?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Indexes the label 20 entries of minting txs, one hash per asset keyed
+    /// by policy id and hex asset name, with a member per known field
+    ///
+    /// Fields of a later mint overwrite the previous ones, fields it doesn't
+    /// mention are kept.
+    pub fn reduce_block(
+        &mut self,
+        txs: &[&MultiEraTx],
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in txs.iter().copied() {
+            self.process_tx(tx, output)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self) -> super::Reducer {
+        let reducer = Reducer { config: self };
+
+        super::Reducer::TokenRegistry(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::codec::minicbor;
+    use pallas::ledger::primitives::alonzo::Metadatum;
+
+    use super::registry_fields;
+    use crate::model::Value;
+
+    const POLICY: &str = "d5e6bf0500378d4f0da4e8dde6becec7621cd8cbf5cbb9b87013d4cc";
+
+    // { "d5e6..d4cc": { "SPACE": { "name": "Space Token", "description": "Fuel for SpaceBuds",
+    //   "ticker": "SPC", "decimals": 6, "url": "https://spacebudz.io",
+    //   "logo": ["data:image/png;base64,", "iVBORw0KGgo="] } } }
+    const LABEL_20: &str = "a178386435653662663035303033373864346630646134653864646536626563656337363231636438636266356362623962383730313364346363a1655350414345a6646e616d656b537061636520546f6b656e6b6465736372697074696f6e724675656c20666f7220537061636542756473667469636b65726353504368646563696d616c73066375726c7468747470733a2f2f73706163656275647a2e696f646c6f676f8276646174613a696d6167652f706e673b6261736536342c6c6956424f5277304b47676f3d";
+
+    #[test]
+    fn registry_fields_are_extracted() {
+        let metadata: Metadatum = minicbor::decode(&hex::decode(LABEL_20).unwrap()).unwrap();
+
+        let fields = registry_fields(&metadata, POLICY, b"SPACE");
+        let field = |name: &str| fields.iter().find(|(x, _)| *x == name).map(|(_, v)| v);
+
+        assert_eq!(fields.len(), 6);
+        assert!(matches!(field("name"), Some(Value::String(x)) if x == "Space Token"));
+        assert!(matches!(field("ticker"), Some(Value::String(x)) if x == "SPC"));
+        assert!(matches!(field("decimals"), Some(Value::BigInt(6))));
+        assert!(
+            matches!(field("logo"), Some(Value::String(x)) if x == "data:image/png;base64,iVBORw0KGgo=")
+        );
+
+        assert!(registry_fields(&metadata, POLICY, b"OTHER").is_empty());
+    }
+}
@@ -20,6 +20,7 @@ impl Default for Projection {
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
     pub projection: Option<Projection>,
 }
@@ -37,7 +38,10 @@ impl Reducer {
         tx: &MultiEraTx,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let key_prefix = self.config.key_prefix.as_deref();
+        let key_prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        );
         let crdt = match self.config.projection.unwrap_or_default() {
             Projection::Cbor => {
                 let cbor = tx.encode();
@@ -8,15 +8,30 @@ use crate::{crosscut, model, prelude::*};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<crosscut::filters::Predicate>,
+
+    /// Key by the bucket of the address, out of `2^bits` buckets, instead of
+    /// the address itself. Lossy, see `crosscut::buckets`.
+    pub address_bucket_bits: Option<u8>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 impl Reducer {
+    fn address_key(&self, address: &str) -> String {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("txcount_by_address")
+        .key(&[address])
+    }
+
     fn process_inbound_txo(
         &mut self,
         ctx: &model::BlockContext,
@@ -31,13 +46,17 @@ impl Reducer {
             None => return Ok(())
         };
 
-        let address = utxo.address().map(|addr| addr.to_string()).or_panic()?;
+        let address = match self.addresses.key(&utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let address = crosscut::buckets::address_key(&address, self.config.address_bucket_bits);
+
+        // within a bucket, a tx still counts once
         
         if seen.insert(address.clone()) {
-            let key = match &self.config.key_prefix {
-                Some(prefix) => format!("{}.{}", prefix, address),
-                None => format!("{}.{}", "txcount_by_address".to_string(), address),
-            };
+            let key = self.address_key(&address);
     
             let crdt = model::CRDTCommand::PNCounter(key, 1);
     
@@ -53,13 +72,16 @@ impl Reducer {
         seen: &mut HashSet<String>,
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
-        let address = tx_output.address().map(|x| x.to_string()).or_panic()?;
+        let address = match self.addresses.key(tx_output, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let address = crosscut::buckets::address_key(&address, self.config.address_bucket_bits);
+
         
         if seen.insert(address.clone()) {
-            let key = match &self.config.key_prefix {
-                Some(prefix) => format!("{}.{}", prefix, address),
-                None => format!("{}.{}", "txcount_by_address".to_string(), address),
-            };
+            let key = self.address_key(&address);
     
             let crdt = model::CRDTCommand::PNCounter(key, 1);
     
@@ -98,6 +120,7 @@ impl Config {
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::TxCountByAddress(reducer)
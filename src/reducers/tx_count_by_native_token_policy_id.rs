@@ -1,6 +1,6 @@
 use serde::Deserialize;
 
-use pallas::ledger::traverse::{Feature, MultiEraBlock};
+use pallas::ledger::traverse::{Feature, MultiEraBlock, MultiEraTx};
 
 use crate::crosscut::epochs::block_epoch;
 use crate::{crosscut, model};
@@ -13,6 +13,7 @@ pub enum AggrType {
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub aggr_by: Option<AggrType>,
 }
 
@@ -25,22 +26,21 @@ impl Reducer {
     fn config_key(&self, policy_id: String, epoch_no: u64) -> String {
         let def_key_prefix = "transaction_count_by_native_token_policy";
 
+        let prefix = crosscut::keys::Prefix::new(self.config.key_prefix.as_deref(), self.config.key_separator.as_deref());
+
         match &self.config.aggr_by {
             Some(aggr_type) => {
                 match aggr_type {
                     AggrType::Epoch => {
                         return match &self.config.key_prefix {
-                            Some(prefix) => format!("{}.{}.{}", prefix, policy_id, epoch_no),
-                            None => format!("{}.{}", def_key_prefix.to_string(), policy_id),
+                            Some(_) => prefix.key(&[&policy_id, &epoch_no.to_string()]),
+                            None => prefix.or(def_key_prefix).key(&[&policy_id]),
                         };
                     }
                 }
             },
             None => {
-                return match &self.config.key_prefix {
-                    Some(prefix) => format!("{}.{}", prefix, policy_id),
-                    None => format!("{}.{}", def_key_prefix.to_string(), policy_id),
-                };
+                return prefix.or(def_key_prefix).key(&[&policy_id]);
             },
         };
     }
@@ -48,13 +48,14 @@ impl Reducer {
     pub fn reduce_block(
         &mut self,
         block: &MultiEraBlock,
+        txs: &[&MultiEraTx],
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         if block.era().has_feature(Feature::MultiAssets) {
 
             let epoch_no = block_epoch(&self.chain, block);
 
-            for tx in block.txs() {
+            for tx in txs {
                 if tx.is_valid() {
                     let mint = tx.mint();
 
@@ -0,0 +1,197 @@
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraOutput, MultiEraTx};
+use serde::Deserialize;
+
+use crate::{crosscut, model, prelude::*};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Also record the address and lovelace of the consumed outputs, looked
+    /// up through the enrich stage. Off by default, in which case the edges
+    /// only need the refs carried by the txs themselves.
+    pub resolve_inputs: Option<bool>,
+}
+
+pub struct Reducer {
+    config: Config,
+    policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
+}
+
+impl Reducer {
+    fn prefix(&self) -> crosscut::keys::Prefix {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("tx_graph")
+    }
+
+    /// Address and lovelace of the output, set on its edge
+    fn output_details(&self, key: &str, utxo: &MultiEraOutput) -> Vec<model::CRDTCommand> {
+        let mut crdts = vec![model::CRDTCommand::HashSetValue(
+            key.to_string(),
+            "lovelace".into(),
+            model::Value::BigInt(utxo.lovelace_amount() as i128),
+        )];
+
+        if let Some(address) = self.addresses.key(utxo, |x| x.to_string()) {
+            crdts.push(model::CRDTCommand::HashSetValue(
+                key.to_string(),
+                "address".into(),
+                address.into(),
+            ));
+        }
+
+        crdts
+    }
+
+    /// Edges of a tx in both directions
+    ///
+    /// The refs a tx consumed and produced go to the sets
+    /// `{prefix}.inputs.{tx hash}` and `{prefix}.outputs.{tx hash}`. Each
+    /// output gets a hash under `{prefix}.edge.{output ref}` with the tx
+    /// that produced it and, once it's spent, the one that consumed it: the
+    /// consuming tx isn't known until then, so it's stored as a forward edge
+    /// on the output. Reference inputs aren't spent and aren't edges.
+    fn tx_edges(
+        &self,
+        tx: &MultiEraTx,
+        ctx: &model::BlockContext,
+    ) -> Result<Vec<model::CRDTCommand>, gasket::error::Error> {
+        let prefix = self.prefix();
+        let tx_hash = tx.hash();
+        let tx_id = tx_hash.to_string();
+        let mut crdts = vec![];
+
+        for input in tx.consumes() {
+            let output_ref = input.output_ref();
+            let member = crosscut::refs::ref_key(&output_ref);
+            let edge = prefix.key(&["edge", &member]);
+
+            crdts.push(model::CRDTCommand::SetAdd(
+                prefix.key(&["inputs", &tx_id]),
+                member,
+            ));
+
+            crdts.push(model::CRDTCommand::HashSetValue(
+                edge.clone(),
+                "spent_by".into(),
+                tx_id.clone().into(),
+            ));
+
+            if self.config.resolve_inputs.unwrap_or(false) {
+                let utxo = ctx
+                    .find_utxo(&output_ref)
+                    .apply_policy(&self.policy)
+                    .or_panic()?;
+
+                if let Some(utxo) = utxo {
+                    crdts.extend(self.output_details(&edge, &utxo));
+                }
+            }
+        }
+
+        for (idx, produced) in tx.produces() {
+            let member = crosscut::refs::output_key(&tx_hash, idx as u64);
+            let edge = prefix.key(&["edge", &member]);
+
+            crdts.push(model::CRDTCommand::SetAdd(
+                prefix.key(&["outputs", &tx_id]),
+                member,
+            ));
+
+            crdts.push(model::CRDTCommand::HashSetValue(
+                edge.clone(),
+                "produced_by".into(),
+                tx_id.clone().into(),
+            ));
+
+            crdts.extend(self.output_details(&edge, &produced));
+        }
+
+        Ok(crdts)
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        for tx in block.txs().iter() {
+            for crdt in self.tx_edges(tx, ctx)? {
+                output.send(gasket::messaging::Message::from(crdt))?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, policy: &crosscut::policies::RuntimePolicy) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
+        };
+
+        super::Reducer::TxGraph(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::ledger::traverse::{Era, MultiEraTx};
+
+    use super::{Config, Reducer};
+    use crate::crosscut::addresses::AddressResolver;
+    use crate::model::CRDTCommand;
+
+    // babbage tx spending 1111..11#0 and using 2222..22#0 as reference input
+    const TX: &str = "84a40081825820111111111111111111111111111111111111111111111111111111111111111100018182581d61000000000000000000000000000000000000000000000000000000001a001e8480021a000298101281825820222222222222222222222222222222222222222222222222222222222222222200a0f5f6";
+
+    #[test]
+    fn edges_link_both_directions() {
+        let cbor = hex::decode(TX).unwrap();
+        let tx = MultiEraTx::decode(Era::Babbage, &cbor).unwrap();
+        let hash = tx.hash().to_string();
+
+        let reducer = Reducer {
+            config: Config {
+                key_prefix: Some("g".into()),
+                key_separator: None,
+                resolve_inputs: None,
+            },
+            policy: Default::default(),
+            addresses: AddressResolver::new(None),
+        };
+
+        let crdts = reducer.tx_edges(&tx, &Default::default()).unwrap();
+
+        let spent = format!("{}#0", "11".repeat(32));
+        let produced = format!("{}#0", hash);
+
+        assert!(crdts.iter().any(
+            |x| matches!(x, CRDTCommand::SetAdd(k, m) if *k == format!("g.inputs.{}", hash) && *m == spent)
+        ));
+
+        assert!(crdts.iter().any(|x| matches!(
+            x,
+            CRDTCommand::HashSetValue(k, f, _) if *k == format!("g.edge.{}", spent) && f == "spent_by"
+        )));
+
+        assert!(crdts.iter().any(|x| matches!(
+            x,
+            CRDTCommand::HashSetValue(k, f, _) if *k == format!("g.edge.{}", produced) && f == "produced_by"
+        )));
+
+        // the reference input isn't spent
+        assert!(!crdts
+            .iter()
+            .any(|x| matches!(x, CRDTCommand::SetAdd(_, m) if m.starts_with("2222"))));
+    }
+}
@@ -0,0 +1,192 @@
+use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+
+use super::journal::{inverse, Journal};
+use crate::crosscut::epochs::block_epoch;
+use crate::{crosscut, model};
+
+/// Number of txs kept in the largest set of each epoch when the config
+/// doesn't say
+const DEFAULT_LARGEST: usize = 10;
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+
+    /// Number of txs kept in the `largest` set of each epoch, defaults to 10
+    pub largest_limit: Option<usize>,
+
+    /// Number of blocks that can be undone on rollback, defaults to 2160
+    pub rollback_window: Option<usize>,
+}
+
+pub struct Reducer {
+    config: Config,
+    chain: crosscut::ChainWellKnownInfo,
+    journal: Journal,
+}
+
+impl Reducer {
+    fn key(&self, stat: &str, epoch_no: u64) -> String {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("tx_size")
+        .key(&[stat, &epoch_no.to_string()])
+    }
+
+    /// Sends the stat updates for a single tx
+    ///
+    /// Total bytes and tx count are kept as counters so that the average can
+    /// be derived by the consumer. The largest tx size is kept in a max-wins
+    /// register, while a sorted set scored by tx size keeps the largest txs
+    /// of the epoch, trimmed to `largest_limit` after each add.
+    ///
+    /// On rollback, the counters are subtracted and the tx is taken out of
+    /// the largest set. The max register can't be reverted, keys are scoped
+    /// by epoch so that it only affects the current one.
+    fn send_tx_stats(
+        &self,
+        epoch_no: u64,
+        tx: &MultiEraTx,
+        undo: &mut Vec<model::CRDTCommand>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let size = tx.encode().len() as i64;
+
+        let crdts = [
+            model::CRDTCommand::PNCounter(self.key("bytes", epoch_no), size),
+            model::CRDTCommand::PNCounter(self.key("count", epoch_no), 1),
+            model::CRDTCommand::MaxWins(self.key("max", epoch_no), size),
+            model::CRDTCommand::SortedSetAdd(
+                self.key("largest", epoch_no),
+                tx.hash().to_string(),
+                size,
+            ),
+            model::CRDTCommand::SortedSetTrim(
+                self.key("largest", epoch_no),
+                self.config.largest_limit.unwrap_or(DEFAULT_LARGEST).max(1),
+            ),
+        ];
+
+        for crdt in crdts {
+            undo.extend(inverse(&crdt));
+            output.send(gasket::messaging::Message::from(crdt))?;
+        }
+
+        Ok(())
+    }
+
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let epoch_no = block_epoch(&self.chain, block);
+        let mut undo = vec![];
+
+        for tx in block.txs() {
+            self.send_tx_stats(epoch_no, &tx, &mut undo, output)?;
+        }
+
+        self.journal.record(block.slot(), undo);
+
+        Ok(())
+    }
+
+    pub fn covers_rollback(&self, point: &Point) -> bool {
+        self.journal.covers(point)
+    }
+
+    /// Subtracts the stats of the txs of the blocks past the point
+    pub fn roll_back(&mut self, point: &Point) -> Vec<model::CRDTCommand> {
+        self.journal.roll_back(point)
+    }
+}
+
+impl Config {
+    pub fn plugin(self, chain: &crosscut::ChainWellKnownInfo) -> super::Reducer {
+        let journal = Journal::new(self.rollback_window);
+
+        let reducer = Reducer {
+            config: self,
+            chain: chain.clone(),
+            journal,
+        };
+
+        super::Reducer::TxSizeStats(reducer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::Config;
+    use crate::crosscut::ChainWellKnownInfo;
+    use crate::model::CRDTCommand;
+    use crate::reducers::{dedup, Reducer};
+
+    #[test]
+    fn rollback_subtracts_block_stats() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 1000);
+        let mut output = dedup::Output::new(port, false);
+
+        let config = Config {
+            key_prefix: None,
+            key_separator: None,
+            largest_limit: None,
+            rollback_window: None,
+        };
+
+        let mut reducer = match config.plugin(&ChainWellKnownInfo::mainnet()) {
+            Reducer::TxSizeStats(x) => x,
+            _ => unreachable!(),
+        };
+
+        reducer.roll_back(&before);
+        reducer.reduce_block(&block, &mut output).unwrap();
+
+        let sent: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        assert!(reducer.covers_rollback(&before));
+        let undo = reducer.roll_back(&before);
+
+        // bytes, count and largest for each tx, the max register stays
+        assert_eq!(undo.len(), block.tx_count() * 3);
+
+        let bytes = |cmds: &[CRDTCommand]| -> i64 {
+            cmds.iter()
+                .map(|x| match x {
+                    CRDTCommand::PNCounter(key, delta) if key.starts_with("tx_size.bytes.") => {
+                        *delta
+                    }
+                    _ => 0,
+                })
+                .sum()
+        };
+
+        assert!(bytes(&sent) > 0);
+        assert_eq!(bytes(&sent) + bytes(&undo), 0);
+
+        assert!(undo.iter().all(|x| match x {
+            CRDTCommand::SortedSetRemove(key, _, delta) =>
+                key.starts_with("tx_size.largest.") && *delta < 0,
+            CRDTCommand::PNCounter(_, delta) => *delta < 0,
+            _ => false,
+        }));
+    }
+}
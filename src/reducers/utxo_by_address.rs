@@ -1,21 +1,87 @@
+use pallas::ledger::primitives::babbage::DatumOption;
 use pallas::ledger::traverse::MultiEraOutput;
 use pallas::ledger::traverse::{MultiEraBlock, MultiEraTx, OutputRef};
 use serde::Deserialize;
+use serde_json::json;
 
 use crate::{crosscut, model, prelude::*};
 
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<Vec<String>>,
+
+    /// Keep a counter of utxos per address under `{prefix}.count.{address}`
+    pub utxo_count: Option<bool>,
+
+    /// Store the lovelace amount of the utxo along with its ref
+    pub include_lovelace: Option<bool>,
+
+    /// Store the datum hash of the utxo along with its ref, `null` when the
+    /// output has none or an inline datum
+    pub include_datum_hash: Option<bool>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 impl Reducer {
+    /// Set member for the utxo
+    ///
+    /// Just the `tx#idx` ref by default. When details are included, a json
+    /// object holding the ref under `ref` instead. The member of a consumed
+    /// utxo is rebuilt from the enrich context, so it matches the one added
+    /// when it was produced as long as the flags didn't change in between.
+    fn member(&self, output_ref: String, utxo: &MultiEraOutput) -> String {
+        let lovelace = self.config.include_lovelace.unwrap_or(false);
+        let datum_hash = self.config.include_datum_hash.unwrap_or(false);
+
+        if !lovelace && !datum_hash {
+            return output_ref;
+        }
+
+        let mut data = json!({ "ref": output_ref });
+
+        if lovelace {
+            data["lovelace"] = json!(utxo.lovelace_amount());
+        }
+
+        if datum_hash {
+            data["datum_hash"] = match utxo.datum() {
+                Some(DatumOption::Hash(x)) => json!(x.to_string()),
+                _ => serde_json::Value::Null,
+            };
+        }
+
+        data.to_string()
+    }
+
+    fn prefix(&self) -> crosscut::keys::Prefix {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+    }
+
+    fn send_count_delta(
+        &self,
+        address: &str,
+        delta: i64,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        if !self.config.utxo_count.unwrap_or(false) {
+            return Ok(());
+        }
+
+        let key = self.prefix().key(&["count", address]);
+
+        output.send(model::CRDTCommand::PNCounter(key, delta).into())
+    }
+
     fn process_consumed_txo(
         &mut self,
         ctx: &model::BlockContext,
@@ -29,7 +95,10 @@ impl Reducer {
             None => return Ok(())
         };
 
-        let address = utxo.address().map(|x| x.to_string()).or_panic()?;
+        let address = match self.addresses.key(&utxo, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
 
         if let Some(addresses) = &self.config.filter {
             if let Err(_) = addresses.binary_search(&address) {
@@ -38,12 +107,14 @@ impl Reducer {
         }
 
         let crdt = model::CRDTCommand::set_remove(
-            self.config.key_prefix.as_deref(),
+            self.prefix(),
             &address,
-            input.to_string(),
+            self.member(crosscut::refs::ref_key(input), &utxo),
         );
 
-        output.send(crdt.into())
+        output.send(crdt.into())?;
+
+        self.send_count_delta(&address, -1, output)
     }
 
     fn process_produced_txo(
@@ -54,7 +125,10 @@ impl Reducer {
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         let tx_hash = tx.hash();
-        let address = tx_output.address().map(|addr| addr.to_string()).or_panic()?;
+        let address = match self.addresses.key(tx_output, |x| x.to_string()) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
 
         if let Some(addresses) = &self.config.filter {
             if let Err(_) = addresses.binary_search(&address) {
@@ -62,13 +136,14 @@ impl Reducer {
             }
         }
 
-        let crdt = model::CRDTCommand::set_add(
-            self.config.key_prefix.as_deref(),
-            &address,
-            format!("{}#{}", tx_hash, output_idx),
-        );
+        let utxo = crosscut::refs::output_key(&tx_hash, output_idx as u64);
+        let member = self.member(utxo, tx_output);
 
-        output.send(crdt.into())
+        let crdt = model::CRDTCommand::set_add(self.prefix(), &address, member);
+
+        output.send(crdt.into())?;
+
+        self.send_count_delta(&address, 1, output)
     }
 
     pub fn reduce_block<'b>(
@@ -96,8 +171,148 @@ impl Config {
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::UtxoByAddress(reducer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use pallas::codec::minicbor;
+    use pallas::codec::utils::Bytes;
+    use pallas::crypto::hash::Hash;
+    use pallas::ledger::primitives::alonzo;
+    use pallas::ledger::traverse::{Era, MultiEraBlock, MultiEraOutput, OutputRef};
+
+    use super::{Config, Reducer};
+    use crate::crosscut::addresses::AddressResolver;
+    use crate::crosscut::policies::{ErrorAction, RuntimePolicy};
+    use crate::model::{BlockContext, CRDTCommand};
+    use crate::reducers::dedup;
+
+    fn reducer(include_lovelace: bool, include_datum_hash: bool) -> Reducer {
+        Reducer {
+            config: Config {
+                key_prefix: None,
+                key_separator: None,
+                filter: None,
+                utxo_count: None,
+                include_lovelace: Some(include_lovelace),
+                include_datum_hash: Some(include_datum_hash),
+            },
+            policy: Default::default(),
+            addresses: AddressResolver::new(None),
+        }
+    }
+
+    #[test]
+    fn details_are_opt_in() {
+        let output = alonzo::TransactionOutput {
+            address: Bytes::from(vec![0x61; 29]),
+            amount: alonzo::Value::Coin(2_000_000),
+            datum_hash: Some(Hash::new([3u8; 32])),
+        };
+
+        let cbor = minicbor::to_vec(&output).unwrap();
+        let output = MultiEraOutput::decode(Era::Alonzo, &cbor).unwrap();
+
+        assert_eq!(reducer(false, false).member("ab#0".into(), &output), "ab#0");
+
+        let member = reducer(true, true).member("ab#0".into(), &output);
+        let member: serde_json::Value = serde_json::from_str(&member).unwrap();
+
+        assert_eq!(member["ref"], "ab#0");
+        assert_eq!(member["lovelace"], 2_000_000);
+        assert_eq!(member["datum_hash"], hex::encode([3u8; 32]));
+
+        let member = reducer(true, false).member("ab#0".into(), &output);
+        let member: serde_json::Value = serde_json::from_str(&member).unwrap();
+
+        assert!(member.get("datum_hash").is_none());
+    }
+
+    /// Commands sent for test.block, with one of the outputs it spends known
+    fn reduce_test_block(utxo_count: bool) -> Vec<CRDTCommand> {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        // second input of the first tx, an enterprise output holding 5 ADA
+        let mut ctx = BlockContext::default();
+        ctx.import_ref_output(
+            &OutputRef::new(
+                "4ddea0589dbb0bfa613c2c8fa974fba72d437cbb79f279c765a75a094462983b"
+                    .parse()
+                    .unwrap(),
+                1,
+            ),
+            Era::Alonzo,
+            hex::decode(format!("82581d61{}1a004c4b40", "00".repeat(28))).unwrap(),
+        );
+
+        let mut port = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut port, &mut downstream, 1000);
+        let mut output = dedup::Output::new(port, false);
+
+        let policy = RuntimePolicy {
+            missing_data: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        let mut reducer = Reducer {
+            config: Config {
+                key_prefix: Some("utxos".into()),
+                key_separator: None,
+                filter: None,
+                utxo_count: Some(utxo_count),
+                include_lovelace: None,
+                include_datum_hash: None,
+            },
+            policy,
+            addresses: AddressResolver::new(None),
+        };
+
+        reducer.reduce_block(&block, &ctx, &mut output).unwrap();
+
+        std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect()
+    }
+
+    #[test]
+    fn counters_follow_the_sets() {
+        let mut members = BTreeMap::<String, i64>::new();
+        let mut counters = BTreeMap::<String, i64>::new();
+
+        for cmd in reduce_test_block(true) {
+            match cmd {
+                CRDTCommand::SetAdd(key, _) => *members.entry(key).or_default() += 1,
+                CRDTCommand::SetRemove(key, _) => *members.entry(key).or_default() -= 1,
+                CRDTCommand::PNCounter(key, delta) => *counters.entry(key).or_default() += delta,
+                x => panic!("unexpected command {:?}", x),
+            }
+        }
+
+        // the known spent output is removed from its set
+        assert!(members.values().any(|x| *x < 0));
+
+        let expected: BTreeMap<_, _> = members
+            .into_iter()
+            .map(|(key, x)| (key.replacen("utxos.", "utxos.count.", 1), x))
+            .collect();
+
+        assert_eq!(counters, expected);
+    }
+
+    #[test]
+    fn counters_are_opt_in() {
+        assert!(reduce_test_block(false)
+            .iter()
+            .all(|x| !matches!(x, CRDTCommand::PNCounter(..))));
+    }
+}
@@ -8,12 +8,14 @@ use crate::{crosscut, model, prelude::*};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub filter: Option<Vec<String>>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
+    pub(crate) addresses: crosscut::addresses::AddressResolver,
 }
 
 fn any_address_to_stake_bech32(address: Address) -> Option<String> {
@@ -28,6 +30,13 @@ fn any_address_to_stake_bech32(address: Address) -> Option<String> {
 }
 
 impl Reducer {
+    fn prefix(&self) -> crosscut::keys::Prefix {
+        crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+    }
+
     fn process_consumed_txo(
         &mut self,
         ctx: &model::BlockContext,
@@ -41,8 +50,10 @@ impl Reducer {
             None => return Ok(()),
         };
 
-        let address = utxo.address().or_panic()?;
-        let stake_address = any_address_to_stake_bech32(address);
+        let stake_address = self
+            .addresses
+            .address(&utxo)
+            .and_then(any_address_to_stake_bech32);
 
         let stake_address = match stake_address {
             Some(x) => x,
@@ -56,9 +67,9 @@ impl Reducer {
         }
 
         let crdt = model::CRDTCommand::set_remove(
-            self.config.key_prefix.as_deref(),
+            self.prefix(),
             &stake_address,
-            input.to_string(),
+            crosscut::refs::ref_key(input),
         );
 
         output.send(crdt.into())
@@ -72,8 +83,10 @@ impl Reducer {
         output: &mut super::OutputPort,
     ) -> Result<(), gasket::error::Error> {
         let tx_hash = tx.hash();
-        let address = tx_output.address().or_panic()?;
-        let stake_address = any_address_to_stake_bech32(address);
+        let stake_address = self
+            .addresses
+            .address(tx_output)
+            .and_then(any_address_to_stake_bech32);
 
         let stake_address = match stake_address {
             Some(x) => x,
@@ -87,9 +100,9 @@ impl Reducer {
         }
 
         let crdt = model::CRDTCommand::set_add(
-            self.config.key_prefix.as_deref(),
+            self.prefix(),
             &stake_address,
-            format!("{}#{}", tx_hash, output_idx),
+            crosscut::refs::output_key(&tx_hash, output_idx as u64),
         );
 
         output.send(crdt.into())
@@ -120,6 +133,7 @@ impl Config {
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
+            addresses: crosscut::addresses::AddressResolver::new(policy.unknown_addresses),
         };
 
         super::Reducer::UtxoByStake(reducer)
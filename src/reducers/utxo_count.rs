@@ -0,0 +1,74 @@
+use pallas::ledger::traverse::MultiEraBlock;
+use serde::Deserialize;
+
+use crate::crosscut::epochs::block_epoch;
+use crate::{crosscut, model};
+
+#[derive(Deserialize)]
+pub struct Config {
+    pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
+}
+
+pub struct Reducer {
+    config: Config,
+    chain: crosscut::ChainWellKnownInfo,
+    last_epoch: Option<u64>,
+}
+
+impl Reducer {
+    /// Writes the size of the utxo set at the first block of each epoch
+    ///
+    /// The count is kept by the sled enrich stage (`utxo_count = true`), so
+    /// nothing is scanned here. Without it, blocks don't carry any count and
+    /// the key is left untouched.
+    pub fn reduce_block<'b>(
+        &mut self,
+        block: &'b MultiEraBlock<'b>,
+        ctx: &model::BlockContext,
+        output: &mut super::OutputPort,
+    ) -> Result<(), gasket::error::Error> {
+        let epoch = block_epoch(&self.chain, block);
+
+        if self.last_epoch == Some(epoch) {
+            return Ok(());
+        }
+
+        self.last_epoch = Some(epoch);
+
+        let count = match ctx.utxo_count() {
+            Some(x) => x,
+            None => {
+                log::warn!("utxo count not available, enable utxo_count on the enrich stage");
+                return Ok(());
+            }
+        };
+
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        )
+        .or("utxo_set");
+
+        let crdt = model::CRDTCommand::AnyWriteWins(
+            prefix.key(&["utxo_count"]),
+            model::Value::BigInt(count as i128),
+        );
+
+        output.send(gasket::messaging::Message::from(crdt))?;
+
+        Ok(())
+    }
+}
+
+impl Config {
+    pub fn plugin(self, chain: &crosscut::ChainWellKnownInfo) -> super::Reducer {
+        let reducer = Reducer {
+            config: self,
+            chain: chain.clone(),
+            last_epoch: None,
+        };
+
+        super::Reducer::UtxoCount(reducer)
+    }
+}
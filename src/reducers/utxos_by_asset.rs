@@ -11,13 +11,20 @@ use crate::{crosscut, model};
 #[derive(Deserialize)]
 pub struct Config {
     pub key_prefix: Option<String>,
+    pub key_separator: Option<String>,
     pub policy_ids_hex: Option<Vec<String>>,
+    pub asset_name_encoding: Option<crosscut::assets::NameEncoding>,
+
+    /// Outputs carrying more native assets than this are skipped, see
+    /// `crosscut::assets::AssetLimit`
+    pub max_assets_per_output: Option<usize>,
 }
 
 pub struct Reducer {
     config: Config,
     policy: crosscut::policies::RuntimePolicy,
     policy_ids: Option<Vec<Hash<28>>>,
+    pub(crate) asset_limit: crosscut::assets::AssetLimit,
 }
 
 impl Reducer {
@@ -40,13 +47,21 @@ impl Reducer {
         if !self.is_policy_id_accepted(&policy) {
             return Ok(());
         }
-        let prefix = self.config.key_prefix.as_deref();
-        let key = &format!("{}{}", policy, hex::encode(asset));
-        let member = format!("{}#{}", tx_hash, txo_idx);
+        let prefix = crosscut::keys::Prefix::new(
+            self.config.key_prefix.as_deref(),
+            self.config.key_separator.as_deref(),
+        );
+        let encoding = self.config.asset_name_encoding.unwrap_or_default();
+
+        let key = match crosscut::assets::asset_id(&policy, &asset, encoding) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+        let member = crosscut::refs::output_key(tx_hash, txo_idx);
 
         let crdt = match delta {
-            x if x < 0 => model::CRDTCommand::sorted_set_remove(prefix, key, member, delta),
-            _ => model::CRDTCommand::sorted_set_add(prefix, key, member, delta),
+            x if x < 0 => model::CRDTCommand::sorted_set_remove(prefix, &key, member, delta),
+            _ => model::CRDTCommand::sorted_set_add(prefix, &key, member, delta),
         };
 
         output.send(crdt.into())
@@ -60,6 +75,10 @@ impl Reducer {
     ) -> Result<(), gasket::error::Error> {
         for tx in block.txs().into_iter() {
             for (tx_ref, tx_output) in ctx.find_consumed_txos(&tx, &self.policy).or_panic()? {
+                if !self.asset_limit.admits_spent(&tx_output) {
+                    continue;
+                }
+
                 for asset in tx_output.assets() {
                     if let Asset::NativeAsset(policy, asset, delta) = asset {
                         self.process_asset(
@@ -75,6 +94,10 @@ impl Reducer {
             }
 
             for (idx, txo) in tx.produces() {
+                if !self.asset_limit.admits(&txo) {
+                    continue;
+                }
+
                 for asset in txo.assets() {
                     if let Asset::NativeAsset(policy, asset, delta) = asset {
                         self.process_asset(
@@ -107,10 +130,13 @@ impl Config {
             }
             None => None,
         };
+        let asset_limit = crosscut::assets::AssetLimit::new(self.max_assets_per_output);
+
         let reducer = Reducer {
             config: self,
             policy: policy.clone(),
             policy_ids: policy_ids.clone(),
+            asset_limit,
         };
 
         super::Reducer::UtxosByAsset(reducer)
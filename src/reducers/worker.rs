@@ -1,7 +1,15 @@
+use std::collections::{HashSet, VecDeque};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use lazy_static::lazy_static;
 use pallas::ledger::traverse::MultiEraBlock;
+use pallas::network::miniprotocols::Point;
 
-use crate::{crosscut, model, prelude::*};
+use crate::{crosscut, model, prelude::*, storage};
 
+use super::interest::{ScreenedTxs, TxInterest};
 use super::Reducer;
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::EnrichedBlockPayload>;
@@ -9,11 +17,140 @@ type OutputPort = gasket::messaging::OutputPort<model::CRDTCommand>;
 
 pub struct Worker {
     input: InputPort,
-    output: OutputPort,
+    output: super::OutputPort,
     reducers: Vec<Reducer>,
     policy: crosscut::policies::RuntimePolicy,
+    last_point: Option<Point>,
     ops_count: gasket::metrics::Counter,
+    idle_count: gasket::metrics::Counter,
+    duplicate_blocks: gasket::metrics::Counter,
     last_block: gasket::metrics::Gauge,
+    decode_time: crosscut::timing::Histogram,
+    reduce_time: crosscut::timing::Histogram,
+    panics: Vec<(&'static str, gasket::metrics::Counter)>,
+    skipped_outputs: Vec<(usize, &'static str)>,
+    unknown_addresses: Vec<(usize, &'static str)>,
+    decode_ahead: usize,
+    decode_pool_size: Option<usize>,
+    decode_pool: Option<Arc<rayon::ThreadPool>>,
+    /// Messages taken from the input but not reduced yet, when decoding
+    /// ahead
+    pending: VecDeque<model::EnrichedBlockPayload>,
+    sample_every: Option<u64>,
+    idle_sleep: Option<Duration>,
+    recent_blocks: Option<RecentBlocks>,
+    recent_seed: Option<storage::Cursor>,
+}
+
+/// Points of the last reduced blocks, oldest first
+///
+/// Kept in memory, seeded on startup with the points the storage committed
+/// last if it keeps them (see `storage::Cursor::recent_points`). Without
+/// those, it only catches a source re-delivering blocks after a
+/// reconnection, not a restart of the process from an imprecise cursor.
+struct RecentBlocks {
+    points: VecDeque<Point>,
+    window: usize,
+}
+
+impl RecentBlocks {
+    fn new(window: usize) -> Self {
+        Self {
+            points: VecDeque::with_capacity(window),
+            window,
+        }
+    }
+
+    fn contains(&self, point: &Point) -> bool {
+        self.points.contains(point)
+    }
+
+    fn record(&mut self, point: Point) {
+        if self.points.len() == self.window {
+            self.points.pop_front();
+        }
+
+        self.points.push_back(point);
+    }
+
+    /// Forgets the blocks after the point, they're new blocks if delivered
+    /// again
+    fn roll_back(&mut self, point: &Point) {
+        let slot = match point {
+            Point::Origin => None,
+            Point::Specific(slot, _) => Some(*slot),
+        };
+
+        while let Some(Point::Specific(last, _)) = self.points.back() {
+            if Some(*last) <= slot {
+                break;
+            }
+
+            self.points.pop_back();
+        }
+    }
+}
+
+/// Checks that a rollback doesn't require undoing already reduced blocks
+///
+/// Used when some reducer doesn't keep the data required to revert its output
+/// (see `journal::Journal`): honoring a rollback to a point older than the
+/// last reduced block would silently desync the collections. Rolling back to the last reduced point (which is
+/// what chain-sync does right after finding the intersection) is a no-op.
+fn check_rollback(last_point: Option<&Point>, requested: &Point) -> Result<(), crate::Error> {
+    match last_point {
+        None => Ok(()),
+        Some(last) if last == requested => Ok(()),
+        Some(last) => Err(crate::Error::message(format!(
+            "can't rollback to {:?}, blocks up to {:?} were already reduced. Consider increasing the source min_depth and restarting from a point before the fork",
+            requested, last
+        ))),
+    }
+}
+
+lazy_static! {
+    static ref METRIC_KEYS: Mutex<HashSet<&'static str>> = Mutex::new(HashSet::new());
+}
+
+/// Static metric key for a per-reducer metric
+///
+/// Gasket only takes static keys. Each distinct key is leaked once for the
+/// whole process, building workers again (tests, restarts of the stage)
+/// reuses it.
+fn metric_key(idx: usize, reducer: &Reducer, metric: &str) -> &'static str {
+    let key = format!("reducer_{}_{}_{}", idx, reducer.name(), metric);
+    let mut keys = METRIC_KEYS.lock().unwrap();
+
+    match keys.get(key.as_str()) {
+        Some(x) => x,
+        None => {
+            let x: &'static str = Box::leak(key.into_boxed_str());
+            keys.insert(x);
+            x
+        }
+    }
+}
+
+/// Runs a reducer, turning a panic into a `ReducerPanic` error
+///
+/// The outer result carries the panic, the inner one whatever the reducer
+/// returned. Commands sent before the panic have already left the worker.
+fn catch_reducer_panic<F>(
+    name: &str,
+    reduce: F,
+) -> Result<Result<(), gasket::error::Error>, crate::Error>
+where
+    F: FnOnce() -> Result<(), gasket::error::Error>,
+{
+    catch_unwind(AssertUnwindSafe(reduce)).map_err(|payload| {
+        let msg = payload
+            .downcast_ref::<&str>()
+            .map(|x| x.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "unknown panic payload".to_string());
+
+        crate::Error::ReducerPanic(format!("{}: {}", name, msg))
+    })
 }
 
 impl Worker {
@@ -22,72 +159,970 @@ impl Worker {
         input: InputPort,
         output: OutputPort,
         policy: crosscut::policies::RuntimePolicy,
+        decode_ahead: Option<usize>,
+        decode_pool_size: Option<usize>,
     ) -> Self {
+        let panics = reducers
+            .iter()
+            .enumerate()
+            .map(|(idx, reducer)| (metric_key(idx, reducer, "panics"), Default::default()))
+            .collect();
+
+        let skipped_outputs = reducers
+            .iter()
+            .enumerate()
+            .filter(|(_, reducer)| reducer.skipped_outputs().is_some())
+            .map(|(idx, reducer)| (idx, metric_key(idx, reducer, "skipped_outputs")))
+            .collect();
+
+        let unknown_addresses = reducers
+            .iter()
+            .enumerate()
+            .filter(|(_, reducer)| reducer.unknown_addresses().is_some())
+            .map(|(idx, reducer)| (idx, metric_key(idx, reducer, "unknown_addresses")))
+            .collect();
+
         Worker {
             reducers,
             input,
-            output,
+            output: super::OutputPort::new(output, false),
             policy,
+            last_point: None,
             ops_count: Default::default(),
+            idle_count: Default::default(),
+            duplicate_blocks: Default::default(),
             last_block: Default::default(),
+            decode_time: Default::default(),
+            reduce_time: Default::default(),
+            panics,
+            skipped_outputs,
+            unknown_addresses,
+            decode_ahead: decode_ahead.unwrap_or(0),
+            decode_pool_size,
+            decode_pool: None,
+            pending: VecDeque::new(),
+            sample_every: None,
+            idle_sleep: None,
+            recent_blocks: None,
+            recent_seed: None,
         }
     }
 
+    /// Only runs the reducers on blocks whose height is a multiple of `every`
+    ///
+    /// The other blocks are still framed by `BlockStarting` / `BlockFinished`
+    /// so that the cursor keeps moving, but no reducer sees them. Anything
+    /// accumulated across blocks (counters, balances, utxo sets) is wrong
+    /// under sampling, it's only meant for per-block analytics.
+    pub(crate) fn sampled(mut self, every: Option<u64>) -> Self {
+        self.sample_every = every.filter(|x| *x > 1);
+        self
+    }
+
+    /// Holds the commands of each block until it finishes, sending the
+    /// redundant ones only once, see `dedup::BlockBuffer`
+    pub(crate) fn deduped(mut self, enabled: bool) -> Self {
+        self.output = super::OutputPort::new(self.output.into_port(), enabled);
+        self
+    }
+
+    /// Writes the commands of each block under a volatile prefix until it's
+    /// deep enough, see `confirmation::Confirmation`. Rollbacks to a block not
+    /// confirmed yet are accepted.
+    pub(crate) fn confirmed(mut self, config: Option<super::confirmation::Config>) -> Self {
+        let confirmation = config.map(super::confirmation::Confirmation::new);
+        self.output = self.output.confirmed(confirmation);
+        self
+    }
+
+    /// Holds the commands of each epoch until the next one starts, writing
+    /// them under an in-progress prefix meanwhile, see
+    /// `epoch_commit::EpochCommit`. Rollbacks to a block not committed yet are
+    /// accepted.
+    pub(crate) fn epoch_aligned(
+        mut self,
+        config: Option<super::epoch_commit::Config>,
+        chain: &crosscut::ChainWellKnownInfo,
+    ) -> Self {
+        let epoch_commit = config.map(|x| super::epoch_commit::EpochCommit::new(x, chain));
+        self.output = self.output.epoch_aligned(epoch_commit);
+        self
+    }
+
+    /// Skips the blocks already reduced among the last `window` ones, so
+    /// that counters aren't applied twice when a source delivers a block
+    /// again. Skipped blocks aren't framed, the cursor already went past
+    /// them.
+    pub(crate) fn skipping_duplicates(mut self, window: Option<usize>) -> Self {
+        self.recent_blocks = window.filter(|x| *x > 0).map(RecentBlocks::new);
+        self
+    }
+
+    /// Fills the window of `skipping_duplicates` on bootstrap with the last
+    /// points committed by the storage, so that blocks already in the store
+    /// are skipped after a restart too
+    pub(crate) fn seeded_from(mut self, cursor: Option<storage::Cursor>) -> Self {
+        self.recent_seed = cursor;
+        self
+    }
+
+    /// Sleeps for this long each time the input is idle, see `idle`
+    pub(crate) fn idle_sleep(mut self, sleep: Option<Duration>) -> Self {
+        self.idle_sleep = sleep;
+        self
+    }
+
+    /// Accounts for an idle input, returning the error that tells gasket so
+    ///
+    /// The `idle_count` metric against `ops_count` shows whether the stage
+    /// waits on upstream or the other way around. With an idle sleep, the
+    /// stage backs off instead of polling again right away.
+    pub(crate) fn idle(&self) -> gasket::error::Error {
+        self.idle_count.inc(1);
+
+        if let Some(sleep) = self.idle_sleep {
+            std::thread::sleep(sleep);
+        }
+
+        gasket::error::Error::RecvIdle
+    }
+
     fn reduce_block<'b>(
         &mut self,
         block: &'b [u8],
         ctx: &model::BlockContext,
     ) -> Result<(), gasket::error::Error> {
-        let block = MultiEraBlock::decode(block)
-            .map_err(crate::Error::cbor)
-            .apply_policy(&self.policy)
-            .or_panic()?;
+        let start = Instant::now();
+        let block = MultiEraBlock::decode(block).map_err(crate::Error::cbor);
+        self.decode_time.observe_since(start);
+
+        self.reduce_decoded(block, ctx)
+    }
+
+    fn reduce_decoded(
+        &mut self,
+        block: Result<MultiEraBlock, crate::Error>,
+        ctx: &model::BlockContext,
+    ) -> Result<(), gasket::error::Error> {
+        let block = block.apply_policy(&self.policy).or_panic()?;
 
         let block = match block {
             Some(x) => x,
             None => return Ok(()),
         };
 
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        if let Some(recent) = &self.recent_blocks {
+            if recent.contains(&point) {
+                log::warn!("skipping block {:?}, it was already reduced", point);
+                self.duplicate_blocks.inc(1);
+                return Ok(());
+            }
+        }
+
         self.last_block.set(block.number() as i64);
 
         self.output.send(gasket::messaging::Message::from(
             model::CRDTCommand::block_starting(&block),
         ))?;
 
-        for reducer in self.reducers.iter_mut() {
-            reducer.reduce_block(&block, ctx, &mut self.output)?;
-            self.ops_count.inc(1);
+        let sampled = match self.sample_every {
+            Some(every) => block.number() % every == 0,
+            None => true,
+        };
+
+        let start = Instant::now();
+
+        let screened = ScreenedTxs::of(&block);
+
+        if sampled {
+            for (reducer, (_, panics)) in self.reducers.iter_mut().zip(self.panics.iter()) {
+                let interest = reducer.interest();
+                let txs = screened.pick(interest);
+
+                // blocks without a tx of its kinds have nothing for the reducer
+                if txs.is_empty() && !interest.contains(&TxInterest::Any) {
+                    continue;
+                }
+
+                let output = &mut self.output;
+                let outcome = catch_reducer_panic(reducer.name(), || {
+                    reducer.reduce_block(&block, &txs, ctx, output)
+                });
+
+                match outcome {
+                    Ok(result) => result?,
+                    Err(err) => {
+                        panics.inc(1);
+
+                        // with a skip / warn policy the reducer misses this block
+                        Err::<(), _>(err).apply_policy(&self.policy).or_panic()?;
+                    }
+                }
+
+                self.ops_count.inc(1);
+            }
         }
 
+        self.reduce_time.observe_since(start);
+
         self.output.send(gasket::messaging::Message::from(
             model::CRDTCommand::block_finished(&block),
         ))?;
 
+        // only recorded once reduced, a block that failed has to go through
+        // again on retry
+        if let Some(recent) = &mut self.recent_blocks {
+            recent.record(point.clone());
+        }
+
+        self.last_point = Some(point);
+
         Ok(())
     }
+
+    fn roll_back(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        log::warn!("rollback requested for {:?}", point);
+
+        // blocks held by the output are dropped there, otherwise the reducers
+        // have to undo the writes of the blocks past the point
+        let undo = if self.output.covers_rollback(&point) {
+            self.last_point = Some(point.clone());
+            false
+        } else if !self.output.holds_blocks()
+            && self.reducers.iter().all(|x| x.covers_rollback(&point))
+        {
+            self.last_point = Some(point.clone());
+            true
+        } else {
+            check_rollback(self.last_point.as_ref(), &point).or_panic()?;
+            false
+        };
+
+        if let Some(recent) = &mut self.recent_blocks {
+            recent.roll_back(&point);
+        }
+
+        // the marker goes first, any command reverting the rolled back
+        // blocks has to come after it
+        self.output.send(gasket::messaging::Message::from(
+            model::CRDTCommand::RollbackMarker(point.clone()),
+        ))?;
+
+        let mut commands = vec![];
+
+        for reducer in self.reducers.iter_mut() {
+            commands.extend(reducer.roll_back(&point).or_panic()?);
+        }
+
+        // sent as a block at the rollback point, the storages apply them
+        // like any other and move their cursor there
+        if undo {
+            self.output.send(gasket::messaging::Message::from(
+                model::CRDTCommand::BlockStarting(point.clone()),
+            ))?;
+
+            for cmd in commands {
+                self.output.send(gasket::messaging::Message::from(cmd))?;
+            }
+
+            self.output.send(gasket::messaging::Message::from(
+                model::CRDTCommand::BlockFinished(point),
+            ))?;
+        }
+
+        Ok(())
+    }
+
+    /// Reduces a single message, without going through the input port
+    pub(crate) fn process(
+        &mut self,
+        payload: model::EnrichedBlockPayload,
+    ) -> Result<(), gasket::error::Error> {
+        match payload {
+            model::EnrichedBlockPayload::RollForward(block, ctx) => self.reduce_block(&block, &ctx),
+            model::EnrichedBlockPayload::RollBack(point) => self.roll_back(point),
+        }
+    }
+
+    pub(crate) fn register_metrics(
+        &self,
+        builder: gasket::metrics::Builder,
+    ) -> gasket::metrics::Builder {
+        let builder = builder
+            .with_counter("ops_count", &self.ops_count)
+            .with_counter("idle_count", &self.idle_count)
+            .with_counter("duplicate_blocks", &self.duplicate_blocks)
+            .with_counter("merged_commands", self.output.merged())
+            .with_gauge("last_block", &self.last_block);
+
+        let builder = self
+            .panics
+            .iter()
+            .fold(builder, |b, (key, counter)| b.with_counter(*key, counter));
+
+        let builder = self.skipped_outputs.iter().fold(builder, |b, (idx, key)| {
+            match self.reducers[*idx].skipped_outputs() {
+                Some(counter) => b.with_counter(*key, counter),
+                None => b,
+            }
+        });
+
+        let builder = self
+            .unknown_addresses
+            .iter()
+            .fold(builder, |b, (idx, key)| {
+                match self.reducers[*idx].unknown_addresses() {
+                    Some(counter) => b.with_counter(*key, counter),
+                    None => b,
+                }
+            });
+
+        let builder = self
+            .decode_time
+            .register(builder, &crate::histogram_keys!("reducer_decode_time"));
+
+        self.reduce_time
+            .register(builder, &crate::histogram_keys!("reduce_time"))
+    }
+
+    /// Tops the pending messages up to `decode_ahead`, stopping early once
+    /// the input is idle
+    ///
+    /// The input port only hands out a message again until it's committed,
+    /// so each message is committed as it's taken and kept in `pending`
+    /// instead, until it's reduced. A failed batch leaves its unreduced
+    /// messages there for the retry.
+    fn recv_batch(&mut self) -> Result<(), gasket::error::Error> {
+        while self.pending.len() < self.decode_ahead {
+            match self.input.recv_or_idle() {
+                Ok(x) => {
+                    self.pending.push_back(x.payload);
+                    self.input.commit();
+                }
+                Err(gasket::error::Error::RecvIdle) if !self.pending.is_empty() => break,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reduces the pending messages in order, the blocks are decoded by the
+    /// pool while the previous ones are being reduced
+    ///
+    /// The decode time only accounts for the time spent waiting on the pool,
+    /// which is the part of the decode that isn't hidden by the look-ahead.
+    fn reduce_batch(&mut self) -> Result<(), gasket::error::Error> {
+        let pool = self.decode_pool.clone().expect("decode pool not built");
+        let batch: Vec<_> = self.pending.drain(..).collect();
+        let mut reduced = 0;
+
+        let result = pool.in_place_scope(|scope| {
+            let mut decoded = Vec::with_capacity(batch.len());
+
+            for payload in batch.iter() {
+                if let model::EnrichedBlockPayload::RollForward(cbor, _) = payload {
+                    let (sender, receiver) = mpsc::sync_channel(1);
+
+                    scope.spawn(move |_| {
+                        let block = MultiEraBlock::decode(cbor).map_err(crate::Error::cbor);
+
+                        // the receiver is gone if reducing an earlier block failed
+                        let _ = sender.send(block);
+                    });
+
+                    decoded.push(receiver);
+                }
+            }
+
+            let mut decoded = decoded.into_iter();
+
+            for payload in batch.iter() {
+                match payload {
+                    model::EnrichedBlockPayload::RollForward(_, ctx) => {
+                        let start = Instant::now();
+
+                        let block = decoded
+                            .next()
+                            .and_then(|x| x.recv().ok())
+                            .expect("decode task dropped its block");
+
+                        self.decode_time.observe_since(start);
+
+                        self.reduce_decoded(block, ctx)?;
+                    }
+                    model::EnrichedBlockPayload::RollBack(point) => {
+                        self.roll_back(point.clone())?;
+                    }
+                }
+
+                reduced += 1;
+            }
+
+            Ok(())
+        });
+
+        // the retry picks up from the message that failed
+        self.pending.extend(batch.into_iter().skip(reduced));
+
+        result
+    }
 }
 
 impl gasket::runtime::Worker for Worker {
     fn metrics(&self) -> gasket::metrics::Registry {
-        gasket::metrics::Builder::new()
-            .with_counter("ops_count", &self.ops_count)
-            .with_gauge("last_block", &self.last_block)
+        self.register_metrics(gasket::metrics::Builder::new())
             .build()
     }
 
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        if self.decode_ahead > 1 && self.decode_pool.is_none() {
+            let mut builder =
+                rayon::ThreadPoolBuilder::new().thread_name(|i| format!("decode-{}", i));
+
+            if let Some(size) = self.decode_pool_size {
+                builder = builder.num_threads(size);
+            }
+
+            let pool = builder
+                .build()
+                .map_err(|err| crate::Error::config(err.to_string()))
+                .or_panic()?;
+
+            self.decode_pool = Some(Arc::new(pool));
+        }
+
+        if let (Some(recent), Some(cursor)) = (&mut self.recent_blocks, &mut self.recent_seed) {
+            let points = cursor.recent_points().or_retry()?;
+            log::info!(
+                "skipping {} blocks committed last if delivered again",
+                points.len()
+            );
+
+            points.into_iter().for_each(|x| recent.record(x));
+            self.recent_seed = None;
+        }
+
+        Ok(())
+    }
+
     fn work(&mut self) -> gasket::runtime::WorkResult {
-        let msg = self.input.recv_or_idle()?;
+        if self.decode_ahead > 1 {
+            match self.recv_batch() {
+                Err(gasket::error::Error::RecvIdle) => return Err(self.idle()),
+                x => x?,
+            };
+
+            self.reduce_batch()?;
+
+            return Ok(gasket::runtime::WorkOutcome::Partial);
+        }
+
+        let msg = match self.input.recv_or_idle() {
+            Err(gasket::error::Error::RecvIdle) => return Err(self.idle()),
+            x => x?,
+        };
+
+        self.process(msg.payload)?;
+
+        self.input.commit();
+        Ok(gasket::runtime::WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::{
+        messaging::{connect_ports, OutputPort, TwoPhaseInputPort},
+        runtime::Worker as _,
+    };
+    use std::time::{Duration, Instant};
+
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::Point;
+
+    use super::{catch_reducer_panic, check_rollback, metric_key, Worker};
+    use crate::crosscut::policies::{AppliesPolicy, ErrorAction, RuntimePolicy};
+    use crate::model;
+
+    #[test]
+    fn rollback_before_any_block_is_noop() {
+        let requested = Point::Specific(10, vec![1u8; 32]);
+        assert!(check_rollback(None, &requested).is_ok());
+    }
+
+    #[test]
+    fn rollback_to_last_reduced_point_is_noop() {
+        let last = Point::Specific(10, vec![1u8; 32]);
+        assert!(check_rollback(Some(&last), &last).is_ok());
+    }
+
+    #[test]
+    fn rollback_past_reduced_blocks_fails() {
+        let last = Point::Specific(20, vec![2u8; 32]);
+        let requested = Point::Specific(10, vec![1u8; 32]);
+        assert!(check_rollback(Some(&last), &requested).is_err());
+    }
+
+    #[test]
+    fn idle_input_backs_off() {
+        let mut upstream = OutputPort::<model::EnrichedBlockPayload>::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<model::CRDTCommand>::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        let sleep = Duration::from_millis(50);
+        let mut worker = Worker::new(vec![], input, output, Default::default(), None, None)
+            .idle_sleep(Some(sleep));
+
+        assert_eq!(worker.idle_count.get(), 0);
+
+        let start = Instant::now();
+        let result = worker.work();
+
+        assert!(matches!(result, Err(gasket::error::Error::RecvIdle)));
+        assert!(start.elapsed() >= sleep);
+        assert_eq!(worker.idle_count.get(), 1);
+
+        // every idle poll is counted, not just the first one
+        assert!(worker.work().is_err());
+        assert_eq!(worker.idle_count.get(), 2);
+    }
+
+    #[test]
+    fn rollback_emits_marker_first() {
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        let mut worker = Worker::new(vec![], input, output, Default::default(), None, None);
+
+        let point = Point::Specific(10, vec![1u8; 32]);
+        worker.last_point = Some(point.clone());
+
+        upstream
+            .send(model::EnrichedBlockPayload::roll_back(point.clone()))
+            .unwrap();
+
+        worker.work().unwrap();
+
+        let msg = downstream.recv_or_idle().unwrap();
 
         match msg.payload {
-            model::EnrichedBlockPayload::RollForward(block, ctx) => {
-                self.reduce_block(&block, &ctx)?
+            model::CRDTCommand::RollbackMarker(x) => assert_eq!(x, point),
+            x => panic!("expected a rollback marker, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn decode_ahead_keeps_message_order() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, 20);
+
+        let mut worker = Worker::new(vec![], input, output, Default::default(), Some(4), Some(2));
+        worker.bootstrap().unwrap();
+
+        for _ in 0..3 {
+            upstream
+                .send(model::EnrichedBlockPayload::roll_forward(
+                    cbor.clone(),
+                    Default::default(),
+                ))
+                .unwrap();
+        }
+
+        upstream
+            .send(model::EnrichedBlockPayload::roll_back(point.clone()))
+            .unwrap();
+
+        worker.work().unwrap();
+
+        for _ in 0..3 {
+            let msg = downstream.recv_or_idle().unwrap();
+            assert!(matches!(msg.payload, model::CRDTCommand::BlockStarting(_)));
+
+            let msg = downstream.recv_or_idle().unwrap();
+            assert!(matches!(msg.payload, model::CRDTCommand::BlockFinished(_)));
+        }
+
+        match downstream.recv_or_idle().unwrap().payload {
+            model::CRDTCommand::RollbackMarker(x) => assert_eq!(x, point),
+            x => panic!("expected a rollback marker, got {:?}", x),
+        }
+    }
+
+    #[test]
+    fn failed_batch_resumes_at_failed_message() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, 20);
+
+        let mut worker = Worker::new(vec![], input, output, Default::default(), Some(4), Some(2));
+        worker.bootstrap().unwrap();
+
+        // past the block reduced right before it, the rollback fails
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        upstream
+            .send(model::EnrichedBlockPayload::roll_forward(
+                cbor.clone(),
+                Default::default(),
+            ))
+            .unwrap();
+
+        upstream
+            .send(model::EnrichedBlockPayload::roll_back(before))
+            .unwrap();
+
+        upstream
+            .send(model::EnrichedBlockPayload::roll_forward(
+                cbor.clone(),
+                Default::default(),
+            ))
+            .unwrap();
+
+        for _ in 0..2 {
+            assert!(worker.work().is_err());
+
+            // the block before the failure isn't reduced again on retry
+            assert_eq!(worker.pending.len(), 2);
+            assert!(matches!(
+                worker.pending.front(),
+                Some(model::EnrichedBlockPayload::RollBack(_))
+            ));
+        }
+
+        let msg = downstream.recv_or_idle().unwrap();
+        assert!(matches!(msg.payload, model::CRDTCommand::BlockStarting(_)));
+        downstream.commit();
+
+        let msg = downstream.recv_or_idle().unwrap();
+        assert!(matches!(msg.payload, model::CRDTCommand::BlockFinished(_)));
+        downstream.commit();
+
+        assert!(matches!(
+            downstream.recv_or_idle(),
+            Err(gasket::error::Error::RecvIdle)
+        ));
+    }
+
+    #[test]
+    fn redelivered_block_is_reduced_once() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, 20);
+
+        // with no reducer, there's nothing to undo and every rollback is
+        // accepted
+        let mut worker = Worker::new(vec![], input, output, Default::default(), None, None)
+            .skipping_duplicates(Some(10));
+
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut deliver = |payload: model::EnrichedBlockPayload| {
+            upstream.send(payload).unwrap();
+            worker.work().unwrap();
+        };
+
+        // found on startup
+        deliver(model::EnrichedBlockPayload::roll_back(before.clone()));
+
+        for _ in 0..2 {
+            deliver(model::EnrichedBlockPayload::roll_forward(
+                cbor.clone(),
+                Default::default(),
+            ));
+        }
+
+        // once rolled back past it, the block is new again
+        deliver(model::EnrichedBlockPayload::roll_back(before.clone()));
+
+        deliver(model::EnrichedBlockPayload::roll_forward(
+            cbor.clone(),
+            Default::default(),
+        ));
+
+        let received: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| x.payload)
+            .collect();
+
+        // each rollback is followed by its (empty) undo block
+        let frame = |x: &model::CRDTCommand| match x {
+            model::CRDTCommand::RollbackMarker(x) if *x == before => "rollback",
+            model::CRDTCommand::BlockStarting(x) if *x == before => "start undo",
+            model::CRDTCommand::BlockFinished(x) if *x == before => "finish undo",
+            model::CRDTCommand::BlockStarting(x) if *x == point => "start block",
+            model::CRDTCommand::BlockFinished(x) if *x == point => "finish block",
+            x => panic!("unexpected command {:?}", x),
+        };
+
+        assert_eq!(
+            received.iter().map(frame).collect::<Vec<_>>(),
+            vec![
+                "rollback",
+                "start undo",
+                "finish undo",
+                "start block",
+                "finish block",
+                "rollback",
+                "start undo",
+                "finish undo",
+                "start block",
+                "finish block",
+            ]
+        );
+    }
+
+    #[cfg(feature = "recent_blocks")]
+    fn recent_blocks() -> crate::reducers::Reducer {
+        crate::reducers::recent_blocks::Config {
+            key_prefix: None,
+            key_separator: None,
+            limit: None,
+            rollback_window: None,
+        }
+        .plugin()
+    }
+
+    #[cfg(feature = "recent_blocks")]
+    #[test]
+    fn rolled_back_blocks_are_undone_by_reducers() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::default();
+        connect_ports(&mut output, &mut downstream, 20);
+
+        let mut worker = Worker::new(
+            vec![recent_blocks()],
+            input,
+            output,
+            Default::default(),
+            None,
+            None,
+        );
+
+        let mut deliver = |payload: model::EnrichedBlockPayload| {
+            upstream.send(payload).unwrap();
+            worker.work().unwrap();
+        };
+
+        deliver(model::EnrichedBlockPayload::roll_back(before.clone()));
+        deliver(model::EnrichedBlockPayload::roll_forward(
+            cbor.clone(),
+            Default::default(),
+        ));
+        deliver(model::EnrichedBlockPayload::roll_back(before.clone()));
+
+        let received: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| match x.payload {
+                model::CRDTCommand::BlockStarting(x) if x == before => "start before",
+                model::CRDTCommand::BlockFinished(x) if x == before => "finish before",
+                model::CRDTCommand::RollbackMarker(x) if x == before => "rollback",
+                model::CRDTCommand::BlockStarting(x) if x == point => "start block",
+                model::CRDTCommand::BlockFinished(x) if x == point => "finish block",
+                model::CRDTCommand::ListPrepend(_, _, _) => "prepend",
+                model::CRDTCommand::ListPop(_) => "pop",
+                x => panic!("unexpected command {:?}", x),
+            })
+            .collect();
+
+        assert_eq!(
+            received,
+            vec![
+                "rollback",
+                "start before",
+                "finish before",
+                "start block",
+                "prepend",
+                "finish block",
+                "rollback",
+                "start before",
+                "pop",
+                "finish before",
+            ]
+        );
+    }
+
+    #[cfg(all(feature = "recent_blocks", feature = "point_by_tx"))]
+    #[test]
+    fn rollback_needs_every_reducer_to_undo_it() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let before = Point::Specific(block.slot() - 1, vec![0u8; 32]);
+
+        let mut upstream = OutputPort::default();
+        let mut input = TwoPhaseInputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<model::CRDTCommand>::default();
+        connect_ports(&mut output, &mut downstream, 100);
+
+        // point_by_tx keeps nothing to undo its writes
+        let point_by_tx = crate::reducers::point_by_tx::Config {
+            key_prefix: None,
+            key_separator: None,
+        }
+        .plugin();
+
+        let mut worker = Worker::new(
+            vec![recent_blocks(), point_by_tx],
+            input,
+            output,
+            Default::default(),
+            None,
+            None,
+        );
+
+        for payload in [
+            model::EnrichedBlockPayload::roll_back(before.clone()),
+            model::EnrichedBlockPayload::roll_forward(cbor.clone(), Default::default()),
+        ] {
+            upstream.send(payload).unwrap();
+            worker.work().unwrap();
+        }
+
+        upstream
+            .send(model::EnrichedBlockPayload::roll_back(before))
+            .unwrap();
+
+        assert!(worker.work().is_err());
+    }
+
+    #[cfg(feature = "point_by_tx")]
+    #[test]
+    fn only_sampled_blocks_are_reduced() {
+        let cbor = hex::decode(include_str!("../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        assert!(block.tx_count() > 0);
+
+        for (every, reduced) in [(block.number(), true), (block.number() + 1, false)] {
+            let mut upstream = OutputPort::default();
+            let mut input = TwoPhaseInputPort::default();
+            connect_ports(&mut upstream, &mut input, 10);
+
+            let mut output = OutputPort::default();
+            let mut downstream = TwoPhaseInputPort::default();
+            connect_ports(&mut output, &mut downstream, 100);
+
+            let reducer = crate::reducers::point_by_tx::Config {
+                key_prefix: None,
+                key_separator: None,
             }
-            model::EnrichedBlockPayload::RollBack(point) => {
-                log::warn!("rollback requested for {:?}", point);
+            .plugin();
+
+            let mut worker =
+                Worker::new(vec![reducer], input, output, Default::default(), None, None)
+                    .sampled(Some(every));
+
+            upstream
+                .send(model::EnrichedBlockPayload::roll_forward(
+                    cbor.clone(),
+                    Default::default(),
+                ))
+                .unwrap();
+
+            worker.work().unwrap();
+
+            let msg = downstream.recv_or_idle().unwrap();
+            assert!(matches!(msg.payload, model::CRDTCommand::BlockStarting(_)));
+
+            let mut commands = 0;
+
+            loop {
+                match downstream.recv_or_idle().unwrap().payload {
+                    model::CRDTCommand::BlockFinished(_) => break,
+                    _ => commands += 1,
+                }
             }
+
+            assert_eq!(commands > 0, reduced);
         }
+    }
 
-        self.input.commit();
-        Ok(gasket::runtime::WorkOutcome::Partial)
+    #[test]
+    fn metric_keys_are_leaked_once() {
+        let reducer = crate::reducers::point_by_tx::Config {
+            key_prefix: None,
+            key_separator: None,
+        }
+        .plugin();
+
+        let first = metric_key(3, &reducer, "panics");
+        let again = metric_key(3, &reducer, "panics");
+
+        assert_eq!(first, format!("reducer_3_{}_panics", reducer.name()));
+        assert!(std::ptr::eq(first, again));
+        assert!(!std::ptr::eq(first, metric_key(4, &reducer, "panics")));
+    }
+
+    #[test]
+    fn reducer_panic_is_caught() {
+        let outcome = catch_reducer_panic("faulty", || panic!("boom"));
+
+        let err = outcome.unwrap_err();
+        assert!(err.to_string().contains("faulty: boom"));
+
+        let policy = RuntimePolicy {
+            reducer_panics: Some(ErrorAction::Skip),
+            ..Default::default()
+        };
+
+        assert!(matches!(Err::<(), _>(err).apply_policy(&policy), Ok(None)));
+    }
+
+    #[test]
+    fn reducer_panic_fails_by_default() {
+        let outcome = catch_reducer_panic("faulty", || panic!("{}", "boom".to_string()));
+
+        let err = outcome.unwrap_err();
+        assert!(Err::<(), _>(err).apply_policy(&Default::default()).is_err());
+    }
+
+    #[test]
+    fn reducer_errors_pass_through() {
+        let outcome = catch_reducer_panic("healthy", || Ok(()));
+        assert!(matches!(outcome, Ok(Ok(()))));
     }
 }
@@ -0,0 +1,193 @@
+use std::collections::VecDeque;
+
+use pallas::network::miniprotocols::{chainsync::RollbackEffect, Point};
+use serde::Deserialize;
+
+use crate::crosscut;
+
+/// Defines how far behind the tip a block needs to be before it's processed
+///
+/// Blocks closer to the tip are kept in memory so that rollbacks can be
+/// resolved without involving the rest of the pipeline.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(tag = "type", content = "value")]
+pub enum DepthPolicy {
+    /// number of blocks on top of the point (aka: confirmations)
+    Blocks(usize),
+    /// number of slots between the point and the tip
+    Slots(u64),
+    /// wallclock seconds between the point and the tip
+    Seconds(u64),
+}
+
+impl DepthPolicy {
+    /// Resolves the policy from the source config, `min_depth` is kept as the
+    /// default for backward compatibility
+    pub fn from_config(policy: Option<DepthPolicy>, min_depth: Option<usize>) -> Self {
+        policy.unwrap_or(DepthPolicy::Blocks(min_depth.unwrap_or(0)))
+    }
+}
+
+/// In-memory buffer of the chain points not yet deep enough to be processed
+pub struct ChainBuffer {
+    points: VecDeque<Point>,
+}
+
+impl ChainBuffer {
+    pub fn new() -> Self {
+        Self {
+            points: VecDeque::new(),
+        }
+    }
+
+    pub fn roll_forward(&mut self, point: Point) {
+        self.points.push_back(point);
+    }
+
+    /// Discards the points after the rollback point
+    ///
+    /// If the point isn't in the buffer, the rollback affects blocks that were
+    /// already sent downstream and the buffer is cleared.
+    pub fn roll_back(&mut self, point: &Point) -> RollbackEffect {
+        match self.points.iter().position(|p| p == point) {
+            Some(idx) => {
+                self.points.truncate(idx + 1);
+                RollbackEffect::Handled
+            }
+            None => {
+                self.points.clear();
+                RollbackEffect::OutOfScope
+            }
+        }
+    }
+
+    fn is_ready(
+        policy: &DepthPolicy,
+        point: &Point,
+        tip: Option<&Point>,
+        time: &crosscut::time::NaiveProvider,
+    ) -> bool {
+        let tip = match tip {
+            Some(x) => x,
+            None => return false,
+        };
+
+        let point_slot = point.slot_or_default();
+        let tip_slot = tip.slot_or_default();
+
+        match policy {
+            DepthPolicy::Blocks(_) => unreachable!(),
+            DepthPolicy::Slots(n) => tip_slot >= point_slot + n,
+            DepthPolicy::Seconds(n) => {
+                time.slot_to_wallclock(tip_slot) >= time.slot_to_wallclock(point_slot) + n
+            }
+        }
+    }
+
+    /// Removes and returns the points that satisfy the depth policy, oldest
+    /// first
+    pub fn pop_ready(
+        &mut self,
+        policy: &DepthPolicy,
+        tip: Option<&Point>,
+        time: &crosscut::time::NaiveProvider,
+    ) -> Vec<Point> {
+        let count = match policy {
+            DepthPolicy::Blocks(n) => self.points.len().saturating_sub(*n),
+            _ => self
+                .points
+                .iter()
+                .take_while(|p| Self::is_ready(policy, p, tip, time))
+                .count(),
+        };
+
+        self.points.drain(..count).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::{chainsync::RollbackEffect, Point};
+
+    use super::{ChainBuffer, DepthPolicy};
+    use crate::crosscut::{time::NaiveProvider, ChainWellKnownInfo};
+
+    fn point(slot: u64, fork: u8) -> Point {
+        Point::Specific(slot, vec![fork; 32])
+    }
+
+    fn time() -> NaiveProvider {
+        NaiveProvider::new(ChainWellKnownInfo::mainnet())
+    }
+
+    // main chain at slots 100..=104, then a fork after slot 102 with blocks
+    // at slots 105 and 106
+    fn forked_buffer() -> ChainBuffer {
+        let mut buffer = ChainBuffer::new();
+
+        for slot in 100..=104 {
+            buffer.roll_forward(point(slot, 0));
+        }
+
+        let effect = buffer.roll_back(&point(102, 0));
+        assert!(matches!(effect, RollbackEffect::Handled));
+
+        buffer.roll_forward(point(105, 1));
+        buffer.roll_forward(point(106, 1));
+
+        buffer
+    }
+
+    #[test]
+    fn blocks_policy_at_fork() {
+        let mut buffer = forked_buffer();
+        let ready = buffer.pop_ready(&DepthPolicy::Blocks(2), None, &time());
+
+        assert_eq!(ready, vec![point(100, 0), point(101, 0), point(102, 0)]);
+    }
+
+    #[test]
+    fn slots_policy_at_fork() {
+        let mut buffer = forked_buffer();
+        let tip = point(106, 1);
+        let ready = buffer.pop_ready(&DepthPolicy::Slots(5), Some(&tip), &time());
+
+        assert_eq!(ready, vec![point(100, 0), point(101, 0)]);
+    }
+
+    #[test]
+    fn seconds_policy_at_fork() {
+        // mainnet post-byron slots last 1 sec
+        let mut buffer = ChainBuffer::new();
+        let base = 50_000_000;
+
+        for slot in base..base + 3 {
+            buffer.roll_forward(point(slot, 0));
+        }
+
+        buffer.roll_back(&point(base, 0));
+        buffer.roll_forward(point(base + 10, 1));
+
+        let tip = point(base + 10, 1);
+        let ready = buffer.pop_ready(&DepthPolicy::Seconds(5), Some(&tip), &time());
+
+        assert_eq!(ready, vec![point(base, 0)]);
+    }
+
+    #[test]
+    fn unknown_tip_holds_points() {
+        let mut buffer = forked_buffer();
+        let ready = buffer.pop_ready(&DepthPolicy::Slots(0), None, &time());
+
+        assert!(ready.is_empty());
+    }
+
+    #[test]
+    fn rollback_out_of_buffer_clears_it() {
+        let mut buffer = forked_buffer();
+        let effect = buffer.roll_back(&point(50, 0));
+
+        assert!(matches!(effect, RollbackEffect::OutOfScope));
+        assert!(buffer.pop_ready(&DepthPolicy::Blocks(0), None, &time()).is_empty());
+    }
+}
@@ -3,19 +3,28 @@ use serde::Deserialize;
 
 use crate::{bootstrap, crosscut, model, storage};
 
-#[cfg(target_family = "unix")]
+#[cfg(all(feature = "n2c", target_family = "unix"))]
 pub mod n2c;
 
+#[cfg(feature = "n2n")]
 pub mod n2n;
+pub mod depth;
+pub mod stall;
 pub mod utils;
 
 #[derive(Deserialize)]
 #[serde(tag = "type")]
 pub enum Config {
+    #[cfg(feature = "n2n")]
     N2N(n2n::Config),
 
-    #[cfg(target_family = "unix")]
+    #[cfg(all(feature = "n2c", target_family = "unix"))]
     N2C(n2c::Config),
+
+    /// N2C requested where it isn't available, kept so that the config fails
+    /// with a clear error rather than an unknown variant
+    #[cfg(not(all(feature = "n2c", target_family = "unix")))]
+    N2C(serde::de::IgnoredAny),
 }
 
 impl Config {
@@ -25,31 +34,72 @@ impl Config {
         intersect: &crosscut::IntersectConfig,
         finalize: &Option<crosscut::FinalizeConfig>,
         policy: &crosscut::policies::RuntimePolicy,
-    ) -> Bootstrapper {
-        match self {
+    ) -> Result<Bootstrapper, crate::Error> {
+        let bootstrapper = match self {
+            #[cfg(feature = "n2n")]
             Config::N2N(c) => Bootstrapper::N2N(c.bootstrapper(chain, intersect, finalize, policy)),
+
+            #[cfg(all(feature = "n2c", target_family = "unix"))]
             Config::N2C(c) => Bootstrapper::N2C(c.bootstrapper(chain, intersect, finalize, policy)),
-        }
+
+            #[cfg(not(all(feature = "n2c", target_family = "unix")))]
+            Config::N2C(_) => {
+                return Err(crate::Error::config(
+                    "the N2C source requires a unix platform and the n2c feature, use N2N instead",
+                ))
+            }
+        };
+
+        Ok(bootstrapper)
     }
 }
 
 pub enum Bootstrapper {
+    #[cfg(feature = "n2n")]
     N2N(n2n::Bootstrapper),
+
+    #[cfg(all(feature = "n2c", target_family = "unix"))]
     N2C(n2c::Bootstrapper),
 }
 
 impl Bootstrapper {
     pub fn borrow_output_port(&mut self) -> &'_ mut OutputPort<model::RawBlockPayload> {
         match self {
+            #[cfg(feature = "n2n")]
             Bootstrapper::N2N(p) => p.borrow_output_port(),
+
+            #[cfg(all(feature = "n2c", target_family = "unix"))]
             Bootstrapper::N2C(p) => p.borrow_output_port(),
         }
     }
 
-    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline, cursor: storage::Cursor) {
+    /// Spawns the source stage, which won't produce blocks until the
+    /// `downstream_ready` gate is open. Blocks sent are counted in
+    /// `downstream_inflight`, which only the N2N source uses to cap them.
+    pub fn spawn_stages(
+        self,
+        pipeline: &mut bootstrap::Pipeline,
+        cursor: storage::Cursor,
+        downstream_ready: crosscut::ready::ReadyGate,
+        downstream_inflight: crosscut::inflight::Inflight,
+        resume: crosscut::ready::ReadyGate,
+    ) {
         match self {
-            Bootstrapper::N2N(p) => p.spawn_stages(pipeline, cursor),
-            Bootstrapper::N2C(p) => p.spawn_stages(pipeline, cursor),
+            #[cfg(feature = "n2n")]
+            Bootstrapper::N2N(p) => p.spawn_stages(
+                pipeline,
+                cursor,
+                downstream_ready,
+                downstream_inflight,
+                resume,
+            ),
+
+            #[cfg(all(feature = "n2c", target_family = "unix"))]
+            Bootstrapper::N2C(p) => {
+                // blocks come from a local node, n2c doesn't cap them
+                drop(downstream_inflight);
+                p.spawn_stages(pipeline, cursor, downstream_ready, resume)
+            }
         }
     }
 }
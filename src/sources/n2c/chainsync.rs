@@ -3,9 +3,11 @@ use pallas::ledger::traverse::MultiEraBlock;
 use pallas::network::miniprotocols::chainsync::BlockContent;
 use pallas::network::miniprotocols::{chainsync, Point};
 use pallas::network::multiplexer::StdChannel;
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use crate::prelude::*;
+use crate::sources::depth::{ChainBuffer, DepthPolicy};
+use crate::sources::stall::StallWatch;
 use crate::{crosscut, model, sources::utils, storage, Error};
 
 use super::transport::Transport;
@@ -18,45 +20,60 @@ type OutputPort = gasket::messaging::OutputPort<model::RawBlockPayload>;
 
 pub struct Worker {
     socket: String,
-    min_depth: usize,
+    depth: DepthPolicy,
     policy: crosscut::policies::RuntimePolicy,
-    chain_buffer: chainsync::RollbackBuffer,
+    chain_buffer: ChainBuffer,
+    tip: Option<Point>,
+    time: crosscut::time::NaiveProvider,
     blocks: HashMap<Point, chainsync::BlockContent>,
     chain: crosscut::ChainWellKnownInfo,
     intersect: crosscut::IntersectConfig,
     cursor: storage::Cursor,
+    downstream_ready: crosscut::ready::ReadyGate,
+    pub(crate) resume: crosscut::ready::ReadyGate,
+    pub(crate) stall: StallWatch,
     finalize: Option<crosscut::FinalizeConfig>,
     chainsync: Option<chainsync::N2CClient<StdChannel>>,
 
     output: OutputPort,
     block_count: gasket::metrics::Counter,
     chain_tip: gasket::metrics::Gauge,
+    reached_tip: bool,
+    skip_until_slot: Option<utils::SkipUntil>,
 }
 
 impl Worker {
     pub fn new(
         socket: String,
-        min_depth: usize,
+        depth: DepthPolicy,
         policy: crosscut::policies::RuntimePolicy,
         chain: crosscut::ChainWellKnownInfo,
         intersect: crosscut::IntersectConfig,
         finalize: Option<crosscut::FinalizeConfig>,
         cursor: storage::Cursor,
+        downstream_ready: crosscut::ready::ReadyGate,
         output: OutputPort,
     ) -> Self {
         Self {
             socket,
-            min_depth,
+            depth,
             policy,
+            time: crosscut::time::NaiveProvider::new(chain.clone()),
             chain,
             intersect,
             finalize,
             cursor,
+            downstream_ready,
+            resume: crosscut::ready::ReadyGate::opened(),
+            stall: Default::default(),
             output,
             chainsync: None,
             block_count: Default::default(),
             chain_tip: Default::default(),
-            chain_buffer: chainsync::RollbackBuffer::new(),
+            reached_tip: false,
+            skip_until_slot: None,
+            chain_buffer: ChainBuffer::new(),
+            tip: None,
             blocks: HashMap::new(),
         }
     }
@@ -65,6 +82,8 @@ impl Worker {
         &mut self,
         content: chainsync::BlockContent,
     ) -> Result<(), gasket::error::Error> {
+        self.stall.block_received();
+
         // parse the header and extract the point of the chain
         let block = to_traverse(&content)
             .apply_policy(&self.policy)
@@ -75,6 +94,16 @@ impl Worker {
             None => return Ok(()),
         };
 
+        // blocks before the start slot are only walked through
+        let skipped = match &mut self.skip_until_slot {
+            Some(x) => x.skips(block.slot()),
+            None => false,
+        };
+
+        if skipped {
+            return Ok(());
+        }
+
         let point = Point::Specific(block.slot(), block.hash().to_vec());
 
         // store the block for later retrieval
@@ -105,6 +134,11 @@ impl Worker {
         Ok(())
     }
 
+    fn on_tip(&mut self, tip: chainsync::Tip) {
+        self.chain_tip.set(tip.1 as i64);
+        self.tip = Some(tip.0);
+    }
+
     fn request_next(&mut self) -> Result<(), gasket::error::Error> {
         log::info!("requesting next block");
 
@@ -118,16 +152,17 @@ impl Worker {
         match next {
             chainsync::NextResponse::RollForward(h, t) => {
                 self.on_roll_forward(h)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             chainsync::NextResponse::RollBackward(p, t) => {
                 self.on_rollback(&p)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             chainsync::NextResponse::Await => {
                 log::info!("chain-sync reached the tip of the chain");
+                self.reached_tip = true;
                 Ok(())
             }
         }
@@ -146,12 +181,12 @@ impl Worker {
         match next {
             chainsync::NextResponse::RollForward(h, t) => {
                 self.on_roll_forward(h)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             chainsync::NextResponse::RollBackward(p, t) => {
                 self.on_rollback(&p)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             _ => unreachable!("protocol invariant not respected in chain-sync state machine"),
@@ -164,36 +199,59 @@ impl gasket::runtime::Worker for Worker {
         gasket::metrics::Builder::new()
             .with_counter("received_blocks", &self.block_count)
             .with_gauge("chain_tip", &self.chain_tip)
+            .with_gauge("seconds_since_last_block", self.stall.gauge())
             .build()
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        // avoid pulling blocks before the rest of the pipeline is able to
+        // process them, the retry policy takes care of waiting longer
+        if !self.downstream_ready.wait_timeout(Duration::from_secs(10)) {
+            log::warn!("waiting for downstream stages to be ready");
+            return Err(Error::message("downstream stages not ready")).or_retry();
+        }
+
         let transport = Transport::setup(&self.socket, self.chain.magic).or_retry()?;
 
         let mut chainsync = chainsync::N2CClient::new(transport.channel5);
 
-        let start =
-            utils::define_chainsync_start(&self.intersect, &mut self.cursor, &mut chainsync)
-                .or_retry()?;
+        let start = utils::define_chainsync_start(
+            &self.intersect,
+            &self.chain,
+            &mut self.cursor,
+            &mut chainsync,
+        )
+        .or_retry()?;
+
+        let point = start.point.ok_or(Error::IntersectNotFound).or_panic()?;
 
-        let start = start.ok_or(Error::IntersectNotFound).or_panic()?;
+        log::info!("chain-sync intersection is {:?}", point);
 
-        log::info!("chain-sync intersection is {:?}", start);
+        self.skip_until_slot = start.skip_until_slot.map(utils::SkipUntil::new);
 
         self.chainsync = Some(chainsync);
 
+        self.stall.spawn_watchdog();
+
         Ok(())
     }
 
     fn work(&mut self) -> gasket::runtime::WorkResult {
+        // while paused, idle without fetching anything from the node
+        if !self.resume.wait_timeout(Duration::from_secs(1)) {
+            return Ok(gasket::runtime::WorkOutcome::Partial);
+        }
+
         match self.chainsync.as_ref().unwrap().has_agency() {
             true => self.request_next()?,
             false => self.await_next()?,
         };
 
         // see if we have points that already reached certain depth
-        let ready = self.chain_buffer.pop_with_depth(self.min_depth);
-        log::debug!("found {} points with required min depth", ready.len());
+        let ready = self
+            .chain_buffer
+            .pop_ready(&self.depth, self.tip.as_ref(), &self.time);
+        log::debug!("found {} points with required depth", ready.len());
 
         // find confirmed block in memory and send down the pipeline
         for point in ready {
@@ -213,6 +271,13 @@ impl gasket::runtime::Worker for Worker {
             }
         }
 
+        // points not deep enough yet are left for the next run, they'll be
+        // fetched again from the cursor once they are confirmed
+        if self.reached_tip && crosscut::should_finalize_at_tip(&self.finalize) {
+            log::info!("finalizing at the tip of the chain");
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
         Ok(gasket::runtime::WorkOutcome::Partial)
     }
 }
@@ -12,6 +12,13 @@ use gasket::messaging::OutputPort;
 pub struct Config {
     pub path: String,
     pub min_depth: Option<usize>,
+
+    /// How far behind the tip a block must be to be processed, defaults to
+    /// `min_depth` blocks
+    pub depth_policy: Option<super::depth::DepthPolicy>,
+
+    /// Seconds without a new block before chain-sync is reported as stalled
+    pub stall_grace_secs: Option<u64>,
 }
 
 impl Config {
@@ -47,18 +54,36 @@ impl Bootstrapper {
         &mut self.output
     }
 
-    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline, cursor: storage::Cursor) {
-        pipeline.register_stage(gasket::runtime::spawn_stage(
-            self::chainsync::Worker::new(
-                self.config.path.clone(),
-                self.config.min_depth.unwrap_or(0),
-                self.policy,
-                self.chain,
-                self.intersect,
-                self.finalize,
-                cursor,
-                self.output,
+    pub fn spawn_stages(
+        self,
+        pipeline: &mut bootstrap::Pipeline,
+        cursor: storage::Cursor,
+        downstream_ready: crosscut::ready::ReadyGate,
+        resume: crosscut::ready::ReadyGate,
+    ) {
+        let mut worker = self::chainsync::Worker::new(
+            self.config.path.clone(),
+            super::depth::DepthPolicy::from_config(
+                self.config.depth_policy,
+                self.config.min_depth,
             ),
+            self.policy,
+            self.chain,
+            self.intersect,
+            self.finalize,
+            cursor,
+            downstream_ready,
+            self.output,
+        );
+
+        // the source idles until resumed when the daemon starts paused
+        worker.resume = resume;
+
+        worker.stall =
+            super::stall::StallWatch::new(self.config.stall_grace_secs.map(Duration::from_secs));
+
+        pipeline.register_stage(gasket::runtime::spawn_stage(
+            worker,
             gasket::runtime::Policy {
                 tick_timeout: Some(Duration::from_secs(600)),
                 bootstrap_retry: gasket::retries::Policy {
@@ -1,3 +1,6 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
 use pallas::ledger::traverse::MultiEraHeader;
 use pallas::network::miniprotocols::chainsync::HeaderContent;
 use pallas::network::miniprotocols::{blockfetch, chainsync, Point};
@@ -5,7 +8,9 @@ use pallas::network::miniprotocols::{blockfetch, chainsync, Point};
 use gasket::error::AsWorkError;
 use pallas::network::multiplexer::StdChannel;
 
-use crate::sources::n2n::transport::Transport;
+use crate::sources::n2n::transport::{Transport, TransportOptions};
+use crate::sources::depth::{ChainBuffer, DepthPolicy};
+use crate::sources::stall::StallWatch;
 use crate::{crosscut, model, sources::utils, storage, Error};
 
 use crate::prelude::*;
@@ -21,47 +26,82 @@ fn to_traverse<'b>(header: &'b HeaderContent) -> Result<MultiEraHeader<'b>, Erro
 
 pub type OutputPort = gasket::messaging::OutputPort<model::RawBlockPayload>;
 
+/// Time the bootstrap waits for the downstream stages before retrying
+const DOWNSTREAM_READY_TIMEOUT: Duration = Duration::from_secs(10);
+
 pub struct Worker {
     address: String,
-    min_depth: usize,
+    depth: DepthPolicy,
+    transport: TransportOptions,
     policy: crosscut::policies::RuntimePolicy,
-    chain_buffer: chainsync::RollbackBuffer,
+    chain_buffer: ChainBuffer,
+    tip: Option<Point>,
+    time: crosscut::time::NaiveProvider,
     chain: crosscut::ChainWellKnownInfo,
     intersect: crosscut::IntersectConfig,
     cursor: storage::Cursor,
+    downstream_ready: crosscut::ready::ReadyGate,
+    pub(crate) ready_timeout: Duration,
+    pub(crate) resume: crosscut::ready::ReadyGate,
+    pub(crate) stall: StallWatch,
+    pub(crate) inflight: crosscut::inflight::Inflight,
+    pub(crate) max_inflight: Option<usize>,
+    pub(crate) fetch_range_size: usize,
+    pub(crate) fetch_delay: Option<Duration>,
+    pending_fetch: VecDeque<Point>,
     finalize: Option<crosscut::FinalizeConfig>,
     chainsync: Option<chainsync::N2NClient<StdChannel>>,
     blockfetch: Option<blockfetch::Client<StdChannel>>,
     output: OutputPort,
     block_count: gasket::metrics::Counter,
     chain_tip: gasket::metrics::Gauge,
+    inflight_blocks: gasket::metrics::Gauge,
+    reached_tip: bool,
+    skip_until_slot: Option<utils::SkipUntil>,
 }
 
 impl Worker {
     pub fn new(
         address: String,
-        min_depth: usize,
+        depth: DepthPolicy,
+        transport: TransportOptions,
         policy: crosscut::policies::RuntimePolicy,
         chain: crosscut::ChainWellKnownInfo,
         intersect: crosscut::IntersectConfig,
         finalize: Option<crosscut::FinalizeConfig>,
         cursor: storage::Cursor,
+        downstream_ready: crosscut::ready::ReadyGate,
         output: OutputPort,
     ) -> Self {
         Self {
             address,
-            min_depth,
+            depth,
+            transport,
             policy,
+            time: crosscut::time::NaiveProvider::new(chain.clone()),
             chain,
             intersect,
             finalize,
             cursor,
+            downstream_ready,
+            ready_timeout: DOWNSTREAM_READY_TIMEOUT,
+            resume: crosscut::ready::ReadyGate::opened(),
+            stall: Default::default(),
+            inflight: Default::default(),
+            max_inflight: None,
+            fetch_range_size: 1,
+            fetch_delay: None,
+            pending_fetch: VecDeque::new(),
             output,
             chainsync: None,
             blockfetch: None,
             block_count: Default::default(),
             chain_tip: Default::default(),
-            chain_buffer: chainsync::RollbackBuffer::new(),
+            inflight_blocks: Default::default(),
+            reached_tip: false,
+            skip_until_slot: None,
+            chain_buffer: ChainBuffer::new(),
+            tip: None,
         }
     }
 
@@ -69,6 +109,8 @@ impl Worker {
         &mut self,
         content: chainsync::HeaderContent,
     ) -> Result<(), gasket::error::Error> {
+        self.stall.block_received();
+
         // parse the header and extract the point of the chain
         let header = to_traverse(&content)
             .apply_policy(&self.policy)
@@ -79,6 +121,16 @@ impl Worker {
             None => return Ok(()),
         };
 
+        // blocks before the start slot are only walked through
+        let skipped = match &mut self.skip_until_slot {
+            Some(x) => x.skips(header.slot()),
+            None => false,
+        };
+
+        if skipped {
+            return Ok(());
+        }
+
         let point = Point::Specific(header.slot(), header.hash().to_vec());
 
         // track the new point in our memory buffer
@@ -97,6 +149,7 @@ impl Worker {
             }
             chainsync::RollbackEffect::OutOfScope => {
                 log::debug!("rollback out of buffer scope, sending event down the pipeline");
+                self.inflight.emitted();
                 self.output
                     .send(model::RawBlockPayload::roll_back(point.clone()))?;
             }
@@ -105,6 +158,11 @@ impl Worker {
         Ok(())
     }
 
+    fn on_tip(&mut self, tip: chainsync::Tip) {
+        self.chain_tip.set(tip.1 as i64);
+        self.tip = Some(tip.0);
+    }
+
     fn request_next(&mut self) -> Result<(), gasket::error::Error> {
         log::info!("requesting next block");
 
@@ -118,21 +176,84 @@ impl Worker {
         match next {
             chainsync::NextResponse::RollForward(h, t) => {
                 self.on_roll_forward(h)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             chainsync::NextResponse::RollBackward(p, t) => {
                 self.on_rollback(&p)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             chainsync::NextResponse::Await => {
                 log::info!("chain-sync reached the tip of the chain");
+                self.reached_tip = true;
                 Ok(())
             }
         }
     }
 
+    /// Fetches the next confirmed blocks, up to `fetch_range_size` of them
+    /// in a single range request, along with their points
+    ///
+    /// The range never goes past the room left under `max_inflight`, so the
+    /// cap holds whatever the batch size.
+    fn fetch_next(&mut self) -> Result<Vec<(Point, Vec<u8>)>, gasket::error::Error> {
+        let room = match self.max_inflight {
+            Some(max) => max.saturating_sub(self.inflight.count()).max(1),
+            None => usize::MAX,
+        };
+
+        let count = self
+            .fetch_range_size
+            .min(room)
+            .min(self.pending_fetch.len());
+        let points: Vec<_> = self.pending_fetch.iter().take(count).cloned().collect();
+
+        let client = self.blockfetch.as_mut().unwrap();
+
+        let blocks = match &points[..] {
+            [point] => {
+                log::debug!("requesting block fetch for point {:?}", point);
+                vec![client.fetch_single(point.clone()).or_restart()?]
+            }
+            [first, .., last] => {
+                log::debug!("requesting block fetch for range {:?} - {:?}", first, last);
+                client
+                    .fetch_range((first.clone(), last.clone()))
+                    .or_restart()?
+            }
+            [] => vec![],
+        };
+
+        if let Some(delay) = self.fetch_delay {
+            std::thread::sleep(delay);
+        }
+
+        if blocks.len() != points.len() {
+            return Err(Error::message(format!(
+                "relay sent {} blocks for a range of {}",
+                blocks.len(),
+                points.len()
+            )))
+            .or_restart();
+        }
+
+        Ok(points.into_iter().zip(blocks).collect())
+    }
+
+    /// Waits a little for the blocks in flight to drop below the cap, false
+    /// if they are still at it
+    fn wait_inflight(&self) -> bool {
+        let ready = match self.max_inflight {
+            Some(max) => self.inflight.wait_below(max, Duration::from_secs(1)),
+            None => true,
+        };
+
+        self.inflight_blocks.set(self.inflight.count() as i64);
+
+        ready
+    }
+
     fn await_next(&mut self) -> Result<(), gasket::error::Error> {
         log::info!("awaiting next block (blocking)");
 
@@ -146,12 +267,12 @@ impl Worker {
         match next {
             chainsync::NextResponse::RollForward(h, t) => {
                 self.on_roll_forward(h)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             chainsync::NextResponse::RollBackward(p, t) => {
                 self.on_rollback(&p)?;
-                self.chain_tip.set(t.1 as i64);
+                self.on_tip(t);
                 Ok(())
             }
             _ => unreachable!("protocol invariant not respected in chain-sync state machine"),
@@ -164,24 +285,45 @@ impl gasket::runtime::Worker for Worker {
         gasket::metrics::Builder::new()
             .with_counter("received_blocks", &self.block_count)
             .with_gauge("chain_tip", &self.chain_tip)
+            .with_gauge("inflight_blocks", &self.inflight_blocks)
+            .with_gauge("seconds_since_last_block", self.stall.gauge())
             .build()
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
-        let transport = Transport::setup(&self.address, self.chain.magic).or_retry()?;
+        // avoid pulling blocks before the rest of the pipeline is able to
+        // process them, the retry policy takes care of waiting longer
+        if !self.downstream_ready.wait_timeout(self.ready_timeout) {
+            log::warn!("waiting for downstream stages to be ready");
+            return Err(Error::message("downstream stages not ready")).or_retry();
+        }
+
+        let transport = Transport::setup(&self.address, self.chain.magic, &self.transport).or_retry()?;
 
         let mut chainsync = chainsync::N2NClient::new(transport.channel2);
 
-        let start =
-            utils::define_chainsync_start(&self.intersect, &mut self.cursor, &mut chainsync)
-                .or_retry()?;
+        let start = utils::define_chainsync_start(
+            &self.intersect,
+            &self.chain,
+            &mut self.cursor,
+            &mut chainsync,
+        )
+        .or_retry()?;
 
-        let start = start.ok_or(Error::IntersectNotFound).or_panic()?;
+        let point = start.point.ok_or(Error::IntersectNotFound).or_panic()?;
 
-        log::info!("chain-sync intersection is {:?}", start);
+        log::info!("chain-sync intersection is {:?}", point);
+
+        self.skip_until_slot = start.skip_until_slot.map(utils::SkipUntil::new);
+
+        // points left from before a restart are fetched again past the
+        // intersection
+        self.pending_fetch.clear();
 
         self.chainsync = Some(chainsync);
 
+        self.stall.spawn_watchdog();
+
         let blockfetch = blockfetch::Client::new(transport.channel3);
 
         self.blockfetch = Some(blockfetch);
@@ -190,37 +332,322 @@ impl gasket::runtime::Worker for Worker {
     }
 
     fn work(&mut self) -> gasket::runtime::WorkResult {
-        match self.chainsync.as_ref().unwrap().has_agency() {
-            true => self.request_next()?,
-            false => self.await_next()?,
-        };
+        // while paused, idle without fetching anything from the node
+        if !self.resume.wait_timeout(Duration::from_secs(1)) {
+            return Ok(gasket::runtime::WorkOutcome::Partial);
+        }
 
-        // see if we have points that already reached certain depth
-        let ready = self.chain_buffer.pop_with_depth(self.min_depth);
-        log::debug!("found {} points with required min depth", ready.len());
+        // points confirmed in a previous run are fetched before pulling more
+        // headers, so that chain-sync pauses along with the fetching
+        if self.pending_fetch.is_empty() {
+            match self.chainsync.as_ref().unwrap().has_agency() {
+                true => self.request_next()?,
+                false => self.await_next()?,
+            };
+
+            // see if we have points that already reached certain depth
+            let ready = self
+                .chain_buffer
+                .pop_ready(&self.depth, self.tip.as_ref(), &self.time);
+            log::debug!("found {} points with required depth", ready.len());
+
+            self.pending_fetch.extend(ready);
+        }
 
         // request download of blocks for confirmed points
-        for point in ready {
-            log::debug!("requesting block fetch for point {:?}", point);
-
-            let block = self
-                .blockfetch
-                .as_mut()
-                .unwrap()
-                .fetch_single(point.clone())
-                .or_restart()?;
+        while !self.pending_fetch.is_empty() {
+            if !self.wait_inflight() {
+                log::debug!("too many blocks in flight, pausing block fetch");
+                return Ok(gasket::runtime::WorkOutcome::Partial);
+            }
 
-            self.output
-                .send(model::RawBlockPayload::roll_forward(block))?;
+            for (point, block) in self.fetch_next()? {
+                // counted before sending, the ack may come before send returns
+                self.inflight.emitted();
+                self.output
+                    .send(model::RawBlockPayload::roll_forward(block))?;
 
-            self.block_count.inc(1);
+                self.pending_fetch.pop_front();
+                self.block_count.inc(1);
 
-            // evaluate if we should finalize the thread according to config
-            if crosscut::should_finalize(&self.finalize, &point) {
-                return Ok(gasket::runtime::WorkOutcome::Done);
+                // evaluate if we should finalize the thread according to config
+                if crosscut::should_finalize(&self.finalize, &point) {
+                    return Ok(gasket::runtime::WorkOutcome::Done);
+                }
             }
         }
 
+        // points not deep enough yet are left for the next run, they'll be
+        // fetched again from the cursor once they are confirmed
+        if self.reached_tip && crosscut::should_finalize_at_tip(&self.finalize) {
+            log::info!("finalizing at the tip of the chain");
+            return Ok(gasket::runtime::WorkOutcome::Done);
+        }
+
         Ok(gasket::runtime::WorkOutcome::Partial)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::os::unix::net::UnixStream;
+    use std::time::Duration;
+
+    use gasket::{
+        messaging::{connect_ports, TwoPhaseInputPort},
+        runtime::{WorkOutcome, Worker as _},
+    };
+    use pallas::codec::minicbor;
+    use pallas::ledger::traverse::MultiEraBlock;
+    use pallas::network::miniprotocols::{blockfetch, Point};
+    use pallas::network::multiplexer;
+
+    use super::{OutputPort, Worker};
+    use crate::sources::{depth::DepthPolicy, n2n::transport::TransportOptions};
+    use crate::{crosscut, enrich, model, storage};
+
+    fn source(address: String, ready: crosscut::ready::ReadyGate, output: OutputPort) -> Worker {
+        let cursor = storage::Cursor::Skip(storage::skip::Config {}.bootstrapper().build_cursor());
+
+        Worker::new(
+            address,
+            DepthPolicy::Blocks(0),
+            TransportOptions {
+                connect_timeout: Some(Duration::from_millis(200)),
+                ..Default::default()
+            },
+            Default::default(),
+            crosscut::ChainWellKnownInfo::mainnet(),
+            crosscut::IntersectConfig::Origin,
+            None,
+            cursor,
+            ready,
+            output,
+        )
+    }
+
+    /// Writes a message of the responder side of a mini-protocol
+    fn send_message(socket: &mut TcpStream, protocol: u16, payload: &[u8]) {
+        for chunk in payload.chunks(u16::MAX as usize) {
+            let mut header = [0u8; 8];
+            header[4..6].copy_from_slice(&(protocol | 0x8000).to_be_bytes());
+            header[6..8].copy_from_slice(&(chunk.len() as u16).to_be_bytes());
+
+            socket.write_all(&header).unwrap();
+            socket.write_all(chunk).unwrap();
+        }
+    }
+
+    /// Relay answering each block-fetch request with as many copies of the
+    /// block as the given counts say, returns the requested ranges
+    fn serve_blocks(
+        listener: TcpListener,
+        block: Vec<u8>,
+        counts: Vec<usize>,
+    ) -> (TcpStream, Vec<(Point, Point)>) {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut ranges = Vec::new();
+
+        let mut msg = Vec::new();
+        minicbor::Encoder::new(&mut msg)
+            .array(2)
+            .unwrap()
+            .u16(4)
+            .unwrap()
+            .tag(minicbor::data::Tag::Cbor)
+            .unwrap()
+            .bytes(&block)
+            .unwrap();
+
+        for count in counts {
+            let mut header = [0u8; 8];
+            socket.read_exact(&mut header).unwrap();
+            let mut request = vec![0u8; u16::from_be_bytes([header[6], header[7]]) as usize];
+            socket.read_exact(&mut request).unwrap();
+
+            let (_, start, end): (u16, Point, Point) = minicbor::decode(&request).unwrap();
+            ranges.push((start, end));
+
+            // start batch, the blocks and batch done
+            send_message(&mut socket, 3, &[0x81, 0x02]);
+
+            for _ in 0..count {
+                send_message(&mut socket, 3, &msg);
+            }
+
+            send_message(&mut socket, 3, &[0x81, 0x05]);
+        }
+
+        (socket, ranges)
+    }
+
+    /// Source connected to the relay for block-fetch only, as if the
+    /// bootstrap found the intersection
+    fn fetching_source(address: std::net::SocketAddr, output: OutputPort) -> Worker {
+        let mut source = source(
+            address.to_string(),
+            crosscut::ready::ReadyGate::opened(),
+            output,
+        );
+
+        let stream = TcpStream::connect(address).unwrap();
+        let mut plexer = multiplexer::StdPlexer::new(multiplexer::bearers::Bearer::Tcp(stream));
+        let channel3 = plexer.use_channel(3);
+        plexer.muxer.spawn();
+        plexer.demuxer.spawn();
+
+        source.blockfetch = Some(blockfetch::Client::new(channel3));
+
+        source
+    }
+
+    #[test]
+    fn nothing_is_pulled_before_enrich_is_ready() {
+        let dir = std::env::temp_dir().join(format!("scrolls-ready-{}", std::process::id()));
+
+        // the node, which never answers the handshake
+        let node = TcpListener::bind("127.0.0.1:0").unwrap();
+        node.set_nonblocking(true).unwrap();
+
+        let enrich = enrich::sled::Config {
+            db_path: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        }
+        .boostrapper(&Default::default(), None);
+
+        let gate = enrich.ready_gate();
+        let (_, mut enrich) = enrich.into_inline();
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<model::RawBlockPayload>::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        let mut source = source(node.local_addr().unwrap().to_string(), gate.clone(), output);
+
+        // enrich hasn't opened its db yet, the node isn't even contacted
+        source.ready_timeout = Duration::from_millis(50);
+        assert!(source.bootstrap().is_err());
+        assert!(node.accept().is_err());
+        assert!(source.chainsync.is_none());
+        assert!(matches!(
+            downstream.recv_or_idle(),
+            Err(gasket::error::Error::RecvIdle)
+        ));
+
+        let late = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(100));
+            enrich.bootstrap().unwrap();
+        });
+
+        // the source is let through once the stage is bootstrapped, the
+        // handshake times out against the silent node
+        source.ready_timeout = Duration::from_secs(5);
+        assert!(source.bootstrap().is_err());
+        assert!(gate.is_open());
+        assert!(node.accept().is_ok());
+
+        late.join().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paused_source_emits_nothing_until_resumed() {
+        let cbor = hex::decode(include_str!("../../../assets/test.block")).unwrap();
+        let block = MultiEraBlock::decode(&cbor).unwrap();
+        let point = Point::Specific(block.slot(), block.hash().to_vec());
+
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = relay.local_addr().unwrap();
+        let relay = {
+            let cbor = cbor.clone();
+            std::thread::spawn(move || serve_blocks(relay, cbor, vec![1]))
+        };
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<model::RawBlockPayload>::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        // the point is confirmed already, only block-fetch is left
+        let mut source = fetching_source(address, output);
+        source.pending_fetch.push_back(point);
+
+        // started with --paused, the gate is shared with the control socket
+        let resume = crosscut::ready::ReadyGate::default();
+        source.resume = resume.clone();
+
+        let path = std::env::temp_dir().join(format!("scrolls-paused-{}.sock", std::process::id()));
+        crosscut::control::serve(&path, resume).unwrap();
+
+        for _ in 0..2 {
+            assert!(matches!(source.work(), Ok(WorkOutcome::Partial)));
+            assert!(matches!(
+                downstream.recv_or_idle(),
+                Err(gasket::error::Error::RecvIdle)
+            ));
+        }
+
+        assert!(!relay.is_finished());
+        assert_eq!(source.pending_fetch.len(), 1);
+
+        let mut control = UnixStream::connect(&path).unwrap();
+        writeln!(control, "resume").unwrap();
+        let mut answer = [0u8; 3];
+        control.read_exact(&mut answer).unwrap();
+        assert_eq!(&answer, b"ok\n");
+
+        assert!(matches!(source.work(), Ok(WorkOutcome::Partial)));
+
+        match downstream.recv_or_idle().unwrap().payload {
+            model::RawBlockPayload::RollForward(x) => assert_eq!(x, cbor),
+            _ => panic!("expected the fetched block"),
+        }
+
+        assert!(source.pending_fetch.is_empty());
+
+        drop(relay.join());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn confirmed_blocks_are_fetched_in_ranges_one_at_a_time() {
+        let cbor = hex::decode(include_str!("../../../assets/test.block")).unwrap();
+
+        let relay = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = relay.local_addr().unwrap();
+        let relay = {
+            let cbor = cbor.clone();
+            std::thread::spawn(move || serve_blocks(relay, cbor, vec![2, 1]))
+        };
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<model::RawBlockPayload>::default();
+        connect_ports(&mut output, &mut downstream, 10);
+
+        let mut source = fetching_source(address, output);
+        source.fetch_range_size = 2;
+
+        let points: Vec<_> = (1..=3u64)
+            .map(|slot| Point::Specific(slot, vec![slot as u8; 32]))
+            .collect();
+        source.pending_fetch.extend(points.clone());
+
+        assert!(matches!(source.work(), Ok(WorkOutcome::Partial)));
+
+        // the second range is only requested once the first one arrived, the
+        // relay answers them in order on a single connection
+        let (_, ranges) = relay.join().unwrap();
+        assert_eq!(
+            ranges,
+            vec![
+                (points[0].clone(), points[1].clone()),
+                (points[2].clone(), points[2].clone()),
+            ]
+        );
+
+        let fetched = std::iter::from_fn(|| downstream.recv_or_idle().ok()).count();
+        assert_eq!(fetched, 3);
+        assert!(source.pending_fetch.is_empty());
+    }
+}
@@ -34,9 +34,53 @@ impl ChainSyncInternalPayload {
 pub struct Config {
     pub address: String,
     pub min_depth: Option<usize>,
+
+    /// How far behind the tip a block must be to be processed, defaults to
+    /// `min_depth` blocks
+    pub depth_policy: Option<super::depth::DepthPolicy>,
+
+    /// Seconds to wait for the relay connection and handshake
+    pub connect_timeout_secs: Option<u64>,
+
+    /// Seconds between tcp keepalive probes
+    pub keepalive_secs: Option<u64>,
+
+    /// Seconds without any message from the relay before the connection is
+    /// dropped and set up again, with the backoff of the bootstrap retries.
+    /// At the tip the relay only speaks when there's a new block, so this has
+    /// to be well above the longest expected gap between blocks. Disabled by
+    /// default.
+    pub read_timeout_secs: Option<u64>,
+
+    /// Seconds without a new block before chain-sync is reported as stalled
+    pub stall_grace_secs: Option<u64>,
+
+    /// Maximum number of blocks sent downstream and not yet committed by the
+    /// enrich stage. Fetching pauses once reached, which caps the blocks held
+    /// in memory during catch-up regardless of the channel capacities.
+    pub max_inflight_blocks: Option<usize>,
+
+    /// Number of confirmed blocks requested at once through block-fetch, as
+    /// a single range. Requests are never concurrent: the next one is only
+    /// sent once the relay delivered the whole range, so this is also the
+    /// most blocks in flight with the relay. Defaults to 1, a request per
+    /// block.
+    pub fetch_range_size: Option<usize>,
+
+    /// Milliseconds to wait after each block-fetch request, to stay within
+    /// the rate limits of community relays. No delay by default.
+    pub fetch_delay_ms: Option<u64>,
 }
 
 impl Config {
+    fn transport_options(&self) -> transport::TransportOptions {
+        transport::TransportOptions {
+            connect_timeout: self.connect_timeout_secs.map(Duration::from_secs),
+            keepalive: self.keepalive_secs.map(Duration::from_secs),
+            read_timeout: self.read_timeout_secs.map(Duration::from_secs),
+        }
+    }
+
     pub fn bootstrapper(
         self,
         chain: &crosscut::ChainWellKnownInfo,
@@ -69,18 +113,44 @@ impl Bootstrapper {
         &mut self.output
     }
 
-    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline, cursor: storage::Cursor) {
-        pipeline.register_stage(gasket::runtime::spawn_stage(
-            self::chainsync::Worker::new(
-                self.config.address.clone(),
-                self.config.min_depth.unwrap_or(0),
-                self.policy,
-                self.chain.clone(),
-                self.intersect,
-                self.finalize,
-                cursor,
-                self.output,
+    pub fn spawn_stages(
+        self,
+        pipeline: &mut bootstrap::Pipeline,
+        cursor: storage::Cursor,
+        downstream_ready: crosscut::ready::ReadyGate,
+        downstream_inflight: crosscut::inflight::Inflight,
+        resume: crosscut::ready::ReadyGate,
+    ) {
+        let mut worker = self::chainsync::Worker::new(
+            self.config.address.clone(),
+            super::depth::DepthPolicy::from_config(
+                self.config.depth_policy,
+                self.config.min_depth,
             ),
+            self.config.transport_options(),
+            self.policy,
+            self.chain.clone(),
+            self.intersect,
+            self.finalize,
+            cursor,
+            downstream_ready,
+            self.output,
+        );
+
+        // the source idles until resumed when the daemon starts paused
+        worker.resume = resume;
+
+        worker.inflight = downstream_inflight;
+        worker.max_inflight = self.config.max_inflight_blocks;
+
+        worker.fetch_range_size = self.config.fetch_range_size.unwrap_or(1).max(1);
+        worker.fetch_delay = self.config.fetch_delay_ms.map(Duration::from_millis);
+
+        worker.stall =
+            super::stall::StallWatch::new(self.config.stall_grace_secs.map(Duration::from_secs));
+
+        pipeline.register_stage(gasket::runtime::spawn_stage(
+            worker,
             gasket::runtime::Policy {
                 tick_timeout: Some(Duration::from_secs(600)),
                 bootstrap_retry: gasket::retries::Policy {
@@ -1,5 +1,26 @@
+use std::{
+    io,
+    net::{TcpStream, ToSocketAddrs},
+    time::Duration,
+};
+
+use net2::TcpStreamExt;
 use pallas::network::{miniprotocols::handshake, multiplexer};
 
+/// Socket level settings applied when connecting to the relay
+#[derive(Clone, Debug, Default)]
+pub struct TransportOptions {
+    /// Max time to wait for the tcp connection and the handshake
+    pub connect_timeout: Option<Duration>,
+
+    /// Interval of the tcp keepalive probes, disabled if not set
+    pub keepalive: Option<Duration>,
+
+    /// Max time without receiving anything from the relay, after which reads
+    /// fail and the connection is dropped. Disabled if not set.
+    pub read_timeout: Option<Duration>,
+}
+
 pub struct Transport {
     pub channel2: multiplexer::StdChannel,
     pub channel3: multiplexer::StdChannel,
@@ -30,11 +51,52 @@ impl Transport {
         }
     }
 
-    pub fn setup(address: &str, magic: u64) -> Result<Self, crate::Error> {
+    fn connect_tcp(address: &str, options: &TransportOptions) -> Result<TcpStream, crate::Error> {
+        let timeout = match options.connect_timeout {
+            Some(x) => x,
+            None => return TcpStream::connect(address).map_err(crate::Error::network),
+        };
+
+        let mut last_err = None;
+
+        for addr in address.to_socket_addrs().map_err(crate::Error::network)? {
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => {
+                    last_err = Some(crate::Error::network(format!(
+                        "timed out after {:?} connecting to {}",
+                        timeout, addr
+                    )));
+                }
+                Err(err) => last_err = Some(crate::Error::network(err)),
+            }
+        }
+
+        Err(last_err.unwrap_or_else(|| {
+            crate::Error::network(format!("{} didn't resolve to any address", address))
+        }))
+    }
+
+    pub fn setup(address: &str, magic: u64, options: &TransportOptions) -> Result<Self, crate::Error> {
         log::debug!("connecting muxer");
 
-        let bearer =
-            multiplexer::bearers::Bearer::connect_tcp(address).map_err(crate::Error::network)?;
+        let stream = Self::connect_tcp(address, options)?;
+        stream.set_nodelay(true).map_err(crate::Error::network)?;
+
+        if let Some(keepalive) = options.keepalive {
+            stream
+                .set_keepalive(Some(keepalive))
+                .map_err(crate::Error::network)?;
+        }
+
+        // the read timeout bounds the handshake, the socket is shared with the
+        // bearer so we keep a handle to reset it once the handshake is done
+        let control = stream.try_clone().map_err(crate::Error::network)?;
+        control
+            .set_read_timeout(options.connect_timeout.or(options.read_timeout))
+            .map_err(crate::Error::network)?;
+
+        let bearer = multiplexer::bearers::Bearer::Tcp(stream);
         let mut plexer = multiplexer::StdPlexer::new(bearer);
 
         let channel0 = plexer.use_channel(0);
@@ -46,6 +108,13 @@ impl Transport {
 
         let version = Self::do_handshake(channel0, magic)?;
 
+        // a relay that goes silent without closing the socket would block the
+        // mini-protocols forever, the timeout makes the demuxer fail instead,
+        // which surfaces as an error on the channels
+        control
+            .set_read_timeout(options.read_timeout)
+            .map_err(crate::Error::network)?;
+
         Ok(Self {
             channel2,
             channel3,
@@ -53,3 +122,113 @@ impl Transport {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use std::time::{Duration, Instant};
+
+    use pallas::codec::minicbor;
+    use pallas::network::miniprotocols::chainsync;
+
+    use super::{Transport, TransportOptions};
+
+    #[test]
+    fn connect_timeout_fires() {
+        let options = TransportOptions {
+            connect_timeout: Some(Duration::from_secs(1)),
+            keepalive: None,
+            read_timeout: None,
+        };
+
+        let start = Instant::now();
+
+        // non-routable address, the connection attempt never gets an answer
+        let result = Transport::setup("10.255.255.1:3001", 764824073, &options);
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn read_timeout_drops_a_silent_peer() {
+        // accepts the connection and never answers
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let peer = std::thread::spawn(move || listener.accept().map(|(socket, _)| socket));
+
+        let options = TransportOptions {
+            connect_timeout: None,
+            keepalive: None,
+            read_timeout: Some(Duration::from_secs(1)),
+        };
+
+        let start = Instant::now();
+        let result = Transport::setup(&address, 764824073, &options);
+
+        assert!(result.is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        drop(peer.join());
+    }
+
+    #[test]
+    fn read_timeout_drops_a_peer_silent_after_the_handshake() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        // accepts the proposed version 7 and never speaks again
+        let peer = std::thread::spawn(move || {
+            let (mut socket, _) = listener.accept().unwrap();
+
+            let mut header = [0u8; 8];
+            socket.read_exact(&mut header).unwrap();
+            let mut proposal = vec![0u8; u16::from_be_bytes([header[6], header[7]]) as usize];
+            socket.read_exact(&mut proposal).unwrap();
+
+            let mut accept = Vec::new();
+            minicbor::Encoder::new(&mut accept)
+                .array(3)
+                .unwrap()
+                .u16(1)
+                .unwrap()
+                .u64(7)
+                .unwrap()
+                .array(2)
+                .unwrap()
+                .u64(764824073)
+                .unwrap()
+                .bool(false)
+                .unwrap();
+
+            let mut header = [0u8; 8];
+            header[4..6].copy_from_slice(&0x8000u16.to_be_bytes());
+            header[6..8].copy_from_slice(&(accept.len() as u16).to_be_bytes());
+            socket.write_all(&header).unwrap();
+            socket.write_all(&accept).unwrap();
+
+            socket
+        });
+
+        // the demuxer may already be waiting with the handshake timeout when
+        // the read timeout is set, either one bounds the wait
+        let options = TransportOptions {
+            connect_timeout: Some(Duration::from_secs(2)),
+            keepalive: None,
+            read_timeout: Some(Duration::from_secs(1)),
+        };
+
+        let transport = Transport::setup(&address, 764824073, &options).unwrap();
+        assert_eq!(transport.version, 7);
+
+        // the request is never answered, the demuxer gives up on the socket
+        let start = Instant::now();
+        let mut client = chainsync::N2NClient::new(transport.channel2);
+
+        assert!(client.request_next().is_err());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        drop(peer.join());
+    }
+}
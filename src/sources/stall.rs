@@ -0,0 +1,150 @@
+use std::sync::{Arc, Mutex, Weak};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How often the watchdog refreshes the gauge while the worker is blocked
+const WATCHDOG_INTERVAL: Duration = Duration::from_secs(1);
+
+struct State {
+    last_block: Instant,
+    warned: bool,
+}
+
+struct Inner {
+    grace: Option<Duration>,
+    state: Mutex<State>,
+    gauge: gasket::metrics::Gauge,
+}
+
+/// Tracks the time since chain-sync last received a block
+///
+/// At the tip, the worker blocks until the node announces the next block, so
+/// a stuck node or a network partition looks the same as a quiet chain from
+/// within the worker. A watchdog thread keeps the `seconds_since_last_block`
+/// gauge fresh while the worker waits and logs a warning once the grace
+/// period is exceeded, once per stall.
+pub struct StallWatch {
+    inner: Arc<Inner>,
+    watchdog: bool,
+}
+
+impl StallWatch {
+    pub fn new(grace: Option<Duration>) -> Self {
+        let inner = Inner {
+            grace,
+            state: Mutex::new(State {
+                last_block: Instant::now(),
+                warned: false,
+            }),
+            gauge: Default::default(),
+        };
+
+        Self {
+            inner: Arc::new(inner),
+            watchdog: false,
+        }
+    }
+
+    pub fn gauge(&self) -> &gasket::metrics::Gauge {
+        &self.inner.gauge
+    }
+
+    pub fn block_received(&self) {
+        let mut state = self.inner.state.lock().unwrap();
+
+        if state.warned {
+            log::warn!("chain-sync received a block again after a stall");
+        }
+
+        state.last_block = Instant::now();
+        state.warned = false;
+
+        self.inner.gauge.set(0);
+    }
+
+    /// Updates the gauge as of `now`, returns true if the grace period is
+    /// exceeded
+    pub fn refresh(&self, now: Instant) -> bool {
+        self.inner.refresh(now)
+    }
+
+    /// Starts the watchdog thread, unless already running. The thread stops
+    /// once the watch is dropped.
+    pub fn spawn_watchdog(&mut self) {
+        if self.watchdog {
+            return;
+        }
+
+        let inner = Arc::downgrade(&self.inner);
+
+        let spawned = thread::Builder::new()
+            .name("stall-watchdog".into())
+            .spawn(move || watchdog(inner));
+
+        match spawned {
+            Ok(_) => self.watchdog = true,
+            Err(err) => log::error!("can't spawn the stall watchdog: {}", err),
+        }
+    }
+}
+
+impl Default for StallWatch {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+impl Inner {
+    fn refresh(&self, now: Instant) -> bool {
+        let mut state = self.state.lock().unwrap();
+
+        let elapsed = now.saturating_duration_since(state.last_block);
+        self.gauge.set(elapsed.as_secs() as i64);
+
+        let stalled = matches!(self.grace, Some(grace) if elapsed > grace);
+
+        if stalled && !state.warned {
+            log::warn!(
+                "chain-sync hasn't received a block for {}s, the node might be stalled",
+                elapsed.as_secs()
+            );
+
+            state.warned = true;
+        }
+
+        stalled
+    }
+}
+
+fn watchdog(inner: Weak<Inner>) {
+    loop {
+        thread::sleep(WATCHDOG_INTERVAL);
+
+        match inner.upgrade() {
+            Some(inner) => inner.refresh(Instant::now()),
+            None => break,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::StallWatch;
+
+    #[test]
+    fn long_await_is_flagged_as_stall() {
+        let watch = StallWatch::new(Some(Duration::from_secs(60)));
+
+        assert!(!watch.refresh(Instant::now() + Duration::from_secs(30)));
+        assert!(watch.refresh(Instant::now() + Duration::from_secs(90)));
+
+        watch.block_received();
+        assert!(!watch.refresh(Instant::now()));
+
+        // without a grace period the gauge is kept but nothing is flagged
+        let watch = StallWatch::new(None);
+        assert!(!watch.refresh(Instant::now() + Duration::from_secs(3600)));
+    }
+}
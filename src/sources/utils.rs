@@ -10,11 +10,87 @@ use pallas::{
 
 use crate::{crosscut, storage};
 
+/// Where chain-sync starts reading from
+pub struct ChainsyncStart {
+    pub point: Option<Point>,
+    /// Blocks before this slot are walked through without being processed
+    pub skip_until_slot: Option<u64>,
+}
+
+impl ChainsyncStart {
+    fn at(point: Option<Point>) -> Self {
+        Self {
+            point,
+            skip_until_slot: None,
+        }
+    }
+}
+
+/// Slots walked through between two progress logs while skipping
+const SKIP_LOG_EVERY_SLOTS: u64 = 432_000;
+
+/// Blocks before the start slot of a `TipMinus` intersection, walked through
+/// without being processed
+pub struct SkipUntil {
+    slot: u64,
+    logged: Option<u64>,
+}
+
+impl SkipUntil {
+    pub fn new(slot: u64) -> Self {
+        Self { slot, logged: None }
+    }
+
+    /// True if the block at the slot comes before the start, logging the
+    /// progress every now and then since it may take hours
+    pub fn skips(&mut self, slot: u64) -> bool {
+        if slot >= self.slot {
+            return false;
+        }
+
+        let due = match self.logged {
+            Some(x) => slot >= x + SKIP_LOG_EVERY_SLOTS,
+            None => true,
+        };
+
+        if due {
+            log::info!(
+                "skipping blocks until slot {}, {} slots to go",
+                self.slot,
+                self.slot - slot
+            );
+
+            self.logged = Some(slot);
+        }
+
+        true
+    }
+}
+
+/// Well-known points of the chain at or before the slot, newest first
+///
+/// The node only intersects with points whose hash it's given, the block at
+/// an arbitrary slot can't be asked for (nor bisected). These are the closest
+/// chain-sync can get to the slot without walking the chain.
+fn known_points_before(chain: &crosscut::ChainWellKnownInfo, slot: u64) -> Vec<Point> {
+    let known = [
+        (chain.shelley_known_slot, &chain.shelley_known_hash),
+        (chain.byron_known_slot, &chain.byron_known_hash),
+    ];
+
+    known
+        .into_iter()
+        .filter(|(x, _)| *x <= slot)
+        .filter_map(|(x, hash)| Some(Point::Specific(x, hex::decode(hash).ok()?)))
+        .collect()
+}
+
 pub fn define_chainsync_start<C: Fragment>(
     intersect: &crosscut::IntersectConfig,
+    chain: &crosscut::ChainWellKnownInfo,
     cursor: &mut storage::Cursor,
     client: &mut chainsync::Client<StdChannel, C>,
-) -> Result<Option<Point>, crate::Error> {
+) -> Result<ChainsyncStart, crate::Error> {
     match cursor.last_point()? {
         Some(x) => {
             log::info!("found existing cursor in storage plugin: {:?}", x);
@@ -22,7 +98,7 @@ pub fn define_chainsync_start<C: Fragment>(
             let (point, _) = client
                 .find_intersect(vec![point])
                 .map_err(crate::Error::ouroboros)?;
-            return Ok(point);
+            return Ok(ChainsyncStart::at(point));
         }
         None => log::info!("no cursor found in storage plugin"),
     };
@@ -30,25 +106,90 @@ pub fn define_chainsync_start<C: Fragment>(
     match &intersect {
         crosscut::IntersectConfig::Origin => {
             let point = client.intersect_origin().map_err(crate::Error::ouroboros)?;
-            Ok(Some(point))
+            Ok(ChainsyncStart::at(Some(point)))
         }
         crosscut::IntersectConfig::Tip => {
             let point = client.intersect_tip().map_err(crate::Error::ouroboros)?;
-            Ok(Some(point))
+            Ok(ChainsyncStart::at(Some(point)))
         }
         crosscut::IntersectConfig::Point(_, _) => {
             let point = intersect.get_point().expect("point value");
             let (point, _) = client
                 .find_intersect(vec![point])
                 .map_err(crate::Error::ouroboros)?;
-            Ok(point)
+            Ok(ChainsyncStart::at(point))
         }
         crosscut::IntersectConfig::Fallbacks(_) => {
             let points = intersect.get_fallbacks().expect("fallback values");
             let (point, _) = client
                 .find_intersect(points)
                 .map_err(crate::Error::ouroboros)?;
-            Ok(point)
+            Ok(ChainsyncStart::at(point))
+        }
+        crosscut::IntersectConfig::TipMinus(_) => {
+            // there's no way to ask the node for a point by slot, so we start
+            // from the closest well-known point and skip blocks until
+            // reaching the target slot
+            let tip = client.intersect_tip().map_err(crate::Error::ouroboros)?;
+
+            let skip_until_slot = intersect.get_start_slot(&tip);
+            log::info!(
+                "node tip is {:?}, starting at slot {:?}",
+                tip,
+                skip_until_slot
+            );
+
+            let known = known_points_before(chain, skip_until_slot.unwrap_or_default());
+
+            let (point, _) = client
+                .find_intersect(known)
+                .map_err(crate::Error::ouroboros)?;
+
+            let point = match point {
+                Some(x) => x,
+                None => client.intersect_origin().map_err(crate::Error::ouroboros)?,
+            };
+
+            log::info!("walking the chain from {:?} to the start slot", point);
+
+            Ok(ChainsyncStart {
+                point: Some(point),
+                skip_until_slot,
+            })
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::{known_points_before, SkipUntil};
+    use crate::crosscut::ChainWellKnownInfo;
+
+    #[test]
+    fn closest_known_point_comes_first() {
+        let chain = ChainWellKnownInfo::mainnet();
+
+        let slots = |slot| -> Vec<_> {
+            known_points_before(&chain, slot)
+                .iter()
+                .map(Point::slot_or_default)
+                .collect()
+        };
+
+        assert_eq!(slots(50_000_000), vec![4_492_800, 0]);
+        assert_eq!(slots(4_492_800), vec![4_492_800, 0]);
+        assert_eq!(slots(1_000), vec![0]);
+    }
+
+    #[test]
+    fn blocks_before_the_start_slot_are_skipped() {
+        let mut skip = SkipUntil::new(100);
+
+        assert!(skip.skips(0));
+        assert!(skip.skips(99));
+        assert!(!skip.skips(100));
+        assert!(!skip.skips(101));
+    }
+}
@@ -0,0 +1,166 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
+use std::num::NonZeroUsize;
+
+use lru::LruCache;
+
+use crate::model;
+
+fn hash_of(value: impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Remembers the last idempotent command applied to each key
+///
+/// A command is redundant when it's idempotent and identical to the last one
+/// applied to the same key, in which case writing it again can't change the
+/// store. This only holds if nothing else writes to those keys: a store shared
+/// with other writers or commands that need to be re-applied (eg: to recreate
+/// an expired key) require the cache to be disabled.
+pub struct WriteCache {
+    entries: LruCache<u64, u64>,
+}
+
+impl WriteCache {
+    pub fn new(size: NonZeroUsize) -> Self {
+        Self {
+            entries: LruCache::new(size),
+        }
+    }
+
+    pub fn is_redundant(&self, cmd: &model::CRDTCommand) -> bool {
+        if !cmd.is_idempotent() {
+            return false;
+        }
+
+        match cmd.key() {
+            Some(key) => self.entries.peek(&hash_of(key)) == Some(&hash_of(format!("{:?}", cmd))),
+            None => false,
+        }
+    }
+
+    /// Commands of a batch that have to be written, in order
+    ///
+    /// The cache only learns about a batch once it's in the store, so it
+    /// tells what each key held before the batch. Once a command of the batch
+    /// touched a key, the cache can't tell what it holds anymore: only the
+    /// first command on each key can be skipped.
+    pub fn unwritten<'a>(&self, batch: &'a [model::CRDTCommand]) -> Vec<&'a model::CRDTCommand> {
+        let mut touched = HashSet::new();
+
+        batch
+            .iter()
+            .filter(|cmd| {
+                let first = match cmd.key() {
+                    Some(key) => touched.insert(hash_of(key)),
+                    None => true,
+                };
+
+                !(first && self.is_redundant(cmd))
+            })
+            .collect()
+    }
+
+    /// Tracks a command that was written to the store
+    pub fn record(&mut self, cmd: &model::CRDTCommand) {
+        let key = match cmd.key() {
+            Some(x) => hash_of(x),
+            None => return,
+        };
+
+        match cmd.is_idempotent() {
+            true => {
+                self.entries.put(key, hash_of(format!("{:?}", cmd)));
+            }
+            // whatever we knew about the key isn't true anymore
+            false => {
+                self.entries.pop(&key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::WriteCache;
+    use crate::model::{CRDTCommand, Value};
+
+    fn write(key: &str, value: &str) -> CRDTCommand {
+        CRDTCommand::AnyWriteWins(key.into(), Value::String(value.into()))
+    }
+
+    #[test]
+    fn identical_writes_are_redundant() {
+        let mut cache = WriteCache::new(NonZeroUsize::new(10).unwrap());
+
+        assert!(!cache.is_redundant(&write("a", "1")));
+
+        cache.record(&write("a", "1"));
+        assert!(cache.is_redundant(&write("a", "1")));
+        assert!(!cache.is_redundant(&write("a", "2")));
+
+        cache.record(&write("a", "2"));
+        assert!(!cache.is_redundant(&write("a", "1")));
+
+        cache.clear();
+        assert!(!cache.is_redundant(&write("a", "2")));
+    }
+
+    #[test]
+    fn non_idempotent_commands_invalidate_the_key() {
+        let mut cache = WriteCache::new(NonZeroUsize::new(10).unwrap());
+
+        let counter = CRDTCommand::PNCounter("a".into(), 1);
+
+        cache.record(&counter);
+        assert!(!cache.is_redundant(&counter));
+
+        cache.record(&write("a", "1"));
+        cache.record(&counter);
+        assert!(!cache.is_redundant(&write("a", "1")));
+    }
+
+    #[test]
+    fn keys_written_earlier_in_the_batch_are_kept() {
+        let mut cache = WriteCache::new(NonZeroUsize::new(10).unwrap());
+
+        // the store holds a=A, the batch moves it to B and back
+        cache.record(&write("a", "A"));
+
+        let batch = vec![write("a", "A"), write("a", "B"), write("a", "A")];
+        let kept: Vec<_> = cache.unwritten(&batch).into_iter().cloned().collect();
+        assert_eq!(
+            format!("{:?}", kept),
+            format!("{:?}", vec![write("a", "B"), write("a", "A")])
+        );
+
+        // a member removed and added back within the batch
+        let add = CRDTCommand::SetAdd("s".into(), "x".into());
+        let remove = CRDTCommand::SetRemove("s".into(), "x".into());
+        cache.record(&add);
+
+        let batch = vec![remove.clone(), add.clone()];
+        let kept: Vec<_> = cache.unwritten(&batch).into_iter().cloned().collect();
+        assert_eq!(format!("{:?}", kept), format!("{:?}", vec![remove, add]));
+    }
+
+    #[test]
+    fn least_recent_keys_are_evicted() {
+        let mut cache = WriteCache::new(NonZeroUsize::new(1).unwrap());
+
+        cache.record(&write("a", "1"));
+        cache.record(&write("b", "1"));
+
+        assert!(!cache.is_redundant(&write("a", "1")));
+        assert!(cache.is_redundant(&write("b", "1")));
+    }
+}
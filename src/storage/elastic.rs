@@ -20,18 +20,6 @@ use crate::{
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
 
-impl From<model::Value> for JsonValue {
-    fn from(other: model::Value) -> JsonValue {
-        match other {
-            model::Value::String(x) => json!(x),
-            model::Value::Cbor(x) => json!(hex::encode(x)),
-            model::Value::BigInt(x) => json!(x),
-            model::Value::Json(x) => x,
-            model::Value::BigInt(x) => json!({ "value": x }),
-        }
-    }
-}
-
 #[derive(Deserialize, Clone)]
 pub struct Config {
     pub connection_url: String,
@@ -159,6 +147,7 @@ type ESResult = Result<Response, elasticsearch::Error>;
 async fn apply_command(cmd: CRDTCommand, client: &Elasticsearch) -> Option<ESResult> {
     match cmd {
         CRDTCommand::BlockStarting(_) => None,
+        CRDTCommand::RollbackMarker(_) => None,
         CRDTCommand::AnyWriteWins(key, value) => client
             .index(elasticsearch::IndexParts::IndexId("scrolls", &key))
             .body::<JsonValue>(json!({ "key": &key, "value": JsonValue::from(value) }))
@@ -0,0 +1,357 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use flate2::{write::GzEncoder, Compression};
+use gasket::{
+    error::AsWorkError,
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{bootstrap, crosscut, model, storage};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    /// Path of the active output file, rotated files are written next to it
+    pub path: String,
+
+    /// Rotate once the active file reaches this size
+    pub max_bytes: Option<u64>,
+
+    /// Rotate once the active file is older than this
+    pub max_age_secs: Option<u64>,
+
+    /// Gzip files as they are rotated
+    pub compress: Option<bool>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        _chain: &crosscut::ChainWellKnownInfo,
+        _intersect: &crosscut::IntersectConfig,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            input: Default::default(),
+        }
+    }
+
+    fn cursor_path(&self) -> String {
+        format!("{}.cursor", self.path)
+    }
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    input: InputPort,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn build_cursor(&self) -> Cursor {
+        Cursor {
+            config: self.config.clone(),
+        }
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker::new(self.config, self.input);
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            Some("file"),
+        ));
+    }
+}
+
+pub struct Cursor {
+    config: Config,
+}
+
+impl Cursor {
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        storage::read_cursor_file(&self.config.cursor_path())
+    }
+}
+
+struct ActiveFile {
+    writer: BufWriter<File>,
+    size: u64,
+    opened_at: Instant,
+}
+
+pub struct Worker {
+    config: Config,
+    active: Option<ActiveFile>,
+    pending: Vec<model::CRDTCommand>,
+    ops_count: gasket::metrics::Counter,
+    rotations: gasket::metrics::Counter,
+    input: InputPort,
+}
+
+/// Json line closing a block, carrying the cursor needed to resume after it
+fn block_finished_line(point: Point) -> String {
+    let cursor = crosscut::PointArg::from(point.clone()).to_string();
+
+    json!({
+        "type": "block_finished",
+        "point": model::point_to_json(&point),
+        "cursor": cursor,
+    })
+    .to_string()
+}
+
+fn gzip_file(src: &str) -> io::Result<()> {
+    let mut input = File::open(src)?;
+    let output = File::create(format!("{}.gz", src))?;
+
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+
+    fs::remove_file(src)
+}
+
+impl Worker {
+    pub fn new(config: Config, input: InputPort) -> Self {
+        Self {
+            config,
+            input,
+            active: None,
+            pending: Vec::new(),
+            ops_count: Default::default(),
+            rotations: Default::default(),
+        }
+    }
+
+    fn open(&self) -> io::Result<ActiveFile> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.path)?;
+
+        Ok(ActiveFile {
+            size: file.metadata()?.len(),
+            writer: BufWriter::new(file),
+            opened_at: Instant::now(),
+        })
+    }
+
+    fn should_rotate(&self, active: &ActiveFile) -> bool {
+        let by_size = matches!(self.config.max_bytes, Some(max) if active.size >= max);
+
+        let by_age = matches!(
+            self.config.max_age_secs,
+            Some(max) if active.opened_at.elapsed() >= Duration::from_secs(max)
+        );
+
+        by_size || by_age
+    }
+
+    /// Moves the active file aside and starts a new one
+    ///
+    /// Rotation only happens between blocks, so every file holds whole
+    /// blocks and can be replayed on its own.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(mut active) = self.active.take() {
+            active.writer.flush()?;
+        }
+
+        let ts = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+
+        let rotated = format!("{}.{}", self.config.path, ts);
+        fs::rename(&self.config.path, &rotated)?;
+
+        if self.config.compress.unwrap_or(false) {
+            gzip_file(&rotated)?;
+        }
+
+        self.rotations.inc(1);
+        self.active = Some(self.open()?);
+
+        Ok(())
+    }
+
+    fn write_lines(&mut self, lines: Vec<String>) -> io::Result<()> {
+        if self.active.is_none() {
+            self.active = Some(self.open()?);
+        }
+
+        let active = self.active.as_mut().unwrap();
+
+        for line in lines {
+            active.writer.write_all(line.as_bytes())?;
+            active.writer.write_all(b"\n")?;
+            active.size += line.len() as u64 + 1;
+        }
+
+        active.writer.flush()?;
+
+        Ok(())
+    }
+
+    fn write_block(&mut self, point: Point) -> io::Result<()> {
+        let lines = self
+            .pending
+            .iter()
+            .map(|cmd| cmd.to_json().to_string())
+            .chain(std::iter::once(block_finished_line(point)))
+            .collect();
+
+        self.write_lines(lines)
+    }
+
+    fn flush_block(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        self.write_block(point.clone()).or_panic()?;
+
+        storage::write_cursor_file(&self.config.cursor_path(), crosscut::PointArg::from(point))
+            .or_panic()?;
+
+        self.ops_count.inc(self.pending.len() as u64);
+        self.pending.clear();
+
+        let rotate = match &self.active {
+            Some(active) => self.should_rotate(active),
+            None => false,
+        };
+
+        if rotate {
+            self.rotate().or_panic()?;
+        }
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .with_counter("storage_rotations", &self.rotations)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(_) => {
+                self.pending.clear();
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                self.flush_block(point)?;
+            }
+            cmd @ model::CRDTCommand::RollbackMarker(_) => {
+                // markers live outside of blocks, write them right away
+                self.write_lines(vec![cmd.to_json().to_string()]).or_panic()?;
+            }
+            cmd => {
+                self.pending.push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        self.active = Some(self.open().or_panic()?);
+
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        if let Some(active) = self.active.as_mut() {
+            active.writer.flush().or_panic()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+
+    use super::{Config, Worker};
+    use crate::{crosscut::PointArg, model::CRDTCommand, storage};
+
+    fn test_path(name: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("scrolls-file-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        dir.join("out.ndjson").to_str().unwrap().to_string()
+    }
+
+    fn config(path: &str, max_bytes: Option<u64>) -> Config {
+        Config {
+            path: path.to_string(),
+            max_bytes,
+            max_age_secs: None,
+            compress: None,
+        }
+    }
+
+    #[test]
+    fn blocks_are_framed_with_cursor() {
+        let path = test_path("framing");
+        let mut worker = Worker::new(config(&path, None), Default::default());
+
+        worker.pending.push(CRDTCommand::PNCounter("a".into(), 1));
+        worker.flush_block(Point::Specific(10, vec![1u8; 32])).unwrap();
+
+        let content = std::fs::read_to_string(&path).unwrap();
+        let lines: Vec<serde_json::Value> = content
+            .lines()
+            .map(|x| serde_json::from_str(x).unwrap())
+            .collect();
+
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["type"], "pn_counter");
+        assert_eq!(lines[1]["type"], "block_finished");
+
+        let expected = PointArg::Specific(10, hex::encode([1u8; 32])).to_string();
+        assert_eq!(lines[1]["cursor"], expected);
+
+        let stored = storage::read_cursor_file(&format!("{}.cursor", path))
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.to_string(), expected);
+    }
+
+    #[test]
+    fn rotates_between_blocks() {
+        let path = test_path("rotation");
+        let mut worker = Worker::new(config(&path, Some(1)), Default::default());
+
+        worker.pending.push(CRDTCommand::PNCounter("a".into(), 1));
+        worker.flush_block(Point::Specific(10, vec![1u8; 32])).unwrap();
+
+        let dir = std::path::Path::new(&path).parent().unwrap();
+        let files = std::fs::read_dir(dir).unwrap().count();
+
+        // active file, cursor file and the rotated one
+        assert_eq!(files, 3);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+}
@@ -0,0 +1,335 @@
+use std::time::Duration;
+
+use gasket::{
+    error::AsWorkError,
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use kafka::producer::{Producer, Record, RequiredAcks};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::{bootstrap, crosscut, model, storage};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    pub brokers: Vec<String>,
+    pub topic: String,
+
+    /// File where the cursor is stored once a block is acknowledged. If not
+    /// set, the pipeline always starts from the configured intersect.
+    pub cursor_path: Option<String>,
+
+    pub ack_timeout_secs: Option<u64>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        _chain: &crosscut::ChainWellKnownInfo,
+        _intersect: &crosscut::IntersectConfig,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            input: Default::default(),
+        }
+    }
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    input: InputPort,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn build_cursor(&self) -> Cursor {
+        Cursor {
+            config: self.config.clone(),
+        }
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker::new(self.config, self.input);
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                bootstrap_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
+                work_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
+                ..Default::default()
+            },
+            Some("kafka"),
+        ));
+    }
+}
+
+pub struct Cursor {
+    config: Config,
+}
+
+impl Cursor {
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        match &self.config.cursor_path {
+            Some(path) => storage::read_cursor_file(path),
+            None => Ok(None),
+        }
+    }
+}
+
+pub struct Worker {
+    config: Config,
+    producer: Option<Producer>,
+    pending: Vec<model::CRDTCommand>,
+    ops_count: gasket::metrics::Counter,
+    reconnect_count: gasket::metrics::Counter,
+    input: InputPort,
+}
+
+/// Wraps a command with the block it belongs to
+///
+/// Records don't carry headers, so the block framing travels as part of the
+/// payload. Every block ends with a `block_finished` record that consumers can
+/// use as a checkpoint.
+fn to_record_payload(point: &Point, cmd: &model::CRDTCommand) -> String {
+    json!({ "block": model::point_to_json(point), "command": cmd.to_json() }).to_string()
+}
+
+/// Key and payload of each record of the block, `block_finished` last
+fn block_records(point: &Point, pending: &[model::CRDTCommand]) -> Vec<(String, String)> {
+    let finished = model::CRDTCommand::BlockFinished(point.clone());
+
+    pending
+        .iter()
+        .chain(std::iter::once(&finished))
+        .map(|cmd| {
+            let key = cmd.key().unwrap_or_default().to_string();
+            (key, to_record_payload(point, cmd))
+        })
+        .collect()
+}
+
+/// Payload of a command that isn't part of any block
+fn marker_payload(cmd: &model::CRDTCommand) -> String {
+    json!({ "command": cmd.to_json() }).to_string()
+}
+
+impl Worker {
+    pub fn new(config: Config, input: InputPort) -> Self {
+        Self {
+            config,
+            input,
+            producer: None,
+            pending: Vec::new(),
+            ops_count: Default::default(),
+            reconnect_count: Default::default(),
+        }
+    }
+
+    fn connect(&self) -> Result<Producer, kafka::Error> {
+        let ack_timeout = Duration::from_secs(self.config.ack_timeout_secs.unwrap_or(10));
+
+        Producer::from_hosts(self.config.brokers.clone())
+            .with_ack_timeout(ack_timeout)
+            .with_required_acks(RequiredAcks::All)
+            .create()
+    }
+
+    /// Produces a command that isn't part of any block, such as rollback
+    /// markers
+    fn send_marker(&mut self, cmd: model::CRDTCommand) -> Result<(), gasket::error::Error> {
+        let payload = marker_payload(&cmd);
+        let record = Record::from_value(&self.config.topic, payload.as_bytes());
+
+        if self.producer.is_none() {
+            log::warn!("reconnecting to kafka");
+            self.producer = Some(self.connect().or_retry()?);
+            self.reconnect_count.inc(1);
+        }
+
+        if let Err(err) = self.producer.as_mut().unwrap().send(&record) {
+            log::warn!("failed to produce marker to kafka, will retry: {}", err);
+            self.producer = None;
+            return Err(err).or_retry();
+        }
+
+        Ok(())
+    }
+
+    /// Produces the buffered commands of the block and then saves the cursor
+    ///
+    /// Messages are keyed by the storage key of each command so that all the
+    /// updates to a key land in the same partition, in order. If the producer
+    /// fails, the block is retried as a whole; consumers may see duplicated
+    /// records for a block but never a moved cursor without its records.
+    fn flush_block(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        let entries = block_records(&point, &self.pending);
+
+        let records: Vec<_> = entries
+            .iter()
+            .map(|(key, payload)| {
+                Record::from_key_value(&self.config.topic, key.as_bytes(), payload.as_bytes())
+            })
+            .collect();
+
+        if self.producer.is_none() {
+            log::warn!("reconnecting to kafka");
+            self.producer = Some(self.connect().or_retry()?);
+            self.reconnect_count.inc(1);
+        }
+
+        let result = self.producer.as_mut().unwrap().send_all(&records);
+
+        let failed = match result {
+            Ok(confirms) => confirms
+                .iter()
+                .flat_map(|c| c.partition_confirms.iter())
+                .find_map(|p| p.offset.clone().err()),
+            Err(err) => {
+                log::warn!("failed to produce block to kafka, will retry: {}", err);
+                self.producer = None;
+                return Err(err).or_retry();
+            }
+        };
+
+        if let Some(code) = failed {
+            log::warn!("kafka rejected block records, will retry: {:?}", code);
+            self.producer = None;
+            return Err(crate::Error::storage(format!("{:?}", code))).or_retry();
+        }
+
+        if let Some(path) = &self.config.cursor_path {
+            storage::write_cursor_file(path, crosscut::PointArg::from(point)).or_retry()?;
+        }
+
+        self.ops_count.inc(self.pending.len() as u64);
+        self.pending.clear();
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .with_counter("storage_reconnects", &self.reconnect_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(_) => {
+                self.pending.clear();
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                // same as the redis storage, the input is only committed once
+                // the block is acknowledged
+                self.flush_block(point)?;
+            }
+            cmd @ model::CRDTCommand::RollbackMarker(_) => {
+                self.send_marker(cmd)?;
+            }
+            cmd => {
+                self.pending.push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        self.producer = self.connect().or_retry()?.into();
+
+        Ok(())
+    }
+
+    fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+    use serde_json::json;
+
+    use super::{block_records, marker_payload};
+    use crate::model::CRDTCommand;
+
+    #[test]
+    fn records_are_keyed_by_storage_key_and_framed_by_block() {
+        let point = Point::Specific(42, vec![0xab; 32]);
+
+        let pending = vec![
+            CRDTCommand::PNCounter("c1.addr1".into(), 5),
+            CRDTCommand::SetAdd("c2.addr1".into(), "tx#0".into()),
+        ];
+
+        let records: Vec<_> = block_records(&point, &pending)
+            .into_iter()
+            .map(|(key, payload)| (key, serde_json::from_str(&payload).unwrap()))
+            .collect::<Vec<(String, serde_json::Value)>>();
+
+        let block = json!({ "slot": 42, "hash": hex::encode([0xab; 32]) });
+
+        assert_eq!(
+            records,
+            vec![
+                (
+                    "c1.addr1".to_string(),
+                    json!({
+                        "block": block,
+                        "command": { "type": "pn_counter", "key": "c1.addr1", "delta": 5 },
+                    })
+                ),
+                (
+                    "c2.addr1".to_string(),
+                    json!({
+                        "block": block,
+                        "command": { "type": "set_add", "key": "c2.addr1", "member": "tx#0" },
+                    })
+                ),
+                (
+                    "".to_string(),
+                    json!({
+                        "block": block,
+                        "command": { "type": "block_finished", "point": block },
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn markers_carry_no_block() {
+        let payload = marker_payload(&CRDTCommand::RollbackMarker(Point::Origin));
+        let payload: serde_json::Value = serde_json::from_str(&payload).unwrap();
+
+        assert_eq!(
+            payload,
+            json!({ "command": { "type": "rollback", "point": "origin" } })
+        );
+    }
+}
@@ -0,0 +1,178 @@
+use std::time::Duration;
+
+use gasket::{
+    messaging::{connect_ports, OutputPort},
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use serde::Deserialize;
+
+use crate::{bootstrap, crosscut, model};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// Storage backends receiving the command stream. The cursor is read from
+    /// the first one.
+    pub sinks: Vec<super::Config>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        intersect: &crosscut::IntersectConfig,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> Result<Bootstrapper, crate::Error> {
+        if self.sinks.is_empty() {
+            return Err(crate::Error::config("mirror storage requires at least one sink"));
+        }
+
+        let sinks = self
+            .sinks
+            .into_iter()
+            .map(|c| c.plugin(chain, intersect, policy))
+            .collect::<Result<_, _>>()?;
+
+        Ok(Bootstrapper {
+            input: Default::default(),
+            sinks,
+        })
+    }
+}
+
+pub struct Bootstrapper {
+    input: InputPort,
+    sinks: Vec<super::Bootstrapper>,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn build_cursor(&mut self) -> super::Cursor {
+        self.sinks[0].build_cursor()
+    }
+
+    pub fn spawn_stages(mut self, pipeline: &mut bootstrap::Pipeline) {
+        let mut outputs = Vec::with_capacity(self.sinks.len());
+
+        for sink in self.sinks.iter_mut() {
+            let mut output = OutputPort::default();
+            connect_ports(&mut output, sink.borrow_input_port(), 100);
+            outputs.push(output);
+        }
+
+        for sink in self.sinks {
+            sink.spawn_stages(pipeline);
+        }
+
+        let worker = Worker::new(self.input, outputs);
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            Some("mirror"),
+        ));
+    }
+}
+
+/// Fans out each command to every sink
+///
+/// Sends block once a sink's port is full, so the pipeline moves at the pace
+/// of the slowest sink.
+pub struct Worker {
+    input: InputPort,
+    outputs: Vec<OutputPort<model::CRDTCommand>>,
+    ops_count: gasket::metrics::Counter,
+}
+
+impl Worker {
+    pub fn new(input: InputPort, outputs: Vec<OutputPort<model::CRDTCommand>>) -> Self {
+        Self {
+            input,
+            outputs,
+            ops_count: Default::default(),
+        }
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("mirror_ops", &self.ops_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        for output in self.outputs.iter_mut() {
+            output.send(gasket::messaging::Message::from(msg.payload.clone()))?;
+        }
+
+        self.ops_count.inc(1);
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::{
+        messaging::{connect_ports, Message, OutputPort},
+        runtime::Worker as _,
+    };
+    use pallas::network::miniprotocols::Point;
+
+    use super::{InputPort, Worker};
+    use crate::model::CRDTCommand;
+
+    fn drain(port: &mut InputPort, count: usize) -> Vec<String> {
+        (0..count)
+            .map(|_| {
+                let msg = port.recv_or_idle().unwrap();
+                port.commit();
+                format!("{:?}", msg.payload)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn sinks_receive_identical_streams() {
+        let mut upstream = OutputPort::default();
+        let mut input = InputPort::default();
+        connect_ports(&mut upstream, &mut input, 10);
+
+        let mut outputs = vec![OutputPort::default(), OutputPort::default()];
+        let mut sink_a = InputPort::default();
+        let mut sink_b = InputPort::default();
+        connect_ports(&mut outputs[0], &mut sink_a, 10);
+        connect_ports(&mut outputs[1], &mut sink_b, 10);
+
+        let mut worker = Worker::new(input, outputs);
+
+        let commands = vec![
+            CRDTCommand::BlockStarting(Point::Specific(1, vec![1u8; 32])),
+            CRDTCommand::SetAdd("a".into(), "b".into()),
+            CRDTCommand::PNCounter("c".into(), 5),
+            CRDTCommand::BlockFinished(Point::Specific(1, vec![1u8; 32])),
+        ];
+
+        for cmd in commands.iter().cloned() {
+            upstream.send(Message::from(cmd)).unwrap();
+            worker.work().unwrap();
+        }
+
+        let expected: Vec<_> = commands.iter().map(|x| format!("{:?}", x)).collect();
+
+        assert_eq!(drain(&mut sink_a, commands.len()), expected);
+        assert_eq!(drain(&mut sink_b, commands.len()), expected);
+    }
+}
@@ -1,10 +1,25 @@
+pub mod dedup;
+pub mod file;
+pub mod mirror;
+pub mod queue;
 pub mod redis;
 pub mod skip;
 
 #[cfg(feature = "elastic")]
 pub mod elastic;
 
+#[cfg(feature = "kafka")]
+pub mod kafka;
+
+#[cfg(feature = "webhook")]
+pub mod webhook;
+
+use std::io::{Read, Write};
+use std::str::FromStr;
+
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
 use gasket::messaging::TwoPhaseInputPort;
+use pallas::network::miniprotocols::Point;
 use serde::Deserialize;
 
 use crate::{
@@ -18,9 +33,18 @@ use crate::{
 pub enum Config {
     Skip(skip::Config),
     Redis(redis::Config),
+    Mirror(mirror::Config),
+    File(file::Config),
+    Queue(queue::Config),
 
     #[cfg(feature = "elastic")]
     Elastic(elastic::Config),
+
+    #[cfg(feature = "kafka")]
+    Kafka(kafka::Config),
+
+    #[cfg(feature = "webhook")]
+    Webhook(webhook::Config),
 }
 
 impl Config {
@@ -29,13 +53,45 @@ impl Config {
         chain: &crosscut::ChainWellKnownInfo,
         intersect: &crosscut::IntersectConfig,
         policy: &crosscut::policies::RuntimePolicy,
-    ) -> Bootstrapper {
-        match self {
+    ) -> Result<Bootstrapper, crate::Error> {
+        let bootstrapper = match self {
             Config::Skip(c) => Bootstrapper::Skip(c.bootstrapper()),
             Config::Redis(c) => Bootstrapper::Redis(c.bootstrapper(chain, intersect)),
+            Config::Mirror(c) => Bootstrapper::Mirror(c.bootstrapper(chain, intersect, policy)?),
+            Config::File(c) => Bootstrapper::File(c.bootstrapper(chain, intersect)),
+            Config::Queue(c) => Bootstrapper::Queue(c.bootstrapper(chain, intersect, policy)?),
 
             #[cfg(feature = "elastic")]
             Config::Elastic(c) => Bootstrapper::Elastic(c.bootstrapper(chain, intersect, policy)),
+
+            #[cfg(feature = "kafka")]
+            Config::Kafka(c) => Bootstrapper::Kafka(c.bootstrapper(chain, intersect)),
+
+            #[cfg(feature = "webhook")]
+            Config::Webhook(c) => Bootstrapper::Webhook(c.bootstrapper(chain, intersect)),
+        };
+
+        Ok(bootstrapper)
+    }
+
+    /// True if the cursor of the storage tells which blocks it applied,
+    /// false for the ones that never keep one
+    pub fn keeps_cursor(&self) -> bool {
+        match self {
+            Config::Skip(_) => true,
+            Config::Redis(_) => true,
+            Config::Mirror(c) => c.sinks.first().map_or(false, |x| x.keeps_cursor()),
+            Config::File(_) => true,
+            Config::Queue(_) => true,
+
+            #[cfg(feature = "elastic")]
+            Config::Elastic(_) => false,
+
+            #[cfg(feature = "kafka")]
+            Config::Kafka(c) => c.cursor_path.is_some(),
+
+            #[cfg(feature = "webhook")]
+            Config::Webhook(c) => c.cursor_path.is_some(),
         }
     }
 }
@@ -43,9 +99,18 @@ impl Config {
 pub enum Bootstrapper {
     Redis(redis::Bootstrapper),
     Skip(skip::Bootstrapper),
+    Mirror(mirror::Bootstrapper),
+    File(file::Bootstrapper),
+    Queue(queue::Bootstrapper),
 
     #[cfg(feature = "elastic")]
     Elastic(elastic::Bootstrapper),
+
+    #[cfg(feature = "kafka")]
+    Kafka(kafka::Bootstrapper),
+
+    #[cfg(feature = "webhook")]
+    Webhook(webhook::Bootstrapper),
 }
 
 impl Bootstrapper {
@@ -53,9 +118,27 @@ impl Bootstrapper {
         match self {
             Bootstrapper::Skip(x) => x.borrow_input_port(),
             Bootstrapper::Redis(x) => x.borrow_input_port(),
+            Bootstrapper::Mirror(x) => x.borrow_input_port(),
+            Bootstrapper::File(x) => x.borrow_input_port(),
+            Bootstrapper::Queue(x) => x.borrow_input_port(),
 
             #[cfg(feature = "elastic")]
             Bootstrapper::Elastic(x) => x.borrow_input_port(),
+
+            #[cfg(feature = "kafka")]
+            Bootstrapper::Kafka(x) => x.borrow_input_port(),
+
+            #[cfg(feature = "webhook")]
+            Bootstrapper::Webhook(x) => x.borrow_input_port(),
+        }
+    }
+
+    /// Keeps the last `window` committed points next to the cursor, so that
+    /// the reducers can tell the blocks already in the store after a restart.
+    /// Only the redis storage keeps them.
+    pub fn record_recent_points(&mut self, window: Option<usize>) {
+        if let Bootstrapper::Redis(x) = self {
+            x.record_recent_points(window);
         }
     }
 
@@ -63,9 +146,18 @@ impl Bootstrapper {
         match self {
             Bootstrapper::Skip(x) => Cursor::Skip(x.build_cursor()),
             Bootstrapper::Redis(x) => Cursor::Redis(x.build_cursor()),
+            Bootstrapper::Mirror(x) => x.build_cursor(),
+            Bootstrapper::File(x) => Cursor::File(x.build_cursor()),
+            Bootstrapper::Queue(x) => x.build_cursor(),
 
             #[cfg(feature = "elastic")]
             Bootstrapper::Elastic(x) => Cursor::Elastic(x.build_cursor()),
+
+            #[cfg(feature = "kafka")]
+            Bootstrapper::Kafka(x) => Cursor::Kafka(x.build_cursor()),
+
+            #[cfg(feature = "webhook")]
+            Bootstrapper::Webhook(x) => Cursor::Webhook(x.build_cursor()),
         }
     }
 
@@ -73,19 +165,198 @@ impl Bootstrapper {
         match self {
             Bootstrapper::Skip(x) => x.spawn_stages(pipeline),
             Bootstrapper::Redis(x) => x.spawn_stages(pipeline),
+            Bootstrapper::Mirror(x) => x.spawn_stages(pipeline),
+            Bootstrapper::File(x) => x.spawn_stages(pipeline),
+            Bootstrapper::Queue(x) => x.spawn_stages(pipeline),
 
             #[cfg(feature = "elastic")]
             Bootstrapper::Elastic(x) => x.spawn_stages(pipeline),
+
+            #[cfg(feature = "kafka")]
+            Bootstrapper::Kafka(x) => x.spawn_stages(pipeline),
+
+            #[cfg(feature = "webhook")]
+            Bootstrapper::Webhook(x) => x.spawn_stages(pipeline),
+        }
+    }
+}
+
+/// Action to take when a value exceeds the configured max size
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum OversizePolicy {
+    /// cut the value down to the limit and append a truncation marker
+    Truncate,
+    /// don't write the value at all
+    Skip,
+}
+
+impl Default for OversizePolicy {
+    fn default() -> Self {
+        OversizePolicy::Skip
+    }
+}
+
+pub const TRUNCATION_MARKER: &str = "...[truncated]";
+
+/// Approximate size in bytes of a command as sent to the store: its keys,
+/// members and value, numbers counting as 8 bytes
+pub fn command_size(cmd: &model::CRDTCommand) -> usize {
+    match cmd {
+        model::CRDTCommand::BlockStarting(_) => 0,
+        model::CRDTCommand::SetAdd(key, member)
+        | model::CRDTCommand::SetRemove(key, member)
+        | model::CRDTCommand::TwoPhaseSetAdd(key, member)
+        | model::CRDTCommand::TwoPhaseSetRemove(key, member)
+        | model::CRDTCommand::GrowOnlySetAdd(key, member)
+        | model::CRDTCommand::HashUnsetKey(key, member) => key.len() + member.len(),
+        model::CRDTCommand::SortedSetAdd(key, member, _)
+        | model::CRDTCommand::SortedSetRemove(key, member, _)
+        | model::CRDTCommand::HashCounter(key, member, _) => key.len() + member.len() + 8,
+        model::CRDTCommand::LastWriteWins(key, value, _) => key.len() + value_size(value) + 8,
+        model::CRDTCommand::AnyWriteWins(key, value)
+        | model::CRDTCommand::ListPrepend(key, value, _) => key.len() + value_size(value),
+        model::CRDTCommand::ListPop(key) | model::CRDTCommand::DeleteKey(key) => key.len(),
+        model::CRDTCommand::SortedSetTrim(key, _) => key.len() + 8,
+        model::CRDTCommand::PNCounter(key, _)
+        | model::CRDTCommand::MaxWins(key, _)
+        | model::CRDTCommand::MinWins(key, _) => key.len() + 8,
+        model::CRDTCommand::HashSetValue(key, member, value) => {
+            key.len() + member.len() + value_size(value)
+        }
+        model::CRDTCommand::BlockFinished(_) => 0,
+        model::CRDTCommand::RollbackMarker(_) => 0,
+    }
+}
+
+/// Size in bytes of a value as it would be written to the store
+pub fn value_size(value: &model::Value) -> usize {
+    match value {
+        model::Value::String(x) => x.len(),
+        model::Value::BigInt(x) => x.to_string().len(),
+        model::Value::Cbor(x) => x.len(),
+        model::Value::Json(x) => x.to_string().len(),
+    }
+}
+
+/// Truncation marker cut down to the limit, for limits too small to hold it
+fn truncation_marker(max_bytes: usize) -> &'static str {
+    &TRUNCATION_MARKER[..TRUNCATION_MARKER.len().min(max_bytes)]
+}
+
+fn truncate_str(value: &str, max_bytes: usize) -> String {
+    let marker = truncation_marker(max_bytes);
+    let mut end = (max_bytes - marker.len()).min(value.len());
+
+    while !value.is_char_boundary(end) {
+        end -= 1;
+    }
+
+    format!("{}{}", &value[..end], marker)
+}
+
+/// Cuts a value down to `max_bytes`, including the truncation marker
+///
+/// Json values are turned into plain strings since a truncated document isn't
+/// valid json anymore.
+pub fn truncate_value(value: model::Value, max_bytes: usize) -> model::Value {
+    match value {
+        model::Value::String(x) => model::Value::String(truncate_str(&x, max_bytes)),
+        model::Value::Json(x) => model::Value::String(truncate_str(&x.to_string(), max_bytes)),
+        model::Value::Cbor(mut x) => {
+            let marker = truncation_marker(max_bytes);
+            x.truncate(max_bytes - marker.len());
+            x.extend_from_slice(marker.as_bytes());
+            model::Value::Cbor(x)
+        }
+        model::Value::BigInt(x) => model::Value::BigInt(x),
+    }
+}
+
+/// First byte of a compressed value
+///
+/// It can't start a utf-8 string nor a cbor item, so compressed values can
+/// be told apart from the plain ones written before compression was enabled.
+pub const COMPRESSED_HEADER: u8 = 0xff;
+
+fn value_bytes(value: &model::Value) -> Vec<u8> {
+    match value {
+        model::Value::String(x) => x.as_bytes().to_vec(),
+        model::Value::Json(x) => x.to_string().into_bytes(),
+        model::Value::Cbor(x) => x.clone(),
+        model::Value::BigInt(x) => x.to_string().into_bytes(),
+    }
+}
+
+/// Deflates values larger than `min_bytes`, prefixed by `COMPRESSED_HEADER`
+///
+/// Integers and values that don't shrink are left as they are. Compressed
+/// values have to be read through `read_value`.
+pub fn compress_value(value: model::Value, min_bytes: usize) -> model::Value {
+    if matches!(value, model::Value::BigInt(_)) || value_size(&value) <= min_bytes {
+        return value;
+    }
+
+    let raw = value_bytes(&value);
+
+    let mut encoder = DeflateEncoder::new(vec![COMPRESSED_HEADER], Compression::default());
+
+    let compressed = encoder.write_all(&raw).and_then(|_| encoder.finish()).ok();
+
+    match compressed {
+        Some(x) if x.len() < raw.len() => model::Value::Cbor(x),
+        _ => value,
+    }
+}
+
+/// Reads a value as written by a storage, inflating it when compressed
+pub fn read_value(raw: &[u8]) -> Result<Vec<u8>, crate::Error> {
+    match raw.split_first() {
+        Some((&COMPRESSED_HEADER, compressed)) => {
+            let mut out = Vec::new();
+
+            DeflateDecoder::new(compressed)
+                .read_to_end(&mut out)
+                .map_err(crate::Error::storage)?;
+
+            Ok(out)
         }
+        _ => Ok(raw.to_vec()),
     }
 }
 
+/// Reads a cursor persisted by `write_cursor_file`, if any
+pub(crate) fn read_cursor_file(path: &str) -> Result<Option<PointArg>, crate::Error> {
+    match std::fs::read_to_string(path) {
+        Ok(x) => Ok(Some(PointArg::from_str(x.trim())?)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(crate::Error::storage(err)),
+    }
+}
+
+/// Persists a cursor to a local file
+///
+/// The value is written to a sibling temp file and renamed so that a crash
+/// never leaves a partially written cursor behind.
+pub(crate) fn write_cursor_file(path: &str, point: PointArg) -> Result<(), crate::Error> {
+    let tmp = format!("{}.tmp", path);
+    std::fs::write(&tmp, point.to_string()).map_err(crate::Error::storage)?;
+    std::fs::rename(&tmp, path).map_err(crate::Error::storage)
+}
+
 pub enum Cursor {
     Skip(skip::Cursor),
     Redis(redis::Cursor),
+    File(file::Cursor),
+    Queue(queue::Cursor),
 
     #[cfg(feature = "elastic")]
     Elastic(elastic::Cursor),
+
+    #[cfg(feature = "kafka")]
+    Kafka(kafka::Cursor),
+
+    #[cfg(feature = "webhook")]
+    Webhook(webhook::Cursor),
 }
 
 impl Cursor {
@@ -93,9 +364,100 @@ impl Cursor {
         match self {
             Cursor::Skip(x) => x.last_point(),
             Cursor::Redis(x) => x.last_point(),
+            Cursor::File(x) => x.last_point(),
+            Cursor::Queue(x) => x.last_point(),
 
             #[cfg(feature = "elastic")]
             Cursor::Elastic(x) => x.last_point(),
+
+            #[cfg(feature = "kafka")]
+            Cursor::Kafka(x) => x.last_point(),
+
+            #[cfg(feature = "webhook")]
+            Cursor::Webhook(x) => x.last_point(),
+        }
+    }
+
+    /// Last committed points, oldest first, see
+    /// `Bootstrapper::record_recent_points`. Empty for the storages that
+    /// don't keep them.
+    pub fn recent_points(&mut self) -> Result<Vec<Point>, crate::Error> {
+        match self {
+            Cursor::Redis(x) => x.recent_points(),
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        compress_value, read_value, truncate_value, value_size, COMPRESSED_HEADER,
+        TRUNCATION_MARKER,
+    };
+    use crate::model::Value;
+
+    #[test]
+    fn oversized_string_is_truncated() {
+        let value = Value::String("a".repeat(1000));
+        assert_eq!(value_size(&value), 1000);
+
+        let truncated = truncate_value(value, 100);
+        assert_eq!(value_size(&truncated), 100);
+
+        match truncated {
+            Value::String(x) => assert!(x.ends_with(TRUNCATION_MARKER)),
+            _ => panic!("expected a string value"),
+        }
+    }
+
+    #[test]
+    fn truncation_respects_char_boundaries() {
+        let value = Value::String("ñ".repeat(100));
+        let truncated = truncate_value(value, 51);
+
+        assert!(value_size(&truncated) <= 51);
+    }
+
+    #[test]
+    fn oversized_cbor_is_truncated() {
+        let value = Value::Cbor(vec![0u8; 500]);
+        let truncated = truncate_value(value, 64);
+
+        assert_eq!(value_size(&truncated), 64);
+    }
+
+    #[test]
+    fn limits_below_the_marker_are_honored() {
+        for max in [0, 1, TRUNCATION_MARKER.len() - 1, TRUNCATION_MARKER.len()] {
+            let truncated = truncate_value(Value::String("ñ".repeat(100)), max);
+            assert_eq!(value_size(&truncated), max);
+
+            let truncated = truncate_value(Value::Cbor(vec![0u8; 500]), max);
+            assert_eq!(value_size(&truncated), max);
+        }
+    }
+
+    #[test]
+    fn compressed_values_round_trip() {
+        let json =
+            r#"{"name":"SpaceBud","description":"a very repetitive description"}"#.repeat(20);
+
+        let compressed = compress_value(Value::String(json.clone()), 256);
+
+        match &compressed {
+            Value::Cbor(x) => {
+                assert_eq!(x[0], COMPRESSED_HEADER);
+                assert!(x.len() < json.len());
+                assert_eq!(read_value(x).unwrap(), json.as_bytes());
+            }
+            _ => panic!("expected a compressed value"),
+        }
+
+        // small values are kept as they are, and read as they are
+        match compress_value(Value::String("small".into()), 256) {
+            Value::String(x) => assert_eq!(read_value(x.as_bytes()).unwrap(), b"small"),
+            _ => panic!("expected the value to be left alone"),
         }
     }
 }
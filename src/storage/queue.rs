@@ -0,0 +1,578 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use gasket::{
+    error::AsWorkError,
+    messaging::{connect_ports, OutputPort},
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use pallas::network::miniprotocols::Point;
+use serde::{Deserialize, Serialize};
+use sled::transaction::{ConflictableTransactionError, TransactionResult, Transactional};
+
+use crate::{bootstrap, crosscut, model};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+const BLOCKS_TREE: &str = "blocks";
+const META_TREE: &str = "meta";
+const CURSOR_KEY: &str = "cursor";
+const NEXT_SEQ_KEY: &str = "next_seq";
+
+const DEFAULT_MAX_BLOCKS: usize = 100_000;
+
+#[derive(Deserialize)]
+pub struct Config {
+    /// Directory of the sled db holding the queued blocks
+    pub path: String,
+
+    /// Max number of blocks waiting to be drained. Once reached, the pipeline
+    /// stops pulling from the chain until the sink catches up.
+    pub max_blocks: Option<usize>,
+
+    /// Storage the queued blocks are drained into
+    pub sink: Box<super::Config>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        chain: &crosscut::ChainWellKnownInfo,
+        intersect: &crosscut::IntersectConfig,
+        policy: &crosscut::policies::RuntimePolicy,
+    ) -> Result<Bootstrapper, crate::Error> {
+        // entries are dropped once the cursor of the sink is past them
+        if !self.sink.keeps_cursor() {
+            return Err(crate::Error::config(
+                "the queue sink has to keep a cursor (eg: set its cursor_path), otherwise the queued blocks are never dropped",
+            ));
+        }
+
+        let db = sled::open(&self.path).map_err(crate::Error::storage)?;
+        let blocks = db.open_tree(BLOCKS_TREE).map_err(crate::Error::storage)?;
+        let meta = db.open_tree(META_TREE).map_err(crate::Error::storage)?;
+
+        let sink = self.sink.plugin(chain, intersect, policy)?;
+
+        Ok(Bootstrapper {
+            max_blocks: self.max_blocks.unwrap_or(DEFAULT_MAX_BLOCKS),
+            _db: db,
+            blocks,
+            meta,
+            input: Default::default(),
+            sink: Box::new(sink),
+        })
+    }
+}
+
+pub struct Bootstrapper {
+    max_blocks: usize,
+    _db: sled::Db,
+    blocks: sled::Tree,
+    meta: sled::Tree,
+    input: InputPort,
+    sink: Box<super::Bootstrapper>,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn build_cursor(&mut self) -> super::Cursor {
+        super::Cursor::Queue(Cursor {
+            meta: self.meta.clone(),
+            sink: Box::new(self.sink.build_cursor()),
+        })
+    }
+
+    pub fn spawn_stages(mut self, pipeline: &mut bootstrap::Pipeline) {
+        let mut output = OutputPort::default();
+        connect_ports(&mut output, self.sink.borrow_input_port(), 100);
+
+        let reader = Reader {
+            blocks: self.blocks.clone(),
+            meta: self.meta.clone(),
+            cursor: self.sink.build_cursor(),
+            next_seq: 0,
+            applied_slot: None,
+            output,
+            drained_count: Default::default(),
+        };
+
+        self.sink.spawn_stages(pipeline);
+
+        let writer = Writer {
+            blocks: self.blocks,
+            meta: self.meta,
+            max_blocks: self.max_blocks,
+            input: self.input,
+            pending: Vec::new(),
+            next_seq: 0,
+            depth: Default::default(),
+            enqueued_count: Default::default(),
+            full_count: Default::default(),
+        };
+
+        pipeline.register_stage(spawn_stage(
+            writer,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            Some("queue-writer"),
+        ));
+
+        pipeline.register_stage(spawn_stage(
+            reader,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                ..Default::default()
+            },
+            Some("queue-reader"),
+        ));
+    }
+}
+
+/// Chain cursor of the pipeline when the queue is enabled
+///
+/// The chain is consumed up to the last enqueued block, regardless of what
+/// the sink already applied. Falls back to the cursor of the sink when
+/// nothing was ever enqueued.
+pub struct Cursor {
+    meta: sled::Tree,
+    sink: Box<super::Cursor>,
+}
+
+impl Cursor {
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        match self.meta.get(CURSOR_KEY).map_err(crate::Error::storage)? {
+            Some(raw) => {
+                let raw = String::from_utf8_lossy(&raw);
+                crosscut::PointArg::from_str(&raw).map(Some)
+            }
+            None => self.sink.last_point(),
+        }
+    }
+}
+
+/// A block (or a command outside of any block) as stored in the queue
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// Slot of the block, none for entries such as rollback markers
+    slot: Option<u64>,
+    commands: Vec<model::CRDTCommand>,
+}
+
+fn seq_key(seq: u64) -> [u8; 8] {
+    seq.to_be_bytes()
+}
+
+fn key_seq(key: &[u8]) -> u64 {
+    let mut seq = [0u8; 8];
+    seq.copy_from_slice(&key[..8]);
+    u64::from_be_bytes(seq)
+}
+
+fn read_next_seq(meta: &sled::Tree) -> Result<u64, crate::Error> {
+    match meta.get(NEXT_SEQ_KEY).map_err(crate::Error::storage)? {
+        Some(raw) => Ok(key_seq(&raw)),
+        None => Ok(0),
+    }
+}
+
+/// Stores each block received from the reducers as a single queue entry
+pub struct Writer {
+    blocks: sled::Tree,
+    meta: sled::Tree,
+    max_blocks: usize,
+    input: InputPort,
+    pending: Vec<model::CRDTCommand>,
+    next_seq: u64,
+    depth: gasket::metrics::Gauge,
+    enqueued_count: gasket::metrics::Counter,
+    full_count: gasket::metrics::Counter,
+}
+
+impl Writer {
+    fn depth(&self) -> Result<u64, crate::Error> {
+        let first = self.blocks.first().map_err(crate::Error::storage)?;
+
+        match first {
+            Some((key, _)) => Ok(self.next_seq - key_seq(&key)),
+            None => Ok(0),
+        }
+    }
+
+    /// Appends the pending commands as a new entry
+    ///
+    /// The entry, the next sequence and the chain cursor are written in a
+    /// single transaction, the cursor never points past what was enqueued.
+    fn enqueue(&mut self, block: Option<&Point>) -> Result<(), crate::Error> {
+        let entry = Entry {
+            slot: match block {
+                Some(Point::Specific(slot, _)) => Some(*slot),
+                _ => None,
+            },
+            commands: std::mem::take(&mut self.pending),
+        };
+
+        let value = serde_json::to_vec(&entry).map_err(crate::Error::storage)?;
+        let cursor = block.map(|x| crosscut::PointArg::from(x.clone()).to_string());
+        let seq = self.next_seq;
+
+        let result: TransactionResult<()> =
+            (&self.blocks, &self.meta).transaction(|(blocks, meta)| {
+                blocks.insert(&seq_key(seq)[..], value.as_slice())?;
+                meta.insert(NEXT_SEQ_KEY, &seq_key(seq + 1)[..])?;
+
+                if let Some(cursor) = &cursor {
+                    meta.insert(CURSOR_KEY, cursor.as_bytes())?;
+                }
+
+                Ok::<_, ConflictableTransactionError<()>>(())
+            });
+
+        result.map_err(|err| crate::Error::storage(format!("{:?}", err)))?;
+
+        self.next_seq += 1;
+        self.enqueued_count.inc(1);
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Writer {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_gauge("queue_depth", &self.depth)
+            .with_counter("queue_enqueued_blocks", &self.enqueued_count)
+            .with_counter("queue_full_waits", &self.full_count)
+            .build()
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        self.next_seq = read_next_seq(&self.meta).or_panic()?;
+
+        Ok(())
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let depth = self.depth().or_restart()?;
+        self.depth.set(depth as i64);
+
+        // backpressure: stop pulling from the reducers until the sink drains
+        // some blocks, the upstream ports fill up and the source waits
+        if depth >= self.max_blocks as u64 {
+            self.full_count.inc(1);
+            std::thread::sleep(Duration::from_millis(500));
+            return Ok(WorkOutcome::Partial);
+        }
+
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(point) => {
+                self.pending.clear();
+                self.pending.push(model::CRDTCommand::BlockStarting(point));
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                self.pending
+                    .push(model::CRDTCommand::BlockFinished(point.clone()));
+                self.enqueue(Some(&point)).or_restart()?;
+            }
+            cmd @ model::CRDTCommand::RollbackMarker(_) => {
+                self.pending = vec![cmd];
+                self.enqueue(None).or_restart()?;
+            }
+            cmd => {
+                self.pending.push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+}
+
+/// Drains the queue into the sink, in order
+///
+/// Entries are kept until the cursor of the sink shows they were applied, so
+/// that a crash doesn't lose the blocks that were in-flight. Sinks without a
+/// cursor are refused when the queue is configured.
+pub struct Reader {
+    blocks: sled::Tree,
+    meta: sled::Tree,
+    cursor: super::Cursor,
+    next_seq: u64,
+    applied_slot: Option<u64>,
+    output: OutputPort<model::CRDTCommand>,
+    drained_count: gasket::metrics::Counter,
+}
+
+impl Reader {
+    fn applied_slot(&mut self) -> Result<Option<u64>, crate::Error> {
+        match self.cursor.last_point()? {
+            Some(crosscut::PointArg::Specific(slot, _)) => Ok(Some(slot)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Removes the sent entries that the sink already applied
+    fn prune(&mut self) -> Result<(), crate::Error> {
+        let applied = self.applied_slot()?;
+
+        while let Some((key, value)) = self.blocks.first().map_err(crate::Error::storage)? {
+            if key_seq(&key) >= self.next_seq {
+                break;
+            }
+
+            let entry: Entry = serde_json::from_slice(&value).map_err(crate::Error::storage)?;
+
+            // markers have no slot, they go along with the blocks before them
+            let done = match (entry.slot, applied) {
+                (None, _) => true,
+                (Some(slot), Some(applied)) => slot <= applied,
+                (Some(_), None) => false,
+            };
+
+            if !done {
+                break;
+            }
+
+            self.blocks.remove(key).map_err(crate::Error::storage)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Reader {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("queue_drained_blocks", &self.drained_count)
+            .build()
+    }
+
+    fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
+        // read before looking at the entries, an entry written in between is
+        // then still picked up as the first one
+        let next_seq = read_next_seq(&self.meta).or_panic()?;
+
+        self.next_seq = match self.blocks.first().or_restart()? {
+            Some((key, _)) => key_seq(&key),
+            None => next_seq,
+        };
+
+        // leading entries up to this slot were applied before a restart
+        self.applied_slot = self.applied_slot().or_retry()?;
+
+        Ok(())
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let value = self.blocks.get(seq_key(self.next_seq)).or_restart()?;
+
+        let value = match value {
+            Some(x) => x,
+            None => {
+                std::thread::sleep(Duration::from_millis(500));
+                return Ok(WorkOutcome::Partial);
+            }
+        };
+
+        let entry: Entry = serde_json::from_slice(&value).or_panic()?;
+
+        let applied = match (entry.slot, self.applied_slot) {
+            (Some(slot), Some(applied)) => slot <= applied,
+            _ => false,
+        };
+
+        if applied {
+            log::debug!("skipping queued block already applied by the sink");
+        } else {
+            // only the entries at the head of the queue can be already applied
+            self.applied_slot = None;
+
+            for cmd in entry.commands {
+                self.output.send(gasket::messaging::Message::from(cmd))?;
+            }
+        }
+
+        self.next_seq += 1;
+        self.drained_count.inc(1);
+
+        self.prune().or_retry()?;
+
+        Ok(WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use gasket::messaging::{connect_ports, OutputPort, TwoPhaseInputPort};
+    use gasket::runtime::Worker as _;
+    use pallas::network::miniprotocols::Point;
+
+    use super::{Entry, Reader, Writer};
+    use crate::crosscut::{ChainWellKnownInfo, IntersectConfig};
+    use crate::model::CRDTCommand;
+    use crate::storage;
+
+    fn writer(db: &sled::Db) -> Writer {
+        Writer {
+            blocks: db.open_tree("blocks").unwrap(),
+            meta: db.open_tree("meta").unwrap(),
+            max_blocks: 10,
+            input: Default::default(),
+            pending: vec![],
+            next_seq: 0,
+            depth: Default::default(),
+            enqueued_count: Default::default(),
+            full_count: Default::default(),
+        }
+    }
+
+    #[test]
+    fn blocks_are_enqueued_with_their_cursor() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+
+        let mut writer = writer(&db);
+        writer.pending = vec![CRDTCommand::PNCounter("a".into(), 1)];
+
+        writer
+            .enqueue(Some(&Point::Specific(10, vec![1u8; 32])))
+            .unwrap();
+
+        writer.pending = vec![CRDTCommand::RollbackMarker(Point::Origin)];
+        writer.enqueue(None).unwrap();
+
+        assert_eq!(writer.depth().unwrap(), 2);
+
+        let (_, value) = writer.blocks.first().unwrap().unwrap();
+        let entry: Entry = serde_json::from_slice(&value).unwrap();
+
+        assert_eq!(entry.slot, Some(10));
+        assert!(matches!(entry.commands[0], CRDTCommand::PNCounter(_, 1)));
+
+        // markers don't move the chain cursor
+        let cursor = writer.meta.get(super::CURSOR_KEY).unwrap().unwrap();
+        assert_eq!(
+            String::from_utf8(cursor.to_vec()).unwrap(),
+            format!("10,{}", hex::encode([1u8; 32]))
+        );
+    }
+
+    #[test]
+    fn queued_blocks_are_drained_in_order_and_kept_until_applied() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut writer = writer(&db);
+
+        let block = |slot: u64| {
+            let point = Point::Specific(slot, vec![slot as u8; 32]);
+
+            vec![
+                CRDTCommand::BlockStarting(point.clone()),
+                CRDTCommand::PNCounter("a".into(), slot as i64),
+                CRDTCommand::BlockFinished(point),
+            ]
+        };
+
+        let entries = vec![
+            (Some(Point::Specific(10, vec![10u8; 32])), block(10)),
+            (None, vec![CRDTCommand::RollbackMarker(Point::Origin)]),
+            (Some(Point::Specific(20, vec![20u8; 32])), block(20)),
+        ];
+
+        for (point, commands) in entries.iter() {
+            writer.pending = commands.clone();
+            writer.enqueue(point.as_ref()).unwrap();
+        }
+
+        // the sink is a file storage, its cursor is written by hand below
+        let path = std::env::temp_dir().join(format!("scrolls-queue-{}", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let cursor_path = format!("{}.cursor", path);
+        let _ = std::fs::remove_file(&cursor_path);
+
+        let sink = storage::file::Config {
+            path,
+            max_bytes: None,
+            max_age_secs: None,
+            compress: None,
+        }
+        .bootstrapper(&ChainWellKnownInfo::mainnet(), &IntersectConfig::Origin);
+
+        let mut output = OutputPort::default();
+        let mut downstream = TwoPhaseInputPort::<CRDTCommand>::default();
+        connect_ports(&mut output, &mut downstream, 20);
+
+        let mut reader = Reader {
+            blocks: writer.blocks.clone(),
+            meta: writer.meta.clone(),
+            cursor: storage::Cursor::File(sink.build_cursor()),
+            next_seq: 0,
+            applied_slot: None,
+            output,
+            drained_count: Default::default(),
+        };
+
+        reader.bootstrap().unwrap();
+
+        for _ in 0..entries.len() {
+            reader.work().unwrap();
+        }
+
+        let received: Vec<_> = std::iter::from_fn(|| downstream.recv_or_idle().ok())
+            .map(|x| format!("{:?}", x.payload))
+            .collect();
+
+        let sent: Vec<_> = entries
+            .iter()
+            .flat_map(|(_, commands)| commands.iter())
+            .map(|x| format!("{:?}", x))
+            .collect();
+
+        assert_eq!(received, sent);
+
+        // nothing applied by the sink yet, every entry is kept
+        assert_eq!(reader.blocks.len(), 3);
+
+        // the marker goes along with the block before it
+        storage::write_cursor_file(&cursor_path, Point::Specific(10, vec![10u8; 32]).into())
+            .unwrap();
+        reader.prune().unwrap();
+        assert_eq!(reader.blocks.len(), 1);
+
+        storage::write_cursor_file(&cursor_path, Point::Specific(20, vec![20u8; 32]).into())
+            .unwrap();
+        reader.prune().unwrap();
+        assert!(reader.blocks.is_empty());
+
+        std::fs::remove_file(&cursor_path).unwrap();
+    }
+
+    #[cfg(feature = "webhook")]
+    #[test]
+    fn sink_without_cursor_is_refused() {
+        let config = |sink: serde_json::Value| -> super::Config {
+            serde_json::from_value(serde_json::json!({
+                "path": "unused",
+                "sink": sink,
+            }))
+            .unwrap()
+        };
+
+        let queue = config(serde_json::json!({ "type": "Webhook", "url": "http://localhost" }));
+
+        let result = queue.bootstrapper(
+            &ChainWellKnownInfo::mainnet(),
+            &IntersectConfig::Origin,
+            &Default::default(),
+        );
+
+        assert!(result.is_err());
+    }
+}
@@ -1,17 +1,35 @@
-use std::{str::FromStr, time::Duration};
+use std::{
+    num::NonZeroUsize,
+    str::FromStr,
+    time::{Duration, Instant},
+};
 
 use gasket::{
     error::AsWorkError,
     runtime::{spawn_stage, WorkOutcome},
 };
 
+use pallas::network::miniprotocols::Point;
 use redis::{Commands, ToRedisArgs};
 use serde::Deserialize;
 
-use crate::{bootstrap, crosscut, model};
+use crate::{bootstrap, crosscut, model, storage};
 
 type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
 
+/// Sets the key to ARGV[2] if there's no current value or if the comparison
+/// requested by ARGV[1] ("max" or "min") favors the new value
+const CONDITIONAL_SET_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+local value = tonumber(ARGV[2])
+if current == false
+    or (ARGV[1] == 'max' and value > tonumber(current))
+    or (ARGV[1] == 'min' and value < tonumber(current)) then
+    redis.call('SET', KEYS[1], ARGV[2])
+end
+return 0
+"#;
+
 impl ToRedisArgs for model::Value {
     fn write_redis_args<W>(&self, out: &mut W)
     where
@@ -30,6 +48,41 @@ impl ToRedisArgs for model::Value {
 pub struct Config {
     pub connection_params: String,
     pub cursor_key: Option<String>,
+    pub max_value_bytes: Option<usize>,
+    pub oversize_policy: Option<storage::OversizePolicy>,
+
+    /// Number of keys to remember for skipping idempotent writes identical to
+    /// the last one applied to the same key. Disabled by default; must stay
+    /// disabled if other processes write to the same keys.
+    pub dedup_cache_size: Option<usize>,
+
+    /// Values larger than this many bytes are deflated before being written,
+    /// see `storage::compress_value`. Disabled by default; consumers have to
+    /// read them through `storage::read_value`. The max value size applies to
+    /// the compressed value, which the truncate policy would leave unreadable.
+    pub compress_values_above: Option<usize>,
+
+    /// Commit the cursor once every this many blocks, along with the commands
+    /// of the whole batch. Defaults to every block. A crash loses the blocks
+    /// of the uncommitted batch, which are replayed from the stored cursor.
+    pub commit_every_blocks: Option<usize>,
+
+    /// Commit the batch once this many seconds have passed since the last
+    /// commit, even if it's not full. Only checked as blocks finish.
+    pub commit_every_secs: Option<u64>,
+
+    /// Max commands per second taken from the reducers, see
+    /// `crosscut::throttle`. Unlimited by default.
+    pub max_commands_per_sec: Option<u64>,
+
+    /// Max bytes per second taken from the reducers, as estimated by
+    /// `storage::command_size`. Unlimited by default.
+    pub max_bytes_per_sec: Option<u64>,
+
+    /// Also commit an unfinished block once it buffered this many commands,
+    /// along with a sub-block cursor. Disabled by default, see
+    /// `SubBlockCursor` for when it's safe.
+    pub commit_within_block: Option<usize>,
 }
 
 impl Config {
@@ -41,17 +94,89 @@ impl Config {
         Bootstrapper {
             config: self,
             input: Default::default(),
+            recent_points: None,
         }
     }
 
     pub fn cursor_key(&self) -> &str {
         self.cursor_key.as_deref().unwrap_or("_cursor")
     }
+
+    /// Key of the sub-block cursor, next to the cursor
+    pub fn sub_block_cursor_key(&self) -> String {
+        format!("{}.partial", self.cursor_key())
+    }
+
+    /// Key of the list of the last committed points, next to the cursor
+    pub fn recent_points_key(&self) -> String {
+        format!("{}.recent", self.cursor_key())
+    }
+
+    fn connect(&self) -> Result<redis::Connection, crate::Error> {
+        redis::Client::open(self.connection_params.clone())
+            .and_then(|x| x.get_connection())
+            .map_err(crate::Error::storage)
+    }
+
+    /// Deletes every key of a collection, that is every key starting with the
+    /// prefix and its separator, returning how many were removed
+    ///
+    /// Keys are found with SCAN, so keys written by someone else while this
+    /// runs may survive.
+    pub fn clear_prefix(&self, prefix: crosscut::keys::Prefix) -> Result<usize, crate::Error> {
+        let mut connection = self.connect()?;
+
+        let pattern = prefix_pattern(prefix);
+
+        let keys: Vec<String> = connection
+            .scan_match::<_, String>(pattern)
+            .map_err(crate::Error::storage)?
+            .collect();
+
+        for chunk in keys.chunks(1000) {
+            connection
+                .del::<_, ()>(chunk)
+                .map_err(crate::Error::storage)?;
+        }
+
+        Ok(keys.len())
+    }
+
+    pub fn clear_cursor(&self) -> Result<(), crate::Error> {
+        self.connect()?
+            .del::<_, ()>(&[
+                self.cursor_key().to_string(),
+                self.sub_block_cursor_key(),
+                self.recent_points_key(),
+            ])
+            .map_err(crate::Error::storage)
+    }
+}
+
+/// SCAN pattern matching the keys under the prefix
+fn prefix_pattern(prefix: crosscut::keys::Prefix) -> String {
+    format!("{}*", escape_pattern(&prefix.key(&[""])))
+}
+
+/// Escapes the glob characters of SCAN patterns
+fn escape_pattern(raw: &str) -> String {
+    let mut escaped = String::with_capacity(raw.len());
+
+    for c in raw.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            escaped.push('\\');
+        }
+
+        escaped.push(c);
+    }
+
+    escaped
 }
 
 pub struct Bootstrapper {
     config: Config,
     input: InputPort,
+    recent_points: Option<usize>,
 }
 
 impl Bootstrapper {
@@ -59,6 +184,12 @@ impl Bootstrapper {
         &mut self.input
     }
 
+    /// Keeps the last `window` committed points in a list next to the
+    /// cursor, written in the same transaction, see `Cursor::recent_points`
+    pub fn record_recent_points(&mut self, window: Option<usize>) {
+        self.recent_points = window.filter(|x| *x > 0);
+    }
+
     pub fn build_cursor(&self) -> Cursor {
         Cursor {
             config: self.config.clone(),
@@ -66,12 +197,8 @@ impl Bootstrapper {
     }
 
     pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
-        let worker = Worker {
-            config: self.config.clone(),
-            connection: None,
-            input: self.input,
-            ops_count: Default::default(),
-        };
+        let mut worker = Worker::new(self.config.clone(), self.input);
+        worker.recent_points = self.recent_points;
 
         pipeline.register_stage(spawn_stage(
             worker,
@@ -83,6 +210,12 @@ impl Bootstrapper {
                     backoff_factor: 2,
                     max_backoff: Duration::from_secs(60),
                 },
+                work_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
                 ..Default::default()
             },
             Some("redis"),
@@ -111,83 +244,265 @@ impl Cursor {
 
         Ok(point)
     }
+
+    /// Points recorded by `Bootstrapper::record_recent_points`, oldest first.
+    /// They were all committed, up to the cursor.
+    pub fn recent_points(&mut self) -> Result<Vec<Point>, crate::Error> {
+        let mut connection = self.config.connect()?;
+
+        let raw: Vec<String> = connection
+            .lrange(self.config.recent_points_key(), 0, -1)
+            .map_err(crate::Error::storage)?;
+
+        raw.iter()
+            .rev()
+            .map(|x| crosscut::PointArg::from_str(x)?.try_into())
+            .collect()
+    }
+}
+
+/// Progress committed within a block that didn't finish yet
+///
+/// The reducers emit the commands of a block reducer by reducer rather than
+/// tx by tx, so the progress is measured in commands: `applied` counts the
+/// non-idempotent commands of the block (counters, sorted set scores) that
+/// made it to the store. When the block is replayed after a crash, those
+/// are skipped while the idempotent ones are written again, which leaves
+/// the same state as applying the block once.
+///
+/// This only holds if the replayed block yields the same non-idempotent
+/// commands in the same order, which is the case as long as the reducers
+/// and their config don't change in between. Don't enable it along with
+/// reducers that read a local db (eg: `holding_distribution`): their state
+/// already moved past the block and the replay emits different commands.
+#[derive(Debug, Clone, PartialEq)]
+struct SubBlockCursor {
+    point: Point,
+    applied: usize,
+}
+
+impl std::fmt::Display for SubBlockCursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let point = crosscut::PointArg::from(self.point.clone()).to_string();
+        write!(f, "{},{}", point, self.applied)
+    }
+}
+
+impl FromStr for SubBlockCursor {
+    type Err = crate::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || crate::Error::message(format!("invalid sub-block cursor {}", s));
+
+        let (point, applied) = s.rsplit_once(',').ok_or_else(invalid)?;
+
+        Ok(Self {
+            point: crosscut::PointArg::from_str(point)?.try_into()?,
+            applied: applied.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+/// Non-idempotent commands of the block being buffered
+struct BlockProgress {
+    point: Point,
+    counted: usize,
+    /// Commands already committed by a previous run
+    skip: usize,
+}
+
+impl BlockProgress {
+    fn new(point: Point, resume: Option<&SubBlockCursor>) -> Self {
+        let skip = match resume {
+            Some(x) if x.point == point => x.applied,
+            _ => 0,
+        };
+
+        Self {
+            point,
+            counted: 0,
+            skip,
+        }
+    }
+
+    /// True if the command has to be written, false if it was committed
+    /// before the block was replayed
+    fn admit(&mut self, cmd: &model::CRDTCommand) -> bool {
+        if cmd.is_idempotent() {
+            return true;
+        }
+
+        self.counted += 1;
+        self.counted > self.skip
+    }
+
+    fn cursor(&self) -> SubBlockCursor {
+        SubBlockCursor {
+            point: self.point.clone(),
+            applied: self.counted,
+        }
+    }
+}
+
+/// Blocks finished but not yet committed
+struct Batch {
+    /// Number of commands in `pending` that belong to finished blocks
+    len: usize,
+    blocks: usize,
+    point: Option<Point>,
+    /// Distinct points of the finished blocks, oldest first
+    points: Vec<Point>,
+    last_commit: Instant,
+}
+
+impl Default for Batch {
+    fn default() -> Self {
+        Self {
+            len: 0,
+            blocks: 0,
+            point: None,
+            points: Vec::new(),
+            last_commit: Instant::now(),
+        }
+    }
 }
 
 pub struct Worker {
     config: Config,
     connection: Option<redis::Connection>,
+    pending: Vec<model::CRDTCommand>,
+    batch: Batch,
+    block: Option<BlockProgress>,
+    resume: Option<SubBlockCursor>,
     ops_count: gasket::metrics::Counter,
+    reconnect_count: gasket::metrics::Counter,
+    oversize_skipped: gasket::metrics::Counter,
+    oversize_truncated: gasket::metrics::Counter,
+    send_time: crosscut::timing::Histogram,
+    dedup: Option<storage::dedup::WriteCache>,
+    dedup_hits: gasket::metrics::Counter,
+    dedup_misses: gasket::metrics::Counter,
+    throttle: crosscut::throttle::Throttle,
+    pending_commands: gasket::metrics::Gauge,
+    recent_points: Option<usize>,
     input: InputPort,
 }
 
-impl gasket::runtime::Worker for Worker {
-    fn metrics(&self) -> gasket::metrics::Registry {
-        gasket::metrics::Builder::new()
-            .with_counter("storage_ops", &self.ops_count)
-            .build()
+impl Worker {
+    pub fn new(config: Config, input: InputPort) -> Self {
+        let dedup = config
+            .dedup_cache_size
+            .and_then(NonZeroUsize::new)
+            .map(storage::dedup::WriteCache::new);
+
+        let throttle = crosscut::throttle::Throttle::new(
+            config.max_commands_per_sec,
+            config.max_bytes_per_sec,
+        );
+
+        Self {
+            config,
+            input,
+            connection: None,
+            pending: Vec::new(),
+            batch: Default::default(),
+            block: None,
+            resume: None,
+            ops_count: Default::default(),
+            reconnect_count: Default::default(),
+            oversize_skipped: Default::default(),
+            oversize_truncated: Default::default(),
+            send_time: Default::default(),
+            dedup,
+            dedup_hits: Default::default(),
+            dedup_misses: Default::default(),
+            throttle,
+            pending_commands: Default::default(),
+            recent_points: None,
+        }
     }
 
-    fn work(&mut self) -> gasket::runtime::WorkResult {
-        let msg = self.input.recv_or_idle()?;
+    fn connect(&self) -> Result<redis::Connection, redis::RedisError> {
+        redis::Client::open(self.config.connection_params.clone()).and_then(|c| c.get_connection())
+    }
+
+    /// Compresses the value if enabled, then applies the configured max value
+    /// size to the result, returning `None` if the value should not be written
+    fn guard_value_size(&self, key: &str, value: model::Value) -> Option<model::Value> {
+        let value = match self.config.compress_values_above {
+            Some(min) => storage::compress_value(value, min),
+            None => value,
+        };
+
+        let max = match self.config.max_value_bytes {
+            Some(x) => x,
+            None => return Some(value),
+        };
+
+        let size = storage::value_size(&value);
+
+        if size <= max {
+            return Some(value);
+        }
 
-        match msg.payload {
-            model::CRDTCommand::BlockStarting(_) => {
-                // start redis transaction
-                redis::cmd("MULTI")
-                    .query(self.connection.as_mut().unwrap())
-                    .or_restart()?;
+        match self.config.oversize_policy.unwrap_or_default() {
+            storage::OversizePolicy::Skip => {
+                log::warn!(
+                    "skipping value for key [{}], size {} exceeds max of {} bytes",
+                    key,
+                    size,
+                    max
+                );
+
+                self.oversize_skipped.inc(1);
+                None
             }
+            storage::OversizePolicy::Truncate => {
+                log::warn!(
+                    "truncating value for key [{}], size {} exceeds max of {} bytes",
+                    key,
+                    size,
+                    max
+                );
+
+                self.oversize_truncated.inc(1);
+                Some(storage::truncate_value(value, max))
+            }
+        }
+    }
+
+    fn queue_command(&self, pipe: &mut redis::Pipeline, cmd: model::CRDTCommand) {
+        match cmd {
+            model::CRDTCommand::BlockStarting(_) => (),
             model::CRDTCommand::GrowOnlySetAdd(key, value) => {
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(key, value)
-                    .or_restart()?;
+                pipe.sadd(key, value).ignore();
             }
             model::CRDTCommand::TwoPhaseSetAdd(key, value) => {
                 log::debug!("adding to 2-phase set [{}], value [{}]", key, value);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(key, value)
-                    .or_restart()?;
+                pipe.sadd(key, value).ignore();
             }
             model::CRDTCommand::TwoPhaseSetRemove(key, value) => {
                 log::debug!("removing from 2-phase set [{}], value [{}]", key, value);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(format!("{}.ts", key), value)
-                    .or_restart()?;
+                pipe.sadd(format!("{}.ts", key), value).ignore();
             }
             model::CRDTCommand::SetAdd(key, value) => {
                 log::debug!("adding to set [{}], value [{}]", key, value);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .sadd(key, value)
-                    .or_restart()?;
+                pipe.sadd(key, value).ignore();
             }
             model::CRDTCommand::SetRemove(key, value) => {
                 log::debug!("removing from set [{}], value [{}]", key, value);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .srem(key, value)
-                    .or_restart()?;
+                pipe.srem(key, value).ignore();
             }
             model::CRDTCommand::LastWriteWins(key, value, ts) => {
                 log::debug!("last write for [{}], slot [{}]", key, ts);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zadd(key, value, ts)
-                    .or_restart()?;
+                if let Some(value) = self.guard_value_size(&key, value) {
+                    pipe.zadd(key, value, ts).ignore();
+                }
             }
             model::CRDTCommand::SortedSetAdd(key, value, delta) => {
                 log::debug!(
@@ -197,11 +512,7 @@ impl gasket::runtime::Worker for Worker {
                     delta
                 );
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zincr(key, value, delta)
-                    .or_restart()?;
+                pipe.zincr(key, value, delta).ignore();
             }
             model::CRDTCommand::SortedSetRemove(key, value, delta) => {
                 log::debug!(
@@ -211,102 +522,710 @@ impl gasket::runtime::Worker for Worker {
                     delta
                 );
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zincr(&key, value, delta)
-                    .or_restart()?;
+                pipe.zincr(&key, value, delta).ignore();
 
                 // removal of dangling scores  (aka garage collection)
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .zrembyscore(&key, 0, 0)
-                    .or_restart()?;
+                pipe.zrembyscore(&key, 0, 0).ignore();
+            }
+            model::CRDTCommand::SortedSetTrim(key, limit) => {
+                log::debug!("trimming sorted set [{}], limit [{}]", key, limit);
+
+                // ranks go by ascending score, the lowest ones are dropped
+                pipe.zremrangebyrank(key, 0, -(limit as isize) - 1).ignore();
             }
             model::CRDTCommand::AnyWriteWins(key, value) => {
                 log::debug!("overwrite [{}]", key);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .set(key, value)
-                    .or_restart()?;
+                if let Some(value) = self.guard_value_size(&key, value) {
+                    pipe.set(key, value).ignore();
+                }
             }
             model::CRDTCommand::PNCounter(key, value) => {
                 log::debug!("increasing counter [{}], by [{}]", key, value);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .incr(key, value)
-                    .or_restart()?;
+                pipe.incr(key, value).ignore();
+            }
+            model::CRDTCommand::MaxWins(key, value) => {
+                log::debug!("max wins for [{}], value [{}]", key, value);
+
+                pipe.cmd("EVAL")
+                    .arg(CONDITIONAL_SET_SCRIPT)
+                    .arg(1)
+                    .arg(key)
+                    .arg("max")
+                    .arg(value)
+                    .ignore();
+            }
+            model::CRDTCommand::MinWins(key, value) => {
+                log::debug!("min wins for [{}], value [{}]", key, value);
+
+                pipe.cmd("EVAL")
+                    .arg(CONDITIONAL_SET_SCRIPT)
+                    .arg(1)
+                    .arg(key)
+                    .arg("min")
+                    .arg(value)
+                    .ignore();
+            }
+            model::CRDTCommand::ListPrepend(key, value, limit) => {
+                log::debug!("prepending to list [{}], limit [{}]", key, limit);
+
+                if let Some(value) = self.guard_value_size(&key, value) {
+                    pipe.lpush(&key, value).ignore();
+                    pipe.ltrim(key, 0, limit as isize - 1).ignore();
+                }
+            }
+            model::CRDTCommand::ListPop(key) => {
+                log::debug!("popping from list [{}]", key);
+
+                pipe.cmd("LPOP").arg(key).ignore();
             }
             model::CRDTCommand::HashSetValue(member, key, value) => {
                 log::debug!("setting hash key {} member {}", member, key);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .hset(member, key, value)
-                    .or_restart()?;
+                if let Some(value) = self.guard_value_size(&member, value) {
+                    pipe.hset(member, key, value).ignore();
+                }
             }
             model::CRDTCommand::HashCounter(key, member, delta) => {
                 log::debug!("increasing hash key {} member {} by {}", key, member, delta);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .hincr(member, key, delta)
-                    .or_restart()?;
+                pipe.hincr(member, key, delta).ignore();
             }
             model::CRDTCommand::HashUnsetKey(key, member) => {
                 log::debug!("deleting hash key {} member {}", key, member);
 
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .hdel(member, key)
-                    .or_restart()?;
+                pipe.hdel(member, key).ignore();
+            }
+            model::CRDTCommand::DeleteKey(key) => {
+                log::debug!("deleting key [{}]", key);
+
+                pipe.del(key).ignore();
+            }
+            model::CRDTCommand::BlockFinished(_) => (),
+            model::CRDTCommand::RollbackMarker(_) => (),
+        };
+    }
+
+    /// True if the finished blocks should be committed now
+    fn batch_is_due(&self) -> bool {
+        let blocks = self.config.commit_every_blocks.unwrap_or(1).max(1);
+
+        let expired = match self.config.commit_every_secs {
+            Some(secs) => self.batch.last_commit.elapsed() >= Duration::from_secs(secs),
+            None => false,
+        };
+
+        self.batch.blocks >= blocks || expired
+    }
+
+    fn block_finished(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        self.block = None;
+        self.batch.len = self.pending.len();
+        self.batch.blocks += 1;
+        self.batch.point = Some(point.clone());
+
+        // frames held back by the reducers repeat the committed point
+        if self.batch.points.last() != Some(&point) {
+            self.batch.points.push(point.clone());
+        }
+
+        if self.batch_is_due() {
+            self.flush_block(point)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes the buffered commands of the batch together with the new cursor
+    /// in a single redis transaction.
+    ///
+    /// Since the cursor is part of the same MULTI / EXEC as the block changes,
+    /// a crash can never leave a block applied without its cursor (or vice
+    /// versa). This is what makes non-idempotent commands (counters) safe to
+    /// replay from the stored cursor on restart, whatever the batch size.
+    ///
+    /// If the write fails, the connection is dropped and the buffered commands
+    /// are kept so that the whole block can be re-applied once a new
+    /// connection is available. The cursor only moves forward once the block
+    /// has been written.
+    fn flush_block(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        // progress committed within a block that's still unfinished (on
+        // teardown) has to survive the flush
+        let partial = match (&self.block, &self.resume) {
+            (Some(block), Some(resume)) if block.point == resume.point => Some(resume.clone()),
+            _ => None,
+        };
+
+        self.flush(Some(point), partial)
+    }
+
+    /// Writes the commands buffered so far, including those of the block
+    /// that didn't finish yet, along with a sub-block cursor
+    fn flush_within_block(&mut self) -> Result<(), gasket::error::Error> {
+        let partial = match &self.block {
+            Some(x) => x.cursor(),
+            None => return Ok(()),
+        };
+
+        self.flush(self.batch.point.clone(), Some(partial))
+    }
+
+    /// Writes the buffered commands, moving the cursor if there's a finished
+    /// block and setting the sub-block cursor if the last one isn't
+    fn flush(
+        &mut self,
+        point: Option<Point>,
+        partial: Option<SubBlockCursor>,
+    ) -> Result<(), gasket::error::Error> {
+        let cursor_str = point.map(|x| crosscut::PointArg::from(x).to_string());
+
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+
+        let commands = match &self.dedup {
+            Some(cache) => cache.unwritten(&self.pending),
+            None => self.pending.iter().collect(),
+        };
+
+        let hits = (self.pending.len() - commands.len()) as u64;
+
+        for cmd in commands {
+            self.queue_command(&mut pipe, cmd.clone());
+        }
+
+        if let Some(cursor_str) = &cursor_str {
+            pipe.set(self.config.cursor_key(), cursor_str).ignore();
+        }
+
+        if let Some(window) = self.recent_points {
+            let key = self.config.recent_points_key();
+
+            for point in self.batch.points.iter() {
+                let point = crosscut::PointArg::from(point.clone()).to_string();
+                pipe.lpush(&key, point).ignore();
+            }
+
+            pipe.ltrim(key, 0, window as isize - 1).ignore();
+        }
+
+        if self.config.commit_within_block.is_some() {
+            match &partial {
+                Some(x) => pipe.set(self.config.sub_block_cursor_key(), x.to_string()),
+                None => pipe.del(self.config.sub_block_cursor_key()),
+            }
+            .ignore();
+        }
+
+        if self.connection.is_none() {
+            log::warn!("reconnecting to redis");
+            self.connection = Some(self.connect().or_retry()?);
+            self.reconnect_count.inc(1);
+        }
+
+        let start = Instant::now();
+        let result: Result<(), _> = pipe.query(self.connection.as_mut().unwrap());
+        self.send_time.observe_since(start);
+
+        if let Err(err) = result {
+            log::warn!("failed to write block to redis, will retry: {}", err);
+            self.connection = None;
+            return Err(err).or_retry();
+        }
+
+        if let Some(cursor_str) = &cursor_str {
+            log::info!(
+                "new cursor saved to redis {} {}",
+                &self.config.cursor_key(),
+                cursor_str
+            );
+        }
+
+        if let Some(partial) = &partial {
+            log::info!("sub-block cursor saved to redis {}", partial);
+        }
+
+        // the cache can only learn about writes once they are in the store
+        if let Some(cache) = &mut self.dedup {
+            let candidates = self
+                .pending
+                .iter()
+                .filter(|cmd| cmd.is_idempotent() && cmd.key().is_some())
+                .count();
+
+            self.pending.iter().for_each(|cmd| cache.record(cmd));
+
+            self.dedup_hits.inc(hits);
+            self.dedup_misses
+                .inc((candidates as u64).saturating_sub(hits));
+        }
+
+        self.ops_count.inc(self.pending.len() as u64);
+        self.pending.clear();
+        self.batch = Default::default();
+        self.resume = partial;
+
+        Ok(())
+    }
+
+    /// Buffers a command, committing the batch when a block finishing makes
+    /// it due
+    fn apply(&mut self, cmd: model::CRDTCommand) -> Result<(), gasket::error::Error> {
+        match cmd {
+            model::CRDTCommand::BlockStarting(point) => {
+                // drop leftovers of an unfinished block, keeping the batch
+                self.pending.truncate(self.batch.len);
+                self.block = Some(BlockProgress::new(point, self.resume.as_ref()));
             }
             model::CRDTCommand::BlockFinished(point) => {
-                let cursor_str = crosscut::PointArg::from(point).to_string();
-
-                self.connection
-                    .as_mut()
-                    .unwrap()
-                    .set(self.config.cursor_key(), &cursor_str)
-                    .or_restart()?;
-
-                log::info!(
-                    "new cursor saved to redis {} {}",
-                    &self.config.cursor_key(),
-                    &cursor_str
-                );
+                // the input is not committed until the batch is written, a
+                // failed flush will see this same message again on retry
+                self.block_finished(point)?;
+            }
+            cmd @ model::CRDTCommand::RollbackMarker(_) => {
+                // commands reverting rolled back blocks may look identical
+                // to writes we already skipped
+                if let Some(cache) = &mut self.dedup {
+                    cache.clear();
+                }
 
-                // end redis transaction
-                redis::cmd("EXEC")
-                    .query(self.connection.as_mut().unwrap())
-                    .or_restart()?;
+                self.pending.push(cmd);
+            }
+            cmd => {
+                // flushing before taking the command in, a failed flush will
+                // see this same message again on retry
+                if let Some(max) = self.config.commit_within_block {
+                    if self.pending.len() - self.batch.len >= max.max(1) {
+                        self.flush_within_block()?;
+                    }
+                }
+
+                if let Some(block) = &mut self.block {
+                    if !block.admit(&cmd) {
+                        return Ok(());
+                    }
+                }
+
+                self.throttle.acquire(storage::command_size(&cmd));
+                self.pending.push(cmd);
             }
         };
 
-        self.ops_count.inc(1);
+        self.pending_commands.set(self.pending.len() as i64);
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        let builder = gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .with_counter("storage_reconnects", &self.reconnect_count)
+            .with_counter("storage_oversize_skipped", &self.oversize_skipped)
+            .with_counter("storage_oversize_truncated", &self.oversize_truncated)
+            .with_counter("storage_dedup_hits", &self.dedup_hits)
+            .with_counter("storage_dedup_misses", &self.dedup_misses)
+            .with_counter("storage_throttle_wait_ms", self.throttle.wait_ms())
+            .with_gauge("storage_throttle_rate", self.throttle.rate())
+            .with_gauge("storage_pending_commands", &self.pending_commands);
+
+        self.send_time
+            .register(builder, &crate::histogram_keys!("storage_send_time"))
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        self.apply(msg.payload)?;
+
         self.input.commit();
 
         Ok(WorkOutcome::Partial)
     }
 
     fn bootstrap(&mut self) -> Result<(), gasket::error::Error> {
-        self.connection = redis::Client::open(self.config.connection_params.clone())
-            .and_then(|c| c.get_connection())
-            .or_retry()?
-            .into();
+        let mut connection = self.connect().or_retry()?;
+
+        if self.config.commit_within_block.is_some() {
+            let raw: Option<String> = connection
+                .get(self.config.sub_block_cursor_key())
+                .or_retry()?;
+
+            self.resume = raw
+                .map(|x| SubBlockCursor::from_str(&x))
+                .transpose()
+                .or_panic()?;
+        }
+
+        self.connection = Some(connection);
 
         Ok(())
     }
 
     fn teardown(&mut self) -> Result<(), gasket::error::Error> {
+        if let Some(point) = self.batch.point.take() {
+            self.pending.truncate(self.batch.len);
+
+            if self.flush_block(point).is_err() {
+                log::warn!("couldn't commit the last batch, it will be replayed on restart");
+            }
+        }
+
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use pallas::network::miniprotocols::Point;
+    use redis::Commands;
+
+    use super::{
+        escape_pattern, prefix_pattern, BlockProgress, Config, Cursor, SubBlockCursor, Worker,
+    };
+    use crate::model::CRDTCommand;
+
+    const CONNECTION: &str = "redis://127.0.0.1:6379";
+
+    fn kill_other_connections() {
+        let mut admin = redis::Client::open(CONNECTION)
+            .and_then(|c| c.get_connection())
+            .unwrap();
+
+        let _: () = redis::cmd("CLIENT")
+            .arg("KILL")
+            .arg("TYPE")
+            .arg("normal")
+            .arg("SKIPME")
+            .arg("yes")
+            .query(&mut admin)
+            .unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires a local redis instance"]
+    fn resumes_block_after_connection_loss() {
+        let config = Config {
+            connection_params: CONNECTION.into(),
+            cursor_key: Some("_test_reconnect_cursor".into()),
+            max_value_bytes: None,
+            oversize_policy: None,
+            dedup_cache_size: None,
+            compress_values_above: None,
+            commit_every_blocks: None,
+            commit_every_secs: None,
+            max_commands_per_sec: None,
+            max_bytes_per_sec: None,
+            commit_within_block: None,
+        };
+
+        let mut worker = Worker::new(config, Default::default());
+        worker.connection = worker.connect().ok();
+
+        worker.pending = vec![CRDTCommand::AnyWriteWins(
+            "_test_reconnect.a".into(),
+            "1".to_string().into(),
+        )];
+
+        worker
+            .flush_block(Point::Specific(1, vec![1u8; 32]))
+            .unwrap();
+
+        kill_other_connections();
+
+        worker.pending = vec![CRDTCommand::AnyWriteWins(
+            "_test_reconnect.b".into(),
+            "2".to_string().into(),
+        )];
+
+        let mut attempts = 0;
+        while worker
+            .flush_block(Point::Specific(2, vec![2u8; 32]))
+            .is_err()
+        {
+            attempts += 1;
+            assert!(attempts < 3, "worker didn't recover its connection");
+        }
+
+        assert!(worker.pending.is_empty());
+
+        let mut check = worker.connect().unwrap();
+        let b: String = check.get("_test_reconnect.b").unwrap();
+        let cursor: String = check.get("_test_reconnect_cursor").unwrap();
+
+        assert_eq!(b, "2");
+        assert_eq!(cursor, format!("2,{}", hex::encode([2u8; 32])));
+    }
+
+    fn feed_block(worker: &mut Worker, slot: u64) {
+        let point = Point::Specific(slot, vec![slot as u8; 32]);
+
+        worker
+            .apply(CRDTCommand::BlockStarting(point.clone()))
+            .unwrap();
+
+        worker
+            .apply(CRDTCommand::PNCounter("_test_batch.count".into(), 1))
+            .unwrap();
+
+        worker.apply(CRDTCommand::BlockFinished(point)).unwrap();
+    }
+
+    #[test]
+    #[ignore = "requires a local redis instance"]
+    fn batch_replay_after_crash_applies_blocks_once() {
+        let config = Config {
+            connection_params: CONNECTION.into(),
+            cursor_key: Some("_test_batch_cursor".into()),
+            max_value_bytes: None,
+            oversize_policy: None,
+            dedup_cache_size: None,
+            compress_values_above: None,
+            commit_every_blocks: Some(3),
+            commit_every_secs: None,
+            max_commands_per_sec: None,
+            max_bytes_per_sec: None,
+            commit_within_block: None,
+        };
+
+        let mut check = redis::Client::open(CONNECTION)
+            .and_then(|c| c.get_connection())
+            .unwrap();
+
+        let _: () = check.del("_test_batch.count").unwrap();
+
+        let mut worker = Worker::new(config.clone(), Default::default());
+        worker.connection = worker.connect().ok();
+
+        for slot in 1..=5 {
+            feed_block(&mut worker, slot);
+        }
+
+        // crash: blocks 4 and 5 never made it out of the batch
+        drop(worker);
+
+        let cursor: String = check.get("_test_batch_cursor").unwrap();
+        let count: i64 = check.get("_test_batch.count").unwrap();
+
+        assert_eq!(cursor, format!("3,{}", hex::encode([3u8; 32])));
+        assert_eq!(count, 3);
+
+        // resume from the cursor, replaying what was lost
+        let mut worker = Worker::new(config, Default::default());
+        worker.connection = worker.connect().ok();
+
+        for slot in 4..=6 {
+            feed_block(&mut worker, slot);
+        }
+
+        let cursor: String = check.get("_test_batch_cursor").unwrap();
+        let count: i64 = check.get("_test_batch.count").unwrap();
+
+        assert_eq!(cursor, format!("6,{}", hex::encode([6u8; 32])));
+        assert_eq!(count, 6);
+    }
+
+    #[test]
+    #[ignore = "requires a local redis instance"]
+    fn recent_points_follow_committed_blocks() {
+        let config = Config {
+            connection_params: CONNECTION.into(),
+            cursor_key: Some("_test_recent_cursor".into()),
+            max_value_bytes: None,
+            oversize_policy: None,
+            dedup_cache_size: None,
+            compress_values_above: None,
+            commit_every_blocks: None,
+            commit_every_secs: None,
+            max_commands_per_sec: None,
+            max_bytes_per_sec: None,
+            commit_within_block: None,
+        };
+
+        let mut check = redis::Client::open(CONNECTION)
+            .and_then(|c| c.get_connection())
+            .unwrap();
+
+        let _: () = check.del(config.recent_points_key()).unwrap();
+
+        let mut worker = Worker::new(config.clone(), Default::default());
+        worker.connection = worker.connect().ok();
+        worker.recent_points = Some(2);
+
+        for slot in 1..=3 {
+            let point = Point::Specific(slot, vec![slot as u8; 32]);
+
+            worker
+                .apply(CRDTCommand::BlockStarting(point.clone()))
+                .unwrap();
+            worker.apply(CRDTCommand::BlockFinished(point)).unwrap();
+        }
+
+        // what the reducers skip on startup: the last committed blocks,
+        // trimmed to the window
+        let mut cursor = Cursor { config };
+
+        assert_eq!(
+            cursor.recent_points().unwrap(),
+            vec![
+                Point::Specific(2, vec![2u8; 32]),
+                Point::Specific(3, vec![3u8; 32]),
+            ]
+        );
+    }
+
+    #[test]
+    fn replayed_block_resumes_after_committed_commands() {
+        let point = Point::Specific(1, vec![1u8; 32]);
+
+        let block = vec![
+            CRDTCommand::PNCounter("a".into(), 1),
+            CRDTCommand::SetAdd("s".into(), "x".into()),
+            CRDTCommand::PNCounter("a".into(), 1),
+            CRDTCommand::PNCounter("b".into(), 1),
+        ];
+
+        // crash after committing the first 3 commands of the block
+        let mut progress = BlockProgress::new(point.clone(), None);
+        let committed: Vec<_> = block[..3]
+            .iter()
+            .filter(|x| progress.admit(x))
+            .cloned()
+            .collect();
+
+        let cursor = progress.cursor().to_string();
+        let resume = cursor.parse::<SubBlockCursor>().unwrap();
+        assert_eq!(resume, progress.cursor());
+
+        // the whole block is replayed from the start
+        let mut progress = BlockProgress::new(point, Some(&resume));
+        let replayed: Vec<_> = block
+            .iter()
+            .filter(|x| progress.admit(x))
+            .cloned()
+            .collect();
+
+        let count = |key: &str| {
+            committed
+                .iter()
+                .chain(replayed.iter())
+                .filter(|x| matches!(x, CRDTCommand::PNCounter(k, _) if k == key))
+                .count()
+        };
+
+        assert_eq!(count("a"), 2);
+        assert_eq!(count("b"), 1);
+        assert!(matches!(&replayed[0], CRDTCommand::SetAdd(_, _)));
+
+        // the cursor of another block doesn't skip anything
+        let other = BlockProgress::new(Point::Specific(2, vec![2u8; 32]), Some(&resume));
+        assert_eq!(other.skip, 0);
+    }
+
+    #[test]
+    #[ignore = "requires a local redis instance"]
+    fn block_interrupted_mid_way_is_resumed_after_a_restart() {
+        use gasket::runtime::Worker as _;
+
+        let config = Config {
+            connection_params: CONNECTION.into(),
+            cursor_key: Some("_test_partial_cursor".into()),
+            max_value_bytes: None,
+            oversize_policy: None,
+            dedup_cache_size: None,
+            compress_values_above: None,
+            commit_every_blocks: None,
+            commit_every_secs: None,
+            max_commands_per_sec: None,
+            max_bytes_per_sec: None,
+            commit_within_block: Some(2),
+        };
+
+        let mut check = redis::Client::open(CONNECTION)
+            .and_then(|c| c.get_connection())
+            .unwrap();
+
+        let _: () = check
+            .del(&[
+                "_test_partial.a".to_string(),
+                "_test_partial.b".to_string(),
+                "_test_partial.s".to_string(),
+                config.cursor_key().to_string(),
+                config.sub_block_cursor_key(),
+            ])
+            .unwrap();
+
+        let point = Point::Specific(1, vec![1u8; 32]);
+
+        let block = vec![
+            CRDTCommand::PNCounter("_test_partial.a".into(), 1),
+            CRDTCommand::SetAdd("_test_partial.s".into(), "x".into()),
+            CRDTCommand::PNCounter("_test_partial.a".into(), 1),
+            CRDTCommand::PNCounter("_test_partial.b".into(), 1),
+        ];
+
+        // the third command flushes the first two, then the worker crashes
+        // before the block is finished
+        let mut worker = Worker::new(config.clone(), Default::default());
+        worker.bootstrap().unwrap();
+
+        worker
+            .apply(CRDTCommand::BlockStarting(point.clone()))
+            .unwrap();
+
+        for cmd in block[..3].iter().cloned() {
+            worker.apply(cmd).unwrap();
+        }
+
+        drop(worker);
+
+        let a: i64 = check.get("_test_partial.a").unwrap();
+        let cursor: Option<String> = check.get(config.cursor_key()).unwrap();
+        let partial: String = check.get(config.sub_block_cursor_key()).unwrap();
+
+        assert_eq!(a, 1);
+        assert_eq!(cursor, None);
+        assert_eq!(partial, format!("1,{},1", hex::encode([1u8; 32])));
+
+        // the restarted worker picks up the sub-block cursor and gets the
+        // whole block again from the reducers
+        let mut worker = Worker::new(config.clone(), Default::default());
+        worker.bootstrap().unwrap();
+
+        worker
+            .apply(CRDTCommand::BlockStarting(point.clone()))
+            .unwrap();
+
+        for cmd in block.iter().cloned() {
+            worker.apply(cmd).unwrap();
+        }
+
+        worker.apply(CRDTCommand::BlockFinished(point)).unwrap();
+
+        let a: i64 = check.get("_test_partial.a").unwrap();
+        let b: i64 = check.get("_test_partial.b").unwrap();
+        let s: bool = check.sismember("_test_partial.s", "x").unwrap();
+        let cursor: String = check.get(config.cursor_key()).unwrap();
+        let partial: bool = check.exists(config.sub_block_cursor_key()).unwrap();
+
+        assert_eq!(a, 2);
+        assert_eq!(b, 1);
+        assert!(s);
+        assert_eq!(cursor, format!("1,{}", hex::encode([1u8; 32])));
+        assert!(!partial);
+    }
+
+    #[test]
+    fn prefix_pattern_is_escaped() {
+        assert_eq!(escape_pattern("c1"), "c1");
+        assert_eq!(escape_pattern("a*b?[c]"), "a\\*b\\?\\[c\\]");
+
+        let prefix = crate::crosscut::keys::Prefix::new(Some("c1"), None);
+        assert_eq!(prefix_pattern(prefix), "c1.*");
+
+        let prefix = crate::crosscut::keys::Prefix::new(Some("c*1"), Some(":"));
+        assert_eq!(prefix_pattern(prefix), "c\\*1:*");
+    }
+}
@@ -116,6 +116,9 @@ impl gasket::runtime::Worker for Worker {
                     delta
                 );
             }
+            model::CRDTCommand::SortedSetTrim(key, limit) => {
+                log::debug!("trimming sorted set [{}], limit [{}]", key, limit);
+            }
             model::CRDTCommand::SetRemove(key, value) => {
                 log::debug!("removing from set [{}], value [{}]", key, value);
             }
@@ -128,6 +131,18 @@ impl gasket::runtime::Worker for Worker {
             model::CRDTCommand::PNCounter(key, value) => {
                 log::debug!("increasing counter [{}], by [{}]", key, value);
             }
+            model::CRDTCommand::MaxWins(key, value) => {
+                log::debug!("max wins for [{}], value [{}]", key, value);
+            }
+            model::CRDTCommand::MinWins(key, value) => {
+                log::debug!("min wins for [{}], value [{}]", key, value);
+            }
+            model::CRDTCommand::ListPrepend(key, _, limit) => {
+                log::debug!("prepending to list [{}], limit [{}]", key, limit);
+            }
+            model::CRDTCommand::ListPop(key) => {
+                log::debug!("popping from list [{}]", key);
+            }
             model::CRDTCommand::HashSetValue(key, member, _) => {
                 log::debug!("setting hash key {} member {}", key, member);
             }
@@ -137,6 +152,12 @@ impl gasket::runtime::Worker for Worker {
             model::CRDTCommand::HashUnsetKey(key, member) => {
                 log::debug!("deleting hash key {} member {}", member, key);
             }
+            model::CRDTCommand::DeleteKey(key) => {
+                log::debug!("deleting key [{}]", key);
+            }
+            model::CRDTCommand::RollbackMarker(point) => {
+                log::debug!("rollback to {:?}", point);
+            }
             model::CRDTCommand::BlockFinished(point) => {
                 log::debug!("block finished {:?}", point);
                 let mut last_point = self.last_point.lock().unwrap();
@@ -0,0 +1,354 @@
+use std::time::Duration;
+
+use gasket::{
+    error::AsWorkError,
+    runtime::{spawn_stage, WorkOutcome},
+};
+
+use hmac::{Hmac, Mac};
+use pallas::network::miniprotocols::Point;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::Sha256;
+
+use crate::{bootstrap, crosscut, model, storage};
+
+type InputPort = gasket::messaging::TwoPhaseInputPort<model::CRDTCommand>;
+
+pub const SIGNATURE_HEADER: &str = "X-Scrolls-Signature";
+
+#[derive(Deserialize, Clone)]
+pub struct Config {
+    pub url: String,
+
+    /// Shared secret used to sign each request body with HMAC-SHA256. The
+    /// hex digest is sent in the `X-Scrolls-Signature` header as
+    /// `sha256=<digest>`.
+    pub secret: Option<String>,
+
+    /// File where the cursor is stored once a block is accepted by the
+    /// endpoint. If not set, the pipeline always starts from the configured
+    /// intersect.
+    pub cursor_path: Option<String>,
+
+    pub timeout_secs: Option<u64>,
+}
+
+impl Config {
+    pub fn bootstrapper(
+        self,
+        _chain: &crosscut::ChainWellKnownInfo,
+        _intersect: &crosscut::IntersectConfig,
+    ) -> Bootstrapper {
+        Bootstrapper {
+            config: self,
+            input: Default::default(),
+        }
+    }
+}
+
+pub struct Bootstrapper {
+    config: Config,
+    input: InputPort,
+}
+
+impl Bootstrapper {
+    pub fn borrow_input_port(&mut self) -> &'_ mut InputPort {
+        &mut self.input
+    }
+
+    pub fn build_cursor(&self) -> Cursor {
+        Cursor {
+            config: self.config.clone(),
+        }
+    }
+
+    pub fn spawn_stages(self, pipeline: &mut bootstrap::Pipeline) {
+        let worker = Worker::new(self.config, self.input);
+
+        pipeline.register_stage(spawn_stage(
+            worker,
+            gasket::runtime::Policy {
+                tick_timeout: Some(Duration::from_secs(600)),
+                work_retry: gasket::retries::Policy {
+                    max_retries: 20,
+                    backoff_unit: Duration::from_secs(1),
+                    backoff_factor: 2,
+                    max_backoff: Duration::from_secs(60),
+                },
+                ..Default::default()
+            },
+            Some("webhook"),
+        ));
+    }
+}
+
+pub struct Cursor {
+    config: Config,
+}
+
+impl Cursor {
+    pub fn last_point(&mut self) -> Result<Option<crosscut::PointArg>, crate::Error> {
+        match &self.config.cursor_path {
+            Some(path) => storage::read_cursor_file(path),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Hex encoded HMAC-SHA256 of a request body, as sent in the signature header
+pub fn sign(secret: &str, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key size");
+
+    mac.update(body);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+pub struct Worker {
+    config: Config,
+    agent: ureq::Agent,
+    pending: Vec<model::CRDTCommand>,
+    ops_count: gasket::metrics::Counter,
+    failed_count: gasket::metrics::Counter,
+    input: InputPort,
+}
+
+impl Worker {
+    pub fn new(config: Config, input: InputPort) -> Self {
+        let timeout = Duration::from_secs(config.timeout_secs.unwrap_or(30));
+
+        Self {
+            agent: ureq::AgentBuilder::new().timeout(timeout).build(),
+            config,
+            input,
+            pending: Vec::new(),
+            ops_count: Default::default(),
+            failed_count: Default::default(),
+        }
+    }
+
+    fn post(&self, body: String) -> Result<(), crate::Error> {
+        let mut request = self
+            .agent
+            .post(&self.config.url)
+            .set("Content-Type", "application/json");
+
+        if let Some(secret) = &self.config.secret {
+            request = request.set(SIGNATURE_HEADER, &sign(secret, body.as_bytes()));
+        }
+
+        // non-2xx responses are reported by ureq as errors too
+        request.send_string(&body).map_err(crate::Error::storage)?;
+
+        Ok(())
+    }
+
+    /// Posts a command that isn't part of any block, such as rollback markers
+    fn send_marker(&mut self, cmd: model::CRDTCommand) -> Result<(), gasket::error::Error> {
+        let body = json!({ "commands": [cmd.to_json()] }).to_string();
+
+        if let Err(err) = self.post(body) {
+            log::warn!("failed to post marker to webhook, will retry: {}", err);
+            self.failed_count.inc(1);
+            return Err(err).or_retry();
+        }
+
+        Ok(())
+    }
+
+    /// Posts the buffered commands of the block and then saves the cursor
+    ///
+    /// The block is sent as a single request. If it fails, the whole block is
+    /// retried; the endpoint may receive a block more than once but the
+    /// cursor never moves past a block that wasn't accepted.
+    fn flush_block(&mut self, point: Point) -> Result<(), gasket::error::Error> {
+        let commands: Vec<_> = self.pending.iter().map(|cmd| cmd.to_json()).collect();
+
+        let body = json!({
+            "block": model::point_to_json(&point),
+            "commands": commands,
+        })
+        .to_string();
+
+        if let Err(err) = self.post(body) {
+            log::warn!("failed to post block to webhook, will retry: {}", err);
+            self.failed_count.inc(1);
+            return Err(err).or_retry();
+        }
+
+        if let Some(path) = &self.config.cursor_path {
+            storage::write_cursor_file(path, crosscut::PointArg::from(point)).or_retry()?;
+        }
+
+        self.ops_count.inc(self.pending.len() as u64);
+        self.pending.clear();
+
+        Ok(())
+    }
+}
+
+impl gasket::runtime::Worker for Worker {
+    fn metrics(&self) -> gasket::metrics::Registry {
+        gasket::metrics::Builder::new()
+            .with_counter("storage_ops", &self.ops_count)
+            .with_counter("storage_failed_requests", &self.failed_count)
+            .build()
+    }
+
+    fn work(&mut self) -> gasket::runtime::WorkResult {
+        let msg = self.input.recv_or_idle()?;
+
+        match msg.payload {
+            model::CRDTCommand::BlockStarting(_) => {
+                self.pending.clear();
+            }
+            model::CRDTCommand::BlockFinished(point) => {
+                // the input is only committed once the endpoint accepted the
+                // block
+                self.flush_block(point)?;
+            }
+            cmd @ model::CRDTCommand::RollbackMarker(_) => {
+                self.send_marker(cmd)?;
+            }
+            cmd => {
+                self.pending.push(cmd);
+            }
+        };
+
+        self.input.commit();
+
+        Ok(WorkOutcome::Partial)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    use pallas::network::miniprotocols::Point;
+
+    use super::{sign, Config, Worker, SIGNATURE_HEADER};
+    use crate::{model::CRDTCommand, storage};
+
+    struct Request {
+        headers: Vec<String>,
+        body: String,
+    }
+
+    /// Serves a single request with the given status and hands it back
+    fn mock_server(status: &'static str) -> (String, mpsc::Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (tx, rx) = mpsc::channel();
+
+        std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+
+            let mut headers = Vec::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                let line = line.trim_end().to_string();
+
+                if line.is_empty() {
+                    break;
+                }
+
+                headers.push(line);
+            }
+
+            let length = headers
+                .iter()
+                .find_map(|h| {
+                    h.to_lowercase()
+                        .strip_prefix("content-length:")
+                        .map(|x| x.trim().parse::<usize>().unwrap())
+                })
+                .unwrap_or_default();
+
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body).unwrap();
+
+            let mut stream = stream;
+            write!(stream, "HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status).unwrap();
+
+            tx.send(Request {
+                headers,
+                body: String::from_utf8(body).unwrap(),
+            })
+            .unwrap();
+        });
+
+        (url, rx)
+    }
+
+    fn config(url: String, cursor_path: &str) -> Config {
+        let _ = std::fs::remove_file(cursor_path);
+
+        Config {
+            url,
+            secret: Some("s3cr3t".into()),
+            cursor_path: Some(cursor_path.to_string()),
+            timeout_secs: Some(5),
+        }
+    }
+
+    fn cursor_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!(
+            "scrolls-webhook-{}-{}.cursor",
+            name,
+            std::process::id()
+        ));
+
+        path.to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn posts_signed_block() {
+        let (url, requests) = mock_server("200 OK");
+        let cursor = cursor_path("ok");
+        let mut worker = Worker::new(config(url, &cursor), Default::default());
+
+        worker.pending.push(CRDTCommand::PNCounter("a".into(), 1));
+        worker
+            .flush_block(Point::Specific(10, vec![1u8; 32]))
+            .unwrap();
+
+        let request = requests.recv().unwrap();
+
+        let body: serde_json::Value = serde_json::from_str(&request.body).unwrap();
+        assert_eq!(body["block"]["slot"], 10);
+        assert_eq!(body["commands"][0]["type"], "pn_counter");
+
+        let expected = format!(
+            "{}: {}",
+            SIGNATURE_HEADER.to_lowercase(),
+            sign("s3cr3t", request.body.as_bytes())
+        );
+
+        assert!(request.headers.iter().any(|h| h.to_lowercase() == expected));
+        assert!(storage::read_cursor_file(&cursor).unwrap().is_some());
+    }
+
+    #[test]
+    fn failed_post_keeps_cursor() {
+        let (url, requests) = mock_server("500 Internal Server Error");
+        let cursor = cursor_path("failed");
+        let mut worker = Worker::new(config(url, &cursor), Default::default());
+
+        worker.pending.push(CRDTCommand::PNCounter("a".into(), 1));
+        assert!(worker
+            .flush_block(Point::Specific(10, vec![1u8; 32]))
+            .is_err());
+
+        requests.recv().unwrap();
+
+        assert!(storage::read_cursor_file(&cursor).unwrap().is_none());
+        assert_eq!(worker.pending.len(), 1);
+    }
+}